@@ -0,0 +1,195 @@
+//! Index and query latency over a generated library, `cargo bench --bench search`
+//!
+//! The library is synthetic (no audio files) so runs are comparable between machines. Indexes
+//! and their stores go to temporary directories, `./data` isn't used.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use tantivy::query::AllQuery;
+use tempfile::TempDir;
+
+use audio_playground::library_roots::DEFAULT_ROOT_ID;
+use audio_playground::schema::{
+    DocumentSearchRequest, Faceted, Filters, OrderBy, OrderType, SearchWatcher, TrackJson,
+    TrackStatus,
+};
+
+const LIBRARY_SIZE: usize = 100_000;
+
+const WORDS: [&str; 24] = [
+    "love", "night", "river", "electric", "blue", "dream", "fire", "summer", "ghost", "city",
+    "heart", "golden", "rain", "shadow", "wild", "echo", "silver", "ocean", "dance", "lost",
+    "star", "winter", "machine", "garden",
+];
+const GENRES: [&str; 12] = [
+    "Rock", "Pop", "Jazz", "Techno", "Hip-Hop", "Metal", "Folk", "Blues", "Classical", "Soul",
+    "Ambient", "Reggae",
+];
+
+/// Deterministic, so every run indexes the same library
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self, bound: usize) -> usize {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        ((self.0 >> 33) as usize) % bound
+    }
+
+    fn words(&mut self, count: usize) -> String {
+        (0..count)
+            .map(|_| WORDS[self.next(WORDS.len())])
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// ~2000 artists with 5 albums of 10 tracks each
+fn synthetic_library(size: usize) -> Vec<TrackJson> {
+    let mut random = Lcg(42);
+    let mut tracks = Vec::with_capacity(size);
+    for index in 0..size {
+        let album_index = index / 10;
+        let artist_index = album_index / 5;
+        let mut album_random = Lcg(album_index as u64);
+        let mut artist_random = Lcg(artist_index as u64 + 1_000_000);
+
+        let artist = format!("{} {}", artist_random.words(2), artist_index);
+        let album = album_random.words(3);
+        let genre = GENRES[artist_index % GENRES.len()].to_string();
+        let track = random.words(1 + random.next(4));
+        tracks.push(TrackJson {
+            id: format!("track-{}", index),
+            abs_path: format!("/music/{}/{}/{:02} {}.mp3", artist, album, index % 10 + 1, track),
+            root_id: DEFAULT_ROOT_ID.to_string(),
+            created_date: 1_600_000_000_000 + index as i64 * 60_000,
+            modified_date: 1_600_000_000_000 + index as i64 * 60_000,
+            indexed_date: 1_650_000_000_000,
+            size: 3_000_000 + random.next(7_000_000) as i64,
+            album,
+            artists: vec![artist.clone()],
+            artist,
+            genre: genre.clone(),
+            genres: vec![genre],
+            name: format!("{:02} {}", index % 10 + 1, track),
+            track,
+            duration: 120.0 + random.next(300) as f64,
+            year: 1960 + (album_index % 64) as u64,
+            album_artist: String::new(),
+            track_number: (index % 10 + 1) as u64,
+            disc_number: 1,
+            lyrics: String::new(),
+            synced_lyrics: vec![],
+            start: 0.0,
+            end: 0.0,
+            content_kind: None,
+            chapters: vec![],
+        });
+    }
+    tracks
+}
+
+fn index_library(directory: &TempDir, tracks: &[TrackJson]) -> SearchWatcher {
+    let (index_directory, data_directory) = directories(directory);
+    let search_watcher = SearchWatcher::with_data_directory(&index_directory, &data_directory)
+        .expect("the index can be created");
+    {
+        let mut writer = search_watcher.writer().expect("read-write handle");
+        for track in tracks {
+            writer
+                .add_document(search_watcher.document_for(track, TrackStatus::Online))
+                .expect("the document can be added");
+        }
+        writer.commit().expect("the index can be committed");
+    }
+    search_watcher.reader.reload().expect("the reader can reload");
+    search_watcher
+}
+
+/// The index and the stores (user data, albums...) of a benchmark, both in `directory`
+fn directories(directory: &TempDir) -> (String, String) {
+    let path = |name: &str| directory.path().join(name).to_string_lossy().to_string();
+    (path("index"), path("data"))
+}
+
+fn request(text: &str) -> DocumentSearchRequest {
+    DocumentSearchRequest {
+        text: text.to_string(),
+        fields: vec![],
+        filters: Filters::default(),
+        order: None,
+        faceted: None,
+        page_number: 0,
+        result_per_page: 50,
+        reload: false,
+        explain: false,
+        search_after: None,
+    }
+}
+
+fn bench_index(c: &mut Criterion) {
+    let tracks = synthetic_library(LIBRARY_SIZE);
+    let mut group = c.benchmark_group("index");
+    group.sample_size(10);
+    group.bench_function("100k tracks", |b| {
+        b.iter_batched(
+            || TempDir::new().expect("a temporary directory"),
+            |directory| index_library(&directory, &tracks),
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+fn bench_queries(c: &mut Criterion) {
+    let directory = TempDir::new().expect("a temporary directory");
+    index_library(&directory, &synthetic_library(LIBRARY_SIZE));
+    // searches go through a read-only handle, as the helpers in `main.rs` do
+    let (index_directory, data_directory) = directories(&directory);
+    let search_watcher =
+        SearchWatcher::open_read_only_with_data_directory(&index_directory, &data_directory)
+            .expect("the index can be opened read-only");
+
+    let mut group = c.benchmark_group("query");
+    group.bench_function("text", |b| {
+        b.iter(|| search_watcher.do_search(&request("electric dream"), false))
+    });
+    group.bench_function("empty sorted by year", |b| {
+        let mut request = request("");
+        request.order = Some(OrderBy {
+            field: "year".to_string(),
+            order_type: OrderType::Desc,
+        });
+        b.iter(|| search_watcher.do_search(&request, false))
+    });
+    group.bench_function("facet filter", |b| {
+        let mut request = request("love");
+        request.filters.facets = vec!["/genre/Jazz".to_string(), "/genre/Soul".to_string()];
+        b.iter(|| search_watcher.do_search(&request, false))
+    });
+    group.bench_function("facet counts", |b| {
+        let mut request = request("");
+        request.faceted = Some(Faceted {
+            tags: vec!["/genre".to_string(), "/year".to_string()],
+        });
+        b.iter(|| search_watcher.do_search(&request, true))
+    });
+    group.bench_function("deep page with cursor", |b| {
+        let mut request = request("");
+        for _ in 0..20 {
+            let response = search_watcher
+                .do_search(&request, false)
+                .expect("the search runs");
+            request.search_after = response.cursor;
+        }
+        b.iter(|| search_watcher.do_search(&request, false))
+    });
+    group.bench_function("all artists aggregation", |b| {
+        b.iter(|| search_watcher.term_counts(&AllQuery, "artist"))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_index, bench_queries);
+criterion_main!(benches);
@@ -0,0 +1,125 @@
+use tantivy::aggregation::agg_req::{
+    Aggregation, Aggregations, BucketAggregation, BucketAggregationType, MetricAggregation,
+};
+use tantivy::aggregation::agg_result::{
+    AggregationResult, AggregationResults, BucketEntry, BucketResult, MetricResult,
+};
+use tantivy::aggregation::bucket::{
+    CustomOrder, HistogramAggregation, Order, OrderTarget, TermsAggregation,
+};
+use tantivy::aggregation::metric::{Stats, StatsAggregation};
+use tantivy::aggregation::{AggregationCollector, Key};
+use tantivy::query::Query;
+use tantivy::Searcher;
+
+use crate::error::Result;
+use crate::schema::SearchWatcher;
+
+/// Size of a terms aggregation that should return every value (e.g. all artists)
+pub const ALL_TERMS: u32 = 100_000;
+
+/// Buckets for the values of a `STRING | FAST` field, by number of docs unless `key_order` is set
+pub fn terms(field: &str, size: u32, key_order: Option<Order>) -> Aggregation {
+    terms_with_sub_aggregation(field, size, key_order, Aggregations::default())
+}
+
+pub fn terms_with_sub_aggregation(
+    field: &str,
+    size: u32,
+    key_order: Option<Order>,
+    sub_aggregation: Aggregations,
+) -> Aggregation {
+    Aggregation::Bucket(BucketAggregation {
+        bucket_agg: BucketAggregationType::Terms(TermsAggregation {
+            field: field.to_string(),
+            size: Some(size),
+            order: key_order.map(|order| CustomOrder {
+                target: OrderTarget::Key,
+                order,
+            }),
+            ..Default::default()
+        }),
+        sub_aggregation,
+    })
+}
+
+/// One bucket per year
+pub fn year_histogram() -> Aggregation {
+    Aggregation::Bucket(BucketAggregation {
+        bucket_agg: BucketAggregationType::Histogram(HistogramAggregation {
+            field: "year".to_string(),
+            interval: 1.0,
+            ..Default::default()
+        }),
+        sub_aggregation: Default::default(),
+    })
+}
+
+/// Count, sum, min, max... of a numeric fast field
+pub fn stats(field: &str) -> Aggregation {
+    Aggregation::Metric(MetricAggregation::Stats(StatsAggregation {
+        field: field.to_string(),
+    }))
+}
+
+pub fn aggregations(named: Vec<(&str, Aggregation)>) -> Aggregations {
+    named
+        .into_iter()
+        .map(|(name, aggregation)| (name.to_string(), aggregation))
+        .collect()
+}
+
+pub fn aggregate(
+    searcher: &Searcher,
+    query: &dyn Query,
+    request: Aggregations,
+) -> Result<AggregationResults> {
+    let collector = AggregationCollector::from_aggs(request);
+    Ok(searcher.search(query, &collector)?)
+}
+
+/// Buckets of the terms or histogram aggregation `name`, empty when there's no such result
+pub fn buckets<'a>(results: &'a AggregationResults, name: &str) -> &'a [BucketEntry] {
+    match results.0.get(name) {
+        Some(AggregationResult::BucketResult(BucketResult::Terms { buckets, .. }))
+        | Some(AggregationResult::BucketResult(BucketResult::Histogram { buckets })) => buckets,
+        _ => &[],
+    }
+}
+
+/// Value and doc count of every bucket of `name`, histogram keys are formatted as integers
+pub fn term_counts(results: &AggregationResults, name: &str) -> Vec<(String, u64)> {
+    buckets(results, name)
+        .iter()
+        .map(|bucket| (key_string(&bucket.key), bucket.doc_count))
+        .collect()
+}
+
+pub fn stats_result(results: &AggregationResults, name: &str) -> Option<Stats> {
+    match results.0.get(name) {
+        Some(AggregationResult::MetricResult(MetricResult::Stats(stats))) => Some(stats.clone()),
+        _ => None,
+    }
+}
+
+pub fn key_string(key: &Key) -> String {
+    match key {
+        Key::Str(value) => value.clone(),
+        Key::F64(value) => format!("{}", value.trunc() as i64),
+    }
+}
+
+impl SearchWatcher {
+    pub fn aggregate(&self, query: &dyn Query, request: Aggregations) -> Result<AggregationResults> {
+        aggregate(&self.reader.searcher(), query, request)
+    }
+
+    /// Every value of `field` in the docs matching `query` with its number of docs, by value
+    pub fn term_counts(&self, query: &dyn Query, field: &str) -> Result<Vec<(String, u64)>> {
+        let results = self.aggregate(
+            query,
+            aggregations(vec![("terms", terms(field, ALL_TERMS, Some(Order::Asc)))]),
+        )?;
+        Ok(term_counts(&results, "terms"))
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use audiotags::Tag;
+use log::{info, trace};
+use serde::{Deserialize, Serialize};
+use slug::slugify;
+
+use crate::content_kind::ContentKind;
+use crate::error::{AudioError, Result};
+use crate::json_store::JsonStore;
+use crate::schema::SearchWatcher;
+use crate::utils::file_ext;
+
+pub const ALBUMS_FILE: &str = "./data/albums.json";
+
+/// Looked for (case insensitive) next to the tracks before falling back to embedded art
+const COVER_FILE_NAMES: [&str; 5] = ["cover", "folder", "front", "album", "albumart"];
+const COVER_FILE_TYPES: [&str; 4] = ["jpg", "jpeg", "png", "webp"];
+
+/// The album artist (or the artist for untagged albums), album and year, so two
+/// "Greatest Hits" of different artists are different albums
+pub fn album_id(album_artist: &str, album: &str, year: u64) -> String {
+    slugify(format!("{}-{}-{}", album_artist, album, year))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CoverRef {
+    /// An image file next to the tracks
+    Sidecar { path: String },
+    /// Embedded in the tags of this track
+    Embedded { track_path: String },
+}
+
+impl CoverRef {
+    /// The image and its mime type
+    pub fn read(&self) -> Result<(Vec<u8>, String)> {
+        match self {
+            CoverRef::Sidecar { path } => {
+                let mime_type = match file_ext(path).to_lowercase().as_str() {
+                    "png" => "image/png",
+                    "webp" => "image/webp",
+                    _ => "image/jpeg",
+                };
+                Ok((std::fs::read(path)?, mime_type.to_string()))
+            }
+            CoverRef::Embedded { track_path } => {
+                let cover = if file_ext(track_path).eq_ignore_ascii_case("wav") {
+                    id3::Tag::read_from_wav_path(track_path)
+                        .map_err(|e| AudioError::tag(track_path, e))?
+                        .pictures()
+                        .next()
+                        .map(|picture| (picture.data.clone(), picture.mime_type.clone()))
+                } else {
+                    Tag::new()
+                        .read_from_path(track_path)
+                        .map_err(|e| AudioError::tag(track_path, e))?
+                        .album_cover()
+                        .map(|cover| (cover.data.to_vec(), String::from(cover.mime_type)))
+                };
+                cover.ok_or_else(|| AudioError::tag(track_path, "no embedded cover"))
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct AlbumTrack {
+    pub id: String,
+    pub name: String,
+    pub artist: String,
+    pub disc_number: u64,
+    pub track_number: u64,
+    pub duration: f64,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct Album {
+    pub id: String,
+    pub title: String,
+    pub album_artist: String,
+    pub year: u64,
+    pub genres: Vec<String>,
+    pub cover: Option<CoverRef>,
+    /// Ordered by disc then track number
+    pub tracks: Vec<AlbumTrack>,
+    pub track_count: usize,
+    pub disc_count: u64,
+    /// Seconds
+    pub total_duration: f64,
+    pub total_size: i64,
+    /// Latest `created_date` of its tracks, for "recently added" lists
+    pub created_date: i64,
+}
+
+/// `Album` without its tracks, for lists
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct AlbumSummary {
+    pub id: String,
+    pub title: String,
+    pub album_artist: String,
+    pub year: u64,
+    pub cover: Option<CoverRef>,
+    pub track_count: usize,
+    pub total_duration: f64,
+}
+
+impl From<&Album> for AlbumSummary {
+    fn from(album: &Album) -> Self {
+        AlbumSummary {
+            id: album.id.clone(),
+            title: album.title.clone(),
+            album_artist: album.album_artist.clone(),
+            year: album.year,
+            cover: album.cover.clone(),
+            track_count: album.track_count,
+            total_duration: album.total_duration,
+        }
+    }
+}
+
+/// Keyed by `Album.id`, rebuilt as a whole after each index pass
+pub type AlbumStore = JsonStore<Album>;
+
+impl SearchWatcher {
+    /// Sorted by album artist, then year and title
+    pub fn list_albums(&self) -> Vec<AlbumSummary> {
+        let mut summaries: Vec<AlbumSummary> =
+            self.albums.values().iter().map(AlbumSummary::from).collect();
+        summaries.sort_by(|a, b| {
+            a.album_artist
+                .to_lowercase()
+                .cmp(&b.album_artist.to_lowercase())
+                .then(a.year.cmp(&b.year))
+                .then(a.title.to_lowercase().cmp(&b.title.to_lowercase()))
+        });
+        summaries
+    }
+
+    pub fn album(&self, album_id: &str) -> Option<Album> {
+        self.albums.get(album_id)
+    }
+
+    /// Groups every indexed track (but audiobooks) into its album, called after each index pass.
+    /// Covers of albums that were already known are kept rather than searched again.
+    pub fn rebuild_albums(&self) -> Result<usize> {
+        let mut albums: BTreeMap<String, Album> = BTreeMap::new();
+        let mut first_paths: BTreeMap<String, String> = BTreeMap::new();
+
+        for track in self.all_tracks() {
+            // audiobooks are grouped into books instead, see `rebuild_books`
+            if track.album_id.is_empty() || track.content_kind == ContentKind::Audiobook {
+                continue;
+            }
+
+            let album = albums
+                .entry(track.album_id.clone())
+                .or_insert_with(|| Album {
+                    id: track.album_id.clone(),
+                    title: track.album.clone(),
+                    album_artist: track.album_artist.clone(),
+                    year: track.year,
+                    ..Default::default()
+                });
+            for genre in &track.genres {
+                if !album.genres.contains(genre) {
+                    album.genres.push(genre.clone());
+                }
+            }
+            album.total_duration += track.duration;
+            album.total_size += track.size;
+            album.created_date = album.created_date.max(track.created_date);
+            album.tracks.push(AlbumTrack {
+                id: track.id.clone(),
+                name: track.name.clone(),
+                artist: track.artist.clone(),
+                disc_number: track.disc_number,
+                track_number: track.track_number,
+                duration: track.duration,
+            });
+            first_paths
+                .entry(track.album_id.clone())
+                .or_insert(track.abs_path);
+        }
+
+        for album in albums.values_mut() {
+            album.tracks.sort_by(|a, b| {
+                a.disc_number
+                    .cmp(&b.disc_number)
+                    .then(a.track_number.cmp(&b.track_number))
+                    .then(a.name.cmp(&b.name))
+            });
+            album.track_count = album.tracks.len();
+            album.disc_count = album
+                .tracks
+                .iter()
+                .map(|track| track.disc_number)
+                .max()
+                .unwrap_or(0)
+                .max(1);
+
+            album.cover = match self.albums.get(&album.id).and_then(|known| known.cover) {
+                Some(cover) => Some(cover),
+                None => first_paths
+                    .get(&album.id)
+                    .and_then(|path| find_cover(Path::new(path))),
+            };
+        }
+
+        let count = albums.len();
+        self.albums.replace_all(albums)?;
+        info!("{} album(s)", count);
+        Ok(count)
+    }
+}
+
+/// A cover image in the folder of `track_path`, or else the art embedded in the track
+pub fn find_cover(track_path: &Path) -> Option<CoverRef> {
+    if let Some(folder) = track_path.parent() {
+        if let Ok(entries) = folder.read_dir() {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                let stem = path
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().to_lowercase())
+                    .unwrap_or_default();
+                let ext = file_ext(&path.to_string_lossy()).to_lowercase();
+                if COVER_FILE_NAMES.iter().any(|name| stem.starts_with(name))
+                    && COVER_FILE_TYPES.contains(&ext.as_str())
+                {
+                    return Some(CoverRef::Sidecar {
+                        path: path.to_string_lossy().to_string(),
+                    });
+                }
+            }
+        }
+    }
+
+    let track_path_string = track_path.to_string_lossy().to_string();
+    let has_embedded = if file_ext(&track_path_string).eq_ignore_ascii_case("wav") {
+        id3::Tag::read_from_wav_path(track_path)
+            .map(|tag| tag.pictures().next().is_some())
+            .unwrap_or(false)
+    } else {
+        match Tag::new().read_from_path(track_path) {
+            Ok(tag) => tag.album_cover().is_some(),
+            Err(e) => {
+                trace!("No tags for {:?}: {}", track_path, e);
+                false
+            }
+        }
+    };
+
+    if has_embedded {
+        Some(CoverRef::Embedded {
+            track_path: track_path_string,
+        })
+    } else {
+        None
+    }
+}
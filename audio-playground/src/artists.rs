@@ -0,0 +1,156 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::settings::{Setting, SETTINGS};
+
+/// Separators of artist tags when `artist_separators` isn't set. Matched case-insensitively,
+/// `\0` is how ID3v2.4 stores multiple values.
+pub const DEFAULT_ARTIST_SEPARATORS: [&str; 8] = [
+    "\0",
+    ";",
+    " / ",
+    " & ",
+    " feat. ",
+    " feat ",
+    " ft. ",
+    " featuring ",
+];
+
+lazy_static! {
+    /// `Song (feat. A & B)`, `Song [ft. A]`, `Song (with A)`
+    static ref FEATURED_IN_BRACKETS: Regex =
+        Regex::new(r"(?i)[(\[]\s*(?:feat\.?|ft\.|featuring|with)\s+([^)\]]+)[)\]]").unwrap();
+    /// `Song feat. A`, "with" is left out as it's too common in plain titles
+    static ref FEATURED_AT_END: Regex =
+        Regex::new(r"(?i)\s(?:feat\.?|ft\.|featuring)\s+(.+)$").unwrap();
+}
+
+/// How artist tags and titles are split into the individual artists of a track
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArtistRules {
+    separators: Vec<String>,
+    /// Names that contain a separator but are one artist, e.g. `Simon & Garfunkel`
+    exceptions: Vec<String>,
+}
+
+impl Default for ArtistRules {
+    fn default() -> Self {
+        ArtistRules::new(&[], &[])
+    }
+}
+
+impl ArtistRules {
+    /// Empty `separators` uses `DEFAULT_ARTIST_SEPARATORS`
+    pub fn new(separators: &[String], exceptions: &[String]) -> Self {
+        let separators = if separators.is_empty() {
+            DEFAULT_ARTIST_SEPARATORS
+                .iter()
+                .map(|separator| separator.to_string())
+                .collect()
+        } else {
+            separators.to_vec()
+        };
+        ArtistRules {
+            separators: separators
+                .into_iter()
+                .filter(|separator| !separator.is_empty())
+                .map(|separator| separator.to_ascii_lowercase())
+                .collect(),
+            exceptions: exceptions
+                .iter()
+                .filter(|exception| !exception.is_empty())
+                .map(|exception| exception.to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    pub fn from_setting(setting: &Setting) -> Self {
+        ArtistRules::new(&setting.artist_separators, &setting.artist_split_exceptions)
+    }
+
+    /// The rules of the current settings, the defaults when they can't be read
+    pub fn current() -> Self {
+        SETTINGS
+            .read()
+            .map(|setting| ArtistRules::from_setting(&setting))
+            .unwrap_or_default()
+    }
+
+    /// `A & B; C` -> `[A, B, C]`, without duplicates (ignoring case)
+    pub fn split(&self, artist: &str) -> Vec<String> {
+        // ASCII lowercasing keeps the byte offsets, so matches can be sliced out of `artist`
+        let lower = artist.to_ascii_lowercase();
+        let protected: Vec<(usize, usize)> = self
+            .exceptions
+            .iter()
+            .flat_map(|exception| {
+                lower
+                    .match_indices(exception.as_str())
+                    .map(|(start, matched)| (start, start + matched.len()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        let is_protected = |at: usize| protected.iter().any(|&(start, end)| start <= at && at < end);
+
+        let mut names = vec![];
+        let mut start = 0;
+        let mut at = 0;
+        while at < lower.len() {
+            let separator = self
+                .separators
+                .iter()
+                .find(|separator| lower[at..].starts_with(separator.as_str()));
+            match separator {
+                Some(separator) if !is_protected(at) => {
+                    names.push(&artist[start..at]);
+                    at += separator.len();
+                    start = at;
+                }
+                _ => at += lower[at..].chars().next().map_or(1, char::len_utf8),
+            }
+        }
+        names.push(&artist[start..]);
+
+        unique(names.into_iter().map(str::trim))
+    }
+
+    /// Artists credited in the title, `21 Questions (Feat. Nate Dogg)` -> `[Nate Dogg]`
+    pub fn featured(&self, title: &str) -> Vec<String> {
+        let mut featured: Vec<&str> = FEATURED_IN_BRACKETS
+            .captures_iter(title)
+            .filter_map(|captures| captures.get(1))
+            .map(|matched| matched.as_str())
+            .collect();
+        if featured.is_empty() {
+            featured.extend(
+                FEATURED_AT_END
+                    .captures(title)
+                    .and_then(|captures| captures.get(1))
+                    .map(|matched| matched.as_str()),
+            );
+        }
+        let names: Vec<String> = featured
+            .into_iter()
+            .flat_map(|names| self.split(names))
+            .collect();
+        unique(names.iter().map(String::as_str))
+    }
+
+    /// Everyone credited on a track: the artists of the tag, then the ones featured in the title
+    pub fn track_artists(&self, artist: &str, title: &str) -> Vec<String> {
+        let mut artists = self.split(artist);
+        artists.extend(self.featured(title));
+        unique(artists.iter().map(String::as_str))
+    }
+}
+
+/// Non-empty `names` in their order, the first spelling wins when they only differ in case
+fn unique<'a>(names: impl Iterator<Item = &'a str>) -> Vec<String> {
+    let mut unique: Vec<String> = vec![];
+    for name in names.filter(|name| !name.is_empty()) {
+        if !unique.iter().any(|known| known.to_lowercase() == name.to_lowercase()) {
+            unique.push(name.to_string());
+        }
+    }
+    unique
+}
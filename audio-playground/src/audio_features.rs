@@ -0,0 +1,421 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use log::{error, warn};
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use serde::{Deserialize, Serialize};
+use tantivy::collector::TopDocs;
+use tantivy::query::{
+    BooleanQuery, BoostQuery, MoreLikeThisQuery, Occur, Query, RangeQuery, TermQuery,
+};
+use tantivy::schema::{IndexRecordOption, Term, Value};
+
+use crate::decode::{decode_path, Decoding};
+use crate::error::{AudioError, Result};
+use crate::json_store::JsonStore;
+use crate::schema::{SearchWatcher, Track, TrackJson};
+
+pub const AUDIO_FEATURES_FILE: &str = "./data/audio_features.json";
+
+const FRAME_SIZE: usize = 2048;
+const HOP_SIZE: usize = 1024;
+/// The first two minutes are enough to tell the tempo and the key of most tracks
+const MAX_ANALYSIS_SECS: f64 = 120.0;
+const MIN_BPM: f64 = 60.0;
+const MAX_BPM: f64 = 200.0;
+/// Chroma only looks at the range where notes (rather than noise/cymbals) are
+const CHROMA_MIN_HZ: f64 = 55.0;
+const CHROMA_MAX_HZ: f64 = 5000.0;
+
+/// Tracks released this many years apart are still from the same era
+const ERA_YEARS: u64 = 5;
+/// Tempos within this ratio are considered close
+const TEMPO_TOLERANCE: f64 = 0.08;
+/// Tag matches are reranked with the audio features, fetch more than needed
+const CANDIDATES_PER_RESULT: usize = 5;
+/// Share of the tag similarity in the final score, the rest comes from the audio features
+const TAG_WEIGHT: f32 = 0.5;
+
+/// Computed from the decoded audio during the scan pass, kept outside of the index
+/// (keyed by `Track.id`) so a rebuild doesn't decode the whole library again
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct AudioFeatures {
+    /// Beats per minute, 0 when no tempo could be found
+    pub tempo: f64,
+    /// Average "brightness" of the track in Hz
+    pub spectral_centroid: f64,
+    /// Energy per pitch class (C, C#, ... B), normalized to unit length
+    pub chroma: Vec<f64>,
+    /// `modified_date` of the file the features were computed from
+    pub modified_date: i64,
+}
+
+pub type AudioFeatureStore = JsonStore<AudioFeatures>;
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct SimilarTrack {
+    pub track: Track,
+    pub score: f32,
+    /// Same artist/genre/album/era, relative to the best match (0 to 1)
+    pub tag_score: f32,
+    /// Tempo, brightness and chroma (0 to 1), 0 when either track has no features
+    pub feature_score: f32,
+}
+
+impl SearchWatcher {
+    /// The `n` tracks most like `track_id`: candidates come from a `MoreLikeThisQuery` on the
+    /// tags plus the era and tempo, then get reranked by audio feature similarity
+    pub fn similar(&self, track_id: &str, n: usize) -> Result<Vec<SimilarTrack>> {
+        let searcher = self.reader.searcher();
+        let id_term = Term::from_field_text(self.field_schema.id, track_id);
+
+        let id_query = TermQuery::new(id_term.clone(), IndexRecordOption::Basic);
+        let source_address = searcher
+            .search(&id_query, &TopDocs::with_limit(1))?
+            .into_iter()
+            .next()
+            .map(|(_, doc_address)| doc_address)
+            .ok_or_else(|| AudioError::Config(format!("no track with id {:?}", track_id)))?;
+        let source_doc = searcher.doc(source_address)?;
+        let source_features = self.audio_features.get(track_id);
+
+        let mut queries: Vec<(Occur, Box<dyn Query>)> = vec![];
+
+        // Same artist, genres or album
+        let document_fields = [
+            self.field_schema.artist,
+            self.field_schema.genre,
+            self.field_schema.album,
+        ]
+        .iter()
+        .map(|field| (*field, source_doc.get_all(*field).cloned().collect::<Vec<Value>>()))
+        .filter(|(_, values)| !values.is_empty())
+        .collect();
+        let more_like_this = MoreLikeThisQuery::builder()
+            .with_min_doc_frequency(1)
+            .with_min_term_frequency(1)
+            .with_document_fields(document_fields);
+        queries.push((Occur::Should, Box::new(more_like_this)));
+
+        // Same era
+        let year = source_doc
+            .get_first(self.field_schema.year)
+            .and_then(Value::as_u64)
+            .unwrap_or(0);
+        if year > 0 {
+            let era_query = RangeQuery::new_u64(
+                self.field_schema.year,
+                year.saturating_sub(ERA_YEARS)..(year + ERA_YEARS + 1),
+            );
+            queries.push((Occur::Should, Box::new(BoostQuery::new(Box::new(era_query), 0.5))));
+        }
+
+        // Similar tempo
+        if let Some(features) = source_features.as_ref().filter(|f| f.tempo > 0.0) {
+            let tempo_query = RangeQuery::new_f64(
+                self.field_schema.tempo,
+                (features.tempo * (1.0 - TEMPO_TOLERANCE))..(features.tempo * (1.0 + TEMPO_TOLERANCE)),
+            );
+            queries.push((Occur::Should, Box::new(BoostQuery::new(Box::new(tempo_query), 0.5))));
+        }
+
+        queries.push((
+            Occur::MustNot,
+            Box::new(TermQuery::new(id_term, IndexRecordOption::Basic)),
+        ));
+
+        let candidates = searcher.search(
+            &BooleanQuery::new(queries),
+            &TopDocs::with_limit(n.max(1) * CANDIDATES_PER_RESULT),
+        )?;
+        let max_score = candidates
+            .first()
+            .map(|(score, _)| *score)
+            .unwrap_or(1.0)
+            .max(f32::EPSILON);
+
+        let mut results = Vec::with_capacity(candidates.len());
+        for (score, doc_address) in candidates {
+            let doc = searcher.doc(doc_address)?;
+            let track = Track::with_document(&self.field_schema, doc);
+
+            let tag_score = score / max_score;
+            let feature_score = match (&source_features, self.audio_features.get(&track.id)) {
+                (Some(source), Some(candidate)) => feature_similarity(source, &candidate),
+                _ => 0.0,
+            };
+            let score = if source_features.is_some() {
+                TAG_WEIGHT * tag_score + (1.0 - TAG_WEIGHT) * feature_score
+            } else {
+                tag_score
+            };
+
+            results.push(SimilarTrack {
+                track,
+                score,
+                tag_score,
+                feature_score,
+            });
+        }
+
+        results.sort_by(|a, b| b.score.total_cmp(&a.score));
+        results.truncate(n);
+        Ok(results)
+    }
+
+    /// Computes the features of `item` unless they are stored for the same version of the file.
+    /// Only kept in memory, the scan pass saves the store once it's done.
+    pub fn update_audio_features(&self, item: &TrackJson) {
+        let up_to_date = self
+            .audio_features
+            .get(&item.id)
+            .map(|features| features.modified_date == item.modified_date)
+            .unwrap_or(false);
+        if up_to_date {
+            return;
+        }
+
+        match compute_features(Path::new(&item.abs_path)) {
+            Ok(mut features) => {
+                features.modified_date = item.modified_date;
+                if let Err(e) = self.audio_features.insert(&item.id, features) {
+                    error!("Error storing audio features for {}: {}", item.abs_path, e);
+                }
+            }
+            Err(e) => warn!("No audio features for {}: {}", item.abs_path, e),
+        }
+    }
+}
+
+/// 0 (nothing in common) to 1 (same tempo, brightness and chroma)
+pub fn feature_similarity(a: &AudioFeatures, b: &AudioFeatures) -> f32 {
+    let ratio = |x: f64, y: f64| {
+        if x <= 0.0 || y <= 0.0 {
+            0.0
+        } else {
+            x.min(y) / x.max(y)
+        }
+    };
+
+    // tempo detection often lands on half or double the actual tempo
+    let tempo = [1.0, 0.5, 2.0]
+        .iter()
+        .map(|factor| ratio(a.tempo, b.tempo * factor))
+        .fold(0.0, f64::max);
+    let brightness = ratio(a.spectral_centroid, b.spectral_centroid);
+    let chroma: f64 = a
+        .chroma
+        .iter()
+        .zip(b.chroma.iter())
+        .map(|(x, y)| x * y)
+        .sum::<f64>()
+        .max(0.0);
+
+    (0.3 * tempo + 0.2 * brightness + 0.5 * chroma) as f32
+}
+
+pub fn compute_features(path: &Path) -> Result<AudioFeatures> {
+    let mut extractor: Option<FeatureExtractor> = None;
+    decode_path(path, |format, samples| {
+        let extractor =
+            extractor.get_or_insert_with(|| FeatureExtractor::new(format.sample_rate));
+
+        // features are computed on the mono downmix
+        for frame in samples.chunks(format.channels.max(1)) {
+            extractor.push(frame.iter().sum::<f32>() / frame.len() as f32);
+        }
+
+        if extractor.seconds() >= MAX_ANALYSIS_SECS {
+            Ok(Decoding::Stop)
+        } else {
+            Ok(Decoding::Continue)
+        }
+    })?;
+
+    extractor
+        .map(FeatureExtractor::finish)
+        .ok_or_else(|| AudioError::decode(&path.to_string_lossy(), "no audio"))
+}
+
+struct FeatureExtractor {
+    sample_rate: u32,
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    pending: Vec<f32>,
+    samples_seen: usize,
+    previous_magnitudes: Vec<f64>,
+    centroid_sum: f64,
+    centroid_frames: usize,
+    chroma: [f64; 12],
+    /// Spectral flux per frame, peaks where notes/beats start
+    onsets: Vec<f64>,
+}
+
+impl FeatureExtractor {
+    fn new(sample_rate: u32) -> Self {
+        // Hann window
+        let window = (0..FRAME_SIZE)
+            .map(|i| {
+                let phase = 2.0 * std::f32::consts::PI * i as f32 / (FRAME_SIZE - 1) as f32;
+                0.5 - 0.5 * phase.cos()
+            })
+            .collect();
+
+        FeatureExtractor {
+            sample_rate,
+            fft: FftPlanner::new().plan_fft_forward(FRAME_SIZE),
+            window,
+            pending: Vec::with_capacity(FRAME_SIZE),
+            samples_seen: 0,
+            previous_magnitudes: vec![0.0; FRAME_SIZE / 2],
+            centroid_sum: 0.0,
+            centroid_frames: 0,
+            chroma: [0.0; 12],
+            onsets: vec![],
+        }
+    }
+
+    fn seconds(&self) -> f64 {
+        self.samples_seen as f64 / self.sample_rate.max(1) as f64
+    }
+
+    fn push(&mut self, sample: f32) {
+        self.pending.push(sample);
+        self.samples_seen += 1;
+        if self.pending.len() == FRAME_SIZE {
+            self.process_frame();
+            self.pending.drain(..HOP_SIZE);
+        }
+    }
+
+    fn process_frame(&mut self) {
+        let mut buffer: Vec<Complex<f32>> = self
+            .pending
+            .iter()
+            .zip(self.window.iter())
+            .map(|(sample, weight)| Complex::new(sample * weight, 0.0))
+            .collect();
+        self.fft.process(&mut buffer);
+
+        let bin_hz = self.sample_rate as f64 / FRAME_SIZE as f64;
+        let magnitudes: Vec<f64> = buffer[..FRAME_SIZE / 2]
+            .iter()
+            .map(|bin| bin.norm() as f64)
+            .collect();
+
+        let total: f64 = magnitudes.iter().sum();
+        if total > f64::EPSILON {
+            let weighted: f64 = magnitudes
+                .iter()
+                .enumerate()
+                .map(|(k, magnitude)| k as f64 * bin_hz * magnitude)
+                .sum();
+            self.centroid_sum += weighted / total;
+            self.centroid_frames += 1;
+        }
+
+        for (k, magnitude) in magnitudes.iter().enumerate().skip(1) {
+            let frequency = k as f64 * bin_hz;
+            if !(CHROMA_MIN_HZ..=CHROMA_MAX_HZ).contains(&frequency) {
+                continue;
+            }
+            // MIDI note number, 69 is A4 (440 Hz)
+            let note = 12.0 * (frequency / 440.0).log2() + 69.0;
+            let pitch_class = (note.round() as i64).rem_euclid(12) as usize;
+            self.chroma[pitch_class] += magnitude * magnitude;
+        }
+
+        let flux: f64 = magnitudes
+            .iter()
+            .zip(self.previous_magnitudes.iter())
+            .map(|(current, previous)| (current - previous).max(0.0))
+            .sum();
+        self.onsets.push(flux);
+        self.previous_magnitudes = magnitudes;
+    }
+
+    fn finish(self) -> AudioFeatures {
+        let spectral_centroid = if self.centroid_frames > 0 {
+            self.centroid_sum / self.centroid_frames as f64
+        } else {
+            0.0
+        };
+
+        let chroma_length = self.chroma.iter().map(|x| x * x).sum::<f64>().sqrt();
+        let chroma = self
+            .chroma
+            .iter()
+            .map(|x| if chroma_length > 0.0 { x / chroma_length } else { 0.0 })
+            .collect();
+
+        AudioFeatures {
+            tempo: self.tempo(),
+            spectral_centroid,
+            chroma,
+            modified_date: 0,
+        }
+    }
+
+    /// The beat period is the lag (within `MIN_BPM`..`MAX_BPM`) where the onset envelope
+    /// correlates best with itself
+    fn tempo(&self) -> f64 {
+        let onsets_per_sec = self.sample_rate as f64 / HOP_SIZE as f64;
+        let min_lag = (60.0 * onsets_per_sec / MAX_BPM).floor().max(1.0) as usize;
+        let max_lag = (60.0 * onsets_per_sec / MIN_BPM).ceil() as usize;
+        if self.onsets.len() <= max_lag * 2 {
+            return 0.0;
+        }
+
+        let mean = self.onsets.iter().sum::<f64>() / self.onsets.len() as f64;
+        let envelope: Vec<f64> = self.onsets.iter().map(|onset| onset - mean).collect();
+
+        let mut best_lag = 0;
+        let mut best_correlation = 0.0;
+        for lag in min_lag..=max_lag {
+            let correlation: f64 = envelope
+                .iter()
+                .zip(envelope[lag..].iter())
+                .map(|(a, b)| a * b)
+                .sum();
+            if correlation > best_correlation {
+                best_correlation = correlation;
+                best_lag = lag;
+            }
+        }
+
+        if best_lag == 0 {
+            0.0
+        } else {
+            60.0 * onsets_per_sec / best_lag as f64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{index_tracks, search_watcher, track};
+
+    #[test]
+    fn similar_prefers_the_same_era() {
+        let (_directory, search_watcher) = search_watcher();
+        index_tracks(
+            &search_watcher,
+            &[
+                track(0, "Blue River", "Night Drive", "Rock", 1990),
+                // both only share the genre, the first one is from another era
+                track(1, "Golden Rain", "Later", "Rock", 2015),
+                track(2, "Silver Echo", "Earlier", "Rock", 1993),
+            ],
+        );
+
+        let similar = search_watcher.similar("track-0", 5).unwrap();
+        let ids: Vec<&str> = similar.iter().map(|result| result.track.id.as_str()).collect();
+        assert_eq!(ids, ["track-2", "track-1"]);
+        assert!(similar[0].tag_score > similar[1].tag_score);
+        // without audio features only the tags count
+        assert_eq!(similar[0].score, similar[0].tag_score);
+        assert_eq!(similar[0].feature_score, 0.0);
+    }
+}
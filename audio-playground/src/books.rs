@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+use slug::slugify;
+
+use crate::albums::{find_cover, CoverRef};
+use crate::chapters::Chapter;
+use crate::content_kind::ContentKind;
+use crate::error::Result;
+use crate::json_store::JsonStore;
+use crate::schema::{SearchWatcher, Track};
+
+pub const BOOKS_FILE: &str = "./data/books.json";
+
+/// The author (album artist or artist) and title of the book
+pub fn book_id(author: &str, title: &str) -> String {
+    slugify(format!("{}-{}", author, title))
+}
+
+/// The album tag, or the folder of the files when it's missing (`TrackJson` reads it as
+/// "untitled"). Books ripped from CDs often only have one folder per book.
+fn book_title(track: &Track) -> String {
+    if !track.album.is_empty() && track.album != "untitled" {
+        return track.album.clone();
+    }
+    Path::new(&track.abs_path)
+        .parent()
+        .and_then(Path::file_name)
+        .map(|folder| folder.to_string_lossy().to_string())
+        .unwrap_or_else(|| track.album.clone())
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct BookFile {
+    pub id: String,
+    pub name: String,
+    pub title: String,
+    pub disc_number: u64,
+    pub track_number: u64,
+    pub duration: f64,
+    pub chapters: Vec<Chapter>,
+}
+
+/// The files of one audiobook, played one after the other
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct Book {
+    pub id: String,
+    pub title: String,
+    pub author: String,
+    pub cover: Option<CoverRef>,
+    /// Ordered by disc, track number then file name
+    pub files: Vec<BookFile>,
+    pub file_count: usize,
+    /// Seconds
+    pub total_duration: f64,
+    /// Latest `created_date` of its files
+    pub created_date: i64,
+}
+
+/// Where listening to a book continues
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct BookPosition {
+    /// Index in `Book.files`
+    pub file_index: usize,
+    pub file_id: String,
+    /// Seconds into the file
+    pub position: f64,
+}
+
+/// Keyed by `Book.id`, rebuilt as a whole next to the albums
+pub type BookStore = JsonStore<Book>;
+
+impl SearchWatcher {
+    /// Sorted by author, then title
+    pub fn list_books(&self) -> Vec<Book> {
+        let mut books = self.books.values();
+        books.sort_by(|a, b| {
+            a.author
+                .to_lowercase()
+                .cmp(&b.author.to_lowercase())
+                .then(a.title.to_lowercase().cmp(&b.title.to_lowercase()))
+        });
+        books
+    }
+
+    pub fn book(&self, book_id: &str) -> Option<Book> {
+        self.books.get(book_id)
+    }
+
+    /// Groups the audiobook tracks (see `ContentKind`) into books, called after each index pass
+    /// next to `rebuild_albums`, which leaves them out
+    pub fn rebuild_books(&self) -> Result<usize> {
+        let mut books: BTreeMap<String, Book> = BTreeMap::new();
+        let mut first_paths: BTreeMap<String, String> = BTreeMap::new();
+
+        for track in self.all_tracks() {
+            if track.content_kind != ContentKind::Audiobook {
+                continue;
+            }
+            let title = book_title(&track);
+            let id = book_id(&track.album_artist, &title);
+
+            let book = books.entry(id.clone()).or_insert_with(|| Book {
+                id: id.clone(),
+                title: title.clone(),
+                author: track.album_artist.clone(),
+                ..Default::default()
+            });
+            book.total_duration += track.duration;
+            book.created_date = book.created_date.max(track.created_date);
+            book.files.push(BookFile {
+                id: track.id.clone(),
+                name: track.name.clone(),
+                title: track.track.clone(),
+                disc_number: track.disc_number,
+                track_number: track.track_number,
+                duration: track.duration,
+                chapters: track.chapters.clone(),
+            });
+            first_paths.entry(id).or_insert(track.abs_path);
+        }
+
+        for book in books.values_mut() {
+            book.files.sort_by(|a, b| {
+                a.disc_number
+                    .cmp(&b.disc_number)
+                    .then(a.track_number.cmp(&b.track_number))
+                    .then(a.name.cmp(&b.name))
+            });
+            book.file_count = book.files.len();
+
+            book.cover = match self.books.get(&book.id).and_then(|known| known.cover) {
+                Some(cover) => Some(cover),
+                None => first_paths
+                    .get(&book.id)
+                    .and_then(|path| find_cover(Path::new(path))),
+            };
+        }
+
+        let count = books.len();
+        self.books.replace_all(books)?;
+        info!("{} book(s)", count);
+        Ok(count)
+    }
+
+    /// The file that was listened to last and where it was left, or the file after it when it
+    /// was played to the end. `None` for books that weren't started (or were finished).
+    pub fn book_position(&self, book_id: &str) -> Option<BookPosition> {
+        let book = self.books.get(book_id)?;
+        let (file_index, user_data) = book
+            .files
+            .iter()
+            .map(|file| self.user_data.get(&file.id))
+            .enumerate()
+            .filter(|(_, user_data)| user_data.resumed_date > 0 || user_data.last_played > 0)
+            .max_by_key(|(_, user_data)| user_data.resumed_date.max(user_data.last_played))?;
+
+        if user_data.resume_position > 0.0 {
+            return Some(BookPosition {
+                file_index,
+                file_id: book.files[file_index].id.clone(),
+                position: user_data.resume_position,
+            });
+        }
+        let next = book.files.get(file_index + 1)?;
+        Some(BookPosition {
+            file_index: file_index + 1,
+            file_id: next.id.clone(),
+            position: 0.0,
+        })
+    }
+}
@@ -0,0 +1,489 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{AudioError, Result};
+use crate::utils::file_ext;
+
+/// `moov` boxes bigger than this aren't read, they're a few KB to a few MB
+const MAX_MOOV_BYTES: u64 = 64 * 1024 * 1024;
+/// Chapter titles are short, bigger text samples are something else
+const MAX_TEXT_SAMPLE_BYTES: u32 = 64 * 1024;
+/// Sample counts of the tables are untrusted, a chapter track has a few hundred samples at most
+const MAX_CHAPTER_SAMPLES: usize = 10_000;
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct Chapter {
+    pub title: String,
+    /// Milliseconds from the start of the file
+    pub start_ms: u64,
+    /// 0 when the file doesn't say, the chapter then lasts until the next one
+    pub end_ms: u64,
+}
+
+/// Chapters of ID3 tags (`CHAP`, ordered by the top-level `CTOC`) and MP4 files (Nero `chpl` or a
+/// QuickTime chapter track). Files without chapters, or with unreadable ones, have none.
+pub fn read_chapters(path: &Path) -> Vec<Chapter> {
+    let path_string = path.to_string_lossy().to_string();
+    let chapters = match file_ext(&path_string).to_lowercase().as_str() {
+        "mp3" => id3::Tag::read_from_path(path)
+            .map(|tag| id3_chapters(&tag))
+            .map_err(|e| AudioError::tag(&path_string, e)),
+        "wav" => id3::Tag::read_from_wav_path(path)
+            .map(|tag| id3_chapters(&tag))
+            .map_err(|e| AudioError::tag(&path_string, e)),
+        "m4a" | "m4b" | "mp4" => mp4_chapters(path),
+        _ => Ok(vec![]),
+    };
+    match chapters {
+        Ok(chapters) => chapters,
+        // most files have no tag or no chapters at all, that's not worth a warning
+        Err(AudioError::Tag { .. }) => vec![],
+        Err(e) => {
+            warn!("Error reading chapters of {}: {}", path_string, e);
+            vec![]
+        }
+    }
+}
+
+/// The chapter `position_ms` is in
+pub fn chapter_at(chapters: &[Chapter], position_ms: u64) -> Option<usize> {
+    chapters
+        .iter()
+        .rposition(|chapter| chapter.start_ms <= position_ms)
+}
+
+fn id3_chapters(tag: &id3::Tag) -> Vec<Chapter> {
+    let order: Vec<String> = tag
+        .tables_of_contents()
+        .find(|toc| toc.top_level)
+        .map(|toc| toc.elements.clone())
+        .unwrap_or_default();
+    let mut frames: Vec<&id3::frame::Chapter> = tag.chapters().collect();
+    // chapters missing from the table of contents go last
+    frames.sort_by_key(|chapter| {
+        let position = order
+            .iter()
+            .position(|element_id| *element_id == chapter.element_id)
+            .unwrap_or(usize::MAX);
+        (position, chapter.start_time)
+    });
+
+    frames
+        .iter()
+        .enumerate()
+        .map(|(index, chapter)| Chapter {
+            title: chapter
+                .frames
+                .iter()
+                .find(|frame| frame.id() == "TIT2")
+                .and_then(|frame| frame.content().text())
+                .map(str::to_string)
+                .unwrap_or_else(|| format!("Chapter {}", index + 1)),
+            start_ms: chapter.start_time as u64,
+            end_ms: chapter.end_time as u64,
+        })
+        .collect()
+}
+
+fn mp4_chapters(path: &Path) -> Result<Vec<Chapter>> {
+    let mut file = File::open(path)?;
+    let moov = match read_moov(&mut file)? {
+        Some(moov) => moov,
+        None => return Ok(vec![]),
+    };
+
+    let nero = find_box(&moov, &[b"udta", b"chpl"]).and_then(nero_chapters);
+    let mut chapters = match nero {
+        Some(chapters) if !chapters.is_empty() => chapters,
+        _ => quicktime_chapters(&moov, &mut file)?,
+    };
+    // the chapter lists only have start times
+    for index in 0..chapters.len() {
+        let next_start = chapters.get(index + 1).map_or(0, |next| next.start_ms);
+        if chapters[index].end_ms == 0 {
+            chapters[index].end_ms = next_start;
+        }
+    }
+    Ok(chapters)
+}
+
+/// The payload of the top-level `moov` box, read without loading the (large) `mdat`
+fn read_moov(file: &mut File) -> Result<Option<Vec<u8>>> {
+    let length = file.metadata()?.len();
+    let mut at = 0;
+    while at + 8 <= length {
+        file.seek(SeekFrom::Start(at))?;
+        let mut header = [0u8; 8];
+        file.read_exact(&mut header)?;
+        let mut size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+        let mut header_size = 8;
+        if size == 1 {
+            let mut large_size = [0u8; 8];
+            file.read_exact(&mut large_size)?;
+            size = u64::from_be_bytes(large_size);
+            header_size = 16;
+        } else if size == 0 {
+            size = length - at;
+        }
+        if size < header_size {
+            return Ok(None);
+        }
+
+        if &header[4..8] == b"moov" {
+            let payload_size = size - header_size;
+            if payload_size > MAX_MOOV_BYTES {
+                return Ok(None);
+            }
+            let mut moov = vec![0u8; payload_size as usize];
+            file.read_exact(&mut moov)?;
+            return Ok(Some(moov));
+        }
+        at += size;
+    }
+    Ok(None)
+}
+
+/// `(type, payload)` of the boxes directly in `data`
+fn boxes(data: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut found = vec![];
+    let mut at = 0;
+    while let Some(size) = u32_at(data, at) {
+        let kind = match data.get(at + 4..at + 8) {
+            Some(kind) => kind,
+            None => break,
+        };
+        let (size, header_size) = match size {
+            0 => (data.len() - at, 8),
+            1 => match u64_at(data, at + 8) {
+                Some(size) => (size as usize, 16),
+                None => break,
+            },
+            size => (size as usize, 8),
+        };
+        match data.get(at + header_size..at.saturating_add(size)) {
+            Some(payload) if size >= header_size => found.push((kind, payload)),
+            _ => break,
+        }
+        at += size;
+    }
+    found
+}
+
+/// The payload of the first box at `path` (e.g. `udta/chpl`) below `data`
+fn find_box<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> Option<&'a [u8]> {
+    let (first, rest) = path.split_first()?;
+    let payload = boxes(data)
+        .into_iter()
+        .find(|(kind, _)| kind == first)
+        .map(|(_, payload)| payload)?;
+    if rest.is_empty() {
+        Some(payload)
+    } else {
+        find_box(payload, rest)
+    }
+}
+
+/// `chpl`: version, flags, (4 reserved bytes from version 1), a count, then per chapter its
+/// start in 100ns units and a length-prefixed title
+fn nero_chapters(chpl: &[u8]) -> Option<Vec<Chapter>> {
+    let mut at = if *chpl.first()? > 0 { 8 } else { 4 };
+    let count = *chpl.get(at)? as usize;
+    at += 1;
+
+    let mut chapters = vec![];
+    for _ in 0..count {
+        let start = u64_at(chpl, at)?;
+        let title_length = *chpl.get(at + 8)? as usize;
+        let title = chpl.get(at + 9..at + 9 + title_length)?;
+        chapters.push(Chapter {
+            title: String::from_utf8_lossy(title).to_string(),
+            start_ms: start / 10_000,
+            end_ms: 0,
+        });
+        at += 9 + title_length;
+    }
+    Some(chapters)
+}
+
+/// The text track a `tref/chap` of another track points to, one sample per chapter
+fn quicktime_chapters(moov: &[u8], file: &mut File) -> Result<Vec<Chapter>> {
+    let tracks: Vec<&[u8]> = boxes(moov)
+        .into_iter()
+        .filter(|(kind, _)| *kind == b"trak")
+        .map(|(_, payload)| payload)
+        .collect();
+    let chapter_ids: Vec<u32> = tracks
+        .iter()
+        .filter_map(|track| find_box(track, &[b"tref", b"chap"]))
+        .flat_map(|chap| (0..chap.len() / 4).filter_map(move |index| u32_at(chap, index * 4)))
+        .collect();
+    let chapter_track = tracks.iter().find(|track| {
+        find_box(track, &[b"tkhd"])
+            .and_then(track_id)
+            .map_or(false, |id| chapter_ids.contains(&id))
+    });
+    let chapter_track = match chapter_track {
+        Some(chapter_track) => chapter_track,
+        None => return Ok(vec![]),
+    };
+
+    let samples = match text_samples(chapter_track) {
+        Some(samples) => samples,
+        None => return Ok(vec![]),
+    };
+    let mut chapters = vec![];
+    for (offset, size, start_ms, end_ms) in samples {
+        if size < 2 || size > MAX_TEXT_SAMPLE_BYTES {
+            continue;
+        }
+        let mut sample = vec![0u8; size as usize];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut sample)?;
+        chapters.push(Chapter {
+            title: sample_text(&sample),
+            start_ms,
+            end_ms,
+        });
+    }
+    Ok(chapters)
+}
+
+fn track_id(tkhd: &[u8]) -> Option<u32> {
+    match tkhd.first()? {
+        0 => u32_at(tkhd, 12),
+        _ => u32_at(tkhd, 20),
+    }
+}
+
+/// `(file offset, size, start ms, end ms)` of every sample of `track`, from its sample tables
+fn text_samples(track: &[u8]) -> Option<Vec<(u64, u32, u64, u64)>> {
+    let mdhd = find_box(track, &[b"mdia", b"mdhd"])?;
+    let timescale = match mdhd.first()? {
+        0 => u32_at(mdhd, 12)?,
+        _ => u32_at(mdhd, 20)?,
+    } as u64;
+    if timescale == 0 {
+        return None;
+    }
+    let stbl = find_box(track, &[b"mdia", b"minf", b"stbl"])?;
+
+    // sizes, read first: the (untrusted) counts of `stts` only go up to the number of samples
+    let stsz = find_box(stbl, &[b"stsz"])?;
+    let fixed_size = u32_at(stsz, 4)?;
+    let sample_count = (u32_at(stsz, 8)? as usize).min(MAX_CHAPTER_SAMPLES);
+    let sizes: Vec<u32> = if fixed_size > 0 {
+        vec![fixed_size; sample_count]
+    } else {
+        (0..sample_count)
+            .map(|sample| u32_at(stsz, 12 + sample * 4))
+            .collect::<Option<_>>()?
+    };
+
+    // durations
+    let stts = find_box(stbl, &[b"stts"])?;
+    let mut times = vec![];
+    let mut time = 0u64;
+    'entries: for entry in 0..u32_at(stts, 4)? as usize {
+        let count = u32_at(stts, 8 + entry * 8)?;
+        let delta = u32_at(stts, 12 + entry * 8)? as u64;
+        for _ in 0..count {
+            if times.len() >= sample_count {
+                break 'entries;
+            }
+            times.push((time * 1000 / timescale, (time + delta) * 1000 / timescale));
+            time += delta;
+        }
+    }
+
+    // chunks and the samples in each of them
+    let chunk_offsets: Vec<u64> = match find_box(stbl, &[b"stco"]) {
+        Some(stco) => (0..u32_at(stco, 4)? as usize)
+            .map(|chunk| u32_at(stco, 8 + chunk * 4).map(u64::from))
+            .collect::<Option<_>>()?,
+        None => {
+            let co64 = find_box(stbl, &[b"co64"])?;
+            (0..u32_at(co64, 4)? as usize)
+                .map(|chunk| u64_at(co64, 8 + chunk * 8))
+                .collect::<Option<_>>()?
+        }
+    };
+    let stsc = find_box(stbl, &[b"stsc"])?;
+    let sample_to_chunk: Vec<(u32, u32)> = (0..u32_at(stsc, 4)? as usize)
+        .map(|entry| Some((u32_at(stsc, 8 + entry * 12)?, u32_at(stsc, 12 + entry * 12)?)))
+        .collect::<Option<_>>()?;
+
+    let mut samples = vec![];
+    for (chunk, chunk_offset) in chunk_offsets.iter().enumerate() {
+        let samples_per_chunk = sample_to_chunk
+            .iter()
+            .rev()
+            .find(|(first_chunk, _)| *first_chunk as usize <= chunk + 1)
+            .map_or(0, |(_, samples_per_chunk)| *samples_per_chunk);
+        let mut offset = *chunk_offset;
+        for _ in 0..samples_per_chunk {
+            let sample = samples.len();
+            let (size, (start_ms, end_ms)) = match (sizes.get(sample), times.get(sample)) {
+                (Some(size), Some(times)) => (*size, *times),
+                _ => return Some(samples),
+            };
+            samples.push((offset, size, start_ms, end_ms));
+            offset += size as u64;
+        }
+    }
+    Some(samples)
+}
+
+/// Text samples are a 16 bit length and the text, UTF-8 or UTF-16 with a byte order mark
+fn sample_text(sample: &[u8]) -> String {
+    let length = u16::from_be_bytes([sample[0], sample[1]]) as usize;
+    let text = &sample[2..(2 + length).min(sample.len())];
+    if text.starts_with(&[0xfe, 0xff]) {
+        let units: Vec<u16> = text[2..]
+            .chunks_exact(2)
+            .map(|unit| u16::from_be_bytes([unit[0], unit[1]]))
+            .collect();
+        String::from_utf16_lossy(&units)
+    } else {
+        String::from_utf8_lossy(text).to_string()
+    }
+}
+
+fn u32_at(data: &[u8], at: usize) -> Option<u32> {
+    let bytes = data.get(at..at + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn u64_at(data: &[u8], at: usize) -> Option<u64> {
+    let bytes = data.get(at..at + 8)?;
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(bytes);
+    Some(u64::from_be_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::Instant;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn mp4_box(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut data = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(kind);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    /// Version and flags, then the big endian numbers
+    fn full_box(kind: &[u8; 4], numbers: &[u32]) -> Vec<u8> {
+        let mut payload = vec![0u8; 4];
+        for number in numbers {
+            payload.extend_from_slice(&number.to_be_bytes());
+        }
+        mp4_box(kind, &payload)
+    }
+
+    /// The payload of a `trak` with a timescale of 1000, one chunk at offset 100
+    fn chapter_track(stts: &[u32], stsz: &[u32], samples_per_chunk: u32) -> Vec<u8> {
+        let mdhd = full_box(b"mdhd", &[0, 0, 1000, 0]);
+        let stbl = [
+            full_box(b"stts", stts),
+            full_box(b"stsz", stsz),
+            full_box(b"stco", &[1, 100]),
+            full_box(b"stsc", &[1, 1, samples_per_chunk, 1]),
+        ]
+        .concat();
+        let minf = mp4_box(b"minf", &mp4_box(b"stbl", &stbl));
+        mp4_box(b"mdia", &[mdhd, minf].concat())
+    }
+
+    /// Nero chapters of version 1, `count` can be more than the chapters that follow
+    fn chpl(count: u8, chapters: &[(u64, &str)]) -> Vec<u8> {
+        let mut payload = vec![1, 0, 0, 0, 0, 0, 0, 0, count];
+        for (start_ms, title) in chapters {
+            payload.extend_from_slice(&(start_ms * 10_000).to_be_bytes());
+            payload.push(title.len() as u8);
+            payload.extend_from_slice(title.as_bytes());
+        }
+        mp4_box(b"chpl", &payload)
+    }
+
+    #[test]
+    fn sample_tables() {
+        // 2 samples of 1.5s then one of 4s, sizes 10, 20, 30
+        let track = chapter_track(&[2, 2, 1500, 1, 4000], &[0, 3, 10, 20, 30], 3);
+        assert_eq!(
+            text_samples(&track),
+            Some(vec![
+                (100, 10, 0, 1500),
+                (110, 20, 1500, 3000),
+                (130, 30, 3000, 7000)
+            ])
+        );
+    }
+
+    #[test]
+    fn corrupt_sample_counts_are_bounded() {
+        let started = Instant::now();
+
+        // billions of durations for 3 samples
+        let track = chapter_track(&[1, u32::MAX, 1000], &[10, 3], 3);
+        let samples = text_samples(&track).unwrap();
+        assert_eq!(samples.len(), 3);
+        assert_eq!(samples[2], (120, 10, 2000, 3000));
+
+        // and billions of samples of a fixed size
+        let track = chapter_track(&[1, u32::MAX, 1000], &[10, u32::MAX], u32::MAX);
+        assert_eq!(text_samples(&track).unwrap().len(), MAX_CHAPTER_SAMPLES);
+
+        // entries missing from the box
+        let track = chapter_track(&[u32::MAX, 1, 1000], &[10, 3], 3);
+        assert_eq!(text_samples(&track), None);
+        let track = chapter_track(&[1, 3, 1000], &[0, 3, 10], 3);
+        assert_eq!(text_samples(&track), None);
+
+        assert!(started.elapsed().as_secs() < 5);
+    }
+
+    #[test]
+    fn nero_chapters_of_a_file() {
+        let directory = TempDir::new().unwrap();
+        let write = |name: &str, chpl: Vec<u8>| {
+            let moov = mp4_box(b"moov", &mp4_box(b"udta", &chpl));
+            let path = directory.path().join(name);
+            fs::write(&path, [mp4_box(b"ftyp", b"M4B 0000"), moov].concat()).unwrap();
+            path
+        };
+
+        let path = write("book.m4b", chpl(2, &[(0, "Opening"), (65_000, "The End")]));
+        assert_eq!(
+            read_chapters(&path),
+            vec![
+                Chapter {
+                    title: "Opening".to_string(),
+                    start_ms: 0,
+                    end_ms: 65_000,
+                },
+                Chapter {
+                    title: "The End".to_string(),
+                    start_ms: 65_000,
+                    end_ms: 0,
+                },
+            ]
+        );
+        assert_eq!(chapter_at(&read_chapters(&path), 70_000), Some(1));
+
+        // the count says 3, the box is cut off in the middle of the second title
+        let mut truncated = chpl(3, &[(0, "Opening"), (65_000, "The End")]);
+        truncated.truncate(truncated.len() - 3);
+        truncated[..4].copy_from_slice(&(truncated.len() as u32).to_be_bytes());
+        let path = write("truncated.m4b", truncated);
+        assert_eq!(read_chapters(&path), vec![]);
+    }
+}
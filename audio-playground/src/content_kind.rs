@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+
+use crate::schema::TrackJson;
+use crate::settings::{Setting, SETTINGS};
+use crate::utils::genre_name;
+
+/// Tracks at least this long (in seconds) that aren't tagged or filed as anything are `LongForm`
+pub const DEFAULT_LONG_FORM_SECS: u64 = 20 * 60;
+/// Compared case-insensitively with the genres of a track
+pub const DEFAULT_AUDIOBOOK_GENRES: [&str; 5] =
+    ["audiobook", "audio book", "audio theatre", "spoken word", "speech"];
+pub const DEFAULT_PODCAST_GENRES: [&str; 1] = ["podcast"];
+/// Compared case-insensitively with the folders of a path, everything below them is that kind
+pub const DEFAULT_AUDIOBOOK_FOLDERS: [&str; 2] = ["audiobooks", "audio books"];
+pub const DEFAULT_PODCAST_FOLDERS: [&str; 1] = ["podcasts"];
+
+/// What a track is, long-form kinds get chapters and resume positions
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentKind {
+    #[default]
+    Music = 0,
+    Audiobook = 1,
+    Podcast = 2,
+    /// Long recordings that aren't tagged or filed as either (talks, lectures, mixes)
+    LongForm = 3,
+}
+
+impl ContentKind {
+    pub fn from_u64(value: u64) -> Self {
+        match value {
+            1 => ContentKind::Audiobook,
+            2 => ContentKind::Podcast,
+            3 => ContentKind::LongForm,
+            _ => ContentKind::Music,
+        }
+    }
+
+    /// As used in the `/kind/...` facets
+    pub fn name(&self) -> &'static str {
+        match self {
+            ContentKind::Music => "music",
+            ContentKind::Audiobook => "audiobook",
+            ContentKind::Podcast => "podcast",
+            ContentKind::LongForm => "long_form",
+        }
+    }
+
+    pub fn is_long_form(&self) -> bool {
+        *self != ContentKind::Music
+    }
+}
+
+/// How tracks are classified: by genre first, then by folder, then by duration
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContentRules {
+    audiobook_genres: Vec<String>,
+    podcast_genres: Vec<String>,
+    audiobook_folders: Vec<String>,
+    podcast_folders: Vec<String>,
+    long_form_secs: f64,
+}
+
+impl Default for ContentRules {
+    fn default() -> Self {
+        ContentRules::from_setting(&Setting::default())
+    }
+}
+
+impl ContentRules {
+    /// Empty lists (and a 0 duration) use the defaults above
+    pub fn from_setting(setting: &Setting) -> Self {
+        let or_default = |values: &[String], default: &[&str]| -> Vec<String> {
+            if values.is_empty() {
+                default.iter().map(|value| value.to_string()).collect()
+            } else {
+                values.iter().map(|value| value.to_lowercase()).collect()
+            }
+        };
+        let long_form_secs = if setting.long_form_min_duration > 0 {
+            setting.long_form_min_duration
+        } else {
+            DEFAULT_LONG_FORM_SECS
+        };
+
+        ContentRules {
+            audiobook_genres: or_default(&setting.audiobook_genres, &DEFAULT_AUDIOBOOK_GENRES),
+            podcast_genres: or_default(&setting.podcast_genres, &DEFAULT_PODCAST_GENRES),
+            audiobook_folders: or_default(&setting.audiobook_folders, &DEFAULT_AUDIOBOOK_FOLDERS),
+            podcast_folders: or_default(&setting.podcast_folders, &DEFAULT_PODCAST_FOLDERS),
+            long_form_secs: long_form_secs as f64,
+        }
+    }
+
+    /// The rules of the current settings, the defaults when they can't be read
+    pub fn current() -> Self {
+        SETTINGS
+            .read()
+            .map(|setting| ContentRules::from_setting(&setting))
+            .unwrap_or_default()
+    }
+
+    pub fn classify(&self, item: &TrackJson) -> ContentKind {
+        let genres: Vec<String> = item
+            .genres
+            .iter()
+            .map(|genre| genre_name(genre).to_lowercase())
+            .collect();
+        if genres.iter().any(|genre| self.audiobook_genres.contains(genre)) {
+            return ContentKind::Audiobook;
+        }
+        if genres.iter().any(|genre| self.podcast_genres.contains(genre)) {
+            return ContentKind::Podcast;
+        }
+
+        let folders: Vec<String> = item
+            .abs_path
+            .rsplit_once('/')
+            .map(|(folder, _)| folder)
+            .unwrap_or("")
+            .split('/')
+            .map(str::to_lowercase)
+            .collect();
+        if folders.iter().any(|folder| self.audiobook_folders.contains(folder)) {
+            return ContentKind::Audiobook;
+        }
+        if folders.iter().any(|folder| self.podcast_folders.contains(folder)) {
+            return ContentKind::Podcast;
+        }
+
+        if item.duration >= self.long_form_secs {
+            ContentKind::LongForm
+        } else {
+            ContentKind::Music
+        }
+    }
+}
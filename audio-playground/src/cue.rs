@@ -0,0 +1,423 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::{trace, warn};
+use slug::slugify;
+
+use crate::artists::ArtistRules;
+use crate::error::{AudioError, Result};
+use crate::schema::TrackJson;
+use crate::utils::{file_ext, genre_string_to_vec};
+
+/// `INDEX` times are `mm:ss:ff`, with 75 frames per second
+const FRAMES_PER_SECOND: f64 = 75.0;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CueSheet {
+    pub performer: String,
+    pub title: String,
+    /// From `REM GENRE`
+    pub genre: String,
+    /// From `REM DATE`, usually just the year
+    pub date: String,
+    pub files: Vec<CueFile>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CueFile {
+    /// As written in the sheet, relative to the folder of the sheet
+    pub path: String,
+    pub tracks: Vec<CueTrack>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CueTrack {
+    pub number: u64,
+    pub title: String,
+    pub performer: String,
+    /// Seconds from the start of the file (`INDEX 01`, or `INDEX 00` when there's no 01)
+    pub start: f64,
+}
+
+impl CueSheet {
+    /// The file of the sheet that is `audio_path`. Rips are often re-encoded after the sheet was
+    /// written (`Album.wav` -> `Album.flac`), so the extension doesn't have to match.
+    pub fn file_for(&self, audio_path: &Path) -> Option<&CueFile> {
+        let stem = |path: &Path| {
+            path.file_stem()
+                .map(|stem| stem.to_string_lossy().to_lowercase())
+        };
+        let audio_stem = stem(audio_path)?;
+        // sheets written on Windows use backslashes
+        self.files.iter().find(|file| {
+            stem(Path::new(&file.path.replace('\\', "/"))).as_ref() == Some(&audio_stem)
+        })
+    }
+}
+
+/// Parses the text of a `.cue` file, `path` is only used in errors
+pub fn parse_cue(path: &str, text: &str) -> Result<CueSheet> {
+    let mut sheet = CueSheet::default();
+
+    for (number, line) in text.trim_start_matches('\u{feff}').lines().enumerate() {
+        let line_error =
+            |message: &str| AudioError::tag(path, format!("line {}: {}", number + 1, message));
+        let (command, args) = split_command(line.trim());
+        let command = command.to_uppercase();
+
+        match command.as_str() {
+            "FILE" => sheet.files.push(CueFile {
+                path: args.first().cloned().unwrap_or_default(),
+                tracks: vec![],
+            }),
+            "TRACK" => {
+                let file = sheet
+                    .files
+                    .last_mut()
+                    .ok_or_else(|| line_error("TRACK before FILE"))?;
+                let number = args
+                    .first()
+                    .and_then(|number| number.parse().ok())
+                    .ok_or_else(|| line_error("invalid track number"))?;
+                file.tracks.push(CueTrack {
+                    number,
+                    start: -1.0,
+                    ..Default::default()
+                });
+            }
+            "INDEX" => {
+                let track = sheet
+                    .files
+                    .last_mut()
+                    .and_then(|file| file.tracks.last_mut())
+                    .ok_or_else(|| line_error("INDEX before TRACK"))?;
+                let start = args
+                    .get(1)
+                    .and_then(|time| parse_time(time))
+                    .ok_or_else(|| line_error("invalid INDEX time"))?;
+                match args.first().map(String::as_str) {
+                    Some("01") => track.start = start,
+                    Some("00") if track.start < 0.0 => track.start = start,
+                    _ => {}
+                }
+            }
+            // TITLE/PERFORMER belong to the current track, or to the sheet before the first one
+            "TITLE" | "PERFORMER" => {
+                let value = args.first().cloned().unwrap_or_default();
+                let track = sheet.files.last_mut().and_then(|file| file.tracks.last_mut());
+                match (command.as_str(), track) {
+                    ("TITLE", Some(track)) => track.title = value,
+                    ("PERFORMER", Some(track)) => track.performer = value,
+                    ("TITLE", None) => sheet.title = value,
+                    _ => sheet.performer = value,
+                }
+            }
+            "REM" => match args.first().map(|name| name.to_uppercase()).as_deref() {
+                Some("GENRE") => sheet.genre = args[1..].join(" "),
+                Some("DATE") => sheet.date = args[1..].join(" "),
+                _ => {}
+            },
+            _ => trace!("Ignoring {:?} in {}", line, path),
+        }
+    }
+
+    for file in &mut sheet.files {
+        for track in &mut file.tracks {
+            if track.start < 0.0 {
+                return Err(AudioError::tag(
+                    path,
+                    format!("track {} has no INDEX", track.number),
+                ));
+            }
+        }
+    }
+    Ok(sheet)
+}
+
+/// Reads a `.cue` file, which is often Latin-1 rather than UTF-8
+pub fn read_cue(path: &Path) -> Result<CueSheet> {
+    let bytes = fs::read(path)?;
+    let text = match String::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(e) => e.into_bytes().iter().map(|&byte| byte as char).collect(),
+    };
+    parse_cue(&path.to_string_lossy(), &text)
+}
+
+/// The sheet next to `audio_path` that splits it into several tracks: `Album.cue` or
+/// `Album.flac.cue` first, then any other `.cue` of the folder that references the file
+pub fn cue_sheet_for(audio_path: &Path) -> Option<CueSheet> {
+    let folder = audio_path.parent()?;
+    let file_name = audio_path.file_name()?.to_string_lossy().to_string();
+    let mut candidates: Vec<PathBuf> = vec![
+        audio_path.with_extension("cue"),
+        folder.join(format!("{}.cue", file_name)),
+    ];
+    if let Ok(entries) = fs::read_dir(folder) {
+        let others: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| file_ext(&path.to_string_lossy()).eq_ignore_ascii_case("cue"))
+            .filter(|path| !candidates.contains(path))
+            .collect();
+        candidates.extend(others);
+    }
+
+    candidates
+        .iter()
+        .filter(|path| path.is_file())
+        .find_map(|path| match read_cue(path) {
+            Ok(sheet) => {
+                let file = sheet.file_for(audio_path)?.clone();
+                (file.tracks.len() > 1).then(|| CueSheet {
+                    files: vec![file],
+                    ..sheet
+                })
+            }
+            Err(e) => {
+                warn!("Ignoring CUE sheet {:?}: {}", path, e);
+                None
+            }
+        })
+}
+
+/// One track per `TRACK` of `sheet` (narrowed by `cue_sheet_for`), made from the track of the
+/// whole file. Each one ends where the next one starts, the last one at the end of the file.
+pub fn virtual_tracks(file_track: &TrackJson, sheet: &CueSheet) -> Vec<TrackJson> {
+    let tracks = match sheet.files.first() {
+        Some(file) => &file.tracks,
+        None => return vec![],
+    };
+    let artist_rules = ArtistRules::current();
+    let or = |value: &str, fallback: &str| {
+        if value.is_empty() {
+            fallback.to_string()
+        } else {
+            value.to_string()
+        }
+    };
+    let year = sheet
+        .date
+        .get(..4)
+        .and_then(|year| year.parse().ok())
+        .unwrap_or(file_track.year);
+    let genre = or(&sheet.genre, &file_track.genre);
+
+    tracks
+        .iter()
+        .enumerate()
+        .map(|(index, cue_track)| {
+            let end = tracks.get(index + 1).map_or(0.0, |next| next.start);
+            let duration = if end > 0.0 {
+                end - cue_track.start
+            } else {
+                (file_track.duration - cue_track.start).max(0.0)
+            };
+            let title = or(&cue_track.title, &format!("Track {:02}", cue_track.number));
+            let artist = or(&cue_track.performer, &or(&sheet.performer, &file_track.artist));
+
+            TrackJson {
+                id: slugify(format!("{}-{:02}", file_track.id, cue_track.number)),
+                name: format!("{} #{:02}", file_track.name, cue_track.number),
+                album: or(&sheet.title, &file_track.album),
+                album_artist: or(&sheet.performer, &file_track.album_artist),
+                artists: artist_rules.track_artists(&artist, &title),
+                artist,
+                track: title,
+                track_number: cue_track.number,
+                year,
+                genres: genre_string_to_vec(&genre),
+                genre: genre.clone(),
+                start: cue_track.start,
+                end,
+                duration,
+                // the lyrics next to the file are for the whole album
+                lyrics: String::new(),
+                synced_lyrics: vec![],
+                content_kind: None,
+                chapters: vec![],
+                ..file_track.clone()
+            }
+        })
+        .collect()
+}
+
+/// `["TITLE", "Some Song"]` from `TITLE "Some Song"`, quoted arguments can contain spaces
+fn split_command(line: &str) -> (String, Vec<String>) {
+    let mut words: Vec<String> = vec![];
+    let mut rest = line;
+    while !rest.is_empty() {
+        rest = rest.trim_start();
+        if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"').unwrap_or(quoted.len());
+            words.push(quoted[..end].to_string());
+            rest = quoted.get(end + 1..).unwrap_or("");
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            if end > 0 {
+                words.push(rest[..end].to_string());
+            }
+            rest = &rest[end..];
+        }
+    }
+    if words.is_empty() {
+        return (String::new(), vec![]);
+    }
+    let command = words.remove(0);
+    (command, words)
+}
+
+/// `mm:ss:ff` in seconds, minutes can go past 59
+fn parse_time(time: &str) -> Option<f64> {
+    let parts: Vec<u64> = time
+        .split(':')
+        .map(|part| part.parse().ok())
+        .collect::<Option<_>>()?;
+    match parts[..] {
+        [minutes, seconds, frames] => Some(
+            (minutes * 60 + seconds) as f64 + frames as f64 / FRAMES_PER_SECOND,
+        ),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const SHEET: &str = r#"REM GENRE "Progressive Rock"
+REM DATE 1999
+PERFORMER "The Band"
+TITLE "Live at the Hall"
+FILE "Live at the Hall.wav" WAVE
+  TRACK 01 AUDIO
+    TITLE "Intro"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE "Song With Spaces"
+    PERFORMER "The Band feat. Guest"
+    INDEX 00 03:58:00
+    INDEX 01 04:00:37
+  TRACK 03 AUDIO
+    INDEX 00 08:30:00
+"#;
+
+    #[test]
+    fn parse_sheet() {
+        let sheet = parse_cue("live.cue", SHEET).unwrap();
+        assert_eq!(sheet.performer, "The Band");
+        assert_eq!(sheet.title, "Live at the Hall");
+        assert_eq!(sheet.genre, "Progressive Rock");
+        assert_eq!(sheet.date, "1999");
+        assert_eq!(sheet.files.len(), 1);
+
+        let file = &sheet.files[0];
+        assert_eq!(file.path, "Live at the Hall.wav");
+        assert_eq!(
+            file.tracks,
+            vec![
+                CueTrack {
+                    number: 1,
+                    title: "Intro".to_string(),
+                    performer: String::new(),
+                    start: 0.0,
+                },
+                // INDEX 01 wins over the pregap of INDEX 00, 37 frames of 1/75s
+                CueTrack {
+                    number: 2,
+                    title: "Song With Spaces".to_string(),
+                    performer: "The Band feat. Guest".to_string(),
+                    start: 240.0 + 37.0 / 75.0,
+                },
+                // INDEX 00 when there's no 01
+                CueTrack {
+                    number: 3,
+                    title: String::new(),
+                    performer: String::new(),
+                    start: 510.0,
+                },
+            ]
+        );
+        assert!(sheet.file_for(Path::new("/music/Live at the Hall.flac")).is_some());
+        assert!(sheet.file_for(Path::new("/music/Other.flac")).is_none());
+    }
+
+    #[test]
+    fn invalid_sheets() {
+        let error = parse_cue("broken.cue", "TRACK 01 AUDIO\n").unwrap_err();
+        assert!(error.to_string().contains("line 1: TRACK before FILE"), "{}", error);
+
+        let without_index = "FILE \"a.wav\" WAVE\n  TRACK 01 AUDIO\n    TITLE \"A\"\n";
+        let error = parse_cue("broken.cue", without_index).unwrap_err();
+        assert!(error.to_string().contains("track 1 has no INDEX"), "{}", error);
+
+        let bad_time = "FILE \"a.wav\" WAVE\n  TRACK 01 AUDIO\n    INDEX 01 1:2\n";
+        let error = parse_cue("broken.cue", bad_time).unwrap_err();
+        assert!(error.to_string().contains("line 3: invalid INDEX time"), "{}", error);
+    }
+
+    #[test]
+    fn latin1_sheet() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("Album.cue");
+        let text = "TITLE \"Café Noir\"\nFILE \"Album.flac\" WAVE\n  TRACK 01 AUDIO\n    \
+                    TITLE \"Über\"\n    INDEX 01 00:00:00\n  TRACK 02 AUDIO\n    INDEX 01 01:00:00\n";
+        // é and Ü as single bytes, which isn't valid UTF-8
+        let latin1: Vec<u8> = text.chars().map(|c| c as u32 as u8).collect();
+        fs::write(&path, latin1).unwrap();
+
+        let sheet = read_cue(&path).unwrap();
+        assert_eq!(sheet.title, "Café Noir");
+        assert_eq!(sheet.files[0].tracks[0].title, "Über");
+
+        // found next to the audio file, which it splits
+        let audio_path = directory.path().join("Album.flac");
+        let found = cue_sheet_for(&audio_path).unwrap();
+        assert_eq!(found.files[0].tracks.len(), 2);
+    }
+
+    #[test]
+    fn virtual_tracks_split_the_file() {
+        let sheet = parse_cue("live.cue", SHEET).unwrap();
+        let file_track = TrackJson {
+            id: "live-file".to_string(),
+            abs_path: "/music/Live at the Hall.wav".to_string(),
+            name: "Live at the Hall".to_string(),
+            artist: "Tag Artist".to_string(),
+            album: "Tag Album".to_string(),
+            duration: 600.0,
+            year: 2001,
+            ..Default::default()
+        };
+
+        let tracks = virtual_tracks(&file_track, &sheet);
+        assert_eq!(tracks.len(), 3);
+        let second_start = 240.0 + 37.0 / 75.0;
+
+        let first = &tracks[0];
+        assert_eq!((first.start, first.end), (0.0, second_start));
+        assert_eq!(first.duration, second_start);
+        assert_eq!(first.track, "Intro");
+        assert_eq!(first.artist, "The Band");
+        assert_eq!(first.album, "Live at the Hall");
+        assert_eq!(first.year, 1999);
+        assert_eq!(first.genre, "Progressive Rock");
+        assert_eq!(first.abs_path, file_track.abs_path);
+        assert_eq!(first.id, "live-file-01");
+
+        let middle = &tracks[1];
+        assert_eq!((middle.start, middle.end), (second_start, 510.0));
+        assert!((middle.duration - (510.0 - second_start)).abs() < 1e-9);
+        assert_eq!(middle.artist, "The Band feat. Guest");
+        assert_eq!(middle.track_number, 2);
+
+        // the last one ends with the file
+        let last = &tracks[2];
+        assert_eq!((last.start, last.end), (510.0, 0.0));
+        assert_eq!(last.duration, 90.0);
+        assert_eq!(last.track, "Track 03");
+        assert_eq!(last.name, "Live at the Hall #03");
+    }
+}
@@ -0,0 +1,239 @@
+use std::fs::File;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use log::warn;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{Decoder, DecoderOptions};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use symphonia::core::units::Time;
+
+use crate::error::{AudioError, Result};
+use crate::utils::file_ext;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AudioFormat {
+    pub sample_rate: u32,
+    pub channels: usize,
+}
+
+/// Returned by the `decode_path` callback, analysis passes often only need the start of a track
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Decoding {
+    Continue,
+    Stop,
+}
+
+/// Decodes the default track of `path`, calling `on_samples` with interleaved `f32` samples
+/// for every packet. Packets that fail to decode are skipped (as most players do).
+pub fn decode_path(
+    path: &Path,
+    mut on_samples: impl FnMut(AudioFormat, &[f32]) -> Result<Decoding>,
+) -> Result<AudioFormat> {
+    let mut decoder = TrackDecoder::open(path)?;
+    while let Some((format, samples)) = decoder.next_samples()? {
+        if on_samples(format, samples)? == Decoding::Stop {
+            break;
+        }
+    }
+    Ok(decoder.format())
+}
+
+/// Pull based decoding of the default track of a file, with seeking (used for playback)
+pub struct TrackDecoder {
+    path: String,
+    format_reader: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    format: AudioFormat,
+    /// Total length in seconds when the container knows it
+    duration: Option<f64>,
+    sample_buf: Option<SampleBuffer<f32>>,
+    /// Frames to drop after a seek, the reader lands on the packet before the requested time
+    skip_frames: u64,
+    /// Part of the file that is played, in seconds (a track of a CUE sheet), `end` 0 is the end
+    start: f64,
+    end: f64,
+    /// Frames from the start of the file up to the next samples
+    position: u64,
+}
+
+impl TrackDecoder {
+    pub fn open(path: &Path) -> Result<Self> {
+        let path_string = path.to_string_lossy().to_string();
+        let decode_error = |e: SymphoniaError| AudioError::decode(&path_string, e);
+
+        let source = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+        let mut hint = Hint::new();
+        hint.with_extension(file_ext(&path_string));
+
+        let probed = symphonia::default::get_probe()
+            .format(
+                &hint,
+                source,
+                &FormatOptions::default(),
+                &MetadataOptions::default(),
+            )
+            .map_err(decode_error)?;
+        let format_reader = probed.format;
+
+        let track = format_reader
+            .default_track()
+            .ok_or_else(|| AudioError::decode(&path_string, "no audio track"))?;
+        let track_id = track.id;
+        let decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions::default())
+            .map_err(decode_error)?;
+
+        let format = AudioFormat {
+            sample_rate: track.codec_params.sample_rate.unwrap_or(0),
+            channels: track
+                .codec_params
+                .channels
+                .map(|channels| channels.count())
+                .unwrap_or(0),
+        };
+        let duration = match (track.codec_params.n_frames, track.codec_params.sample_rate) {
+            (Some(frames), Some(rate)) if rate > 0 => Some(frames as f64 / rate as f64),
+            _ => None,
+        };
+
+        Ok(TrackDecoder {
+            path: path_string,
+            format_reader,
+            decoder,
+            track_id,
+            format,
+            duration,
+            sample_buf: None,
+            skip_frames: 0,
+            start: 0.0,
+            end: 0.0,
+            position: 0,
+        })
+    }
+
+    /// Only decodes `start..end` seconds of the file, `end` 0 goes to the end of the file.
+    /// Seeking and `duration` are then relative to `start`.
+    pub fn open_range(path: &Path, start: f64, end: f64) -> Result<Self> {
+        let mut decoder = TrackDecoder::open(path)?;
+        decoder.start = start.max(0.0);
+        decoder.end = end;
+        if decoder.start > 0.0 {
+            decoder.seek(0.0)?;
+        }
+        Ok(decoder)
+    }
+
+    /// Format of the last decoded packet (or of the track header before the first one)
+    pub fn format(&self) -> AudioFormat {
+        self.format
+    }
+
+    pub fn duration(&self) -> Option<f64> {
+        if self.end > 0.0 {
+            Some(self.end - self.start)
+        } else {
+            self.duration.map(|duration| (duration - self.start).max(0.0))
+        }
+    }
+
+    /// Interleaved samples of the next packet and their format, `None` at the end of the track
+    pub fn next_samples(&mut self) -> Result<Option<(AudioFormat, &[f32])>> {
+        let start = loop {
+            let packet = match self.format_reader.next_packet() {
+                Ok(packet) => packet,
+                // symphonia reports the end of the stream as an unexpected EOF
+                Err(SymphoniaError::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                    return Ok(None)
+                }
+                Err(e) => return Err(AudioError::decode(&self.path, e)),
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                Err(SymphoniaError::DecodeError(e)) => {
+                    warn!("Skipping undecodable packet in {}: {}", self.path, e);
+                    continue;
+                }
+                Err(e) => return Err(AudioError::decode(&self.path, e)),
+            };
+
+            let spec = *decoded.spec();
+            self.format = AudioFormat {
+                sample_rate: spec.rate,
+                channels: spec.channels.count(),
+            };
+
+            // packets are usually the same size, only grow the buffer when one doesn't fit
+            let samples_needed = decoded.capacity() * self.format.channels;
+            if self
+                .sample_buf
+                .as_ref()
+                .map_or(true, |buf| buf.capacity() < samples_needed)
+            {
+                self.sample_buf = Some(SampleBuffer::new(decoded.capacity() as u64, spec));
+            }
+
+            let buf = match self.sample_buf.as_mut() {
+                Some(buf) => buf,
+                None => continue,
+            };
+            buf.copy_interleaved_ref(decoded);
+
+            let channels = self.format.channels.max(1);
+            let frames = (buf.samples().len() / channels) as u64;
+            if self.skip_frames >= frames {
+                self.skip_frames -= frames;
+                continue;
+            }
+            let start = self.skip_frames as usize * channels;
+            self.skip_frames = 0;
+            break start;
+        };
+
+        let format = self.format;
+        let channels = format.channels.max(1);
+        let samples = match self.sample_buf.as_ref() {
+            Some(buf) => &buf.samples()[start..],
+            None => return Ok(None),
+        };
+        let mut frames = (samples.len() / channels) as u64;
+        if self.end > 0.0 {
+            let end_frame = (self.end * format.sample_rate as f64) as u64;
+            if self.position >= end_frame {
+                return Ok(None);
+            }
+            frames = frames.min(end_frame - self.position);
+        }
+        self.position += frames;
+        Ok(Some((format, &samples[..frames as usize * channels])))
+    }
+
+    /// Moves to `seconds` from the start (of the range), the next samples start there
+    pub fn seek(&mut self, seconds: f64) -> Result<()> {
+        let seconds = self.start + seconds.max(0.0);
+        let seeked = self
+            .format_reader
+            .seek(
+                SeekMode::Accurate,
+                SeekTo::Time {
+                    time: Time::new(seconds.trunc() as u64, seconds.fract()),
+                    track_id: Some(self.track_id),
+                },
+            )
+            .map_err(|e| AudioError::decode(&self.path, e))?;
+        self.decoder.reset();
+        // timestamps of audio tracks are in frames
+        self.skip_frames = seeked.required_ts.saturating_sub(seeked.actual_ts);
+        self.position = seeked.required_ts;
+        Ok(())
+    }
+}
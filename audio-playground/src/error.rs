@@ -0,0 +1,119 @@
+use std::fmt;
+use std::io;
+
+use tantivy::query::QueryParserError;
+use tantivy::TantivyError;
+
+pub type Result<T> = std::result::Result<T, AudioError>;
+
+#[derive(Debug)]
+pub enum AudioError {
+    /// Reading/writing files (audio, JSON cache, index directory)
+    Io(io::Error),
+    /// Audio file tags that couldn't be read or written
+    Tag { path: String, message: String },
+    /// Audio that can't be decoded (unsupported codec, corrupt file)
+    Decode { path: String, message: String },
+    /// Anything tantivy related (opening, writing, searching)
+    Index(TantivyError),
+    /// A search text that can't be parsed into a query
+    Query(QueryParserError),
+    /// Invalid settings, scan rules or JSON data
+    Config(String),
+    /// Libraries/playlists of other players that can't be read
+    Import { path: String, message: String },
+    /// Files that couldn't be copied/transcoded by an export job
+    Export { path: String, message: String },
+    /// The audio output device can't be opened or used
+    Playback(String),
+}
+
+impl AudioError {
+    pub fn tag(path: &str, error: impl fmt::Display) -> Self {
+        AudioError::Tag {
+            path: path.to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    pub fn decode(path: &str, error: impl fmt::Display) -> Self {
+        AudioError::Decode {
+            path: path.to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    pub fn import(path: &str, error: impl fmt::Display) -> Self {
+        AudioError::Import {
+            path: path.to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    pub fn export(path: &str, error: impl fmt::Display) -> Self {
+        AudioError::Export {
+            path: path.to_string(),
+            message: error.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for AudioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioError::Io(e) => write!(f, "io error: {}", e),
+            AudioError::Tag { path, message } => write!(f, "tag error for {}: {}", path, message),
+            AudioError::Decode { path, message } => {
+                write!(f, "decode error for {}: {}", path, message)
+            }
+            AudioError::Index(e) => write!(f, "index error: {}", e),
+            AudioError::Query(e) => write!(f, "query error: {:?}", e),
+            AudioError::Config(message) => write!(f, "config error: {}", message),
+            AudioError::Import { path, message } => {
+                write!(f, "import error for {}: {}", path, message)
+            }
+            AudioError::Export { path, message } => {
+                write!(f, "export error for {}: {}", path, message)
+            }
+            AudioError::Playback(message) => write!(f, "playback error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for AudioError {}
+
+impl From<io::Error> for AudioError {
+    fn from(e: io::Error) -> Self {
+        AudioError::Io(e)
+    }
+}
+
+impl From<TantivyError> for AudioError {
+    fn from(e: TantivyError) -> Self {
+        AudioError::Index(e)
+    }
+}
+
+impl From<QueryParserError> for AudioError {
+    fn from(e: QueryParserError) -> Self {
+        AudioError::Query(e)
+    }
+}
+
+impl From<serde_json::Error> for AudioError {
+    fn from(e: serde_json::Error) -> Self {
+        AudioError::Config(e.to_string())
+    }
+}
+
+impl From<ignore::Error> for AudioError {
+    fn from(e: ignore::Error) -> Self {
+        AudioError::Config(e.to_string())
+    }
+}
+
+impl<T> From<std::sync::PoisonError<T>> for AudioError {
+    fn from(_: std::sync::PoisonError<T>) -> Self {
+        AudioError::Index(TantivyError::Poisoned)
+    }
+}
@@ -0,0 +1,674 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::SystemTime;
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{AudioError, Result};
+use crate::schema::{DocumentSearchRequest, SearchWatcher, Track};
+use crate::settings::SETTINGS;
+use crate::utils::{file_ext, write_atomic};
+
+/// Kept in the target directory, an interrupted export picks up where it stopped
+pub const MANIFEST_FILE: &str = ".audio-export.json";
+pub const DEFAULT_LAYOUT: &str = "{album_artist}/{album}/{track_number} {title}";
+/// Partial files get this suffix until they're complete, so they are never mistaken for exports
+const PART_SUFFIX: &str = ".part";
+
+/// What to export, in the order the tracks are copied in
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum ExportSource {
+    Search(DocumentSearchRequest),
+    SavedQuery(String),
+    Playlist(String),
+}
+
+/// `{input}` and `{output}` in `args` are replaced by the source and target paths, `{start}` and
+/// `{duration}` by the offsets in seconds of a track of a CUE sheet (0 for whole files)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EncoderCommand {
+    pub program: String,
+    pub args: Vec<String>,
+    /// Extension of the files written by the command, e.g. `opus`
+    pub extension: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Encoder {
+    /// The files as they are
+    Copy,
+    /// LAME VBR V0 through ffmpeg
+    Mp3V0,
+    /// Opus through ffmpeg
+    Opus { bitrate_kbps: u32 },
+    /// Any other encoder
+    Command(EncoderCommand),
+}
+
+impl Encoder {
+    /// `None` for `Copy`, the presets use the `ffmpeg_path` setting
+    pub fn command(&self) -> Option<EncoderCommand> {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect();
+
+        match self {
+            Encoder::Copy => None,
+            Encoder::Mp3V0 => Some(EncoderCommand {
+                program: ffmpeg_program(),
+                args: args(&[
+                    "-y", "-v", "error", "-i", "{input}", "-map", "0:a", "-map_metadata", "0",
+                    "-codec:a", "libmp3lame", "-q:a", "0", "-id3v2_version", "3", "-f", "mp3",
+                    "{output}",
+                ]),
+                extension: "mp3".to_string(),
+            }),
+            Encoder::Opus { bitrate_kbps } => {
+                let bitrate = format!("{}k", bitrate_kbps);
+                Some(EncoderCommand {
+                    program: ffmpeg_program(),
+                    args: args(&[
+                        "-y", "-v", "error", "-i", "{input}", "-map", "0:a", "-map_metadata", "0",
+                        "-codec:a", "libopus", "-b:a", &bitrate, "-f", "opus", "{output}",
+                    ]),
+                    extension: "opus".to_string(),
+                })
+            }
+            Encoder::Command(command) => Some(command.clone()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExportJob {
+    pub source: ExportSource,
+    pub target_directory: String,
+    pub encoder: Encoder,
+    /// Path of each file relative to `target_directory`, without extension. Placeholders:
+    /// `{artist}`, `{album_artist}`, `{album}`, `{year}`, `{genre}`, `{title}`,
+    /// `{track_number}` (two digits) and `{disc_number}`
+    pub layout: String,
+    /// Tracks that would take the export over this many bytes are skipped
+    pub max_bytes: Option<u64>,
+}
+
+impl ExportJob {
+    pub fn new(source: ExportSource, target_directory: &str, encoder: Encoder) -> Self {
+        ExportJob {
+            source,
+            target_directory: target_directory.to_string(),
+            encoder,
+            layout: DEFAULT_LAYOUT.to_string(),
+            max_bytes: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    pub source_path: String,
+    /// `modified_date` of the source when it was exported, a newer source is exported again
+    pub source_modified_date: i64,
+    /// Relative to the target directory
+    pub target_path: String,
+    pub size: u64,
+    /// The `Encoder` as JSON, changing the encoder exports the track again
+    pub encoder: String,
+}
+
+/// Exported tracks keyed by `Track.id`, saved after every file
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ExportManifest {
+    pub entries: BTreeMap<String, ManifestEntry>,
+}
+
+impl ExportManifest {
+    fn open(path: &Path) -> Result<Self> {
+        if path.exists() {
+            Ok(serde_json::from_str(&read_to_string(path)?)?)
+        } else {
+            Ok(ExportManifest::default())
+        }
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        write_atomic(path, &serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    fn total_bytes(&self) -> u64 {
+        self.entries.values().map(|entry| entry.size).sum()
+    }
+}
+
+#[derive(Serialize, Default, Debug, Clone, PartialEq)]
+pub struct ExportReport {
+    pub exported: usize,
+    /// Already in the manifest and unchanged
+    pub up_to_date: usize,
+    /// `abs_path` of the tracks that didn't fit in `max_bytes`
+    pub skipped_size: Vec<String>,
+    /// `abs_path` of the tracks that couldn't be exported (missing, offline, encoder errors)
+    pub failed: Vec<String>,
+    /// Size of everything in the manifest once done
+    pub total_bytes: u64,
+    pub cost_ms: u128,
+}
+
+impl SearchWatcher {
+    /// Copies or transcodes the tracks of `job.source` into `job.target_directory`.
+    /// Safe to run again after an interruption: finished files are in the manifest and
+    /// files are only renamed into place once complete.
+    pub fn export(&self, job: &ExportJob) -> Result<ExportReport> {
+        let start = SystemTime::now();
+        let tracks = match &job.source {
+            ExportSource::Search(request) => self.all_results(request)?,
+            ExportSource::SavedQuery(name) => self.saved_query_results(name)?,
+            ExportSource::Playlist(name) => self.playlist_tracks(name)?,
+        };
+
+        let target_directory = Path::new(&job.target_directory);
+        fs::create_dir_all(target_directory)?;
+        let manifest_path = target_directory.join(MANIFEST_FILE);
+        let mut manifest = ExportManifest::open(&manifest_path)?;
+
+        let command = job.encoder.command();
+        let encoder_key = serde_json::to_string(&job.encoder)?;
+        let mut report = ExportReport::default();
+
+        info!("Exporting {} track(s) to {:?}", tracks.len(), target_directory);
+        for track in &tracks {
+            // tracks of CUE sheets keep the extension too, see `cue_track_command`
+            let extension = match &command {
+                Some(command) => command.extension.clone(),
+                None => file_ext(&track.abs_path).to_lowercase(),
+            };
+            let relative_path = format!("{}.{}", layout_path(&job.layout, track), extension);
+
+            if is_up_to_date(&manifest, target_directory, track, &relative_path, &encoder_key) {
+                report.up_to_date += 1;
+                continue;
+            }
+            if !track.exists {
+                warn!("Not exporting {}, the file is missing or offline", track.abs_path);
+                report.failed.push(track.abs_path.clone());
+                continue;
+            }
+
+            // a track of a CUE sheet is cut out of its file, even when copying
+            let command = if track.is_virtual() {
+                match cue_track_command(command.as_ref(), track) {
+                    Ok(command) => Some(command),
+                    Err(e) => {
+                        error!("{}", e);
+                        report.failed.push(track.abs_path.clone());
+                        continue;
+                    }
+                }
+            } else {
+                command.clone()
+            };
+            let target_path = target_directory.join(&relative_path);
+
+            // the previous export of this track (other layout/encoder) is replaced
+            let previous = manifest.entries.remove(&track.id);
+            let used_bytes = manifest.total_bytes();
+
+            match export_file(track, &target_path, command.as_ref()) {
+                Ok(size) => {
+                    if job.max_bytes.map(|max| used_bytes + size > max).unwrap_or(false) {
+                        fs::remove_file(&target_path)?;
+                        // unless it was just overwritten, the previous export is still there
+                        if let Some(previous) = previous.filter(|p| p.target_path != relative_path)
+                        {
+                            manifest.entries.insert(track.id.clone(), previous);
+                        }
+                        report.skipped_size.push(track.abs_path.clone());
+                        continue;
+                    }
+
+                    if let Some(previous) = previous.filter(|p| p.target_path != relative_path) {
+                        let _ = fs::remove_file(target_directory.join(&previous.target_path));
+                    }
+                    manifest.entries.insert(
+                        track.id.clone(),
+                        ManifestEntry {
+                            source_path: track.abs_path.clone(),
+                            source_modified_date: track.modified_date,
+                            target_path: relative_path,
+                            size,
+                            encoder: encoder_key.clone(),
+                        },
+                    );
+                    manifest.save(&manifest_path)?;
+                    report.exported += 1;
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    if let Some(previous) = previous {
+                        manifest.entries.insert(track.id.clone(), previous);
+                    }
+                    report.failed.push(track.abs_path.clone());
+                }
+            }
+        }
+
+        report.total_bytes = manifest.total_bytes();
+        report.cost_ms = SystemTime::now()
+            .duration_since(start)
+            .map(|cost| cost.as_millis())
+            .unwrap_or(0);
+        info!(
+            "Exported {}, {} up to date, {} over the size cap, {} failed ({} bytes in total)",
+            report.exported,
+            report.up_to_date,
+            report.skipped_size.len(),
+            report.failed.len(),
+            report.total_bytes
+        );
+        Ok(report)
+    }
+}
+
+/// Exported with the same encoder and layout from the current version of the source, and the
+/// file is still there
+fn is_up_to_date(
+    manifest: &ExportManifest,
+    target_directory: &Path,
+    track: &Track,
+    relative_path: &str,
+    encoder_key: &str,
+) -> bool {
+    match manifest.entries.get(&track.id) {
+        Some(entry) => {
+            entry.encoder == encoder_key
+                && entry.target_path == relative_path
+                && entry.source_modified_date >= track.modified_date
+                && fs::metadata(target_directory.join(&entry.target_path))
+                    .map(|metadata| metadata.len() == entry.size)
+                    .unwrap_or(false)
+        }
+        None => false,
+    }
+}
+
+/// Writes `<target>.part` first and renames it once complete, returns the size of the file
+fn export_file(track: &Track, target_path: &Path, command: Option<&EncoderCommand>) -> Result<u64> {
+    let target_string = target_path.to_string_lossy().to_string();
+    let export_error = |e: &dyn std::fmt::Display| AudioError::export(&track.abs_path, e);
+
+    if let Some(parent) = target_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let part_path = PathBuf::from(format!("{}{}", target_string, PART_SUFFIX));
+
+    match command {
+        None => {
+            fs::copy(&track.abs_path, &part_path)?;
+        }
+        Some(command) => {
+            let part_string = part_path.to_string_lossy().to_string();
+            let args: Vec<String> = command
+                .args
+                .iter()
+                .map(|arg| {
+                    arg.replace("{input}", &track.abs_path)
+                        .replace("{output}", &part_string)
+                        .replace("{start}", &format!("{:.3}", track.start))
+                        .replace("{duration}", &format!("{:.3}", track.duration))
+                })
+                .collect();
+            let output = Command::new(&command.program)
+                .args(&args)
+                .output()
+                .map_err(|e| export_error(&format!("can't run {}: {}", command.program, e)))?;
+            if !output.status.success() {
+                let _ = fs::remove_file(&part_path);
+                return Err(export_error(&format!(
+                    "{} failed ({}): {}",
+                    command.program,
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )));
+            }
+        }
+    }
+
+    fs::rename(&part_path, target_path)?;
+    Ok(fs::metadata(target_path)?.len())
+}
+
+/// The `ffmpeg_path` setting, `ffmpeg` on the `PATH` when it's not set
+fn ffmpeg_program() -> String {
+    let ffmpeg_path = SETTINGS
+        .read()
+        .map(|settings| settings.ffmpeg_path.clone())
+        .unwrap_or_default();
+    if ffmpeg_path.is_empty() {
+        "ffmpeg".to_string()
+    } else {
+        ffmpeg_path
+    }
+}
+
+/// The command exporting a track of a CUE sheet: `command` seeking to the track before its
+/// input and tagged with the track rather than the whole file, or for `Copy` an ffmpeg command
+/// keeping the format of the file. Encoders without an `-i` argument have to use the `{start}`
+/// and `{duration}` placeholders themselves.
+fn cue_track_command(command: Option<&EncoderCommand>, track: &Track) -> Result<EncoderCommand> {
+    let mut command = match command {
+        Some(command) => command.clone(),
+        None => {
+            let extension = file_ext(&track.abs_path).to_lowercase();
+            let muxer = match extension.as_str() {
+                "m4a" => "ipod".to_string(),
+                other => other.to_string(),
+            };
+            EncoderCommand {
+                program: ffmpeg_program(),
+                args: [
+                    "-y", "-v", "error", "-i", "{input}", "-map", "0:a", "-map_metadata", "0",
+                    "-f", muxer.as_str(), "{output}",
+                ]
+                .iter()
+                .map(|arg| arg.to_string())
+                .collect(),
+                extension,
+            }
+        }
+    };
+    if command.args.iter().any(|arg| arg.contains("{start}")) {
+        return Ok(command);
+    }
+
+    let input = command
+        .args
+        .iter()
+        .position(|arg| arg == "-i")
+        .ok_or_else(|| {
+            AudioError::export(
+                &track.abs_path,
+                "the encoder can't cut CUE sheet tracks, use {start} and {duration} in its arguments",
+            )
+        })?;
+    let mut cut = vec!["-ss".to_string(), "{start}".to_string()];
+    if track.end > 0.0 {
+        cut.extend(["-t".to_string(), "{duration}".to_string()]);
+    }
+    command.args.splice(input..input, cut);
+
+    if let Some(output) = command.args.iter().position(|arg| arg == "{output}") {
+        let tags = [
+            format!("title={}", track.track),
+            format!("artist={}", track.artist),
+            format!("track={}", track.track_number),
+        ];
+        let metadata = tags
+            .into_iter()
+            .flat_map(|tag| ["-metadata".to_string(), tag]);
+        command.args.splice(output..output, metadata);
+    }
+    Ok(command)
+}
+
+/// `layout` with the placeholders replaced by the (file name safe) values of `track`
+pub fn layout_path(layout: &str, track: &Track) -> String {
+    let values = [
+        ("{artist}", track.artist.clone()),
+        ("{album_artist}", track.album_artist.clone()),
+        ("{album}", track.album.clone()),
+        ("{year}", track.year.to_string()),
+        ("{genre}", track.genres.first().cloned().unwrap_or_default()),
+        ("{title}", track.title().to_string()),
+        ("{track_number}", format!("{:02}", track.track_number)),
+        ("{disc_number}", track.disc_number.to_string()),
+    ];
+
+    // placeholders are replaced per segment so values can't add folders
+    layout
+        .split('/')
+        .map(|segment| {
+            let mut segment = segment.to_string();
+            for (placeholder, value) in &values {
+                segment = segment.replace(placeholder, &sanitize(value));
+            }
+            sanitize(&segment)
+        })
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+/// Characters FAT32/exFAT (most phones and USB sticks) don't allow in file names
+fn sanitize(value: &str) -> String {
+    let replaced: String = value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    replaced.trim().trim_end_matches('.').trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::playlists::{Playlist, PlaylistStore, PLAYLISTS_FILE};
+    use crate::test_support::{index_tracks, search_watcher, track, write_wav};
+    use crate::utils::norm;
+
+    const TRACKS: usize = 3;
+    /// The header and 8000 frames of 16 bit mono
+    const WAV_SIZE: u64 = 44 + 8000 * 2;
+    const ALBUM_DIRECTORY: &str = "Blue River/Night Drive";
+
+    /// `TRACKS` fixture WAVs of one album in `audio/`, in the playlist "Export"
+    fn exported_library() -> (TempDir, SearchWatcher) {
+        let (directory, search_watcher) = search_watcher();
+        let audio = directory.path().join("audio");
+        fs::create_dir_all(&audio).unwrap();
+        let tracks: Vec<_> = (1..=TRACKS)
+            .map(|index| {
+                let mut item = track(index, "Blue River", "Night Drive", "Rock", 2001);
+                let path = audio.join(format!("{:02} Song {}.wav", index, index));
+                write_wav(&path, 8000, 1, 8000);
+                item.abs_path = norm(&path.to_string_lossy());
+                item
+            })
+            .collect();
+        index_tracks(&search_watcher, &tracks);
+
+        PlaylistStore::open(&search_watcher.data_file(PLAYLISTS_FILE))
+            .unwrap()
+            .save_playlist(Playlist {
+                name: "Export".to_string(),
+                track_ids: tracks.iter().map(|item| item.id.clone()).collect(),
+            })
+            .unwrap();
+        (directory, search_watcher)
+    }
+
+    fn job(target: &Path) -> ExportJob {
+        ExportJob::new(
+            ExportSource::Playlist("Export".to_string()),
+            &target.to_string_lossy(),
+            Encoder::Copy,
+        )
+    }
+
+    fn manifest(target: &Path) -> ExportManifest {
+        ExportManifest::open(&target.join(MANIFEST_FILE)).unwrap()
+    }
+
+    fn exported(target: &Path, index: usize) -> PathBuf {
+        target
+            .join(ALBUM_DIRECTORY)
+            .join(format!("{:02} Song {}.wav", index, index))
+    }
+
+    #[test]
+    fn layout_paths() {
+        let track = Track {
+            artist: "AC/DC".to_string(),
+            album_artist: "AC/DC".to_string(),
+            album: "..".to_string(),
+            name: "05 file".to_string(),
+            track: "What? Now: \"Live\"...".to_string(),
+            track_number: 5,
+            disc_number: 1,
+            year: 1980,
+            genres: vec!["Rock".to_string()],
+            ..Default::default()
+        };
+        // values can't add folders, empty segments are left out
+        assert_eq!(layout_path(DEFAULT_LAYOUT, &track), "AC_DC/05 What_ Now_ _Live_");
+        assert_eq!(
+            layout_path("../{year}/./{genre}/{disc_number}-{track_number}", &track),
+            "1980/Rock/1-05"
+        );
+        let climbing = Track {
+            artist: "../..".to_string(),
+            ..track.clone()
+        };
+        assert_eq!(layout_path("{artist}/{title}", &climbing), ".._/What_ Now_ _Live_");
+
+        // without a title tag the file name is used
+        let untitled = Track {
+            track: String::new(),
+            ..track
+        };
+        assert_eq!(layout_path("{title}", &untitled), "05 file");
+
+        assert_eq!(sanitize(" a\u{7}b<c>|d*. "), "a_b_c__d_");
+    }
+
+    #[test]
+    fn copies_once() {
+        let (directory, search_watcher) = exported_library();
+        let target = directory.path().join("export");
+
+        let report = search_watcher.export(&job(&target)).unwrap();
+        assert_eq!(report.exported, TRACKS);
+        assert!(report.failed.is_empty());
+        assert_eq!(report.total_bytes, TRACKS as u64 * WAV_SIZE);
+        assert_eq!(
+            fs::read(exported(&target, 1)).unwrap(),
+            fs::read(directory.path().join("audio/01 Song 1.wav")).unwrap()
+        );
+        let manifest = manifest(&target);
+        assert_eq!(manifest.entries.len(), TRACKS);
+        assert_eq!(
+            manifest.entries["track-1"].target_path,
+            format!("{}/01 Song 1.wav", ALBUM_DIRECTORY)
+        );
+
+        let again = search_watcher.export(&job(&target)).unwrap();
+        assert_eq!((again.exported, again.up_to_date), (0, TRACKS));
+    }
+
+    #[test]
+    fn interrupted_exports_are_finished() {
+        let (directory, search_watcher) = exported_library();
+        let target = directory.path().join("export");
+        search_watcher.export(&job(&target)).unwrap();
+
+        // stopped while copying the second file: not in the manifest, only its partial file
+        let mut stopped = manifest(&target);
+        stopped.entries.remove("track-2");
+        stopped.save(&target.join(MANIFEST_FILE)).unwrap();
+        fs::remove_file(exported(&target, 2)).unwrap();
+        let part = target.join(ALBUM_DIRECTORY).join("02 Song 2.wav.part");
+        fs::write(&part, b"RIFF").unwrap();
+        // and the third one was changed on the device since
+        fs::write(exported(&target, 3), b"RIFF").unwrap();
+
+        let report = search_watcher.export(&job(&target)).unwrap();
+        assert_eq!((report.exported, report.up_to_date), (2, 1));
+        assert!(!part.exists());
+        for index in 1..=TRACKS {
+            assert_eq!(fs::metadata(exported(&target, index)).unwrap().len(), WAV_SIZE);
+        }
+        assert_eq!(manifest(&target).entries.len(), TRACKS);
+    }
+
+    #[test]
+    fn layout_and_encoder_changes() {
+        let (directory, search_watcher) = exported_library();
+        let target = directory.path().join("export");
+        search_watcher.export(&job(&target)).unwrap();
+
+        // the files move, the previous ones are removed
+        let mut flat = job(&target);
+        flat.layout = "{artist} - {title}".to_string();
+        let report = search_watcher.export(&flat).unwrap();
+        assert_eq!(report.exported, TRACKS);
+        assert_eq!(report.total_bytes, TRACKS as u64 * WAV_SIZE);
+        for index in 1..=TRACKS {
+            assert!(!exported(&target, index).exists());
+            assert!(target
+                .join(format!("Blue River - Song {}.wav", index))
+                .exists());
+        }
+        assert_eq!(
+            manifest(&target).entries["track-1"].target_path,
+            "Blue River - Song 1.wav"
+        );
+
+        // exported with another encoder, and from an older version of the source
+        let mut changed = manifest(&target);
+        changed.entries.get_mut("track-1").unwrap().encoder =
+            serde_json::to_string(&Encoder::Mp3V0).unwrap();
+        changed.entries.get_mut("track-2").unwrap().source_modified_date = 0;
+        changed.save(&target.join(MANIFEST_FILE)).unwrap();
+
+        let report = search_watcher.export(&flat).unwrap();
+        assert_eq!((report.exported, report.up_to_date), (2, 1));
+        let copy = serde_json::to_string(&Encoder::Copy).unwrap();
+        assert!(manifest(&target)
+            .entries
+            .values()
+            .all(|entry| entry.encoder == copy && entry.source_modified_date > 0));
+    }
+
+    #[test]
+    fn max_bytes() {
+        let (directory, search_watcher) = exported_library();
+        let target = directory.path().join("export");
+
+        let mut capped = job(&target);
+        capped.max_bytes = Some(2 * WAV_SIZE + WAV_SIZE / 2);
+        let report = search_watcher.export(&capped).unwrap();
+        assert_eq!(report.exported, 2);
+        assert_eq!(
+            report.skipped_size,
+            [norm(&directory.path().join("audio/03 Song 3.wav").to_string_lossy())]
+        );
+        assert_eq!(report.total_bytes, 2 * WAV_SIZE);
+        assert!(!exported(&target, 3).exists());
+        assert!(!target.join(ALBUM_DIRECTORY).join("03 Song 3.wav.part").exists());
+
+        // moving the files doesn't fit anymore: the previous exports are kept
+        let before = manifest(&target);
+        let mut moved = job(&target);
+        moved.layout = "{title}".to_string();
+        moved.max_bytes = Some(2 * WAV_SIZE - 1);
+        let report = search_watcher.export(&moved).unwrap();
+        assert_eq!(report.exported, 0);
+        assert_eq!(report.skipped_size.len(), TRACKS);
+        assert_eq!(report.total_bytes, 2 * WAV_SIZE);
+        assert_eq!(manifest(&target), before);
+        for index in 1..=2 {
+            assert!(exported(&target, index).exists());
+            assert!(!target.join(format!("Song {}.wav", index)).exists());
+        }
+    }
+}
@@ -0,0 +1,726 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use audiotags::Tag;
+use id3::{TagLike, Version};
+use log::{error, info, warn};
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use serde::{Deserialize, Serialize};
+
+use crate::decode::{decode_path, Decoding};
+use crate::error::{AudioError, Result};
+use crate::json_store::JsonStore;
+use crate::schema::{SearchWatcher, Track, TrackJson};
+use crate::utils::file_ext;
+
+pub const FINGERPRINTS_FILE: &str = "./data/fingerprints.json";
+
+/// Audio is downsampled to about this rate first, nothing above ~5 kHz is used
+const TARGET_SAMPLE_RATE: u32 = 11025;
+const FRAME_SIZE: usize = 4096;
+/// A third of a frame (~0.12s at the target rate), as chromaprint does
+const HOP_SIZE: usize = FRAME_SIZE / 3;
+/// Same as chromaprint, the start of a track is enough to identify it
+const MAX_FINGERPRINT_SECS: f64 = 120.0;
+const CHROMA_MIN_HZ: f64 = 28.0;
+const CHROMA_MAX_HZ: f64 = 3520.0;
+/// Frames averaged together before hashing, smooths out encoder differences
+const SMOOTHING_FRAMES: usize = 3;
+
+/// Offsets (in frames, ~2s) tried when comparing, for files with more or less leading silence
+const MAX_OFFSET: isize = 16;
+/// Fingerprints have to overlap for ~10s to be compared at all
+const MIN_OVERLAP: usize = 80;
+/// Two encodings of the same recording usually differ on less than 10% of the bits,
+/// unrelated tracks on about half of them
+pub const IDENTICAL_SIMILARITY: f32 = 0.85;
+/// Pairs need this many identical sub-fingerprints before being compared bit by bit
+const MIN_SHARED_HASHES: usize = 2;
+
+/// A chromaprint-style fingerprint: one 32 bit sub-fingerprint per frame of the first two
+/// minutes, computed during the scan pass and kept outside of the index (keyed by `Track.id`)
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct Fingerprint {
+    pub hashes: Vec<u32>,
+    /// `modified_date` of the file the fingerprint was computed from
+    pub modified_date: i64,
+}
+
+pub type FingerprintStore = JsonStore<Fingerprint>;
+
+/// Tracks that sound the same, e.g. the same song ripped twice or in two formats
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct IdenticalGroup {
+    pub tracks: Vec<Track>,
+    /// Lowest similarity between a track of the group and the one it was matched with
+    pub similarity: f32,
+}
+
+/// An untagged track that sounds like an already tagged one
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct TagMatch {
+    pub track: Track,
+    pub matched: Track,
+    pub similarity: f32,
+}
+
+impl Track {
+    /// Tracks with the fallback values of `TrackJson::new`
+    pub fn is_untagged(&self) -> bool {
+        self.track == "untitled" || self.artist == "untitled"
+    }
+}
+
+impl SearchWatcher {
+    /// Computes the fingerprint of `item` unless it's stored for the same version of the file.
+    /// Only kept in memory, the scan pass saves the store once it's done.
+    pub fn update_fingerprint(&self, item: &TrackJson) {
+        let up_to_date = self
+            .fingerprints
+            .get(&item.id)
+            .map(|fingerprint| fingerprint.modified_date == item.modified_date)
+            .unwrap_or(false);
+        if up_to_date {
+            return;
+        }
+
+        match compute_fingerprint(Path::new(&item.abs_path)) {
+            Ok(hashes) => {
+                let fingerprint = Fingerprint {
+                    hashes,
+                    modified_date: item.modified_date,
+                };
+                if let Err(e) = self.fingerprints.insert(&item.id, fingerprint) {
+                    error!("Error storing fingerprint for {}: {}", item.abs_path, e);
+                }
+            }
+            Err(e) => warn!("No fingerprint for {}: {}", item.abs_path, e),
+        }
+    }
+
+    /// Groups of indexed tracks that sound identical, whatever their tags say
+    pub fn identical_tracks(&self) -> Result<Vec<IdenticalGroup>> {
+        let tracks = self.fingerprinted_tracks();
+        let pairs = matching_pairs(&tracks, |_, _| true);
+
+        // union-find over the matching pairs
+        let mut parents: Vec<usize> = (0..tracks.len()).collect();
+        fn root(parents: &mut Vec<usize>, mut i: usize) -> usize {
+            while parents[i] != i {
+                parents[i] = parents[parents[i]];
+                i = parents[i];
+            }
+            i
+        }
+        let mut group_similarity: HashMap<usize, f32> = HashMap::new();
+        for (a, b, similarity) in &pairs {
+            let (root_a, root_b) = (root(&mut parents, *a), root(&mut parents, *b));
+            let lowest = similarity
+                .min(*group_similarity.get(&root_a).unwrap_or(&1.0))
+                .min(*group_similarity.get(&root_b).unwrap_or(&1.0));
+            parents[root_b] = root_a;
+            group_similarity.insert(root_a, lowest);
+        }
+
+        let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+        for i in 0..tracks.len() {
+            let group_root = root(&mut parents, i);
+            groups.entry(group_root).or_default().push(i);
+        }
+
+        let mut report: Vec<IdenticalGroup> = groups
+            .into_iter()
+            .filter(|(_, members)| members.len() > 1)
+            .map(|(group_root, members)| IdenticalGroup {
+                tracks: members.iter().map(|i| tracks[*i].0.clone()).collect(),
+                similarity: *group_similarity.get(&group_root).unwrap_or(&1.0),
+            })
+            .collect();
+        report.sort_by(|a, b| b.tracks.len().cmp(&a.tracks.len()));
+
+        info!("{} group(s) of identical tracks", report.len());
+        Ok(report)
+    }
+
+    /// The best tagged match of every untagged track, nothing is changed until the matches
+    /// are passed to `apply_tag_matches`
+    pub fn match_untagged(&self) -> Result<Vec<TagMatch>> {
+        let tracks = self.fingerprinted_tracks();
+        let pairs = matching_pairs(&tracks, |a, b| a.is_untagged() != b.is_untagged());
+
+        let mut best: HashMap<usize, (usize, f32)> = HashMap::new();
+        for (a, b, similarity) in pairs {
+            let (untagged, tagged) = if tracks[a].0.is_untagged() {
+                (a, b)
+            } else {
+                (b, a)
+            };
+            match best.get(&untagged) {
+                Some((_, best_similarity)) if *best_similarity >= similarity => {}
+                _ => {
+                    best.insert(untagged, (tagged, similarity));
+                }
+            }
+        }
+
+        let mut matches: Vec<TagMatch> = best
+            .into_iter()
+            .map(|(untagged, (tagged, similarity))| TagMatch {
+                track: tracks[untagged].0.clone(),
+                matched: tracks[tagged].0.clone(),
+                similarity,
+            })
+            .collect();
+        matches.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+
+        info!("{} untagged track(s) matched", matches.len());
+        Ok(matches)
+    }
+
+    /// Copies the tags of each match onto its untagged track: into the file (so a rescan keeps
+    /// them) when the format supports it, and into the index
+    pub fn apply_tag_matches(&self, matches: &[TagMatch]) -> Result<usize> {
+        let mut applied: HashMap<String, TrackJson> = HashMap::new();
+        for tag_match in matches {
+            let path = Path::new(&tag_match.track.abs_path);
+            if tag_match.track.exists {
+                if let Err(e) = write_tags(path, &tag_match.matched) {
+                    error!("Error writing tags to {}: {}", tag_match.track.abs_path, e);
+                    continue;
+                }
+            }
+            applied.insert(tag_match.track.id.clone(), tags_of(&tag_match.matched));
+        }
+
+        let track_ids: Vec<&str> = applied.keys().map(|id| id.as_str()).collect();
+        self.rewrite_documents(&track_ids, &self.field_schema.tag_fields(), |track_id, document| {
+            if let Some(tags) = applied.get(track_id) {
+                self.field_schema.add_tags(document, tags);
+            }
+        })?;
+
+        info!("Tagged {} track(s)", applied.len());
+        Ok(applied.len())
+    }
+
+    /// Indexed tracks paired with their fingerprints
+    fn fingerprinted_tracks(&self) -> Vec<(Track, Vec<u32>)> {
+        self.all_tracks()
+            .into_iter()
+            .filter_map(|track| {
+                let fingerprint = self.fingerprints.get(&track.id)?;
+                Some((track, fingerprint.hashes))
+            })
+            .filter(|(_, hashes)| hashes.len() >= MIN_OVERLAP)
+            .collect()
+    }
+}
+
+/// The tag values of `track` as the `TrackJson` that `FieldSchema::add_tags` expects
+fn tags_of(track: &Track) -> TrackJson {
+    TrackJson {
+        track: track.track.clone(),
+        artist: track.artist.clone(),
+        artists: track.artists.clone(),
+        album: track.album.clone(),
+        album_artist: track.album_artist.clone(),
+        year: track.year,
+        genre: track.genres.join(", "),
+        genres: track.genres.clone(),
+        track_number: track.track_number,
+        disc_number: track.disc_number,
+        content_kind: Some(track.content_kind),
+        ..Default::default()
+    }
+}
+
+/// Pairs (`a` < `b`) of `tracks` that sound identical. Only pairs sharing a few exact
+/// sub-fingerprints are compared, instead of every track with every other one.
+fn matching_pairs(
+    tracks: &[(Track, Vec<u32>)],
+    keep: impl Fn(&Track, &Track) -> bool,
+) -> Vec<(usize, usize, f32)> {
+    let mut by_hash: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (i, (_, hashes)) in tracks.iter().enumerate() {
+        let mut unique = hashes.clone();
+        unique.sort_unstable();
+        unique.dedup();
+        for hash in unique {
+            by_hash.entry(hash).or_default().push(i);
+        }
+    }
+
+    let mut shared: HashMap<(usize, usize), usize> = HashMap::new();
+    for indexes in by_hash.values() {
+        // hashes found in many tracks (silence, noise) don't tell them apart
+        if indexes.len() > 50 {
+            continue;
+        }
+        for (n, a) in indexes.iter().enumerate() {
+            for b in &indexes[n + 1..] {
+                *shared.entry((*a, *b)).or_default() += 1;
+            }
+        }
+    }
+
+    let mut pairs = vec![];
+    for ((a, b), count) in shared {
+        if count < MIN_SHARED_HASHES || !keep(&tracks[a].0, &tracks[b].0) {
+            continue;
+        }
+        let similarity = fingerprint_similarity(&tracks[a].1, &tracks[b].1);
+        if similarity >= IDENTICAL_SIMILARITY {
+            pairs.push((a, b, similarity));
+        }
+    }
+    pairs
+}
+
+/// 1 minus the bit error rate at the best alignment, about 0.5 for unrelated tracks
+pub fn fingerprint_similarity(a: &[u32], b: &[u32]) -> f32 {
+    let mut best = 0.0_f32;
+    for offset in -MAX_OFFSET..=MAX_OFFSET {
+        let (a, b) = if offset >= 0 {
+            (a.get(offset as usize..).unwrap_or(&[]), b)
+        } else {
+            (a, b.get((-offset) as usize..).unwrap_or(&[]))
+        };
+        let overlap = a.len().min(b.len());
+        if overlap < MIN_OVERLAP {
+            continue;
+        }
+
+        let differing_bits: u32 = a
+            .iter()
+            .zip(b.iter())
+            .map(|(x, y)| (x ^ y).count_ones())
+            .sum();
+        let similarity = 1.0 - differing_bits as f32 / (overlap * 32) as f32;
+        best = best.max(similarity);
+    }
+    best
+}
+
+pub fn compute_fingerprint(path: &Path) -> Result<Vec<u32>> {
+    let mut fingerprinter: Option<Fingerprinter> = None;
+    decode_path(path, |format, samples| {
+        let fingerprinter =
+            fingerprinter.get_or_insert_with(|| Fingerprinter::new(format.sample_rate));
+
+        for frame in samples.chunks(format.channels.max(1)) {
+            fingerprinter.push(frame.iter().sum::<f32>() / frame.len() as f32);
+        }
+
+        if fingerprinter.seconds() >= MAX_FINGERPRINT_SECS {
+            Ok(Decoding::Stop)
+        } else {
+            Ok(Decoding::Continue)
+        }
+    })?;
+
+    fingerprinter
+        .map(Fingerprinter::finish)
+        .ok_or_else(|| AudioError::decode(&path.to_string_lossy(), "no audio"))
+}
+
+struct Fingerprinter {
+    /// Every `decimation` input samples are averaged into one
+    decimation: usize,
+    sample_rate: u32,
+    samples_seen: usize,
+    decimated_sum: f32,
+    decimated_count: usize,
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    pending: Vec<f32>,
+    /// Normalized chroma vector of every frame
+    chroma_frames: Vec<[f64; 12]>,
+}
+
+impl Fingerprinter {
+    fn new(sample_rate: u32) -> Self {
+        let decimation = (sample_rate as f64 / TARGET_SAMPLE_RATE as f64).round().max(1.0) as usize;
+
+        // Hann window
+        let window = (0..FRAME_SIZE)
+            .map(|i| {
+                let phase = 2.0 * std::f32::consts::PI * i as f32 / (FRAME_SIZE - 1) as f32;
+                0.5 - 0.5 * phase.cos()
+            })
+            .collect();
+
+        Fingerprinter {
+            decimation,
+            sample_rate,
+            samples_seen: 0,
+            decimated_sum: 0.0,
+            decimated_count: 0,
+            fft: FftPlanner::new().plan_fft_forward(FRAME_SIZE),
+            window,
+            pending: Vec::with_capacity(FRAME_SIZE),
+            chroma_frames: vec![],
+        }
+    }
+
+    fn seconds(&self) -> f64 {
+        self.samples_seen as f64 / self.sample_rate.max(1) as f64
+    }
+
+    fn push(&mut self, sample: f32) {
+        self.samples_seen += 1;
+        self.decimated_sum += sample;
+        self.decimated_count += 1;
+        if self.decimated_count < self.decimation {
+            return;
+        }
+
+        self.pending
+            .push(self.decimated_sum / self.decimated_count as f32);
+        self.decimated_sum = 0.0;
+        self.decimated_count = 0;
+        if self.pending.len() == FRAME_SIZE {
+            self.process_frame();
+            self.pending.drain(..HOP_SIZE);
+        }
+    }
+
+    fn process_frame(&mut self) {
+        let mut buffer: Vec<Complex<f32>> = self
+            .pending
+            .iter()
+            .zip(self.window.iter())
+            .map(|(sample, weight)| Complex::new(sample * weight, 0.0))
+            .collect();
+        self.fft.process(&mut buffer);
+
+        let bin_hz = self.sample_rate as f64 / self.decimation as f64 / FRAME_SIZE as f64;
+        let mut chroma = [0.0; 12];
+        for (k, bin) in buffer[..FRAME_SIZE / 2].iter().enumerate().skip(1) {
+            let frequency = k as f64 * bin_hz;
+            if !(CHROMA_MIN_HZ..=CHROMA_MAX_HZ).contains(&frequency) {
+                continue;
+            }
+            // MIDI note number, 69 is A4 (440 Hz)
+            let note = 12.0 * (frequency / 440.0).log2() + 69.0;
+            let pitch_class = (note.round() as i64).rem_euclid(12) as usize;
+            chroma[pitch_class] += (bin.norm() as f64).powi(2);
+        }
+
+        // louder or quieter encodings of a track give the same fingerprint
+        let length = chroma.iter().map(|x| x * x).sum::<f64>().sqrt();
+        if length > f64::EPSILON {
+            for value in chroma.iter_mut() {
+                *value /= length;
+            }
+        }
+        self.chroma_frames.push(chroma);
+    }
+
+    fn finish(self) -> Vec<u32> {
+        let smoothed: Vec<[f64; 12]> = self
+            .chroma_frames
+            .windows(SMOOTHING_FRAMES)
+            .map(|frames| {
+                let mut average = [0.0; 12];
+                for frame in frames {
+                    for (sum, value) in average.iter_mut().zip(frame.iter()) {
+                        *sum += value / SMOOTHING_FRAMES as f64;
+                    }
+                }
+                average
+            })
+            .collect();
+
+        smoothed
+            .windows(2)
+            .map(|frames| sub_fingerprint(&frames[0], &frames[1]))
+            .collect()
+    }
+}
+
+/// 32 bits comparing the pitch classes of a frame with each other and with the previous frame:
+/// 12 for neighbouring classes, 12 for rising/falling energy and 8 for classes a third apart
+fn sub_fingerprint(previous: &[f64; 12], current: &[f64; 12]) -> u32 {
+    let mut hash = 0_u32;
+    let mut bit = 0;
+    let mut set = |condition: bool| {
+        if condition {
+            hash |= 1 << bit;
+        }
+        bit += 1;
+    };
+
+    for class in 0..12 {
+        set(current[class] > current[(class + 1) % 12]);
+    }
+    for class in 0..12 {
+        set(current[class] > previous[class]);
+    }
+    for class in 0..8 {
+        set(current[class] > current[(class + 4) % 12]);
+    }
+    hash
+}
+
+/// Writes the main tags of `tags` to `path`, replacing the existing ones
+pub fn write_tags(path: &Path, tags: &Track) -> Result<()> {
+    let path_string = path.to_string_lossy().to_string();
+
+    if file_ext(&path_string).eq_ignore_ascii_case("wav") {
+        let mut tag = id3::Tag::read_from_wav_path(path).unwrap_or_else(|_| id3::Tag::new());
+        tag.set_title(&tags.track);
+        tag.set_artist(&tags.artist);
+        tag.set_album(&tags.album);
+        tag.set_album_artist(&tags.album_artist);
+        if tags.year > 0 {
+            tag.set_year(tags.year as i32);
+        }
+        if !tags.genres.is_empty() {
+            tag.set_genre(tags.genres.join(", "));
+        }
+        if tags.track_number > 0 {
+            tag.set_track(tags.track_number as u32);
+        }
+        if tags.disc_number > 0 {
+            tag.set_disc(tags.disc_number as u32);
+        }
+        return tag
+            .write_to_wav_path(path, Version::Id3v24)
+            .map_err(|e| AudioError::tag(&path_string, e));
+    }
+
+    let mut tag = Tag::new()
+        .read_from_path(path)
+        .map_err(|e| AudioError::tag(&path_string, e))?;
+    tag.set_title(&tags.track);
+    tag.set_artist(&tags.artist);
+    tag.set_album_title(&tags.album);
+    tag.set_album_artist(&tags.album_artist);
+    if tags.year > 0 {
+        tag.set_year(tags.year as i32);
+    }
+    if !tags.genres.is_empty() {
+        tag.set_genre(&tags.genres.join(", "));
+    }
+    if tags.track_number > 0 {
+        tag.set_track_number(tags.track_number as u16);
+    }
+    if tags.disc_number > 0 {
+        tag.set_disc_number(tags.disc_number as u16);
+    }
+    tag.write_to_path(&path_string)
+        .map_err(|e| AudioError::tag(&path_string, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::{DocumentSearchRequest, Filters};
+    use crate::test_support::{index_tracks, search_watcher, track, write_wav_with};
+    use crate::utils::norm;
+
+    /// Pseudo-random sub-fingerprints (xorshift), different for every `seed`
+    fn hashes(seed: u32, len: usize) -> Vec<u32> {
+        let mut state = seed.wrapping_mul(2_654_435_761).max(1);
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state
+            })
+            .collect()
+    }
+
+    /// One bit of every other sub-fingerprint flipped, like another encoding of the same audio
+    fn re_encoded(hashes: &[u32]) -> Vec<u32> {
+        hashes
+            .iter()
+            .enumerate()
+            .map(|(i, hash)| if i % 2 == 1 { hash ^ (1 << (i % 32)) } else { *hash })
+            .collect()
+    }
+
+    fn fingerprinted(id: &str, hashes: Vec<u32>) -> (Track, Vec<u32>) {
+        let track = Track {
+            id: id.to_string(),
+            ..Default::default()
+        };
+        (track, hashes)
+    }
+
+    #[test]
+    fn similarity_at_the_best_offset() {
+        let a = hashes(1, 200);
+        assert_eq!(fingerprint_similarity(&a, &a), 1.0);
+        // leading silence in either file
+        assert_eq!(fingerprint_similarity(&a, &a[12..]), 1.0);
+        assert_eq!(fingerprint_similarity(&a[MAX_OFFSET as usize..], &a), 1.0);
+
+        let similarity = fingerprint_similarity(&a, &re_encoded(&a));
+        assert!((similarity - (1.0 - 100.0 / (200.0 * 32.0))).abs() < 1e-6, "{}", similarity);
+
+        // unrelated, shifted too far, or too short to tell
+        assert!(fingerprint_similarity(&a, &hashes(2, 200)) < 0.6);
+        assert!(fingerprint_similarity(&a, &a[MAX_OFFSET as usize + 4..]) < IDENTICAL_SIMILARITY);
+        let short = &a[..MIN_OVERLAP - 1];
+        assert_eq!(fingerprint_similarity(short, short), 0.0);
+    }
+
+    #[test]
+    fn pairs_need_shared_hashes() {
+        let a = hashes(1, 200);
+        let tracks = vec![
+            fingerprinted("a", a.clone()),
+            fingerprinted("copy", a[5..].to_vec()),
+            fingerprinted("re-encoded", re_encoded(&a)),
+            // close bit by bit, but without a single identical sub-fingerprint
+            fingerprinted("flipped", a.iter().map(|hash| hash ^ 1).collect()),
+            fingerprinted("other", hashes(2, 200)),
+        ];
+        assert!(fingerprint_similarity(&tracks[0].1, &tracks[3].1) >= IDENTICAL_SIMILARITY);
+
+        let mut pairs: Vec<(usize, usize)> = matching_pairs(&tracks, |_, _| true)
+            .into_iter()
+            .map(|(a, b, _)| (a, b))
+            .collect();
+        pairs.sort_unstable();
+        assert_eq!(pairs, [(0, 1), (0, 2), (1, 2)]);
+
+        let kept = matching_pairs(&tracks, |a, b| a.id != "a" && b.id != "a");
+        assert_eq!(kept.len(), 1);
+        assert_eq!((kept[0].0, kept[0].1), (1, 2));
+
+        // a hash most tracks have (silence) doesn't pair them
+        let silent: Vec<_> = (0..60)
+            .map(|i| fingerprinted(&format!("silent-{}", i), vec![0; 100]))
+            .collect();
+        assert!(matching_pairs(&silent, |_, _| true).is_empty());
+    }
+
+    #[test]
+    fn identical_groups() {
+        let (_directory, search_watcher) = search_watcher();
+        let tracks: Vec<_> = (0..6)
+            .map(|index| track(index, "Blue River", "Night Drive", "Rock", 2001))
+            .collect();
+        index_tracks(&search_watcher, &tracks);
+
+        let first = hashes(1, 200);
+        let second = hashes(2, 200);
+        // track-0 and track-2 are too far apart to match, both match track-1
+        assert!(fingerprint_similarity(&first, &first[20..]) < IDENTICAL_SIMILARITY);
+        let fingerprints = vec![
+            first.clone(),
+            first[10..].to_vec(),
+            first[20..].to_vec(),
+            second.clone(),
+            re_encoded(&second),
+            hashes(3, 200),
+        ];
+        for (index, hashes) in fingerprints.into_iter().enumerate() {
+            let fingerprint = Fingerprint {
+                hashes,
+                modified_date: 0,
+            };
+            search_watcher
+                .fingerprints
+                .insert(&format!("track-{}", index), fingerprint)
+                .unwrap();
+        }
+
+        let groups = search_watcher.identical_tracks().unwrap();
+        let ids: Vec<Vec<String>> = groups
+            .iter()
+            .map(|group| {
+                let mut ids: Vec<String> = group.tracks.iter().map(|track| track.id.clone()).collect();
+                ids.sort();
+                ids
+            })
+            .collect();
+        assert_eq!(
+            ids,
+            [vec!["track-0", "track-1", "track-2"], vec!["track-3", "track-4"]]
+        );
+        assert_eq!(groups[0].similarity, 1.0);
+        assert!((groups[1].similarity - (1.0 - 100.0 / (200.0 * 32.0))).abs() < 1e-6);
+    }
+
+    const SAMPLE_RATE: u32 = 11025;
+
+    /// 20s of sine tones, a new note every 0.4s, `step` semitones apart (mod 2 octaves)
+    fn write_melody(path: &Path, step: u32) {
+        write_wav_with(path, SAMPLE_RATE, 1, SAMPLE_RATE * 20, |frame| {
+            let seconds = frame as f64 / SAMPLE_RATE as f64;
+            let note = (seconds / 0.4) as u32 * step % 24;
+            let frequency = 220.0 * 2_f64.powf(note as f64 / 12.0);
+            ((2.0 * std::f64::consts::PI * frequency * seconds).sin() * 8000.0) as i16
+        });
+    }
+
+    #[test]
+    fn untagged_copies_get_the_tags() {
+        let (directory, search_watcher) = search_watcher();
+        let path = |name: &str| directory.path().join(name);
+        write_melody(&path("tagged.wav"), 7);
+        write_melody(&path("untagged.wav"), 7);
+        write_melody(&path("other.wav"), 5);
+
+        let mut tagged = track(1, "Blue River", "Night Drive", "Rock", 2001);
+        tagged.abs_path = norm(&path("tagged.wav").to_string_lossy());
+        let mut untagged = track(2, "untitled", "untitled", "", 0);
+        untagged.track = "untitled".to_string();
+        untagged.abs_path = norm(&path("untagged.wav").to_string_lossy());
+        let mut other = track(3, "Golden Rain", "Later", "Jazz", 2015);
+        other.abs_path = norm(&path("other.wav").to_string_lossy());
+        let tracks = [tagged, untagged, other];
+        index_tracks(&search_watcher, &tracks);
+        for item in &tracks {
+            search_watcher.update_fingerprint(item);
+        }
+        assert!(search_watcher.fingerprints.get("track-2").unwrap().hashes.len() >= MIN_OVERLAP);
+
+        let matches = search_watcher.match_untagged().unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].track.id, "track-2");
+        assert_eq!(matches[0].matched.id, "track-1");
+        assert_eq!(matches[0].similarity, 1.0);
+
+        assert_eq!(search_watcher.apply_tag_matches(&matches).unwrap(), 1);
+        let tag = id3::Tag::read_from_wav_path(path("untagged.wav")).unwrap();
+        assert_eq!(tag.title(), Some("Song 1"));
+        assert_eq!(tag.artist(), Some("Blue River"));
+
+        // the index has the tags too, and can find the track by them
+        let retagged = search_watcher
+            .all_tracks()
+            .into_iter()
+            .find(|track| track.id == "track-2")
+            .unwrap();
+        assert_eq!((retagged.artist.as_str(), retagged.track.as_str()), ("Blue River", "Song 1"));
+        assert_eq!(retagged.album, "Night Drive");
+        let request = DocumentSearchRequest {
+            text: "river".to_string(),
+            fields: vec![],
+            filters: Filters::default(),
+            order: None,
+            faceted: None,
+            page_number: 0,
+            result_per_page: 10,
+            reload: false,
+            explain: false,
+            search_after: None,
+        };
+        let found: Vec<String> = search_watcher
+            .all_results(&request)
+            .unwrap()
+            .into_iter()
+            .map(|track| track.id)
+            .collect();
+        assert!(found.contains(&"track-2".to_string()), "{:?}", found);
+
+        assert!(search_watcher.match_untagged().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::SystemTime;
+
+use log::{error, info};
+use serde::Serialize;
+use tantivy::schema::{Term, Value};
+use tantivy::DocAddress;
+
+use crate::error::Result;
+use crate::library_roots::{configured_roots, root_for_path};
+use crate::schema::{SearchWatcher, TrackStatus, BASE_AUDIO_DIRECTORY};
+use crate::settings::SETTINGS;
+
+pub const DEFAULT_GC_BATCH_SIZE: usize = 500;
+
+#[derive(Serialize, Default, Debug, Clone, PartialEq)]
+pub struct GcReport {
+    /// Number of documents whose files were checked
+    pub checked: usize,
+    /// `abs_path` of every removed document
+    pub removed: Vec<String>,
+    /// Documents skipped because their library root is offline
+    pub skipped_offline: usize,
+    pub segments_before: usize,
+    pub segments_after: usize,
+    pub cost_ms: u128,
+}
+
+struct IndexedPath {
+    id: String,
+    abs_path: String,
+    offline: bool,
+}
+
+impl SearchWatcher {
+    /// Removes documents whose audio files no longer exist, then compacts the segments.
+    /// Tracks of offline library roots are kept (see `TrackStatus::Offline`).
+    pub fn gc(&self, batch_size: usize) -> Result<GcReport> {
+        let start = SystemTime::now();
+        let mut report = GcReport::default();
+
+        let roots = configured_roots(&*SETTINGS.read()?, BASE_AUDIO_DIRECTORY);
+        // a root can go missing (or be unmounted) between incremental indexes, so check it again
+        let offline_roots: HashSet<String> = roots
+            .iter()
+            .filter(|root| !root.is_online())
+            .map(|root| root.id.clone())
+            .collect();
+        let searcher = self.reader.searcher();
+        report.segments_before = searcher.segment_readers().len();
+
+        let mut batch: Vec<IndexedPath> = Vec::with_capacity(batch_size);
+        for (segment_ord, segment_reader) in searcher.segment_readers().iter().enumerate() {
+            for doc_id in segment_reader.doc_ids_alive() {
+                let doc_address = DocAddress::new(segment_ord as u32, doc_id);
+                let doc = match searcher.doc(doc_address) {
+                    Ok(doc) => doc,
+                    Err(e) => {
+                        error!("Error retrieving document from index: {}", e);
+                        continue;
+                    }
+                };
+
+                let text = |field| {
+                    doc.get_first(field)
+                        .and_then(Value::as_text)
+                        .unwrap_or("")
+                        .to_string()
+                };
+                let abs_path = text(self.field_schema.abs_path);
+
+                let root_offline = root_for_path(&roots, &abs_path)
+                    .map(|root| offline_roots.contains(&root.id))
+                    .unwrap_or(false);
+                let status_offline = doc
+                    .get_first(self.field_schema.status)
+                    .and_then(Value::as_u64)
+                    == Some(TrackStatus::Offline as u64);
+
+                batch.push(IndexedPath {
+                    id: text(self.field_schema.id),
+                    abs_path,
+                    offline: root_offline || status_offline,
+                });
+
+                if batch.len() >= batch_size {
+                    self.gc_batch(&mut batch, &mut report)?;
+                }
+            }
+        }
+        self.gc_batch(&mut batch, &mut report)?;
+
+        self.compact(&mut report)?;
+
+        report.cost_ms = SystemTime::now()
+            .duration_since(start)
+            .unwrap_or_default()
+            .as_millis();
+
+        info!(
+            "gc checked {} track(s), removed {}, skipped {} offline, segments {} -> {} ({}ms)",
+            report.checked,
+            report.removed.len(),
+            report.skipped_offline,
+            report.segments_before,
+            report.segments_after,
+            report.cost_ms
+        );
+        Ok(report)
+    }
+
+    fn gc_batch(&self, batch: &mut Vec<IndexedPath>, report: &mut GcReport) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let mut writer = self.writer()?;
+        let mut removed = 0;
+        for indexed in batch.drain(..) {
+            report.checked += 1;
+            if indexed.offline {
+                report.skipped_offline += 1;
+                continue;
+            }
+            if Path::new(&indexed.abs_path).exists() {
+                continue;
+            }
+
+            info!("gc removing {}", indexed.abs_path);
+            writer.delete_term(Term::from_field_text(self.field_schema.id, &indexed.id));
+            report.removed.push(indexed.abs_path);
+            removed += 1;
+        }
+
+        if removed > 0 {
+            writer.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Merges all searchable segments into one, dropping deleted documents along the way
+    fn compact(&self, report: &mut GcReport) -> Result<()> {
+        let mut writer = self.writer()?;
+
+        let segment_ids = self.index.searchable_segment_ids()?;
+        if !segment_ids.is_empty() && (segment_ids.len() > 1 || !report.removed.is_empty()) {
+            writer.merge(&segment_ids).wait()?;
+        }
+
+        // unused files are only cleaned up on a best effort basis
+        if let Err(e) = writer.garbage_collect_files().wait() {
+            error!("Error removing unused index files: {}", e);
+        }
+        drop(writer);
+
+        self.reader.reload()?;
+        report.segments_after = self.reader.searcher().segment_readers().len();
+        Ok(())
+    }
+}
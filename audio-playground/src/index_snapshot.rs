@@ -0,0 +1,306 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+use tantivy::schema::{Document, Field, Value};
+use tantivy::DocAddress;
+
+use crate::error::{AudioError, Result};
+use crate::indexing::SCHEMA_VERSION;
+use crate::library_roots::{configured_roots, LibraryRoot};
+use crate::lyrics::LyricLine;
+use crate::schema::{
+    millis_since_epoch, FieldSchema, SearchWatcher, Track, TrackJson, TrackStatus,
+    BASE_AUDIO_DIRECTORY,
+};
+use crate::settings::SETTINGS;
+use crate::user_data::UserData;
+
+/// `format` of the first line of a snapshot
+const SNAPSHOT_FORMAT: &str = "audio-playground-index";
+
+/// Bumped when the lines of a snapshot change, newer snapshots can't be imported
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// First line of a snapshot, followed by one `SnapshotEntry` per line
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotHeader {
+    pub format: String,
+    pub version: u32,
+    /// `SCHEMA_VERSION` of the exporting index, for information only (the entries are `TrackJson`)
+    pub schema_version: u32,
+    pub exported_date: i64,
+    /// Roots of the exporting machine, so paths can be moved to the roots with the same id
+    pub library_roots: Vec<LibraryRoot>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotEntry {
+    pub track: TrackJson,
+    #[serde(default)]
+    pub offline: bool,
+    #[serde(default)]
+    pub user_data: UserData,
+}
+
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct SnapshotImport {
+    pub imported: usize,
+    /// Tracks whose path was moved from the exported root to the local one
+    pub moved: usize,
+}
+
+impl SearchWatcher {
+    /// Writes every indexed track with its user data as NDJSON, a document at a time
+    pub fn export_index(&self, path: &Path) -> Result<usize> {
+        let path_string = path.to_string_lossy().to_string();
+        let export_error = |e: std::io::Error| AudioError::export(&path_string, e);
+        let mut writer = BufWriter::new(File::create(path).map_err(export_error)?);
+
+        let header = SnapshotHeader {
+            format: SNAPSHOT_FORMAT.to_string(),
+            version: SNAPSHOT_VERSION,
+            schema_version: SCHEMA_VERSION,
+            exported_date: millis_since_epoch(std::time::SystemTime::now()),
+            library_roots: configured_roots(&*SETTINGS.read()?, BASE_AUDIO_DIRECTORY),
+        };
+        serde_json::to_writer(&mut writer, &header)?;
+        writeln!(writer).map_err(export_error)?;
+
+        let searcher = self.reader.searcher();
+        let mut exported = 0;
+        for (segment_ord, segment_reader) in searcher.segment_readers().iter().enumerate() {
+            for doc_id in segment_reader.doc_ids_alive() {
+                let doc = searcher.doc(DocAddress::new(segment_ord as u32, doc_id))?;
+                let entry = snapshot_entry(&self.field_schema, doc);
+                let entry = SnapshotEntry {
+                    user_data: self.user_data.get(&entry.track.id),
+                    ..entry
+                };
+                serde_json::to_writer(&mut writer, &entry)?;
+                writeln!(writer).map_err(export_error)?;
+                exported += 1;
+            }
+        }
+        writer.flush().map_err(export_error)?;
+
+        info!("Exported {} tracks to {}", exported, path_string);
+        Ok(exported)
+    }
+
+    /// Replaces the whole index (and the user data of the imported tracks) with a snapshot of
+    /// `export_index`, without reading any audio file
+    pub fn import_index(&self, path: &Path) -> Result<SnapshotImport> {
+        let path_string = path.to_string_lossy().to_string();
+        let import_error = |e: &dyn std::fmt::Display| AudioError::import(&path_string, e);
+        let mut lines = BufReader::new(File::open(path)?).lines();
+
+        let header_line = lines
+            .next()
+            .ok_or_else(|| import_error(&"empty snapshot"))??;
+        let header: SnapshotHeader =
+            serde_json::from_str(&header_line).map_err(|e| import_error(&e))?;
+        if header.format != SNAPSHOT_FORMAT {
+            return Err(import_error(&format!("not an index snapshot ({})", header.format)));
+        }
+        if header.version > SNAPSHOT_VERSION {
+            return Err(import_error(&format!(
+                "snapshot version {} is newer than {}",
+                header.version, SNAPSHOT_VERSION
+            )));
+        }
+
+        // the whole file is read before the index is touched, a broken line leaves it as it was
+        let mut entries: Vec<SnapshotEntry> = vec![];
+        for (number, line) in lines.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry = serde_json::from_str(&line)
+                .map_err(|e| import_error(&format!("line {}: {}", number + 2, e)))?;
+            entries.push(entry);
+        }
+
+        let local_roots = configured_roots(&*SETTINGS.read()?, BASE_AUDIO_DIRECTORY);
+        let mut result = SnapshotImport::default();
+        let mut user_data: Vec<(String, UserData)> = vec![];
+        let mut writer = self.writer()?;
+        let written = (|| -> Result<()> {
+            writer.delete_all_documents()?;
+            for mut entry in entries {
+                let moved_path =
+                    move_to_local_root(&header.library_roots, &local_roots, &entry.track);
+                if let Some(moved_path) = moved_path {
+                    entry.track.abs_path = moved_path;
+                    result.moved += 1;
+                }
+
+                let status = if entry.offline {
+                    TrackStatus::Offline
+                } else {
+                    TrackStatus::Online
+                };
+                writer.add_document(self.document_with_user_data(
+                    &entry.track,
+                    status,
+                    &entry.user_data,
+                ))?;
+                if entry.user_data != UserData::default() {
+                    user_data.push((entry.track.id, entry.user_data));
+                }
+                result.imported += 1;
+            }
+            writer.commit()?;
+            Ok(())
+        })();
+        if let Err(e) = written {
+            // otherwise the next commit of this writer deletes everything
+            writer.rollback()?;
+            return Err(e);
+        }
+        drop(writer);
+        self.user_data.set_many(&user_data)?;
+        self.reader.reload()?;
+        self.rebuild_albums()?;
+        self.rebuild_books()?;
+
+        info!(
+            "Imported {} tracks from {} ({} moved to a local root)",
+            result.imported, path_string, result.moved
+        );
+        Ok(result)
+    }
+}
+
+/// The `TrackJson` the document was made from. Dates only keep the seconds of the index.
+fn snapshot_entry(field_schema: &FieldSchema, doc: Document) -> SnapshotEntry {
+    let text = |field: Field| {
+        doc.get_first(field)
+            .and_then(Value::as_text)
+            .unwrap_or("")
+            .to_string()
+    };
+    let lyrics = text(field_schema.lyrics);
+    let synced_lyrics: Vec<LyricLine> = doc
+        .get_first(field_schema.synced_lyrics)
+        .and_then(Value::as_text)
+        .and_then(|json| serde_json::from_str(json).ok())
+        .unwrap_or_default();
+
+    let track = Track::with_document(field_schema, doc);
+    SnapshotEntry {
+        offline: track.offline,
+        user_data: UserData::default(),
+        track: TrackJson {
+            id: track.id,
+            abs_path: track.abs_path,
+            root_id: track.root_id,
+            created_date: track.created_date,
+            modified_date: track.modified_date,
+            indexed_date: track.indexed_date,
+            size: track.size,
+            album: track.album,
+            artist: track.artist,
+            artists: track.artists,
+            // `genre_string_to_vec` splits on `;` again
+            genre: track.genres.join(";"),
+            genres: track.genres,
+            name: track.name,
+            track: track.track,
+            duration: track.duration,
+            year: track.year,
+            album_artist: track.album_artist,
+            track_number: track.track_number,
+            disc_number: track.disc_number,
+            lyrics,
+            synced_lyrics,
+            start: track.start,
+            end: track.end,
+            content_kind: Some(track.content_kind),
+            chapters: track.chapters,
+        },
+    }
+}
+
+/// The path below the local root with the same id, when that root is somewhere else here
+fn move_to_local_root(
+    exported_roots: &[LibraryRoot],
+    local_roots: &[LibraryRoot],
+    track: &TrackJson,
+) -> Option<String> {
+    let exported_root = exported_roots.iter().find(|root| root.id == track.root_id)?;
+    let local_root = local_roots.iter().find(|root| root.id == track.root_id)?;
+    if exported_root.path == local_root.path {
+        return None;
+    }
+    let relative = track.abs_path.strip_prefix(&exported_root.path)?;
+    Some(format!(
+        "{}/{}",
+        local_root.path.trim_end_matches('/'),
+        relative.trim_start_matches('/')
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_support::{index_tracks, library, search_watcher};
+
+    fn ids(search_watcher: &SearchWatcher) -> Vec<String> {
+        let mut ids: Vec<String> = search_watcher
+            .all_tracks()
+            .into_iter()
+            .map(|track| track.id)
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn round_trip() {
+        let (directory, exported) = search_watcher();
+        index_tracks(&exported, &library(12));
+        exported.record_play("track-3").unwrap();
+        let path = directory.path().join("snapshot.ndjson");
+        assert_eq!(exported.export_index(&path).unwrap(), 12);
+
+        let (_other_directory, imported) = search_watcher();
+        index_tracks(&imported, &library(20));
+        let result = imported.import_index(&path).unwrap();
+
+        assert_eq!(result.imported, 12);
+        assert_eq!(ids(&imported), ids(&exported));
+        assert_eq!(imported.user_data.get("track-3").play_count, 1);
+        assert_eq!(imported.list_albums().len(), exported.list_albums().len());
+    }
+
+    #[test]
+    fn broken_line_leaves_the_index_unchanged() {
+        let (directory, search_watcher) = search_watcher();
+        index_tracks(&search_watcher, &library(12));
+        let path = directory.path().join("snapshot.ndjson");
+        search_watcher.export_index(&path).unwrap();
+        let before = ids(&search_watcher);
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        // the header and 3 entries, then a line cut off or that isn't an entry
+        for broken in [&lines[4][..20], "{\"track\": 1}"] {
+            fs::write(&path, format!("{}\n{}\n", lines[..4].join("\n"), broken)).unwrap();
+
+            let error = search_watcher.import_index(&path).unwrap_err();
+            assert!(error.to_string().contains("line 5"), "{}", error);
+            assert_eq!(ids(&search_watcher), before);
+
+            // nothing was left pending in the writer either
+            search_watcher.writer().unwrap().commit().unwrap();
+            search_watcher.reader.reload().unwrap();
+            assert_eq!(ids(&search_watcher), before);
+        }
+    }
+}
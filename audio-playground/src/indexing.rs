@@ -1,132 +1,167 @@
-use crate::idx_store::IDX_STORE;
-use crate::kv_store::CONF_STORE;
-use crate::walk_metrics::WALK_METRICS;
-use crate::{utils, walk_exec, watch_exec};
-use log::info;
-
-#[cfg(windows)]
-use log::error;
-#[cfg(windows)]
-use std::sync::mpsc;
-#[cfg(windows)]
-use std::sync::mpsc::Sender;
-
-#[cfg(windows)]
-use std::time::Duration;
-use std::time::SystemTime;
-use std::time::UNIX_EPOCH;
-
-#[cfg(windows)]
-use crate::usn_journal_watcher::Watcher;
-
-#[cfg(windows)]
-const STORE_PATH: &'static str = "orangecachedata";
-#[cfg(windows)]
-const RECYCLE_PATH: &'static str = "$RECYCLE.BIN";
-const VERSION: &'static str = "0.6.1";
-const LAST_INDEX_TS: &'static str = "last_index_ts";
-
-pub fn run() {
-    std::thread::spawn(|| {
-        do_run();
-    });
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use log::{info, warn};
+use tantivy::Index;
+
+use crate::error::AudioError;
+use crate::schema::FieldSchema;
+
+/// Bump whenever `FieldSchema::new` changes, existing indexes are then rebuilt on open
+pub const SCHEMA_VERSION: u32 = 12;
+const VERSION_FILE: &str = "schema_version";
+
+pub struct OpenedIndex {
+    pub index: Index,
+    /// The previous index was dropped (schema version mismatch), it has to be filled again
+    pub needs_rebuild: bool,
 }
 
-fn do_run() {
-    housekeeping();
+/// Opens the index in `index_path`, creating it when missing (or empty) and clearing it when it
+/// was written with a different `SCHEMA_VERSION` (or a schema that doesn't match `field_schema`).
+/// Directories that have files but no index are left alone, so a wrong path isn't wiped.
+pub fn housekeeping(
+    index_path: &Path,
+    field_schema: &FieldSchema,
+) -> Result<OpenedIndex, AudioError> {
+    info!("housekeeping {:?}...", index_path);
+
+    if !index_path.exists() || is_empty_dir(index_path)? {
+        fs::create_dir_all(index_path)?;
+        let index = Index::create_in_dir(index_path, field_schema.schema.clone())?;
+        write_version(index_path)?;
+        info!("init schema version {}", SCHEMA_VERSION);
+        return Ok(OpenedIndex {
+            index,
+            needs_rebuild: false,
+        });
+    }
 
-    let reindex = need_reindex();
-    info!("need reindex: {}", reindex);
-    if reindex {
-        walk_exec::run();
-        CONF_STORE.put_str(LAST_INDEX_TS.to_string(), curr_ts().to_string());
-        info!("walk exec done");
-    };
+    if !index_path.join("meta.json").exists() {
+        return Err(AudioError::Config(format!(
+            "{:?} isn't empty and has no index, not clearing it",
+            index_path
+        )));
+    }
 
-    IDX_STORE.disable_full_indexing();
-    WALK_METRICS.write().unwrap().end_of_no_reindex();
-    info!("start fs watch");
-
-    #[cfg(windows)]
-    if cfg!(target_os = "windows") {
-        if reindex {
-            info!("use watcher due to reindex");
-            watch_exec::run();
-        } else {
-            info!("try use usn");
-            win_watch();
+    let version = read_version(index_path);
+    if version == Some(SCHEMA_VERSION) {
+        let index = Index::open_in_dir(index_path)?;
+        if same_schema(&index, field_schema) {
+            info!("no need to rebuild, current schema version: {}", SCHEMA_VERSION);
+            return Ok(OpenedIndex {
+                index,
+                needs_rebuild: false,
+            });
         }
+        warn!(
+            "index schema differs from FieldSchema but both are version {}, was SCHEMA_VERSION bumped?",
+            SCHEMA_VERSION
+        );
+    } else {
+        warn!(
+            "index schema version {:?} does not match {}, rebuilding",
+            version, SCHEMA_VERSION
+        );
     }
-    #[cfg(unix)]
-    watch_exec::run();
+
+    clear(index_path)?;
+    let index = Index::create_in_dir(index_path, field_schema.schema.clone())?;
+    Ok(OpenedIndex {
+        index,
+        needs_rebuild: true,
+    })
 }
 
-#[cfg(windows)]
-fn win_watch() {
-    watch_exec::run();
+/// Opens the index in `index_path` without changing anything, for read-only handles. Fails when
+/// it's missing or would need a rebuild (a read-write open does that).
+pub fn open_existing(index_path: &Path, field_schema: &FieldSchema) -> Result<Index, AudioError> {
+    let needs_read_write = |reason: &str| {
+        AudioError::Config(format!(
+            "the index in {:?} {}, open it read-write first",
+            index_path, reason
+        ))
+    };
+
+    if !index_path.join("meta.json").exists() {
+        return Err(needs_read_write("doesn't exist"));
+    }
+    let version = read_version(index_path);
+    if version != Some(SCHEMA_VERSION) {
+        return Err(needs_read_write(&format!(
+            "has schema version {:?} instead of {}",
+            version, SCHEMA_VERSION
+        )));
+    }
+    let index = Index::open_in_dir(index_path)?;
+    if !same_schema(&index, field_schema) {
+        return Err(needs_read_write("has a different schema"));
+    }
+    Ok(index)
 }
 
-pub fn reindex() {
-    CONF_STORE.put_str("reindex".to_string(), "1".to_string());
+/// Only written once the index is (re)built, so an interrupted rebuild is retried on the next open
+pub fn write_version(index_path: &Path) -> io::Result<()> {
+    fs::write(index_path.join(VERSION_FILE), SCHEMA_VERSION.to_string())
 }
 
-fn need_reindex() -> bool {
-    let key = LAST_INDEX_TS.to_string();
-
-    return match CONF_STORE.get_str(key.clone()) {
-        None => true,
-        Some(val) => {
-            let ts = val.parse::<u64>().unwrap();
-            if curr_ts() - ts > 3600 * 24 * 30 {
-                return true;
-            }
-            false
-        }
-    };
+fn read_version(index_path: &Path) -> Option<u32> {
+    fs::read_to_string(index_path.join(VERSION_FILE))
+        .ok()
+        .and_then(|version| version.trim().parse::<u32>().ok())
 }
 
-fn curr_ts() -> u64 {
-    let curr_ts = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-    curr_ts
+fn same_schema(index: &Index, field_schema: &FieldSchema) -> bool {
+    match (
+        serde_json::to_string(&index.schema()),
+        serde_json::to_string(&field_schema.schema),
+    ) {
+        (Ok(existing), Ok(expected)) => existing == expected,
+        _ => false,
+    }
 }
 
-pub fn housekeeping() {
-    info!("housekeeping...");
+fn is_empty_dir(path: &Path) -> io::Result<bool> {
+    Ok(path.is_dir() && fs::read_dir(path)?.next().is_none())
+}
 
-    let reidx_opt = CONF_STORE.get_str("reindex".to_string());
-    match reidx_opt {
-        None => {
-            info!("no need to reindex");
-        }
-        Some(_) => {
-            clear();
-            info!("detect reindex sign");
-            return;
-        }
+/// Only called on directories with a `meta.json`, see `housekeeping`
+fn clear(index_path: &Path) -> io::Result<()> {
+    fs::remove_dir_all(index_path)?;
+    fs::create_dir_all(index_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn empty_directory_is_a_fresh_index() {
+        let directory = TempDir::new().unwrap();
+        let opened = housekeeping(directory.path(), &FieldSchema::new()).unwrap();
+        assert!(!opened.needs_rebuild);
+        assert_eq!(read_version(directory.path()), Some(SCHEMA_VERSION));
     }
 
-    let version_opt = CONF_STORE.get_str("version".to_string());
-    match version_opt {
-        None => {
-            clear();
-            info!("init version {}", VERSION);
-        }
-        Some(version) => {
-            if !version.eq(VERSION) {
-                clear();
-                info!("clean old version cachedata");
-            } else {
-                info!("no need to clean, current version:{}", VERSION);
-            }
-        }
+    #[test]
+    fn directory_without_index_is_not_cleared() {
+        let directory = TempDir::new().unwrap();
+        let other_file = directory.path().join("audio.json");
+        fs::write(&other_file, "[]").unwrap();
+
+        assert!(housekeeping(directory.path(), &FieldSchema::new()).is_err());
+        assert!(other_file.exists());
     }
-}
 
-fn clear() {
-    let _ = std::fs::remove_dir_all(&format!("{}/{}/idx", utils::data_dir(), STORE_PATH));
-    CONF_STORE.clear();
-    CONF_STORE.put_str("version".to_string(), VERSION.to_string());
+    #[test]
+    fn other_schema_version_is_rebuilt() {
+        let directory = TempDir::new().unwrap();
+        housekeeping(directory.path(), &FieldSchema::new()).unwrap();
+        fs::write(directory.path().join(VERSION_FILE), "1").unwrap();
+
+        let opened = housekeeping(directory.path(), &FieldSchema::new()).unwrap();
+        assert!(opened.needs_rebuild);
+    }
 }
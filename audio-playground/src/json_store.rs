@@ -0,0 +1,111 @@
+use std::collections::BTreeMap;
+use std::fs::read_to_string;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::Result;
+use crate::utils::write_atomic;
+
+/// Data keyed by an id (`Track.id` for per track data, `Album.id`...) that lives in a JSON file
+/// next to the index, so it survives the index being rebuilt
+pub struct JsonStore<T> {
+    path: PathBuf,
+    entries: RwLock<BTreeMap<String, T>>,
+}
+
+impl<T: Serialize + DeserializeOwned + Clone> JsonStore<T> {
+    pub fn open(file_path: &str) -> Result<Self> {
+        let path = PathBuf::from(file_path);
+        let entries = if path.exists() {
+            serde_json::from_str(&read_to_string(&path)?)?
+        } else {
+            BTreeMap::new()
+        };
+
+        Ok(JsonStore {
+            path,
+            entries: RwLock::new(entries),
+        })
+    }
+
+    pub fn get(&self, id: &str) -> Option<T> {
+        self.entries
+            .read()
+            .ok()
+            .and_then(|entries| entries.get(id).cloned())
+    }
+
+    /// A copy of every value, in the order of their ids
+    pub fn values(&self) -> Vec<T> {
+        self.entries
+            .read()
+            .map(|entries| entries.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// A copy of every entry, for passes over the whole store
+    pub fn entries(&self) -> Vec<(String, T)> {
+        self.entries
+            .read()
+            .map(|entries| {
+                entries
+                    .iter()
+                    .map(|(id, value)| (id.clone(), value.clone()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Changes (or creates) the entry of `id` and saves the file
+    pub fn update(&self, id: &str, change: impl FnOnce(&mut T)) -> Result<T>
+    where
+        T: Default,
+    {
+        let mut entries = self.entries.write()?;
+        let entry = entries.entry(id.to_string()).or_default();
+        change(entry);
+        let updated = entry.clone();
+
+        self.write(&entries)?;
+        Ok(updated)
+    }
+
+    /// Only kept in memory until the next `save`, for passes that touch many tracks
+    pub fn insert(&self, id: &str, value: T) -> Result<()> {
+        self.entries.write()?.insert(id.to_string(), value);
+        Ok(())
+    }
+
+    /// Sets many entries at once, the file is only written once
+    pub fn set_many(&self, updates: &[(String, T)]) -> Result<()> {
+        if updates.is_empty() {
+            return Ok(());
+        }
+
+        let mut entries = self.entries.write()?;
+        for (id, value) in updates {
+            entries.insert(id.clone(), value.clone());
+        }
+        self.write(&entries)
+    }
+
+    /// Replaces every entry and saves the file, for stores that are rebuilt as a whole
+    pub fn replace_all(&self, entries: BTreeMap<String, T>) -> Result<()> {
+        let mut current = self.entries.write()?;
+        self.write(&entries)?;
+        *current = entries;
+        Ok(())
+    }
+
+    pub fn save(&self) -> Result<()> {
+        self.write(&*self.entries.read()?)
+    }
+
+    fn write(&self, entries: &BTreeMap<String, T>) -> Result<()> {
+        write_atomic(&self.path, &serde_json::to_string_pretty(entries)?)?;
+        Ok(())
+    }
+}
@@ -1,4 +1,37 @@
-mod reader;
-mod schema;
-mod search_query;
-mod utils;
+pub mod aggregations;
+pub mod albums;
+pub mod artists;
+pub mod audio_features;
+pub mod books;
+pub mod chapters;
+pub mod content_kind;
+pub mod cue;
+pub mod decode;
+pub mod error;
+pub mod export;
+pub mod fingerprint;
+pub mod gc;
+pub mod index_snapshot;
+pub mod indexing;
+pub mod json_store;
+pub mod library_import;
+pub mod library_roots;
+pub mod logging;
+pub mod loudness;
+pub mod lyrics;
+pub mod metrics;
+pub mod mpd;
+pub mod playback;
+pub mod playlists;
+pub mod reader;
+pub mod scan_rules;
+pub mod schema;
+pub mod search_after;
+pub mod search_query;
+pub mod settings;
+pub mod subsonic;
+pub mod terminal_ui;
+#[cfg(test)]
+mod test_support;
+pub mod user_data;
+pub mod utils;
@@ -0,0 +1,1067 @@
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use log::{info, warn};
+use percent_encoding::percent_decode_str;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use serde::Serialize;
+use tantivy::collector::TopDocs;
+use tantivy::query::{BooleanQuery, Occur, Query, TermQuery};
+use tantivy::schema::{IndexRecordOption, Term};
+
+use crate::error::{AudioError, Result};
+use crate::playlists::{Playlist, PlaylistStore, PLAYLISTS_FILE};
+use crate::schema::{millis_since_epoch, SearchWatcher, Track};
+use crate::user_data::{UserData, MAX_RATING};
+use crate::utils::norm;
+
+/// Tracks whose duration differs by more than this are not the same recording
+const DURATION_TOLERANCE_SECS: f64 = 2.0;
+
+/// A track as another player knows it, only `path` or `artist` + `title` are needed to find it
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct ImportedEntry {
+    pub path: Option<String>,
+    pub artist: String,
+    pub title: String,
+    /// Seconds
+    pub duration: Option<f64>,
+    /// 0 (unrated) to `MAX_RATING`
+    pub rating: Option<u8>,
+    pub play_count: Option<u64>,
+    /// Milliseconds since the epoch
+    pub last_played: Option<i64>,
+    pub favourite: Option<bool>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct ImportedPlaylist {
+    pub name: String,
+    pub entries: Vec<ImportedEntry>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct ImportedLibrary {
+    pub tracks: Vec<ImportedEntry>,
+    pub playlists: Vec<ImportedPlaylist>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum ImportSource {
+    /// iTunes / Music.app `Library.xml`
+    ITunes,
+    /// Rhythmbox `rhythmdb.xml` (tracks) or `playlists.xml`
+    Rhythmbox,
+    /// The text `database` file of MPD (gunzip it first if it is compressed)
+    Mpd,
+    /// `.m3u` / `.m3u8` playlists
+    M3u,
+}
+
+impl ImportSource {
+    /// Guesses the source from the file name, then from the first bytes of the file
+    pub fn detect(path: &Path) -> Result<Self> {
+        let file_name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("")
+            .to_lowercase();
+        let ext = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or("")
+            .to_lowercase();
+
+        if ext == "m3u" || ext == "m3u8" {
+            return Ok(ImportSource::M3u);
+        }
+
+        let head: String = read_to_string(path)?.chars().take(1024).collect();
+        if head.contains("<rhythmdb") {
+            Ok(ImportSource::Rhythmbox)
+        } else if head.contains("<plist") {
+            Ok(ImportSource::ITunes)
+        } else if head.starts_with("info_begin") || file_name == "database" {
+            Ok(ImportSource::Mpd)
+        } else {
+            Err(AudioError::import(
+                &path.to_string_lossy(),
+                "unknown library format",
+            ))
+        }
+    }
+
+    /// `base_dir` is used for relative paths: the MPD `music_directory`, for M3U the folder
+    /// of the playlist is used when it's `None`
+    pub fn parse(&self, path: &Path, base_dir: Option<&Path>) -> Result<ImportedLibrary> {
+        match self {
+            ImportSource::ITunes => parse_itunes(path),
+            ImportSource::Rhythmbox => parse_rhythmbox(path),
+            ImportSource::Mpd => parse_mpd(path, base_dir.unwrap_or_else(|| Path::new(""))),
+            ImportSource::M3u => {
+                let playlist_dir = path.parent().unwrap_or_else(|| Path::new(""));
+                let playlist = parse_m3u(path, base_dir.unwrap_or(playlist_dir))?;
+                Ok(ImportedLibrary {
+                    tracks: vec![],
+                    playlists: vec![playlist],
+                })
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Default, Debug, Clone, PartialEq)]
+pub struct ImportReport {
+    pub entries: usize,
+    pub matched_by_path: usize,
+    pub matched_by_tags: usize,
+    /// Path (or "artist - title") of every entry that isn't in the index
+    pub unmatched: Vec<String>,
+    pub user_data_updated: usize,
+    pub playlists: usize,
+}
+
+impl SearchWatcher {
+    /// Imports ratings, play counts and playlists from another player into the user data
+    /// and playlist stores. Only tracks that are already indexed can be imported.
+    pub fn import_library(&self, path: &Path, base_dir: Option<&Path>) -> Result<ImportReport> {
+        let source = ImportSource::detect(path)?;
+        info!("importing {:?} as {:?}", path, source);
+        let library = source.parse(path, base_dir)?;
+
+        let mut report = ImportReport::default();
+        let mut resolved: HashMap<String, Track> = HashMap::new();
+        let mut updates: Vec<(String, UserData)> = vec![];
+
+        for entry in &library.tracks {
+            if let Some(track) = self.resolve_entry(entry, &mut report)? {
+                if let Some(user_data) = self.merged_user_data(&track, entry) {
+                    updates.push((track.id.clone(), user_data));
+                }
+                if let Some(path) = &entry.path {
+                    resolved.insert(path.clone(), track);
+                }
+            }
+        }
+
+        report.user_data_updated = updates.len();
+        self.user_data.set_many(&updates)?;
+        self.apply_user_data_many(&updates)?;
+
+        let playlist_store = PlaylistStore::open(&self.data_file(PLAYLISTS_FILE))?;
+        for imported in &library.playlists {
+            let mut track_ids = Vec::with_capacity(imported.entries.len());
+            for entry in &imported.entries {
+                // iTunes/Rhythmbox playlists point at tracks that were resolved above
+                let cached = entry.path.as_ref().and_then(|path| resolved.get(path));
+                let track = match cached {
+                    Some(track) => Some(track.clone()),
+                    None => self.resolve_entry(entry, &mut report)?,
+                };
+                if let Some(track) = track {
+                    track_ids.push(track.id);
+                }
+            }
+
+            playlist_store.save_playlist(Playlist {
+                name: imported.name.clone(),
+                track_ids,
+            })?;
+            report.playlists += 1;
+        }
+
+        info!(
+            "Imported {} entries: {} by path, {} by tags, {} unmatched, {} playlist(s)",
+            report.entries,
+            report.matched_by_path,
+            report.matched_by_tags,
+            report.unmatched.len(),
+            report.playlists
+        );
+        Ok(report)
+    }
+
+    /// Finds the indexed track for `entry`, by path first and then by artist + title + duration
+    pub fn resolve(&self, entry: &ImportedEntry) -> Result<Option<Track>> {
+        if let Some(path) = &entry.path {
+            if let Some(track) = self.find_by_path(path)? {
+                return Ok(Some(track));
+            }
+        }
+        self.find_by_tags(entry)
+    }
+
+    fn resolve_entry(
+        &self,
+        entry: &ImportedEntry,
+        report: &mut ImportReport,
+    ) -> Result<Option<Track>> {
+        report.entries += 1;
+
+        if let Some(path) = &entry.path {
+            if let Some(track) = self.find_by_path(path)? {
+                report.matched_by_path += 1;
+                return Ok(Some(track));
+            }
+        }
+        if let Some(track) = self.find_by_tags(entry)? {
+            report.matched_by_tags += 1;
+            return Ok(Some(track));
+        }
+
+        let description = match &entry.path {
+            Some(path) => path.clone(),
+            None => format!("{} - {}", entry.artist, entry.title),
+        };
+        warn!("no indexed track found for {}", description);
+        report.unmatched.push(description);
+        Ok(None)
+    }
+
+    fn find_by_path(&self, path: &str) -> Result<Option<Track>> {
+        let searcher = self.reader.searcher();
+        let query = TermQuery::new(
+            Term::from_field_text(self.field_schema.abs_path, &norm(path)),
+            IndexRecordOption::Basic,
+        );
+
+        let top_docs = searcher.search(&query, &TopDocs::with_limit(1))?;
+        match top_docs.first() {
+            Some((_, doc_address)) => {
+                let doc = searcher.doc(*doc_address)?;
+                Ok(Some(Track::with_document(&self.field_schema, doc)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Exact artist + title match, the closest duration wins when there are several
+    fn find_by_tags(&self, entry: &ImportedEntry) -> Result<Option<Track>> {
+        if entry.artist.is_empty() || entry.title.is_empty() {
+            return Ok(None);
+        }
+
+        let searcher = self.reader.searcher();
+        let artist_query = TermQuery::new(
+            Term::from_field_text(self.field_schema.artist, &entry.artist),
+            IndexRecordOption::Basic,
+        );
+        let title_query = TermQuery::new(
+            Term::from_field_text(self.field_schema.track, &entry.title),
+            IndexRecordOption::Basic,
+        );
+        let query = BooleanQuery::new(vec![
+            (Occur::Must, Box::new(artist_query) as Box<dyn Query>),
+            (Occur::Must, Box::new(title_query)),
+        ]);
+
+        let mut candidates = vec![];
+        for (_, doc_address) in searcher.search(&query, &TopDocs::with_limit(20))? {
+            let doc = searcher.doc(doc_address)?;
+            candidates.push(Track::with_document(&self.field_schema, doc));
+        }
+
+        let duration = match entry.duration {
+            Some(duration) => duration,
+            // without a duration we can't tell different recordings apart
+            None if candidates.len() == 1 => return Ok(candidates.pop()),
+            None => return Ok(None),
+        };
+
+        Ok(candidates
+            .into_iter()
+            .filter(|track| (track.duration - duration).abs() <= DURATION_TOLERANCE_SECS)
+            .min_by(|a, b| {
+                let a_diff = (a.duration - duration).abs();
+                let b_diff = (b.duration - duration).abs();
+                a_diff.total_cmp(&b_diff)
+            }))
+    }
+
+    /// The stored user data with the imported values on top, `None` when nothing changes.
+    /// Counts and dates only go up, so importing the same library twice is harmless.
+    fn merged_user_data(&self, track: &Track, entry: &ImportedEntry) -> Option<UserData> {
+        let existing = self.user_data.get(&track.id);
+        let mut merged = existing.clone();
+
+        if let Some(rating) = entry.rating {
+            if rating > 0 {
+                merged.rating = rating.min(MAX_RATING);
+            }
+        }
+        if let Some(play_count) = entry.play_count {
+            merged.play_count = merged.play_count.max(play_count);
+        }
+        if let Some(last_played) = entry.last_played {
+            merged.last_played = merged.last_played.max(last_played);
+        }
+        if let Some(favourite) = entry.favourite {
+            merged.favourite = merged.favourite || favourite;
+        }
+
+        if merged == existing {
+            None
+        } else {
+            Some(merged)
+        }
+    }
+}
+
+/// `file://localhost/Users/x/a%20b.mp3` -> `/Users/x/a b.mp3`, `file:///C:/a.mp3` -> `C:/a.mp3`
+pub fn path_from_file_url(url: &str) -> Option<String> {
+    let rest = url.strip_prefix("file://")?;
+    let rest = rest.strip_prefix("localhost").unwrap_or(rest);
+    let decoded = percent_decode_str(rest).decode_utf8().ok()?.to_string();
+
+    // windows drive letters come after the slash of the (empty) host
+    let bytes = decoded.as_bytes();
+    if bytes.len() > 2 && bytes[0] == b'/' && bytes[2] == b':' {
+        return Some(decoded[1..].to_string());
+    }
+    Some(decoded)
+}
+
+fn parse_itunes(path: &Path) -> Result<ImportedLibrary> {
+    let path_string = path.to_string_lossy().to_string();
+    let plist = plist::Value::from_file(path).map_err(|e| AudioError::import(&path_string, e))?;
+    let root = plist
+        .as_dictionary()
+        .ok_or_else(|| AudioError::import(&path_string, "expected a dictionary"))?;
+
+    let mut library = ImportedLibrary::default();
+    let mut by_track_id: HashMap<u64, ImportedEntry> = HashMap::new();
+
+    if let Some(tracks) = root.get("Tracks").and_then(|tracks| tracks.as_dictionary()) {
+        for (track_id, value) in tracks {
+            let track = match value.as_dictionary() {
+                Some(track) => track,
+                None => continue,
+            };
+            let text = |key: &str| {
+                track
+                    .get(key)
+                    .and_then(|value| value.as_string())
+                    .unwrap_or("")
+                    .to_string()
+            };
+            let number = |key: &str| track.get(key).and_then(|value| value.as_unsigned_integer());
+
+            let entry = ImportedEntry {
+                path: track
+                    .get("Location")
+                    .and_then(|value| value.as_string())
+                    .and_then(path_from_file_url),
+                artist: text("Artist"),
+                title: text("Name"),
+                duration: number("Total Time").map(|ms| ms as f64 / 1000.0),
+                // iTunes ratings go from 0 to 100 in steps of 20 per star
+                rating: number("Rating").map(|rating| (rating / 20) as u8),
+                play_count: number("Play Count"),
+                last_played: track
+                    .get("Play Date UTC")
+                    .and_then(|value| value.as_date())
+                    .map(|date| millis_since_epoch(SystemTime::from(date))),
+                favourite: track
+                    .get("Loved")
+                    .or_else(|| track.get("Favorited"))
+                    .and_then(|value| value.as_boolean()),
+            };
+
+            if let Ok(track_id) = track_id.parse::<u64>() {
+                by_track_id.insert(track_id, entry.clone());
+            }
+            library.tracks.push(entry);
+        }
+    }
+
+    if let Some(playlists) = root.get("Playlists").and_then(|playlists| playlists.as_array()) {
+        for value in playlists {
+            let playlist = match value.as_dictionary() {
+                Some(playlist) => playlist,
+                None => continue,
+            };
+            // "Library", "Music", "Podcasts"... contain everything, they are not real playlists
+            let is_builtin = playlist.get("Master").is_some()
+                || playlist.get("Distinguished Kind").is_some();
+            if is_builtin {
+                continue;
+            }
+
+            let entries = playlist
+                .get("Playlist Items")
+                .and_then(|items| items.as_array())
+                .map(|items| {
+                    items
+                        .iter()
+                        .filter_map(|item| item.as_dictionary())
+                        .filter_map(|item| item.get("Track ID"))
+                        .filter_map(|track_id| track_id.as_unsigned_integer())
+                        .filter_map(|track_id| by_track_id.get(&track_id).cloned())
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            library.playlists.push(ImportedPlaylist {
+                name: playlist
+                    .get("Name")
+                    .and_then(|name| name.as_string())
+                    .unwrap_or("Untitled")
+                    .to_string(),
+                entries,
+            });
+        }
+    }
+
+    Ok(library)
+}
+
+/// Handles both `rhythmdb.xml` (`<entry type="song">`) and `playlists.xml` (`<playlist>`)
+fn parse_rhythmbox(path: &Path) -> Result<ImportedLibrary> {
+    let path_string = path.to_string_lossy().to_string();
+    let xml_error = |e: quick_xml::Error| AudioError::import(&path_string, e);
+
+    let mut reader = Reader::from_file(path).map_err(xml_error)?;
+    reader.trim_text(true);
+
+    let mut library = ImportedLibrary::default();
+    let mut entry: Option<ImportedEntry> = None;
+    let mut playlist: Option<ImportedPlaylist> = None;
+    let mut element = String::new();
+    let mut buf = Vec::new();
+
+    loop {
+        match reader.read_event_into(&mut buf).map_err(xml_error)? {
+            Event::Start(e) => {
+                element = String::from_utf8_lossy(e.name().as_ref()).to_string();
+                match element.as_str() {
+                    "entry" => {
+                        let entry_type = e
+                            .try_get_attribute("type")
+                            .map_err(|e| xml_error(e.into()))?
+                            .and_then(|attr| attr.unescape_value().ok().map(|v| v.to_string()));
+                        // podcasts, radio stations and ignored files are entries too
+                        if entry_type.as_deref() == Some("song") {
+                            entry = Some(ImportedEntry::default());
+                        }
+                    }
+                    "playlist" => {
+                        let name = e
+                            .try_get_attribute("name")
+                            .map_err(|e| xml_error(e.into()))?
+                            .and_then(|attr| attr.unescape_value().ok().map(|v| v.to_string()))
+                            .unwrap_or_else(|| "Untitled".to_string());
+                        playlist = Some(ImportedPlaylist {
+                            name,
+                            entries: vec![],
+                        });
+                    }
+                    _ => {}
+                }
+            }
+            Event::Text(e) => {
+                let text = e.unescape().map_err(xml_error)?.to_string();
+                if let Some(entry) = entry.as_mut() {
+                    match element.as_str() {
+                        "title" => entry.title = text,
+                        "artist" => entry.artist = text,
+                        "duration" => entry.duration = text.parse().ok(),
+                        "location" => entry.path = path_from_file_url(&text),
+                        "rating" => entry.rating = text.parse().ok(),
+                        "play-count" => entry.play_count = text.parse().ok(),
+                        "last-played" => {
+                            entry.last_played = text.parse::<i64>().ok().map(|secs| secs * 1000)
+                        }
+                        _ => {}
+                    }
+                } else if let Some(playlist) = playlist.as_mut() {
+                    if element == "location" {
+                        playlist.entries.push(ImportedEntry {
+                            path: path_from_file_url(&text),
+                            ..Default::default()
+                        });
+                    }
+                }
+            }
+            Event::End(e) => {
+                match e.name().as_ref() {
+                    b"entry" => library.tracks.extend(entry.take()),
+                    b"playlist" => library.playlists.extend(playlist.take()),
+                    _ => {}
+                }
+                element.clear();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+
+    Ok(library)
+}
+
+/// The MPD database is a tree of `begin: <dir>` / `end: <dir>` blocks containing
+/// `song_begin: <file>` / `song_end` blocks, paths are relative to `music_directory`
+fn parse_mpd(path: &Path, music_directory: &Path) -> Result<ImportedLibrary> {
+    let content = read_to_string(path)?;
+
+    let mut library = ImportedLibrary::default();
+    let mut directories: Vec<String> = vec![];
+    let mut entry: Option<ImportedEntry> = None;
+
+    for line in content.lines() {
+        let (key, value) = match line.split_once(": ") {
+            Some((key, value)) => (key, value),
+            None => (line.trim_end_matches(':'), ""),
+        };
+
+        match key {
+            "begin" => directories.push(value.to_string()),
+            "end" => {
+                directories.pop();
+            }
+            "song_begin" => {
+                // "begin" holds the full path of the directory relative to the music directory
+                let mut song_path = PathBuf::from(music_directory);
+                if let Some(directory) = directories.last() {
+                    song_path.push(directory);
+                }
+                song_path.push(value);
+
+                entry = Some(ImportedEntry {
+                    path: Some(norm(&song_path.to_string_lossy())),
+                    ..Default::default()
+                });
+            }
+            "song_end" => library.tracks.extend(entry.take()),
+            _ => {
+                if let Some(entry) = entry.as_mut() {
+                    match key {
+                        "Artist" => entry.artist = value.to_string(),
+                        "Title" => entry.title = value.to_string(),
+                        "Time" => entry.duration = value.parse().ok(),
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(library)
+}
+
+fn parse_m3u(path: &Path, base_dir: &Path) -> Result<ImportedPlaylist> {
+    let content = read_to_string(path)?;
+
+    let mut playlist = ImportedPlaylist {
+        name: path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_else(|| "Untitled".to_string()),
+        entries: vec![],
+    };
+    let mut extinf: Option<ImportedEntry> = None;
+
+    // a BOM is common in m3u8 files written on windows
+    for line in content.trim_start_matches('\u{feff}').lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix("#PLAYLIST:") {
+            playlist.name = name.trim().to_string();
+        } else if let Some(info) = line.strip_prefix("#EXTINF:") {
+            // #EXTINF:<seconds>,<artist> - <title>
+            let (duration, display) = info.split_once(',').unwrap_or((info, ""));
+            let (artist, title) = display.split_once(" - ").unwrap_or(("", display));
+            extinf = Some(ImportedEntry {
+                artist: artist.trim().to_string(),
+                title: title.trim().to_string(),
+                duration: duration.trim().parse::<f64>().ok().filter(|d| *d > 0.0),
+                ..Default::default()
+            });
+        } else if line.starts_with('#') {
+            continue;
+        } else {
+            let mut entry = extinf.take().unwrap_or_default();
+            entry.path = if line.starts_with("file://") {
+                path_from_file_url(line)
+            } else if line.contains("://") {
+                warn!("Skipping stream {} in {:?}", line, path);
+                continue;
+            } else if Path::new(line).is_absolute() {
+                Some(norm(line))
+            } else {
+                Some(norm(&base_dir.join(line).to_string_lossy()))
+            };
+            playlist.entries.push(entry);
+        }
+    }
+
+    Ok(playlist)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::playlists::to_m3u8;
+    use crate::test_support::{index_tracks, library, search_watcher, track};
+
+    /// `contents` saved as `name` in `directory`
+    fn write(directory: &TempDir, name: &str, contents: &str) -> PathBuf {
+        let path = directory.path().join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn parse(path: &Path, base_dir: Option<&Path>) -> ImportedLibrary {
+        ImportSource::detect(path)
+            .unwrap()
+            .parse(path, base_dir)
+            .unwrap()
+    }
+
+    const SONG_1: &str = "/music/Blue River/Night Blue River/01 Song 1.mp3";
+    const SONG_1_URL: &str = "file:///music/Blue%20River/Night%20Blue%20River/01%20Song%201.mp3";
+
+    #[test]
+    fn itunes_library() {
+        let directory = TempDir::new().unwrap();
+        let xml = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Tracks</key>
+    <dict>
+        <key>1001</key>
+        <dict>
+            <key>Track ID</key><integer>1001</integer>
+            <key>Name</key><string>Song 1</string>
+            <key>Artist</key><string>Blue River</string>
+            <key>Total Time</key><integer>181500</integer>
+            <key>Rating</key><integer>80</integer>
+            <key>Play Count</key><integer>7</integer>
+            <key>Play Date UTC</key><date>2020-01-01T00:00:00Z</date>
+            <key>Loved</key><true/>
+            <key>Location</key><string>{}</string>
+        </dict>
+        <key>1002</key>
+        <dict>
+            <key>Track ID</key><integer>1002</integer>
+            <key>Name</key><string>Song 2</string>
+            <key>Artist</key><string>Golden Rain</string>
+        </dict>
+    </dict>
+    <key>Playlists</key>
+    <array>
+        <dict>
+            <key>Name</key><string>Library</string>
+            <key>Master</key><true/>
+            <key>Playlist Items</key>
+            <array>
+                <dict><key>Track ID</key><integer>1001</integer></dict>
+                <dict><key>Track ID</key><integer>1002</integer></dict>
+            </array>
+        </dict>
+        <dict>
+            <key>Name</key><string>Road Trip</string>
+            <key>Playlist Items</key>
+            <array>
+                <dict><key>Track ID</key><integer>1002</integer></dict>
+                <dict><key>Track ID</key><integer>1001</integer></dict>
+                <dict><key>Track ID</key><integer>9999</integer></dict>
+            </array>
+        </dict>
+    </array>
+</dict>
+</plist>"#,
+            SONG_1_URL.replace("file://", "file://localhost")
+        );
+        let path = write(&directory, "Library.xml", &xml);
+        assert_eq!(ImportSource::detect(&path).unwrap(), ImportSource::ITunes);
+
+        let library = parse(&path, None);
+        let song_1 = library
+            .tracks
+            .iter()
+            .find(|entry| entry.title == "Song 1")
+            .unwrap();
+        assert_eq!(
+            *song_1,
+            ImportedEntry {
+                path: Some(SONG_1.to_string()),
+                artist: "Blue River".to_string(),
+                title: "Song 1".to_string(),
+                duration: Some(181.5),
+                rating: Some(4),
+                play_count: Some(7),
+                last_played: Some(1_577_836_800_000),
+                favourite: Some(true),
+            }
+        );
+        let song_2 = library
+            .tracks
+            .iter()
+            .find(|entry| entry.title == "Song 2")
+            .unwrap();
+        assert_eq!(song_2.path, None);
+        assert_eq!(song_2.rating, None);
+
+        // the master playlist is left out, unknown track ids too
+        assert_eq!(library.playlists.len(), 1);
+        assert_eq!(library.playlists[0].name, "Road Trip");
+        let titles: Vec<&str> = library.playlists[0]
+            .entries
+            .iter()
+            .map(|entry| entry.title.as_str())
+            .collect();
+        assert_eq!(titles, ["Song 2", "Song 1"]);
+    }
+
+    #[test]
+    fn rhythmbox_library() {
+        let directory = TempDir::new().unwrap();
+        let xml = format!(
+            r#"<?xml version="1.0" standalone="yes"?>
+<rhythmdb version="2.0">
+  <entry type="song">
+    <title>Song 1</title>
+    <artist>Blue River</artist>
+    <duration>181</duration>
+    <location>{}</location>
+    <rating>4</rating>
+    <play-count>7</play-count>
+    <last-played>1600000000</last-played>
+  </entry>
+  <entry type="podcast-post">
+    <title>Episode 1</title>
+    <location>http://example.com/episode-1.mp3</location>
+  </entry>
+  <entry type="song">
+    <title>Rock &amp; Roll</title>
+    <artist>Golden Rain</artist>
+  </entry>
+</rhythmdb>"#,
+            SONG_1_URL
+        );
+        let path = write(&directory, "rhythmdb.xml", &xml);
+        assert_eq!(ImportSource::detect(&path).unwrap(), ImportSource::Rhythmbox);
+
+        let library = parse(&path, None);
+        assert_eq!(
+            library.tracks,
+            [
+                ImportedEntry {
+                    path: Some(SONG_1.to_string()),
+                    artist: "Blue River".to_string(),
+                    title: "Song 1".to_string(),
+                    duration: Some(181.0),
+                    rating: Some(4),
+                    play_count: Some(7),
+                    last_played: Some(1_600_000_000_000),
+                    favourite: None,
+                },
+                ImportedEntry {
+                    artist: "Golden Rain".to_string(),
+                    title: "Rock & Roll".to_string(),
+                    ..Default::default()
+                },
+            ]
+        );
+        assert!(library.playlists.is_empty());
+
+        let xml = format!(
+            r#"<?xml version="1.0"?>
+<rhythmdb-playlists>
+  <playlist name="Favourites" type="static">
+    <location>{}</location>
+  </playlist>
+  <playlist name="Recently Added" type="automatic">
+  </playlist>
+</rhythmdb-playlists>"#,
+            SONG_1_URL
+        );
+        let path = write(&directory, "playlists.xml", &xml);
+        let library = parse(&path, None);
+        assert!(library.tracks.is_empty());
+        assert_eq!(library.playlists.len(), 2);
+        assert_eq!(library.playlists[0].name, "Favourites");
+        assert_eq!(
+            library.playlists[0].entries,
+            [ImportedEntry {
+                path: Some(SONG_1.to_string()),
+                ..Default::default()
+            }]
+        );
+        assert!(library.playlists[1].entries.is_empty());
+    }
+
+    #[test]
+    fn mpd_database() {
+        let directory = TempDir::new().unwrap();
+        let database = "info_begin
+format: 2
+mpd_version: 0.23.5
+info_end
+directory: Blue River
+mtime: 1600000000
+begin: Blue River
+directory: Night Blue River
+mtime: 1600000000
+begin: Blue River/Night Blue River
+song_begin: 01 Song 1.mp3
+Time: 181.000
+Artist: Blue River
+Title: Song 1
+song_end
+end: Blue River/Night Blue River
+song_begin: loose.mp3
+Title: Loose
+song_end
+end: Blue River
+song_begin: top.mp3
+song_end
+";
+        let path = write(&directory, "database", database);
+        assert_eq!(ImportSource::detect(&path).unwrap(), ImportSource::Mpd);
+
+        let library = parse(&path, Some(Path::new("/music")));
+        assert_eq!(
+            library.tracks,
+            [
+                ImportedEntry {
+                    path: Some(SONG_1.to_string()),
+                    artist: "Blue River".to_string(),
+                    title: "Song 1".to_string(),
+                    duration: Some(181.0),
+                    ..Default::default()
+                },
+                ImportedEntry {
+                    path: Some("/music/Blue River/loose.mp3".to_string()),
+                    title: "Loose".to_string(),
+                    ..Default::default()
+                },
+                ImportedEntry {
+                    path: Some("/music/top.mp3".to_string()),
+                    ..Default::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn m3u_playlist() {
+        let directory = TempDir::new().unwrap();
+        let m3u = format!(
+            "\u{feff}#EXTM3U\n\
+             #PLAYLIST: Road Trip\n\
+             #EXTINF:181,Blue River - Song 1\n\
+             Blue River/Night Blue River/01 Song 1.mp3\n\
+             \n\
+             #EXTINF:-1,Radio\n\
+             http://example.com/stream\n\
+             #EXTINF:0,Untagged\n\
+             /music/untagged.mp3\n\
+             {}\n",
+            SONG_1_URL
+        );
+        let path = write(&directory, "road trip.m3u8", &m3u);
+        assert_eq!(ImportSource::detect(&path).unwrap(), ImportSource::M3u);
+
+        let library = parse(&path, Some(Path::new("/music")));
+        assert!(library.tracks.is_empty());
+        let playlist = &library.playlists[0];
+        assert_eq!(playlist.name, "Road Trip");
+        assert_eq!(
+            playlist.entries,
+            [
+                ImportedEntry {
+                    path: Some(SONG_1.to_string()),
+                    artist: "Blue River".to_string(),
+                    title: "Song 1".to_string(),
+                    duration: Some(181.0),
+                    ..Default::default()
+                },
+                ImportedEntry {
+                    path: Some("/music/untagged.mp3".to_string()),
+                    title: "Untagged".to_string(),
+                    ..Default::default()
+                },
+                ImportedEntry {
+                    path: Some(SONG_1.to_string()),
+                    ..Default::default()
+                },
+            ]
+        );
+
+        // relative paths are relative to the playlist without a base directory
+        let library = parse(&path, None);
+        let expected = directory
+            .path()
+            .join("Blue River/Night Blue River/01 Song 1.mp3");
+        assert_eq!(
+            library.playlists[0].entries[0].path,
+            Some(norm(&expected.to_string_lossy()))
+        );
+        // named after the file without #PLAYLIST
+        let path = write(&directory, "mix.m3u", "/music/a.mp3\n");
+        assert_eq!(parse(&path, None).playlists[0].name, "mix");
+    }
+
+    #[test]
+    fn unknown_format() {
+        let directory = TempDir::new().unwrap();
+        let path = write(&directory, "notes.txt", "not a library");
+        assert!(ImportSource::detect(&path).is_err());
+    }
+
+    #[test]
+    fn file_urls() {
+        assert_eq!(
+            path_from_file_url("file://localhost/Users/x/a%20b.mp3").as_deref(),
+            Some("/Users/x/a b.mp3")
+        );
+        assert_eq!(
+            path_from_file_url("file:///C:/Music/a.mp3").as_deref(),
+            Some("C:/Music/a.mp3")
+        );
+        assert_eq!(path_from_file_url("http://example.com/a.mp3"), None);
+    }
+
+    #[test]
+    fn resolve_by_path_and_tags() {
+        let (_directory, search_watcher) = search_watcher();
+        let mut tracks = library(8);
+        // a live recording with the title (but not the duration) of track-1
+        let mut live = track(20, "Blue River", "Live", "Rock", 2001);
+        live.track = "Song 1".to_string();
+        live.duration = 240.0;
+        tracks.push(live);
+        index_tracks(&search_watcher, &tracks);
+
+        let resolved = |entry: ImportedEntry| {
+            search_watcher
+                .resolve(&entry)
+                .unwrap()
+                .map(|track| track.id)
+        };
+        let by_tags = |title: &str, duration: Option<f64>| ImportedEntry {
+            artist: "Blue River".to_string(),
+            title: title.to_string(),
+            duration,
+            ..Default::default()
+        };
+
+        let by_path = ImportedEntry {
+            path: Some("\\music\\Blue River\\Night Blue River\\01 Song 1.mp3".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(resolved(by_path).as_deref(), Some("track-1"));
+
+        // the title tag, not the file name, and the closest duration within the tolerance
+        assert_eq!(resolved(by_tags("Song 1", Some(182.0))).as_deref(), Some("track-1"));
+        assert_eq!(resolved(by_tags("Song 1", Some(239.0))).as_deref(), Some("track-20"));
+        assert_eq!(resolved(by_tags("Song 1", Some(210.0))), None);
+        assert_eq!(resolved(by_tags("01 Song 1", Some(181.0))), None);
+        // two recordings and no duration to tell them apart
+        assert_eq!(resolved(by_tags("Song 1", None)), None);
+        assert_eq!(resolved(by_tags("Song 5", None)).as_deref(), Some("track-5"));
+
+        // a path that isn't indexed falls back to the tags
+        let moved = ImportedEntry {
+            path: Some("/old/music/song 5.mp3".to_string()),
+            ..by_tags("Song 5", Some(185.0))
+        };
+        assert_eq!(resolved(moved).as_deref(), Some("track-5"));
+    }
+
+    #[test]
+    fn import_a_playlist() {
+        let (directory, search_watcher) = search_watcher();
+        index_tracks(&search_watcher, &library(8));
+
+        // the titles and durations of an exported playlist find the tracks again
+        let tracks = search_watcher
+            .tracks_by_ids(&["track-5".to_string(), "track-2".to_string()])
+            .unwrap();
+        let exported = to_m3u8(&tracks)
+            .lines()
+            .filter(|line| line.starts_with("#EXTINF"))
+            .map(|line| format!("{}\n/elsewhere/file.mp3\n", line))
+            .collect::<String>();
+        let m3u = format!(
+            "#PLAYLIST:Imported\n{}#EXTINF:200,Nobody - Nothing\n/nowhere.mp3\n{}\n",
+            exported, SONG_1
+        );
+        let path = directory.path().join("imported.m3u8");
+        fs::write(&path, m3u).unwrap();
+
+        let report = search_watcher.import_library(&path, None).unwrap();
+        assert_eq!(report.entries, 4);
+        assert_eq!(report.matched_by_path, 1);
+        assert_eq!(report.matched_by_tags, 2);
+        assert_eq!(report.unmatched, ["/nowhere.mp3"]);
+        assert_eq!(report.playlists, 1);
+        let ids: Vec<String> = search_watcher
+            .playlist_tracks("Imported")
+            .unwrap()
+            .into_iter()
+            .map(|track| track.id)
+            .collect();
+        assert_eq!(ids, ["track-5", "track-2", "track-1"]);
+    }
+
+    #[test]
+    fn import_user_data() {
+        let (directory, search_watcher) = search_watcher();
+        index_tracks(&search_watcher, &library(8));
+        search_watcher.record_play("track-1").unwrap();
+
+        let xml = format!(
+            r#"<rhythmdb version="2.0">
+  <entry type="song">
+    <location>{}</location>
+    <rating>4</rating>
+    <play-count>7</play-count>
+  </entry>
+  <entry type="song">
+    <title>Song 2</title>
+    <artist>Golden Rain</artist>
+    <rating>0</rating>
+  </entry>
+</rhythmdb>"#,
+            SONG_1_URL
+        );
+        let path = directory.path().join("rhythmdb.xml");
+        fs::write(&path, xml).unwrap();
+
+        let report = search_watcher.import_library(&path, None).unwrap();
+        assert_eq!(report.entries, 2);
+        assert_eq!(report.matched_by_path, 1);
+        assert!(report.unmatched.is_empty());
+        // an unrated track keeps its (empty) user data
+        assert_eq!(report.user_data_updated, 1);
+
+        let user_data = search_watcher.user_data.get("track-1");
+        assert_eq!(user_data.rating, 4);
+        assert_eq!(user_data.play_count, 7);
+        // copied onto the document too
+        let track = search_watcher
+            .tracks_by_ids(&["track-1".to_string()])
+            .unwrap()
+            .remove(0);
+        assert_eq!((track.rating, track.play_count), (4, 7));
+
+        // counts only go up, importing again changes nothing
+        let report = search_watcher.import_library(&path, None).unwrap();
+        assert_eq!(report.user_data_updated, 0);
+    }
+}
@@ -0,0 +1,80 @@
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::settings::Setting;
+use crate::utils::norm;
+
+pub const DEFAULT_ROOT_ID: &str = "default";
+
+/// A folder (or drive) of audio files that is scanned into the shared index
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct LibraryRoot {
+    pub id: String,
+    pub path: String,
+}
+
+impl LibraryRoot {
+    pub fn new(id: &str, path: &str) -> Self {
+        LibraryRoot {
+            id: id.to_string(),
+            path: norm(path),
+        }
+    }
+
+    /// Removable drives (e.g. `E:\Music`) disappear when unplugged, in which case
+    /// the tracks are kept but marked as offline. An unmounted mount point (`/media/usb`) is
+    /// still there but empty, so a root without any readable entry is offline too.
+    pub fn is_online(&self) -> bool {
+        fs::read_dir(&self.path)
+            .map(|mut entries| entries.any(|entry| entry.is_ok()))
+            .unwrap_or(false)
+    }
+
+    pub fn contains(&self, abs_path: &str) -> bool {
+        Path::new(&norm(abs_path)).starts_with(&self.path)
+    }
+}
+
+/// All roots from the settings, or `fallback_path` when none are configured
+pub fn configured_roots(setting: &Setting, fallback_path: &str) -> Vec<LibraryRoot> {
+    if setting.library_roots.is_empty() {
+        return vec![LibraryRoot::new(DEFAULT_ROOT_ID, fallback_path)];
+    }
+
+    setting
+        .library_roots
+        .iter()
+        .map(|root| LibraryRoot::new(&root.id, &root.path))
+        .collect()
+}
+
+/// The most specific root containing `abs_path`
+pub fn root_for_path<'a>(roots: &'a [LibraryRoot], abs_path: &str) -> Option<&'a LibraryRoot> {
+    roots
+        .iter()
+        .filter(|root| root.contains(abs_path))
+        .max_by_key(|root| root.path.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn empty_or_missing_root_is_offline() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().to_string_lossy().to_string();
+        let root = LibraryRoot::new("usb", &path);
+        // what's left of an unmounted drive
+        assert!(!root.is_online());
+
+        fs::write(directory.path().join("01 Song.mp3"), b"").unwrap();
+        assert!(root.is_online());
+
+        let missing = LibraryRoot::new("gone", &format!("{}/missing", path));
+        assert!(!missing.is_online());
+    }
+}
@@ -0,0 +1,42 @@
+use std::io::Write;
+
+use env_logger::{Builder, Env};
+use serde_json::json;
+
+/// Filter in the `env_logger` syntax, e.g. `AUDIO_LOG=audio_playground::schema=debug,info`
+pub const LOG_FILTER_ENV: &str = "AUDIO_LOG";
+/// `json` writes one JSON object per line, anything else `key=value` text
+pub const LOG_FORMAT_ENV: &str = "AUDIO_LOG_FORMAT";
+
+/// Configures the `log` macros, `info` and up unless `AUDIO_LOG` says otherwise. Safe to call
+/// more than once, only the first call does anything.
+pub fn init_logging() {
+    let json_format = std::env::var(LOG_FORMAT_ENV)
+        .map(|format| format.eq_ignore_ascii_case("json"))
+        .unwrap_or(false);
+
+    let mut builder = Builder::from_env(Env::default().filter_or(LOG_FILTER_ENV, "info"));
+    if json_format {
+        builder.format(|buf, record| {
+            let line = json!({
+                "ts": buf.timestamp_millis().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "msg": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    } else {
+        builder.format(|buf, record| {
+            writeln!(
+                buf,
+                "ts={} level={} target={} msg={:?}",
+                buf.timestamp_millis(),
+                record.level(),
+                record.target(),
+                record.args().to_string()
+            )
+        });
+    }
+    let _ = builder.try_init();
+}
@@ -0,0 +1,446 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::time::SystemTime;
+
+use ebur128::{EbuR128, Mode};
+use id3::frame::ExtendedText;
+use id3::{ErrorKind, TagLike, Version};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tantivy::schema::Value;
+use tantivy::DocAddress;
+
+use crate::decode::TrackDecoder;
+use crate::error::{AudioError, Result};
+use crate::json_store::JsonStore;
+use crate::schema::{SearchWatcher, Track, TrackStatus};
+use crate::settings::SETTINGS;
+use crate::utils::file_ext;
+
+pub const LOUDNESS_FILE: &str = "./data/loudness.json";
+
+/// ReplayGain 2.0 plays everything at -18 LUFS
+pub const REPLAYGAIN_REFERENCE_LUFS: f64 = -18.0;
+
+/// Result of the EBU R128 analysis of a track, kept outside of the index (keyed by
+/// `Track.id`) because decoding a whole library takes hours
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS
+    pub track_loudness: f64,
+    /// Sample peak, 1.0 is full scale
+    pub track_peak: f64,
+    pub album_loudness: Option<f64>,
+    pub album_peak: Option<f64>,
+}
+
+impl Loudness {
+    pub fn track_gain(&self) -> f64 {
+        REPLAYGAIN_REFERENCE_LUFS - self.track_loudness
+    }
+
+    pub fn album_gain(&self) -> Option<f64> {
+        self.album_loudness
+            .map(|loudness| REPLAYGAIN_REFERENCE_LUFS - loudness)
+    }
+}
+
+impl Track {
+    /// Gain (in dB) to play this track at the reference loudness, the album gain keeps the
+    /// differences between tracks of an album. `None` when the track hasn't been analyzed.
+    pub fn replaygain(&self, prefer_album: bool) -> Option<f64> {
+        let album = if prefer_album {
+            self.album_loudness
+        } else {
+            None
+        };
+        album
+            .or(self.track_loudness)
+            .map(|loudness| REPLAYGAIN_REFERENCE_LUFS - loudness)
+    }
+}
+
+pub type LoudnessStore = JsonStore<Loudness>;
+
+#[derive(Serialize, Default, Debug, Clone, PartialEq)]
+pub struct LoudnessReport {
+    pub analyzed: usize,
+    /// Tracks that already had a result
+    pub skipped: usize,
+    /// `abs_path` of every track that couldn't be decoded
+    pub failed: Vec<String>,
+    pub albums: usize,
+    pub tags_written: usize,
+    pub cost_ms: u128,
+}
+
+struct AnalysisTrack {
+    id: String,
+    abs_path: String,
+    /// See `Track.start`, tracks of a CUE sheet only analyze their part of the file
+    start: f64,
+    end: f64,
+}
+
+impl AnalysisTrack {
+    fn is_virtual(&self) -> bool {
+        self.start > 0.0 || self.end > 0.0
+    }
+}
+
+impl SearchWatcher {
+    /// Decodes every online track and stores its EBU R128 loudness and peak, per track and
+    /// per album. Tracks with a stored result are skipped unless `force` is set.
+    /// With `write_replaygain_tags` in the settings the results are also written as tags.
+    pub fn analyze_loudness(&self, force: bool) -> Result<LoudnessReport> {
+        let start = SystemTime::now();
+        let mut report = LoudnessReport::default();
+        let write_tags = SETTINGS.read()?.write_replaygain_tags;
+
+        for (_, album_tracks) in self.albums_to_analyze()? {
+            let analyzed = album_tracks
+                .iter()
+                .all(|track| self.loudness.get(&track.id).is_some());
+            if analyzed && !force {
+                report.skipped += album_tracks.len();
+                continue;
+            }
+
+            let updates = analyze_album(&album_tracks, &mut report);
+            if updates.is_empty() {
+                continue;
+            }
+
+            self.loudness.set_many(&updates)?;
+            self.apply_loudness_many(&updates)?;
+
+            if write_tags {
+                report.tags_written += write_album_tags(&album_tracks, &updates);
+            }
+        }
+
+        report.cost_ms = SystemTime::now()
+            .duration_since(start)
+            .unwrap_or_default()
+            .as_millis();
+
+        info!(
+            "Analyzed {} track(s) in {} album(s), skipped {}, {} failed ({}ms)",
+            report.analyzed,
+            report.albums,
+            report.skipped,
+            report.failed.len(),
+            report.cost_ms
+        );
+        Ok(report)
+    }
+
+    pub fn apply_loudness_many(&self, updates: &[(String, Loudness)]) -> Result<()> {
+        let by_id: HashMap<&str, &Loudness> = updates
+            .iter()
+            .map(|(track_id, loudness)| (track_id.as_str(), loudness))
+            .collect();
+        let track_ids: Vec<&str> = by_id.keys().copied().collect();
+
+        self.rewrite_documents(
+            &track_ids,
+            &[
+                self.field_schema.track_loudness,
+                self.field_schema.track_peak,
+                self.field_schema.album_loudness,
+                self.field_schema.album_peak,
+            ],
+            |track_id, document| {
+                if let Some(loudness) = by_id.get(track_id) {
+                    self.field_schema.add_loudness(document, loudness);
+                }
+            },
+        )
+    }
+
+    /// Online tracks grouped by album (album name + folder), tracks without an album are
+    /// their own group so they don't get an album gain
+    fn albums_to_analyze(&self) -> Result<BTreeMap<String, Vec<AnalysisTrack>>> {
+        let searcher = self.reader.searcher();
+        let mut albums: BTreeMap<String, Vec<AnalysisTrack>> = BTreeMap::new();
+
+        for (segment_ord, segment_reader) in searcher.segment_readers().iter().enumerate() {
+            for doc_id in segment_reader.doc_ids_alive() {
+                let doc = searcher.doc(DocAddress::new(segment_ord as u32, doc_id))?;
+                let status = doc
+                    .get_first(self.field_schema.status)
+                    .and_then(Value::as_u64);
+                if status == Some(TrackStatus::Offline as u64) {
+                    continue;
+                }
+
+                let text = |field| {
+                    doc.get_first(field)
+                        .and_then(Value::as_text)
+                        .unwrap_or("")
+                        .to_string()
+                };
+                let offset = |field| doc.get_first(field).and_then(Value::as_f64).unwrap_or(0.0);
+                let abs_path = text(self.field_schema.abs_path);
+                let album = text(self.field_schema.album);
+                let folder = Path::new(&abs_path)
+                    .parent()
+                    .map(|parent| parent.to_string_lossy().to_string())
+                    .unwrap_or_default();
+
+                let key = if album.is_empty() {
+                    format!("\u{0}{}", abs_path)
+                } else {
+                    format!("{}\u{0}{}", folder, album)
+                };
+                albums.entry(key).or_default().push(AnalysisTrack {
+                    id: text(self.field_schema.id),
+                    abs_path,
+                    start: offset(self.field_schema.start),
+                    end: offset(self.field_schema.end),
+                });
+            }
+        }
+
+        Ok(albums)
+    }
+}
+
+fn analyze_album(
+    album_tracks: &[AnalysisTrack],
+    report: &mut LoudnessReport,
+) -> Vec<(String, Loudness)> {
+    let mut states: Vec<EbuR128> = vec![];
+    let mut updates: Vec<(String, Loudness)> = vec![];
+
+    for track in album_tracks {
+        info!("analyzing loudness of {}", track.abs_path);
+        match analyze_range(Path::new(&track.abs_path), track.start, track.end) {
+            Ok((state, loudness)) => {
+                states.push(state);
+                updates.push((track.id.clone(), loudness));
+                report.analyzed += 1;
+            }
+            Err(e) => {
+                error!("Error analyzing {}: {}", track.abs_path, e);
+                report.failed.push(track.abs_path.clone());
+            }
+        }
+    }
+
+    // a single track isn't an album, leave the album values empty
+    if album_tracks.len() > 1 && !states.is_empty() {
+        report.albums += 1;
+        let album_loudness = EbuR128::loudness_global_multiple(states.iter())
+            .ok()
+            .filter(|loudness| loudness.is_finite());
+        let album_peak = updates
+            .iter()
+            .map(|(_, loudness)| loudness.track_peak)
+            .fold(0.0, f64::max);
+
+        for (_, loudness) in updates.iter_mut() {
+            loudness.album_loudness = album_loudness;
+            loudness.album_peak = Some(album_peak);
+        }
+    }
+
+    updates
+}
+
+/// Writes the results as tags of the files, returns how many files were tagged. A file split
+/// by a CUE sheet holds the whole album, it only gets the album gain (once).
+fn write_album_tags(album_tracks: &[AnalysisTrack], updates: &[(String, Loudness)]) -> usize {
+    let mut written = 0;
+    let mut cue_files: Vec<&str> = vec![];
+    for (track_id, loudness) in updates {
+        let track = match album_tracks.iter().find(|track| &track.id == track_id) {
+            Some(track) => track,
+            None => continue,
+        };
+        let path = Path::new(&track.abs_path);
+        let result = if track.is_virtual() {
+            if cue_files.contains(&track.abs_path.as_str()) || loudness.album_gain().is_none() {
+                continue;
+            }
+            cue_files.push(&track.abs_path);
+            write_tag_values(path, album_replaygain_values(loudness))
+        } else {
+            write_replaygain_tags(path, loudness)
+        };
+        match result {
+            Ok(()) => written += 1,
+            Err(e) => error!("Error writing ReplayGain tags: {}", e),
+        }
+    }
+    written
+}
+
+/// The analyzer state is returned as well, the album loudness is computed from all of them
+pub fn analyze_track(path: &Path) -> Result<(EbuR128, Loudness)> {
+    analyze_range(path, 0.0, 0.0)
+}
+
+/// `analyze_track` of `start..end` seconds of the file (see `TrackDecoder::open_range`)
+pub fn analyze_range(path: &Path, start: f64, end: f64) -> Result<(EbuR128, Loudness)> {
+    let path_string = path.to_string_lossy().to_string();
+    let ebur128_error = |e: ebur128::Error| AudioError::decode(&path_string, e);
+
+    let mut state: Option<EbuR128> = None;
+    let mut decoder = TrackDecoder::open_range(path, start, end)?;
+    while let Some((format, samples)) = decoder.next_samples()? {
+        if state.is_none() {
+            let mode = Mode::I | Mode::SAMPLE_PEAK | Mode::HISTOGRAM;
+            state = Some(
+                EbuR128::new(format.channels as u32, format.sample_rate, mode)
+                    .map_err(ebur128_error)?,
+            );
+        }
+        if let Some(state) = state.as_mut() {
+            state.add_frames_f32(samples).map_err(ebur128_error)?;
+        }
+    }
+    let format = decoder.format();
+
+    let state = state.ok_or_else(|| AudioError::decode(&path_string, "no audio"))?;
+    let track_loudness = state.loudness_global().map_err(ebur128_error)?;
+    if !track_loudness.is_finite() {
+        // digital silence has no loudness (-inf), there's nothing to normalize
+        return Err(AudioError::decode(&path_string, "silent track"));
+    }
+
+    let mut track_peak: f64 = 0.0;
+    for channel in 0..format.channels as u32 {
+        track_peak = track_peak.max(state.sample_peak(channel).map_err(ebur128_error)?);
+    }
+
+    Ok((
+        state,
+        Loudness {
+            track_loudness,
+            track_peak,
+            album_loudness: None,
+            album_peak: None,
+        },
+    ))
+}
+
+/// Writes the REPLAYGAIN_* tags most players understand: ID3 `TXXX` frames for mp3/wav and
+/// Vorbis comments for flac. Other formats are skipped with a warning.
+pub fn write_replaygain_tags(path: &Path, loudness: &Loudness) -> Result<()> {
+    let mut values = vec![
+        (
+            "REPLAYGAIN_TRACK_GAIN",
+            format!("{:.2} dB", loudness.track_gain()),
+        ),
+        (
+            "REPLAYGAIN_TRACK_PEAK",
+            format!("{:.6}", loudness.track_peak),
+        ),
+    ];
+    values.extend(album_replaygain_values(loudness));
+    write_tag_values(path, values)
+}
+
+fn album_replaygain_values(loudness: &Loudness) -> Vec<(&'static str, String)> {
+    let mut values = vec![];
+    if let Some(album_gain) = loudness.album_gain() {
+        values.push(("REPLAYGAIN_ALBUM_GAIN", format!("{:.2} dB", album_gain)));
+    }
+    if let Some(album_peak) = loudness.album_peak {
+        values.push(("REPLAYGAIN_ALBUM_PEAK", format!("{:.6}", album_peak)));
+    }
+    values
+}
+
+fn write_tag_values(path: &Path, values: Vec<(&'static str, String)>) -> Result<()> {
+    let path_string = path.to_string_lossy().to_string();
+
+    match file_ext(&path_string).to_lowercase().as_str() {
+        "mp3" | "wav" => {
+            let is_wav = file_ext(&path_string).eq_ignore_ascii_case("wav");
+            let read = if is_wav {
+                id3::Tag::read_from_wav_path(path)
+            } else {
+                id3::Tag::read_from_path(path)
+            };
+            let mut tag = match read {
+                Ok(tag) => tag,
+                Err(e) if matches!(e.kind, ErrorKind::NoTag) => id3::Tag::new(),
+                Err(e) => return Err(AudioError::tag(&path_string, e)),
+            };
+
+            for (description, value) in values {
+                // replaces an existing TXXX frame with the same description
+                tag.add_frame(ExtendedText {
+                    description: description.to_string(),
+                    value,
+                });
+            }
+
+            let written = if is_wav {
+                tag.write_to_wav_path(path, Version::Id3v24)
+            } else {
+                tag.write_to_path(path, Version::Id3v24)
+            };
+            written.map_err(|e| AudioError::tag(&path_string, e))
+        }
+        "flac" => {
+            let mut tag = metaflac::Tag::read_from_path(path)
+                .map_err(|e| AudioError::tag(&path_string, e))?;
+            for (key, value) in values {
+                tag.set_vorbis(key, vec![value]);
+            }
+            tag.save().map_err(|e| AudioError::tag(&path_string, e))
+        }
+        ext => {
+            warn!("Writing ReplayGain tags to {} files is not supported", ext);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::test_support::write_wav;
+
+    #[test]
+    fn cue_files_only_get_the_album_gain() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("Album.wav");
+        write_wav(&path, 8000, 1, 16000);
+        let abs_path = path.to_string_lossy().to_string();
+        let album_tracks: Vec<AnalysisTrack> = [(0.0, 1.0), (1.0, 0.0)]
+            .iter()
+            .enumerate()
+            .map(|(index, (start, end))| AnalysisTrack {
+                id: format!("album-{:02}", index + 1),
+                abs_path: abs_path.clone(),
+                start: *start,
+                end: *end,
+            })
+            .collect();
+
+        let mut report = LoudnessReport::default();
+        let updates = analyze_album(&album_tracks, &mut report);
+        assert_eq!(report.analyzed, 2);
+        assert!(report.failed.is_empty());
+        // the same samples in both halves (see `write_wav`)
+        let peak = 999.0 / 32768.0;
+        for (_, loudness) in &updates {
+            assert!((loudness.track_peak - peak).abs() < 1e-6, "{:?}", loudness);
+            assert_eq!(loudness.album_peak, Some(loudness.track_peak));
+        }
+
+        assert_eq!(write_album_tags(&album_tracks, &updates), 1);
+        let tag = id3::Tag::read_from_wav_path(&path).unwrap();
+        let descriptions: Vec<&str> = tag
+            .extended_texts()
+            .map(|text| text.description.as_str())
+            .collect();
+        assert_eq!(descriptions, ["REPLAYGAIN_ALBUM_GAIN", "REPLAYGAIN_ALBUM_PEAK"]);
+    }
+}
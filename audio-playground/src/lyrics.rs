@@ -0,0 +1,235 @@
+use std::collections::HashSet;
+use std::fs::read_to_string;
+use std::path::Path;
+
+use lazy_static::lazy_static;
+use log::{trace, warn};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tantivy::collector::TopDocs;
+use tantivy::query::QueryParser;
+use tantivy::{Snippet, SnippetGenerator};
+
+use crate::error::Result;
+use crate::schema::{SearchWatcher, Track};
+use crate::utils::{adapt_text, file_ext};
+
+lazy_static! {
+    /// `[mm:ss.xx]`, `[mm:ss:xx]` or `[mm:ss]`, a line can have several of them
+    static ref LRC_TIMESTAMP: Regex = Regex::new(r"\[(\d+):(\d{1,2})(?:[.:](\d{1,3}))?\]").unwrap();
+    static ref LRC_OFFSET: Regex = Regex::new(r"(?i)^\[offset:\s*([+-]?\d+)\s*\]").unwrap();
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct LyricLine {
+    /// Milliseconds from the start of the track
+    pub time_ms: u64,
+    pub text: String,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct Lyrics {
+    /// Plain text, one line per lyric line
+    pub text: String,
+    /// Empty when only unsynced lyrics were found
+    pub synced: Vec<LyricLine>,
+}
+
+impl Lyrics {
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    fn from_text(text: &str) -> Self {
+        // USLT frames sometimes hold a whole LRC file
+        let synced = parse_lrc(text);
+        if !synced.is_empty() {
+            return Lyrics::from_synced(synced);
+        }
+        Lyrics {
+            text: text.trim().to_string(),
+            synced: vec![],
+        }
+    }
+
+    fn from_synced(synced: Vec<LyricLine>) -> Self {
+        let text = synced
+            .iter()
+            .map(|line| line.text.as_str())
+            .collect::<Vec<&str>>()
+            .join("\n");
+        Lyrics { text, synced }
+    }
+}
+
+/// A `.lrc` file next to the track (same name) wins over embedded ID3 `USLT` frames,
+/// as it's usually synced and more up to date
+pub fn read_lyrics(path: &Path) -> Lyrics {
+    let sidecar = path.with_extension("lrc");
+    if sidecar.is_file() {
+        match read_to_string(&sidecar) {
+            Ok(content) => return Lyrics::from_text(&content),
+            Err(e) => warn!("Error reading {:?}: {}", sidecar, e),
+        }
+    }
+
+    let path_string = path.to_string_lossy();
+    let tag = match file_ext(&path_string).to_lowercase().as_str() {
+        "mp3" => id3::Tag::read_from_path(path),
+        "wav" => id3::Tag::read_from_wav_path(path),
+        _ => return Lyrics::default(),
+    };
+
+    match tag {
+        Ok(tag) => tag
+            .lyrics()
+            .map(|lyrics| lyrics.text.trim())
+            .find(|text| !text.is_empty())
+            .map(Lyrics::from_text)
+            .unwrap_or_default(),
+        Err(e) => {
+            trace!("No ID3 lyrics for {:?}: {}", path, e);
+            Lyrics::default()
+        }
+    }
+}
+
+/// Lines without a timestamp (and metadata such as `[ar:Artist]`) are dropped,
+/// lines with several timestamps are repeated
+pub fn parse_lrc(content: &str) -> Vec<LyricLine> {
+    let mut offset_ms: i64 = 0;
+    let mut lines = vec![];
+
+    for line in content.lines() {
+        let line = line.trim();
+        if let Some(captures) = LRC_OFFSET.captures(line) {
+            // a positive offset shifts the lyrics up (they show sooner)
+            offset_ms = captures[1].parse().unwrap_or(0);
+            continue;
+        }
+
+        let mut times = vec![];
+        let mut text_start = 0;
+        for captures in LRC_TIMESTAMP.captures_iter(line) {
+            let whole = captures.get(0).unwrap();
+            // timestamps are only at the start of the line
+            if whole.start() != text_start {
+                break;
+            }
+            text_start = whole.end();
+
+            let minutes: i64 = captures[1].parse().unwrap_or(0);
+            let seconds: i64 = captures[2].parse().unwrap_or(0);
+            let fraction = captures.get(3).map(|m| m.as_str()).unwrap_or("0");
+            // "5" is 500ms, "05" is 50ms, "005" is 5ms
+            let fraction_ms = fraction.parse::<i64>().unwrap_or(0) * 10_i64.pow(3 - fraction.len() as u32);
+            times.push((minutes * 60_000 + seconds * 1000 + fraction_ms - offset_ms).max(0) as u64);
+        }
+
+        let text = line[text_start..].trim();
+        for time_ms in times {
+            lines.push(LyricLine {
+                time_ms,
+                text: text.to_string(),
+            });
+        }
+    }
+
+    lines.sort_by_key(|line| line.time_ms);
+    lines
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct LyricMatch {
+    pub track: Track,
+    pub score: f32,
+    /// HTML with the matched words in `<b>` tags
+    pub snippet: String,
+    /// The lyric line that matches the search best
+    pub matched_line: Option<String>,
+    /// Start of `matched_line` in milliseconds, only for synced lyrics
+    pub time_ms: Option<u64>,
+}
+
+impl SearchWatcher {
+    /// Full text search in the lyrics, e.g. for a line stuck in your head
+    pub fn search_lyrics(&self, text: &str, limit: usize) -> Result<Vec<LyricMatch>> {
+        let searcher = self.reader.searcher();
+        let query_parser = QueryParser::for_index(&self.index, vec![self.field_schema.lyrics]);
+        let query = query_parser.parse_query(&adapt_text(&query_parser, text))?;
+
+        let mut snippet_generator =
+            SnippetGenerator::create(&searcher, &*query, self.field_schema.lyrics)?;
+        snippet_generator.set_max_num_chars(200);
+
+        let top_docs = searcher.search(&query, &TopDocs::with_limit(limit))?;
+        let mut matches = Vec::with_capacity(top_docs.len());
+        for (score, doc_address) in top_docs {
+            let doc = searcher.doc(doc_address)?;
+            let snippet = snippet_generator.snippet_from_doc(&doc);
+            let track = Track::with_document(&self.field_schema, doc.clone());
+
+            let synced: Vec<LyricLine> = doc
+                .get_first(self.field_schema.synced_lyrics)
+                .and_then(|value| value.as_text())
+                .and_then(|json| serde_json::from_str(json).ok())
+                .unwrap_or_default();
+            let plain = doc
+                .get_first(self.field_schema.lyrics)
+                .and_then(|value| value.as_text())
+                .unwrap_or("");
+
+            let (matched_line, time_ms) = if synced.is_empty() {
+                let line = best_line(plain.lines(), &snippet).map(|line| line.to_string());
+                (line, None)
+            } else {
+                let line = best_line(synced.iter().map(|line| line.text.as_str()), &snippet)
+                    .and_then(|text| synced.iter().find(|line| line.text == text));
+                (
+                    line.map(|line| line.text.clone()),
+                    line.map(|line| line.time_ms),
+                )
+            };
+
+            matches.push(LyricMatch {
+                track,
+                score,
+                snippet: snippet.to_html(),
+                matched_line,
+                time_ms,
+            });
+        }
+
+        Ok(matches)
+    }
+}
+
+/// The line containing the most of the highlighted words of `snippet`
+fn best_line<'a>(lines: impl Iterator<Item = &'a str>, snippet: &Snippet) -> Option<&'a str> {
+    let fragment = snippet.fragment();
+    let highlighted: HashSet<String> = snippet
+        .highlighted()
+        .iter()
+        .filter_map(|range| fragment.get(range.clone()))
+        .map(|word| word.to_lowercase())
+        .collect();
+    if highlighted.is_empty() {
+        return None;
+    }
+
+    lines
+        .map(|line| {
+            let hits = line
+                .split(|c: char| !c.is_alphanumeric())
+                .filter(|word| highlighted.contains(&word.to_lowercase()))
+                .count();
+            (hits, line)
+        })
+        .filter(|(hits, _)| *hits > 0)
+        // the first of equally good lines, as `max_by_key` would return the last
+        .fold(None, |best: Option<(usize, &str)>, (hits, line)| match best {
+            Some((best_hits, _)) if best_hits >= hits => best,
+            _ => Some((hits, line)),
+        })
+        .map(|(_, line)| line)
+}
@@ -1,9 +1,8 @@
 use std::collections::HashMap;
 use std::fs;
-use std::fs::read_to_string;
-use std::io;
 
 use std::path::Path;
+use std::sync::Arc;
 use std::time::SystemTime;
 
 #[cfg(unix)]
@@ -11,49 +10,63 @@ use std::os::unix::fs::MetadataExt;
 #[cfg(windows)]
 use std::os::windows::fs::MetadataExt;
 
-use tantivy::aggregation::agg_req::{
-    Aggregation, Aggregations, BucketAggregation, BucketAggregationType,
-};
-use tantivy::aggregation::agg_result::{
-    AggregationResult, AggregationResults, BucketEntry, BucketResult,
-};
-use tantivy::aggregation::bucket::{
-    CustomOrder, HistogramAggregation, OrderTarget, TermsAggregation,
-};
-use tantivy::aggregation::AggregationCollector;
+use tantivy::aggregation::agg_result::AggregationResults;
+use tantivy::aggregation::bucket::Order;
 use tantivy::collector::{Count, TopDocs};
 use tantivy::query::{AllQuery, QueryParser, TermQuery};
-use tantivy::{schema::*, Index, IndexReader, IndexWriter, LeasedItem, Searcher, TantivyError};
-
-use jwalk::{DirEntry, WalkDir};
+use tantivy::{schema::*, Index, IndexReader, LeasedItem};
 
-mod reader;
-mod schema;
-mod search_query;
-mod utils;
+use jwalk::DirEntry;
 
-use crate::reader::{get_duration_for_path, get_track_from_path};
-use crate::schema::{
+use audio_playground::aggregations::{
+    aggregate, aggregations, terms, terms_with_sub_aggregation, year_histogram,
+};
+use audio_playground::chapters::chapter_at;
+use audio_playground::error::{AudioError, Result};
+use audio_playground::export::{Encoder, ExportJob, ExportSource};
+use audio_playground::library_roots::{configured_roots, LibraryRoot};
+use audio_playground::mpd::DEFAULT_MPD_ADDRESS;
+use audio_playground::playback::{NullSink, Player, PlayerEvent, ReplayGainMode};
+use audio_playground::playlists::{PlaylistStore, SavedQuery, PLAYLISTS_FILE};
+use audio_playground::reader::get_tracks_from_path;
+use audio_playground::schema::{
     DocumentSearchRequest, DocumentSearchResponse, Faceted, FieldSchema, Filters, OrderBy,
     OrderType, SearchWatcher, TheRealBucket, TrackJson,
 };
-use crate::search_query::do_search;
-use crate::utils::{aggregate_to_bucket, file_ext, norm, ALLOWED_FILE_TYPES};
+use audio_playground::scan_rules::{walk_dir, ScanRules};
+use audio_playground::search_query::do_search;
+use audio_playground::settings::SETTINGS;
+use audio_playground::subsonic::DEFAULT_SUBSONIC_ADDRESS;
+use audio_playground::utils::aggregate_to_bucket;
+use audio_playground::{logging, mpd, subsonic, terminal_ui};
 
 const JSON_DATA_FILE: &str = "./data/audio.json";
 
-// "C:\\Users\\lukes\\Music"
-// "E:\\Music";
+// Only used when no `library_roots` are configured in the settings,
+// e.g. "C:\\Users\\lukes\\Music" and "E:\\Music"
 const BASE_AUDIO_DIRECTORY: &str =
     "C:\\Users\\lukes\\Github\\rust-adventures\\audio-playground\\audio";
 
 const INDEX_CACHE_DIRECTORY: &str =
     "C:\\Users\\lukes\\Github\\rust-adventures\\audio-playground\\.index-cache";
 
-fn main() -> tantivy::Result<()> {
+fn main() -> Result<()> {
+    logging::init_logging();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("play") {
+        return play(&args[1..]);
+    }
+    if args.first().map(String::as_str) == Some("mpd") {
+        return serve_mpd(&args[1..]);
+    }
+    if args.first().map(String::as_str) == Some("subsonic") {
+        return serve_subsonic(&args[1..]);
+    }
+
     if false {
-        // Fetch audio data and save to the local JSON file
-        walk(&norm(BASE_AUDIO_DIRECTORY).to_string());
+        // Fetch audio data (from every library root) and save to the local JSON file
+        let roots = configured_roots(&SETTINGS.read().unwrap(), BASE_AUDIO_DIRECTORY);
+        walk(&roots);
     }
 
     if false {
@@ -84,167 +97,162 @@ fn main() -> tantivy::Result<()> {
         artists_all()?;
     }
 
-    Ok(())
-}
+    if false {
+        // Ratings, play counts and playlists from another player (iTunes, Rhythmbox, MPD, m3u)
+        import_library(Path::new("./data/Library.xml"))?;
+    }
 
-pub fn is_existing_by_path(
-    track_path: &str,
-    searcher: &LeasedItem<Searcher>,
-    query_parser: &QueryParser,
-) -> bool {
-    let path_query = format!("\"{}\"", &track_path);
-    let query = query_parser.parse_query(path_query.as_str()).unwrap();
-    let count = searcher.search(&query, &Count).unwrap();
+    if false {
+        // EBU R128 loudness per track and album, used to normalize the volume on playback
+        let search_watcher = SearchWatcher::new(INDEX_CACHE_DIRECTORY)?;
+        search_watcher.analyze_loudness(false)?;
+    }
 
-    count > 0
-}
+    if false {
+        // "More like this", from the tags plus the tempo/brightness/chroma of the audio
+        let search_watcher = SearchWatcher::new(INDEX_CACHE_DIRECTORY)?;
+        for similar in search_watcher.similar("some-track-id", 10)? {
+            println!(
+                "{:.2} {} - {}",
+                similar.score, similar.track.artist, similar.track.name
+            );
+        }
+    }
 
-fn index_data(
-    field_schema: &FieldSchema,
-    mut index_writer: IndexWriter,
-    searcher: &LeasedItem<Searcher>,
-    query_parser: QueryParser,
-    json_file_path: &str,
-) -> Result<(), TantivyError> {
-    // Read JSON from file
-    let json_file_path_as_path = Path::new(json_file_path);
-    let json_file_str = read_to_string(json_file_path_as_path).expect("file not found");
-    let data: Vec<TrackJson> = serde_json::from_str(&json_file_str).unwrap();
-
-    println!("Total {} items", data.len());
-
-    for item in data.iter() {
-        // duplicate check
-        if !is_existing_by_path(&item.abs_path, &searcher, &query_parser) {
-            let mut document = Document::default();
-            document.add_text(field_schema.id, &item.id);
-            document.add_text(field_schema.abs_path, &item.abs_path);
-            document.add_text(field_schema.title, &item.name);
-            document.add_text(field_schema.track, &item.track);
-            document.add_text(field_schema.album, &item.album);
-            document.add_text(field_schema.artist, &item.artist);
-            // document.add_text(field_schema.genre, &item.genre);
-            document.add_u64(field_schema.year, item.year as u64);
-            document.add_i64(field_schema.size, item.size);
-
-            let date_time_value: tantivy::DateTime =
-                tantivy::DateTime::from_unix_timestamp(item.created_date / 1000);
-            document.add_date(field_schema.created_date, date_time_value);
-
-            let date_time_modified_value: tantivy::DateTime =
-                tantivy::DateTime::from_unix_timestamp(item.modified_date / 1000);
-            document.add_date(field_schema.modified_date, date_time_modified_value);
-
-            let date_time_indexed_value: tantivy::DateTime =
-                tantivy::DateTime::from_unix_timestamp(item.indexed_date / 1000);
-            document.add_date(field_schema.indexed_date, date_time_indexed_value);
-
-            let facet_album_string = format!("/album/{}", &item.album);
-            document.add_facet(field_schema.facets, Facet::from(&facet_album_string));
-
-            let facet_artist_string = format!("/artist/{}", &item.artist);
-            document.add_facet(field_schema.facets, Facet::from(&facet_artist_string));
-
-            let facet_year_string = format!("/year/{}", &item.year);
-            document.add_facet(field_schema.facets, Facet::from(&facet_year_string));
-
-            for genre in &item.genres {
-                document.add_text(field_schema.genre, &genre);
-                let facet_string = format!("/genre/{}", &genre);
-                document.add_facet(field_schema.facets, Facet::from(&facet_string));
-            }
+    if false {
+        let search_watcher = SearchWatcher::new(INDEX_CACHE_DIRECTORY)?;
+        for lyric_match in search_watcher.search_lyrics("\"a line i remember\"", 10)? {
+            println!(
+                "{} - {} @ {:?}ms: {}",
+                lyric_match.track.artist,
+                lyric_match.track.name,
+                lyric_match.time_ms,
+                lyric_match.snippet
+            );
+        }
+    }
+
+    if false {
+        export_saved_query("favourites", Path::new("./data/favourites.m3u8"))?;
+    }
 
-            if let Some(d) = get_duration_for_path(&item.abs_path) {
-                document.add_f64(field_schema.duration, d);
+    if false {
+        // Fingerprints are computed during the scan, no online lookup is needed
+        let search_watcher = SearchWatcher::new(INDEX_CACHE_DIRECTORY)?;
+        for group in search_watcher.identical_tracks()? {
+            println!("{:.2} similar:", group.similarity);
+            for track in &group.tracks {
+                println!("  {}", track.abs_path);
             }
+        }
+        let matches = search_watcher.match_untagged()?;
+        for tag_match in &matches {
+            println!(
+                "{:.2} {} -> {} - {}",
+                tag_match.similarity,
+                tag_match.track.abs_path,
+                tag_match.matched.artist,
+                tag_match.matched.track
+            );
+        }
+        search_watcher.apply_tag_matches(&matches)?;
+    }
+
+    if false {
+        // Copy a playlist onto a phone/USB stick, run it again to resume or update it
+        let search_watcher = SearchWatcher::new(INDEX_CACHE_DIRECTORY)?;
+        let mut job = ExportJob::new(
+            ExportSource::SavedQuery("favourites".to_string()),
+            "E:/Music",
+            Encoder::Opus { bitrate_kbps: 128 },
+        );
+        job.max_bytes = Some(8 * 1024 * 1024 * 1024);
+        search_watcher.export(&job)?;
+    }
 
-            index_writer.add_document(document)?;
+    if false {
+        // Albums are grouped after every index pass, this just lists them
+        let search_watcher = SearchWatcher::new(INDEX_CACHE_DIRECTORY)?;
+        for summary in search_watcher.list_albums() {
+            println!(
+                "{} - {} ({}), {} track(s)",
+                summary.album_artist, summary.title, summary.year, summary.track_count
+            );
+        }
+        if let Some(album) = search_watcher.album("some-album-id") {
+            for track in &album.tracks {
+                println!("{}.{} {}", track.disc_number, track.track_number, track.name);
+            }
         }
     }
 
-    index_writer.commit()?;
+    if false {
+        // Move the index (with ratings and play counts) to another machine without rescanning,
+        // paths follow the library roots with the same id there
+        let search_watcher = SearchWatcher::new(INDEX_CACHE_DIRECTORY)?;
+        search_watcher.export_index(Path::new("./data/index-snapshot.ndjson"))?;
+        search_watcher.import_index(Path::new("./data/index-snapshot.ndjson"))?;
+    }
+
+    if false {
+        // Audiobooks are grouped into books instead of albums, play one with `play --book ID`
+        let search_watcher = SearchWatcher::new(INDEX_CACHE_DIRECTORY)?;
+        for book in search_watcher.list_books() {
+            print!("{} - {}, {} file(s)", book.author, book.title, book.file_count);
+            match search_watcher.book_position(&book.id) {
+                Some(position) => {
+                    println!(", at file {} {:.0}s", position.file_index + 1, position.position)
+                }
+                None => println!(),
+            }
+        }
+    }
 
     Ok(())
 }
 
-fn setup() -> tantivy::Result<(
+/// The helpers below only search: the first run creates and fills the index (from the JSON
+/// cache), after that it's opened read-only, without a writer and without reindexing anything
+fn setup() -> Result<(
     FieldSchema,
     LeasedItem<tantivy::Searcher>,
     Index,
     IndexReader,
 )> {
-    let field_schema: FieldSchema = FieldSchema::new();
+    let search_watcher = match SearchWatcher::open_read_only(INDEX_CACHE_DIRECTORY) {
+        Ok(search_watcher) => search_watcher,
+        Err(e) => {
+            println!("{}", e);
+            let search_watcher = SearchWatcher::new(INDEX_CACHE_DIRECTORY)?;
+            search_watcher.initial_index_from_json(JSON_DATA_FILE)?;
+            search_watcher.reader.reload()?;
+            search_watcher
+        }
+    };
 
-    let index_path: &Path = Path::new(INDEX_CACHE_DIRECTORY);
-    let index: Index;
-    if index_path.exists() {
-        index = Index::open_in_dir(&index_path).ok().unwrap();
-    } else {
-        fs::create_dir(index_path).ok();
-        index = Index::create_in_dir(&index_path, field_schema.schema.clone())
-            .ok()
-            .unwrap();
-    }
-
-    // let index = Index::create_in_ram(field_schema.schema.clone());
-    let index_writer: IndexWriter = index.writer(30_000_000)?;
-
-    let reader = index
-        .reader_builder()
-        .reload_policy(tantivy::ReloadPolicy::OnCommit)
-        .try_into()
-        .unwrap();
-
-    let searcher = reader.searcher();
-    index_data(
-        &field_schema,
-        index_writer,
-        &searcher,
-        QueryParser::for_index(&index, vec![field_schema.abs_path]),
-        JSON_DATA_FILE,
-    )?;
-
-    Ok((field_schema, searcher, index, reader))
+    let searcher = search_watcher.reader.searcher();
+    Ok((
+        search_watcher.field_schema.clone(),
+        searcher,
+        search_watcher.index.clone(),
+        search_watcher.reader.clone(),
+    ))
 }
 
-fn aggregate_search_all() -> tantivy::Result<()> {
+fn aggregate_search_all() -> Result<()> {
     let (_, searcher, _, _) = setup()?;
     // start aggregate search
 
-    let sub_aggregation: Aggregations = vec![(
-        "album_bucket".to_string(),
-        Aggregation::Bucket(BucketAggregation {
-            bucket_agg: BucketAggregationType::Terms(TermsAggregation {
-                field: "album".to_string(),
-                size: Some(50),
-                order: Some(CustomOrder {
-                    target: OrderTarget::Key,
-                    order: tantivy::aggregation::bucket::Order::Desc,
-                }),
-                ..Default::default()
-            }),
-            sub_aggregation: Default::default(),
-        }),
-    )]
-    .into_iter()
-    .collect();
-
-    let aggregate_request: Aggregations = vec![(
-        "artist_bucket".to_string(),
-        Aggregation::Bucket(BucketAggregation {
-            bucket_agg: BucketAggregationType::Terms(TermsAggregation {
-                field: "artist".to_string(),
-                size: Some(1000),
-                ..Default::default()
-            }),
-            sub_aggregation: sub_aggregation,
-        }),
-    )]
-    .into_iter()
-    .collect();
-
-    let collector = AggregationCollector::from_aggs(aggregate_request);
-    let agg_res: AggregationResults = searcher.search(&AllQuery, &collector).unwrap();
+    let sub_aggregation = aggregations(vec![(
+        "album_bucket",
+        terms("album", 50, Some(Order::Desc)),
+    )]);
+    let aggregate_request = aggregations(vec![(
+        "artist_bucket",
+        terms_with_sub_aggregation("artist", 1000, None, sub_aggregation),
+    )]);
+
+    let agg_res: AggregationResults = aggregate(&searcher, &AllQuery, aggregate_request)?;
 
     let json_response_string = serde_json::to_string(&agg_res)?;
 
@@ -254,67 +262,24 @@ fn aggregate_search_all() -> tantivy::Result<()> {
     Ok(())
 }
 
-fn aggregate_search_albums_for_artist(artist: String) -> tantivy::Result<()> {
+fn aggregate_search_albums_for_artist(artist: String) -> Result<()> {
     let (field_schema, searcher, index, _) = setup()?;
 
     // start aggregate search
     // query for the specific artist here `artist`
     let query_parser = QueryParser::for_index(&index, vec![field_schema.artist]);
-    let query = query_parser
-        .parse_query(&format!("artist:{}", &artist))
-        .unwrap();
-
-    let sub_aggregation: Aggregations = vec![
-        (
-            "title_bucket".to_string(),
-            Aggregation::Bucket(BucketAggregation {
-                bucket_agg: BucketAggregationType::Terms(TermsAggregation {
-                    field: "title".to_string(),
-                    size: Some(50),
-                    order: Some(CustomOrder {
-                        target: OrderTarget::Key,
-                        order: tantivy::aggregation::bucket::Order::Desc,
-                    }),
-                    ..Default::default()
-                }),
-                sub_aggregation: Default::default(),
-            }),
-        ),
-        (
-            "year_bucket".to_string(),
-            Aggregation::Bucket(BucketAggregation {
-                bucket_agg: BucketAggregationType::Histogram(HistogramAggregation {
-                    field: "year".to_string(),
-                    interval: 1.0,
-                    ..Default::default()
-                }),
-                sub_aggregation: Default::default(),
-            }),
-        ),
-    ]
-    .into_iter()
-    .collect();
-
-    let aggregate_request: Aggregations = vec![(
-        "album_bucket".to_string(),
-        Aggregation::Bucket(BucketAggregation {
-            bucket_agg: BucketAggregationType::Terms(TermsAggregation {
-                field: "album".to_string(),
-                size: Some(1000),
-                order: Some(CustomOrder {
-                    target: OrderTarget::Key,
-                    order: tantivy::aggregation::bucket::Order::Desc,
-                }),
-                ..Default::default()
-            }),
-            sub_aggregation: sub_aggregation,
-        }),
-    )]
-    .into_iter()
-    .collect();
+    let query = query_parser.parse_query(&format!("artist:{}", &artist))?;
 
-    let collector = AggregationCollector::from_aggs(aggregate_request);
-    let agg_res: AggregationResults = searcher.search(&query, &collector).unwrap();
+    let sub_aggregation = aggregations(vec![
+        ("title_bucket", terms("title", 50, Some(Order::Desc))),
+        ("year_bucket", year_histogram()),
+    ]);
+    let aggregate_request = aggregations(vec![(
+        "album_bucket",
+        terms_with_sub_aggregation("album", 1000, Some(Order::Desc), sub_aggregation),
+    )]);
+
+    let agg_res: AggregationResults = aggregate(&searcher, &query, aggregate_request)?;
 
     let json_response_string = serde_json::to_string(&agg_res)?;
 
@@ -324,71 +289,15 @@ fn aggregate_search_albums_for_artist(artist: String) -> tantivy::Result<()> {
     Ok(())
 }
 
-fn aggregate_search() -> tantivy::Result<()> {
+fn aggregate_search() -> Result<()> {
     let (field_schema, searcher, index, _) = setup()?;
 
-    let aggregate_request: Aggregations = vec![
-        (
-            "album_bucket".to_string(),
-            Aggregation::Bucket(BucketAggregation {
-                bucket_agg: BucketAggregationType::Terms(TermsAggregation {
-                    field: "album".to_string(),
-                    size: Some(50),
-                    order: Some(CustomOrder {
-                        target: OrderTarget::Key,
-                        order: tantivy::aggregation::bucket::Order::Desc,
-                    }),
-                    ..Default::default()
-                }),
-                sub_aggregation: Default::default(),
-            }),
-        ),
-        (
-            "artist_bucket".to_string(),
-            Aggregation::Bucket(BucketAggregation {
-                bucket_agg: BucketAggregationType::Terms(TermsAggregation {
-                    field: "artist".to_string(),
-                    size: Some(50),
-                    order: Some(CustomOrder {
-                        target: OrderTarget::Key,
-                        order: tantivy::aggregation::bucket::Order::Desc,
-                    }),
-                    ..Default::default()
-                }),
-                sub_aggregation: Default::default(),
-            }),
-        ),
-        (
-            "genre_bucket".to_string(),
-            Aggregation::Bucket(BucketAggregation {
-                bucket_agg: BucketAggregationType::Terms(TermsAggregation {
-                    field: "genre".to_string(),
-                    size: Some(50),
-                    order: Some(CustomOrder {
-                        target: OrderTarget::Key,
-                        order: tantivy::aggregation::bucket::Order::Desc,
-                    }),
-                    ..Default::default()
-                }),
-                sub_aggregation: Default::default(),
-            }),
-        ),
-        (
-            "year_bucket".to_string(),
-            Aggregation::Bucket(BucketAggregation {
-                bucket_agg: BucketAggregationType::Histogram(HistogramAggregation {
-                    field: "year".to_string(),
-                    interval: 1.0,
-                    ..Default::default()
-                }),
-                sub_aggregation: Default::default(),
-            }),
-        ),
-    ]
-    .into_iter()
-    .collect();
-
-    let collector = AggregationCollector::from_aggs(aggregate_request);
+    let aggregate_request = aggregations(vec![
+        ("album_bucket", terms("album", 50, Some(Order::Desc))),
+        ("artist_bucket", terms("artist", 50, Some(Order::Desc))),
+        ("genre_bucket", terms("genre", 50, Some(Order::Desc))),
+        ("year_bucket", year_histogram()),
+    ]);
 
     // query for the specific artist here `artist`
     let query_parser = QueryParser::for_index(
@@ -400,11 +309,11 @@ fn aggregate_search() -> tantivy::Result<()> {
             field_schema.artist,
         ],
     );
-    let query = query_parser.parse_query("*").unwrap();
-    let count = searcher.search(&query, &Count).unwrap();
+    let query = query_parser.parse_query("*")?;
+    let count = searcher.search(&query, &Count)?;
     println!("{} total items", count);
 
-    let agg_res: AggregationResults = searcher.search(&query, &collector).unwrap();
+    let agg_res: AggregationResults = aggregate(&searcher, &query, aggregate_request)?;
 
     let json_response_string = serde_json::to_string(&agg_res)?;
 
@@ -414,101 +323,273 @@ fn aggregate_search() -> tantivy::Result<()> {
     Ok(())
 }
 
-fn watch_search() {
-    let search_watcher = SearchWatcher::new(INDEX_CACHE_DIRECTORY);
-    // search_watcher.initial_index_from_json(JSON_DATA_FILE);
-    search_watcher.index_since_last_opened();
-    return;
-
-    println!("Enter a search term...\n");
-    for line in io::stdin().lines() {
-        match line {
-            Ok(line) => {
-                println!("🔎 searching for {:?}\n", line);
-
-                // Query Variables (will be passed to the method from a FE interface of some sort)
-                let mut text: String = "".to_string();
-                if line.len() > 0 {
-                    text = format!("\"{}\"", line);
-                }
-                let faceted = Faceted {
-                    tags: vec![
-                        "/genre".to_string(),
-                        "/year".to_string(),
-                        "/album".to_string(),
-                        "/artist".to_string(),
-                    ],
-                };
-
-                // Order by
-                let order_by_object: OrderBy = OrderBy {
-                    field: "created_date".to_string(),
+fn import_library(path: &Path) -> Result<()> {
+    let search_watcher = SearchWatcher::new(INDEX_CACHE_DIRECTORY)?;
+    let report = search_watcher.import_library(path, None)?;
+    for unmatched in &report.unmatched {
+        println!("Not found: {}", unmatched);
+    }
+    Ok(())
+}
+
+fn export_saved_query(name: &str, path: &Path) -> Result<()> {
+    let playlist_store = PlaylistStore::open(PLAYLISTS_FILE)?;
+    if playlist_store.saved_query(name).is_none() {
+        playlist_store.save_query(SavedQuery {
+            name: name.to_string(),
+            request: DocumentSearchRequest {
+                text: "".to_string(),
+                fields: vec![],
+                filters: Filters {
+                    favourites_only: true,
+                    ..Default::default()
+                },
+                order: Some(OrderBy {
+                    field: "play_count".to_string(),
                     order_type: OrderType::Desc,
-                };
-                let order_by: Option<OrderBy> = Some(order_by_object);
-
-                let faced_only_flag = true;
-
-                let request = DocumentSearchRequest {
-                    text,
-                    fields: vec!["body".to_string()],
-                    filters: Filters {
-                        year_start: None,
-                        year_end: None,
-                        created_date_start: None,
-                        created_date_end: None,
-                    },
-                    faceted: Some(faceted.clone()),
-                    order: order_by,
-                    page_number: 0,
-                    result_per_page: 10,
-                    reload: false,
-                };
-
-                search_watcher.search(request);
-                println!("\nSearch again? ...\n");
+                }),
+                faceted: None,
+                page_number: 0,
+                result_per_page: 100,
+                reload: false,
+                explain: false,
+                search_after: None,
+            },
+        })?;
+    }
+
+    let search_watcher = SearchWatcher::new(INDEX_CACHE_DIRECTORY)?;
+    search_watcher.export_saved_query(name, path)
+}
+
+/// `play [--null] [--track-gain|--album-gain] (--playlist NAME | --saved-query NAME | --album ID | --book ID | TEXT...)`
+///
+/// Then reads commands from stdin: `p` pause/resume, `n` next, `b` previous, `s SECONDS` seek,
+/// `j INDEX` jump, `i` status, `q` quit. With `--null` the queue is decoded without output and
+/// the command returns once it's done.
+fn play(args: &[String]) -> Result<()> {
+    let mut headless = false;
+    let mut replaygain = ReplayGainMode::Off;
+    let mut source: Option<(&str, String)> = None;
+    let mut words: Vec<&str> = vec![];
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--null" => headless = true,
+            "--track-gain" => replaygain = ReplayGainMode::Track,
+            "--album-gain" => replaygain = ReplayGainMode::Album,
+            "--playlist" | "--saved-query" | "--album" | "--book" => {
+                let value = args.next().ok_or_else(|| {
+                    AudioError::Config(format!("{} needs a value", arg))
+                })?;
+                source = Some((arg.as_str(), value.clone()));
+            }
+            word => words.push(word),
+        }
+    }
+
+    let search_watcher = SearchWatcher::new(INDEX_CACHE_DIRECTORY)?;
+    // books continue with the file that was listened to last
+    let mut start = 0;
+    let tracks = match source {
+        Some(("--playlist", name)) => search_watcher.playlist_tracks(&name)?,
+        Some(("--book", book_id)) => {
+            let book = search_watcher
+                .book(&book_id)
+                .ok_or_else(|| AudioError::Config(format!("no book with id {:?}", book_id)))?;
+            if let Some(position) = search_watcher.book_position(&book_id) {
+                start = position.file_index;
+            }
+            let track_ids: Vec<String> = book.files.into_iter().map(|file| file.id).collect();
+            search_watcher.tracks_by_ids(&track_ids)?
+        }
+        Some(("--saved-query", name)) => search_watcher.saved_query_results(&name)?,
+        Some((_, album_id)) => {
+            let album = search_watcher
+                .album(&album_id)
+                .ok_or_else(|| AudioError::Config(format!("no album with id {:?}", album_id)))?;
+            let track_ids: Vec<String> = album.tracks.into_iter().map(|track| track.id).collect();
+            search_watcher.tracks_by_ids(&track_ids)?
+        }
+        None => search_watcher.all_results(&DocumentSearchRequest {
+            text: words.join(" "),
+            fields: vec![],
+            filters: Filters::default(),
+            order: None,
+            faceted: None,
+            page_number: 0,
+            result_per_page: 100,
+            reload: false,
+            explain: false,
+            search_after: None,
+        })?,
+    };
+    if tracks.is_empty() {
+        println!("Nothing to play");
+        return Ok(());
+    }
+    println!("Playing {} track(s)", tracks.len());
+
+    let null_sink = NullSink::new();
+    let player = if headless {
+        Player::headless(null_sink.clone(), replaygain)?
+    } else {
+        Player::with_default_output(replaygain)?
+    };
+    player.set_queue(tracks, start);
+
+    // plays are recorded once a track was played to the end, resume positions when a
+    // long-form track is left before that
+    let handle_event = |event: PlayerEvent| -> Result<bool> {
+        match event {
+            PlayerEvent::TrackStarted(track_id) => println!("▶️ {}", track_id),
+            PlayerEvent::TrackFinished(track_id) => {
+                search_watcher.record_play(&track_id)?;
+            }
+            PlayerEvent::TrackFailed { track_id, message } => {
+                println!("Can't play {}: {}", track_id, message)
+            }
+            PlayerEvent::QueueFinished => return Ok(true),
+            PlayerEvent::ResumePosition { track_id, position } => {
+                search_watcher.set_resume_position(&track_id, position)?;
+            }
+        }
+        Ok(false)
+    };
+    let handle_events = |player: &Player| -> Result<bool> {
+        let mut queue_finished = false;
+        for event in player.events().try_iter() {
+            queue_finished |= handle_event(event)?;
+        }
+        Ok(queue_finished)
+    };
+
+    if headless {
+        loop {
+            if handle_events(&player)? {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        println!("Decoded {} frames", null_sink.frames_written());
+        return Ok(());
+    }
+
+    for line in std::io::stdin().lines() {
+        let line = line?;
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next()) {
+            (Some("p"), _) => player.toggle_pause(),
+            (Some("n"), _) => player.next(),
+            (Some("b"), _) => player.previous(),
+            (Some("s"), Some(seconds)) => match seconds.parse::<f64>() {
+                Ok(seconds) => player.seek(seconds),
+                Err(_) => println!("Not a number of seconds: {}", seconds),
+            },
+            (Some("j"), Some(index)) => match index.parse::<usize>() {
+                Ok(index) => player.jump(index),
+                Err(_) => println!("Not a queue index: {}", index),
+            },
+            (Some("i"), _) => {
+                let status = player.status();
+                let current = status.current.and_then(|index| status.queue.get(index));
+                println!(
+                    "{:?} {} {:.0}s / {:.0}s",
+                    status.state,
+                    current.map(|track| track.abs_path.as_str()).unwrap_or("-"),
+                    status.position,
+                    status.duration.unwrap_or(0.0)
+                );
+                let chapters = current.map(|track| track.chapters.as_slice()).unwrap_or(&[]);
+                if let Some(index) = chapter_at(chapters, (status.position * 1000.0) as u64) {
+                    println!("Chapter {}: {}", index + 1, chapters[index].title);
+                }
             }
-            Err(err) => println!("IO error: {}", err),
+            (Some("q"), _) => break,
+            _ => println!("p: pause/resume, n: next, b: previous, s SECONDS: seek, j INDEX: jump, i: status, q: quit"),
+        }
+        if handle_events(&player)? {
+            break;
+        }
+    }
+    // the position of the track that was still playing
+    for event in player.shutdown() {
+        handle_event(event)?;
+    }
+    Ok(())
+}
+
+/// `mpd [--no-playback] [ADDRESS]`, serves the index to MPD clients (e.g. `mpc`, `ncmpcpp`) on
+/// `DEFAULT_MPD_ADDRESS` unless another address is given
+fn serve_mpd(args: &[String]) -> Result<()> {
+    let playback = !args.iter().any(|arg| arg == "--no-playback");
+    let address = args
+        .iter()
+        .find(|arg| !arg.starts_with("--"))
+        .map(String::as_str)
+        .unwrap_or(DEFAULT_MPD_ADDRESS);
+
+    let search_watcher = SearchWatcher::new(INDEX_CACHE_DIRECTORY)?;
+    search_watcher.index_since_last_opened()?;
+
+    let player = if playback {
+        Some(Player::with_default_output(ReplayGainMode::Off)?)
+    } else {
+        None
+    };
+    let listener = std::net::TcpListener::bind(address)?;
+    println!("MPD server on {}", address);
+    mpd::serve(&search_watcher, player, listener)
+}
+
+/// `subsonic [ADDRESS]`, the Subsonic API for phone clients on `DEFAULT_SUBSONIC_ADDRESS` unless
+/// another address is given. Users are set in `subsonic_users`.
+fn serve_subsonic(args: &[String]) -> Result<()> {
+    let address = args
+        .first()
+        .map(String::as_str)
+        .unwrap_or(DEFAULT_SUBSONIC_ADDRESS);
+
+    let search_watcher = SearchWatcher::new(INDEX_CACHE_DIRECTORY)?;
+    search_watcher.index_since_last_opened()?;
+
+    let listener = std::net::TcpListener::bind(address)?;
+    println!("Subsonic API on http://{}/rest", address);
+    subsonic::serve(&search_watcher, listener)
+}
+
+fn watch_search() {
+    let search_watcher = match SearchWatcher::new(INDEX_CACHE_DIRECTORY) {
+        Ok(search_watcher) => search_watcher,
+        Err(e) => {
+            println!("Unable to open the index: {}", e);
+            return;
         }
+    };
+    // search_watcher.initial_index_from_json(JSON_DATA_FILE);
+    if let Err(e) = search_watcher.index_since_last_opened() {
+        println!("Indexing failed: {}", e);
+    }
+
+    if let Err(e) = terminal_ui::run(&search_watcher) {
+        println!("Terminal UI failed: {}", e);
     }
 }
 
-fn search_by_genre(genre: String) -> tantivy::Result<()> {
+fn search_by_genre(genre: String) -> Result<()> {
     let (field_schema, searcher, index, _) = setup()?;
 
     // start aggregate search
 
-    let sub_aggregation: Aggregations = vec![(
-        "album_bucket".to_string(),
-        Aggregation::Bucket(BucketAggregation {
-            bucket_agg: BucketAggregationType::Terms(TermsAggregation {
-                field: "album".to_string(),
-                size: Some(50),
-                order: Some(CustomOrder {
-                    target: OrderTarget::Key,
-                    order: tantivy::aggregation::bucket::Order::Desc,
-                }),
-                ..Default::default()
-            }),
-            sub_aggregation: Default::default(),
-        }),
-    )]
-    .into_iter()
-    .collect();
-
-    let aggregate_request: Aggregations = vec![(
-        "artist_bucket".to_string(),
-        Aggregation::Bucket(BucketAggregation {
-            bucket_agg: BucketAggregationType::Terms(TermsAggregation {
-                field: "artist".to_string(),
-                size: Some(1000),
-                ..Default::default()
-            }),
-            sub_aggregation: sub_aggregation,
-        }),
-    )]
-    .into_iter()
-    .collect();
+    let sub_aggregation = aggregations(vec![(
+        "album_bucket",
+        terms("album", 50, Some(Order::Desc)),
+    )]);
+    let aggregate_request = aggregations(vec![(
+        "artist_bucket",
+        terms_with_sub_aggregation("artist", 1000, None, sub_aggregation),
+    )]);
 
     // query for the specific genre here
     let query_parser = QueryParser::for_index(
@@ -539,8 +620,7 @@ fn search_by_genre(genre: String) -> tantivy::Result<()> {
         }
     }
 
-    let collector = AggregationCollector::from_aggs(aggregate_request);
-    let agg_res: AggregationResults = searcher.search(&query, &collector).unwrap();
+    let agg_res: AggregationResults = aggregate(&searcher, &query, aggregate_request)?;
 
     let json_response_string = serde_json::to_string(&agg_res)?;
 
@@ -550,27 +630,13 @@ fn search_by_genre(genre: String) -> tantivy::Result<()> {
     Ok(())
 }
 
-fn artists_all() -> tantivy::Result<()> {
+fn artists_all() -> Result<()> {
     let (field_schema, searcher, index, _) = setup()?;
 
     // start aggregate search
 
-    let aggregate_request: Aggregations = vec![(
-        "artist_bucket".to_string(),
-        Aggregation::Bucket(BucketAggregation {
-            bucket_agg: BucketAggregationType::Terms(TermsAggregation {
-                field: "artist".to_string(),
-                size: Some(1000),
-                ..Default::default()
-            }),
-            sub_aggregation: Default::default(),
-        }),
-    )]
-    .into_iter()
-    .collect();
-
-    let collector = AggregationCollector::from_aggs(aggregate_request);
-    let agg_res: AggregationResults = searcher.search(&AllQuery, &collector).unwrap();
+    let aggregate_request = aggregations(vec![("artist_bucket", terms("artist", 1000, None))]);
+    let agg_res: AggregationResults = aggregate(&searcher, &AllQuery, aggregate_request)?;
 
     let the_real_bucket: TheRealBucket = aggregate_to_bucket(agg_res, "artist_bucket");
 
@@ -582,7 +648,7 @@ fn artists_all() -> tantivy::Result<()> {
     Ok(())
 }
 
-fn search() -> tantivy::Result<()> {
+fn search() -> Result<()> {
     let start = SystemTime::now();
     let (field_schema, searcher, index, reader) = setup()?;
 
@@ -619,7 +685,7 @@ fn search() -> tantivy::Result<()> {
 
     // let limit = 10;
     // let offset = 0;
-    let faced_only_flag = true;
+    let facet_only = false;
 
     let request = DocumentSearchRequest {
         text,
@@ -629,17 +695,20 @@ fn search() -> tantivy::Result<()> {
             year_end: Some(2050),
             created_date_start: None,
             created_date_end: None,
+            ..Default::default()
         },
         faceted: Some(faceted.clone()), // Some(faceted.clone()),
         order: order_by,
         page_number: 0,
         result_per_page: 10,
         reload: false,
+        explain: false,
+        search_after: None,
     };
 
     println!("request {:?} ", &request);
     let response: DocumentSearchResponse =
-        do_search(index, reader, field_schema, &request, faced_only_flag);
+        do_search(&index, &reader, &field_schema, &request, facet_only)?;
 
     let response_json = serde_json::to_string(&response)?;
     println!("{}", response_json);
@@ -654,43 +723,56 @@ fn search() -> tantivy::Result<()> {
     Ok(())
 }
 
-fn walk(path: &String) {
+fn walk(roots: &[LibraryRoot]) {
     let start = SystemTime::now();
-    println!("start travel {}", path);
     let mut cnt = 0;
 
-    let mut generic = WalkDir::new(&path);
-    generic = generic.process_read_dir(move |_depth, _path, _read_dir_state, children| {
-        children.iter_mut().for_each(|dir_entry_result| {
-            if let Ok(dir_entry) = dir_entry_result {
-                norm(dir_entry.path().to_str().unwrap_or(""));
-            }
-        });
-    });
-
     let mut all_tracks: Vec<TrackJson> = Vec::new();
     let mut tracks_failed: Vec<String> = Vec::new();
 
-    for entry in generic {
-        cnt += 1;
-        if entry.is_err() {
+    for root in roots {
+        if !root.is_online() {
+            println!("skipping offline root {} ({})", root.id, root.path);
             continue;
         }
 
-        let en: DirEntry<((), ())> = entry.unwrap();
-        let buf = en.path();
-        let file_type = en.file_type();
-        let is_dir = file_type.is_dir();
+        println!("start travel {} ({})", root.path, root.id);
+        let rules = match ScanRules::from_setting(&SETTINGS.read().unwrap(), root) {
+            Ok(rules) => rules,
+            Err(e) => {
+                println!("Invalid scan rules for {}: {}", root.id, e);
+                continue;
+            }
+        };
+        let generic = walk_dir(Arc::new(rules));
 
-        let path_string = buf.to_str().unwrap().to_string();
-        let name = en.file_name().to_str().unwrap();
-        let ext = file_ext(name);
+        for entry in generic {
+            cnt += 1;
+            if entry.is_err() {
+                continue;
+            }
 
-        if !is_dir & ALLOWED_FILE_TYPES.contains(&ext) {
-            if let Some(t) = get_track_from_path(&path_string) {
-                all_tracks.push(t);
-            } else {
-                tracks_failed.push(path_string)
+            let en: DirEntry<((), ())> = entry.unwrap();
+            let buf = en.path();
+            let file_type = en.file_type();
+            let is_dir = file_type.is_dir();
+
+            let path_string = buf.to_str().unwrap().to_string();
+
+            // scan rules have already dropped excluded and unsupported files
+            if !is_dir {
+                match get_tracks_from_path(&path_string) {
+                    Ok(tracks) => {
+                        for mut t in tracks {
+                            t.root_id = root.id.clone();
+                            all_tracks.push(t);
+                        }
+                    }
+                    Err(e) => {
+                        println!("{}", e);
+                        tracks_failed.push(path_string)
+                    }
+                }
             }
         }
     }
@@ -0,0 +1,108 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{AudioError, Result};
+use crate::schema::millis_since_epoch;
+use crate::utils::write_atomic;
+
+/// Why a file didn't make it into the index
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureReason {
+    /// The directory entry itself couldn't be read
+    Walk,
+    Io,
+    Tags,
+    Decode,
+    /// The document couldn't be added to the index
+    Index,
+    Other,
+}
+
+impl From<&AudioError> for FailureReason {
+    fn from(e: &AudioError) -> Self {
+        match e {
+            AudioError::Io(_) => FailureReason::Io,
+            AudioError::Tag { .. } => FailureReason::Tags,
+            AudioError::Decode { .. } => FailureReason::Decode,
+            AudioError::Index(_) => FailureReason::Index,
+            _ => FailureReason::Other,
+        }
+    }
+}
+
+/// Counters and phase durations of one `index_since_last_opened` run
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct IndexMetrics {
+    pub started_date: i64,
+    /// Files that passed the scan rules
+    pub files_seen: u64,
+    /// Files and folders dropped by the scan rules
+    pub excluded: u64,
+    pub indexed: u64,
+    /// Already in the index
+    pub skipped: u64,
+    pub failed: BTreeMap<FailureReason, u64>,
+    /// Milliseconds per phase (`scan`, `commit`, `gc`, `albums`...), summed over the roots
+    pub phases_ms: BTreeMap<String, u64>,
+    pub total_ms: u64,
+    #[serde(skip)]
+    start: Option<Instant>,
+}
+
+impl IndexMetrics {
+    pub fn start() -> Self {
+        IndexMetrics {
+            started_date: millis_since_epoch(std::time::SystemTime::now()),
+            start: Some(Instant::now()),
+            ..Default::default()
+        }
+    }
+
+    pub fn failed(&mut self, reason: FailureReason) {
+        *self.failed.entry(reason).or_insert(0) += 1;
+    }
+
+    pub fn failed_total(&self) -> u64 {
+        self.failed.values().sum()
+    }
+
+    /// Runs `phase` and adds its duration to the phase of the same name
+    pub fn time<T>(&mut self, phase: &str, run: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = run();
+        self.add_phase(phase, start.elapsed());
+        result
+    }
+
+    pub fn add_phase(&mut self, phase: &str, duration: Duration) {
+        *self.phases_ms.entry(phase.to_string()).or_insert(0) += duration.as_millis() as u64;
+    }
+
+    /// Sets the total duration and logs the counters
+    pub fn finish(&mut self) {
+        if let Some(start) = self.start {
+            self.total_ms = start.elapsed().as_millis() as u64;
+        }
+        info!(
+            "index run finished files_seen={} indexed={} skipped={} excluded={} failed={} total_ms={} phases_ms={:?}",
+            self.files_seen,
+            self.indexed,
+            self.skipped,
+            self.excluded,
+            self.failed_total(),
+            self.total_ms,
+            self.phases_ms
+        );
+    }
+
+    /// The JSON summary, e.g. for the `index_metrics_file` setting
+    pub fn write(&self, path: &Path) -> Result<()> {
+        write_atomic(path, &serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,1250 @@
+use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::Instant;
+
+use chrono::NaiveDateTime;
+use log::{info, warn};
+use tantivy::query::{AllQuery, BooleanQuery, Occur, Query, TermQuery};
+use tantivy::schema::{Facet, Field, IndexRecordOption, Term};
+
+use crate::aggregations::{aggregations, stats, stats_result, term_counts, year_histogram};
+use crate::artists::ArtistRules;
+use crate::error::AudioError;
+use crate::library_roots::{configured_roots, LibraryRoot};
+use crate::playback::{PlaybackState, Player, PlayerEvent};
+use crate::playlists::{PlaylistStore, PLAYLISTS_FILE};
+use crate::schema::{
+    DocumentSearchRequest, FieldSchema, Filters, SearchWatcher, Track, BASE_AUDIO_DIRECTORY,
+};
+use crate::settings::SETTINGS;
+use crate::utils::norm;
+
+pub const DEFAULT_MPD_ADDRESS: &str = "127.0.0.1:6600";
+
+/// Version sent in the greeting, clients use it to decide which commands they can send
+const PROTOCOL_VERSION: &str = "0.23.0";
+
+/// A `search` word found in more terms than this doesn't narrow the search down in the index
+const MAX_PREFILTER_TERMS: usize = 1000;
+
+const COMMANDS: [&str; 25] = [
+    "add",
+    "clear",
+    "close",
+    "command_list_begin",
+    "command_list_end",
+    "command_list_ok_begin",
+    "commands",
+    "currentsong",
+    "find",
+    "list",
+    "listplaylistinfo",
+    "listplaylists",
+    "lsinfo",
+    "next",
+    "notcommands",
+    "pause",
+    "ping",
+    "play",
+    "playlistinfo",
+    "previous",
+    "search",
+    "seekcur",
+    "stats",
+    "status",
+    "stop",
+];
+
+/// `ACK` error codes of the protocol
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AckCode {
+    Arg = 2,
+    Unknown = 5,
+    NoExist = 50,
+    System = 52,
+}
+
+#[derive(Debug)]
+struct Ack {
+    code: AckCode,
+    message: String,
+}
+
+impl Ack {
+    fn new(code: AckCode, message: impl Into<String>) -> Self {
+        Ack {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<AudioError> for Ack {
+    fn from(e: AudioError) -> Self {
+        Ack::new(AckCode::System, e.to_string())
+    }
+}
+
+impl From<std::fmt::Error> for Ack {
+    fn from(e: std::fmt::Error) -> Self {
+        Ack::new(AckCode::System, e.to_string())
+    }
+}
+
+type CommandResult = Result<(), Ack>;
+
+/// Tags that can be searched, listed and filtered on
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Tag {
+    Any,
+    File,
+    Base,
+    Artist,
+    AlbumArtist,
+    Album,
+    Title,
+    Genre,
+    Date,
+}
+
+impl Tag {
+    fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "any" => Some(Tag::Any),
+            "file" => Some(Tag::File),
+            "base" => Some(Tag::Base),
+            "artist" => Some(Tag::Artist),
+            "albumartist" => Some(Tag::AlbumArtist),
+            "album" => Some(Tag::Album),
+            "title" => Some(Tag::Title),
+            "genre" => Some(Tag::Genre),
+            "date" => Some(Tag::Date),
+            _ => None,
+        }
+    }
+
+    /// As written in responses
+    fn label(&self) -> &'static str {
+        match self {
+            Tag::Any => "Any",
+            Tag::File | Tag::Base => "file",
+            Tag::Artist => "Artist",
+            Tag::AlbumArtist => "AlbumArtist",
+            Tag::Album => "Album",
+            Tag::Title => "Title",
+            Tag::Genre => "Genre",
+            Tag::Date => "Date",
+        }
+    }
+
+    /// Index field of `list`, `Date` goes through the year histogram
+    fn field(&self) -> Option<&'static str> {
+        match self {
+            Tag::Artist => Some("artist"),
+            Tag::AlbumArtist => Some("album_artist"),
+            Tag::Album => Some("album"),
+            Tag::Title => Some("track"),
+            Tag::Genre => Some("genre"),
+            _ => None,
+        }
+    }
+
+    /// Facet an exact match maps onto (see `Filters.facets`). `None` for artists like "A & B":
+    /// their facets are per name (see `FieldSchema::add_tags`), so they are checked on the results.
+    fn facet(&self, value: &str) -> Option<String> {
+        match self {
+            Tag::Artist if ArtistRules::current().split(value) == [value] => {
+                Some(format!("/artist/{}", value))
+            }
+            Tag::Artist => None,
+            Tag::Album => Some(format!("/album/{}", value)),
+            Tag::Genre => Some(format!("/genre/{}", value)),
+            Tag::Date => Some(format!("/year/{}", value)),
+            _ => None,
+        }
+    }
+}
+
+/// `find` compares whole values, `search` looks for a case-insensitive substring
+#[derive(Clone, Debug, PartialEq)]
+struct TagFilter {
+    tag: Tag,
+    value: String,
+    exact: bool,
+}
+
+impl TagFilter {
+    /// Analyzed field a `search` value is looked up in first (see `Server::prefilter`), `any`
+    /// also covers genres and album artists, which aren't analyzed
+    fn text_field(&self, field_schema: &FieldSchema) -> Option<(&'static str, Field)> {
+        if self.exact {
+            return None;
+        }
+        match self.tag {
+            Tag::Artist => Some(("artist_text", field_schema.artist_text)),
+            Tag::Album => Some(("album_text", field_schema.album_text)),
+            Tag::Title => Some(("track_text", field_schema.track_text)),
+            _ => None,
+        }
+    }
+}
+
+/// Serves the library (and `player` when given) to MPD clients until the listener fails,
+/// every connection gets its own thread
+pub fn serve(
+    search_watcher: &SearchWatcher,
+    player: Option<Player>,
+    listener: TcpListener,
+) -> crate::error::Result<()> {
+    let server = Server {
+        search_watcher,
+        player: player.map(Mutex::new),
+        started: Instant::now(),
+    };
+    info!("MPD server listening on {}", listener.local_addr()?);
+
+    thread::scope(|scope| {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("MPD connection failed: {}", e);
+                    continue;
+                }
+            };
+            let server = &server;
+            scope.spawn(move || {
+                if let Err(e) = server.handle_client(stream) {
+                    warn!("MPD client disconnected: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+struct Server<'a> {
+    search_watcher: &'a SearchWatcher,
+    player: Option<Mutex<Player>>,
+    started: Instant,
+}
+
+impl<'a> Server<'a> {
+    fn handle_client(&self, stream: TcpStream) -> std::io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        writeln!(writer, "OK MPD {}", PROTOCOL_VERSION)?;
+        writer.flush()?;
+
+        // `Some((list_ok, commands))` between `command_list_begin` and `command_list_end`
+        let mut command_list: Option<(bool, Vec<String>)> = None;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let command = line.trim_end_matches(['\r', '\n']);
+
+            match (command_list.as_mut(), command) {
+                (None, "close") => return Ok(()),
+                (None, "command_list_begin") => command_list = Some((false, vec![])),
+                (None, "command_list_ok_begin") => command_list = Some((true, vec![])),
+                (Some(_), "command_list_end") => {
+                    if let Some((list_ok, commands)) = command_list.take() {
+                        self.run_list(&mut writer, list_ok, &commands)?;
+                    }
+                }
+                (Some((_, commands)), _) => commands.push(command.to_string()),
+                (None, _) => self.run_list(&mut writer, false, &[command.to_string()])?,
+            }
+            writer.flush()?;
+        }
+    }
+
+    /// Stops at the first failing command, its position is part of the `ACK`
+    fn run_list(
+        &self,
+        writer: &mut impl Write,
+        list_ok: bool,
+        commands: &[String],
+    ) -> std::io::Result<()> {
+        for (index, command) in commands.iter().enumerate() {
+            let mut response = String::new();
+            match self.execute(command, &mut response) {
+                Ok(()) => {
+                    writer.write_all(response.as_bytes())?;
+                    if list_ok {
+                        writeln!(writer, "list_OK")?;
+                    }
+                }
+                Err(ack) => {
+                    let name = command.split_whitespace().next().unwrap_or("");
+                    return writeln!(
+                        writer,
+                        "ACK [{}@{}] {{{}}} {}",
+                        ack.code as u32, index, name, ack.message
+                    );
+                }
+            }
+        }
+        writeln!(writer, "OK")
+    }
+
+    fn execute(&self, command: &str, out: &mut String) -> CommandResult {
+        let arguments = parse_arguments(command).map_err(|e| Ack::new(AckCode::Arg, e))?;
+        let (name, arguments) = match arguments.split_first() {
+            Some((name, arguments)) => (name.as_str(), arguments),
+            None => return Err(Ack::new(AckCode::Unknown, "No command given")),
+        };
+
+        match name {
+            "ping" => Ok(()),
+            "commands" => {
+                for command in COMMANDS {
+                    writeln!(out, "command: {}", command)?;
+                }
+                Ok(())
+            }
+            "notcommands" => Ok(()),
+            "find" => self.search(arguments, true, out),
+            "search" => self.search(arguments, false, out),
+            "list" => self.list(arguments, out),
+            "lsinfo" => self.lsinfo(arguments.first().map(String::as_str).unwrap_or(""), out),
+            "listplaylists" => {
+                let playlists = PlaylistStore::open(&self.search_watcher.data_file(PLAYLISTS_FILE))?;
+                for name in playlists.playlist_names() {
+                    writeln!(out, "playlist: {}", name)?;
+                }
+                Ok(())
+            }
+            "listplaylistinfo" => {
+                let name = argument(arguments, 0)?;
+                let roots = self.roots();
+                for track in self.search_watcher.playlist_tracks(name)? {
+                    write_song(out, &roots, &track, None)?;
+                }
+                Ok(())
+            }
+            "stats" => self.stats(out),
+            "status" => self.status(out),
+            "currentsong" => self.current_song(out),
+            "playlistinfo" => self.playlist_info(arguments, out),
+            "add" => {
+                let uri = argument(arguments, 0)?;
+                let tracks = self.tracks_under(uri);
+                if tracks.is_empty() {
+                    return Err(Ack::new(AckCode::NoExist, "No such song"));
+                }
+                self.player()?.enqueue(tracks);
+                Ok(())
+            }
+            "clear" => {
+                self.player()?.set_queue(vec![], 0);
+                Ok(())
+            }
+            "play" => {
+                let player = self.player()?;
+                match arguments.first() {
+                    Some(position) => player.jump(parse_number(position)?),
+                    None => player.play(),
+                }
+                Ok(())
+            }
+            "pause" => {
+                let player = self.player()?;
+                match arguments.first().map(String::as_str) {
+                    Some("1") => player.pause(),
+                    Some("0") => player.play(),
+                    _ => player.toggle_pause(),
+                }
+                Ok(())
+            }
+            "stop" => {
+                self.player()?.stop();
+                Ok(())
+            }
+            "next" => {
+                self.player()?.next();
+                Ok(())
+            }
+            "previous" => {
+                self.player()?.previous();
+                Ok(())
+            }
+            "seekcur" => {
+                let seconds = argument(arguments, 0)?;
+                let seconds: f64 = seconds
+                    .parse()
+                    .map_err(|_| Ack::new(AckCode::Arg, format!("Not a time: {}", seconds)))?;
+                self.player()?.seek(seconds);
+                Ok(())
+            }
+            _ => Err(Ack::new(
+                AckCode::Unknown,
+                format!("unknown command \"{}\"", name),
+            )),
+        }
+    }
+
+    fn search(&self, arguments: &[String], exact: bool, out: &mut String) -> CommandResult {
+        let filters = parse_filters(arguments, exact).map_err(|e| Ack::new(AckCode::Arg, e))?;
+        if filters.is_empty() {
+            return Err(Ack::new(AckCode::Arg, "Missing filter"));
+        }
+        let roots = self.roots();
+        for track in self.filtered_tracks(&filters, &roots)? {
+            write_song(out, &roots, &track, None)?;
+        }
+        Ok(())
+    }
+
+    /// Exact matches on faceted tags narrow the search through `Filters.facets`, `search`
+    /// values of the analyzed tags through the query text (see `prefilter`). Every filter is
+    /// then checked on the results: facets of the same tag are OR'ed and the query text only
+    /// finds candidates for the substrings.
+    fn filtered_tracks(
+        &self,
+        filters: &[TagFilter],
+        roots: &[LibraryRoot],
+    ) -> Result<Vec<Track>, Ack> {
+        let mut text = vec![];
+        for filter in filters {
+            if let Some((name, field)) = filter.text_field(&self.search_watcher.field_schema) {
+                match self.prefilter(name, field, &filter.value)? {
+                    Some(clause) => text.push(clause),
+                    None => return Ok(vec![]),
+                }
+            }
+        }
+        let request = DocumentSearchRequest {
+            text: text.join(" "),
+            fields: vec![],
+            filters: Filters {
+                facets: facets(filters),
+                ..Default::default()
+            },
+            order: None,
+            faceted: None,
+            page_number: 0,
+            result_per_page: 1000,
+            reload: false,
+            explain: false,
+            search_after: None,
+        };
+        let mut tracks = self.search_watcher.all_results(&request)?;
+        tracks.retain(|track| filters.iter().all(|filter| matches(filter, track, roots)));
+        Ok(tracks)
+    }
+
+    /// Query clause for the tracks that can contain `value` in the analyzed `field`: every word
+    /// of the value (the tokenizer lowercases and splits on whatever isn't alphanumeric) has to
+    /// be part of one of its terms, so `blu` looks for "blue" and "bluebird". `None` when a word
+    /// is in no term, nothing can match then. Words in too many terms are left out.
+    fn prefilter(
+        &self,
+        name: &str,
+        field: Field,
+        value: &str,
+    ) -> crate::error::Result<Option<String>> {
+        let searcher = self.search_watcher.reader.searcher();
+        let value = value.to_lowercase();
+        let mut clauses = vec![];
+        for word in value
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+        {
+            let mut terms = BTreeSet::new();
+            for segment_reader in searcher.segment_readers() {
+                let inverted_index = segment_reader.inverted_index(field)?;
+                let mut stream = inverted_index.terms().stream()?;
+                while stream.advance() {
+                    match std::str::from_utf8(stream.key()) {
+                        Ok(term) if term.contains(word) => {
+                            terms.insert(term.to_string());
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
+            if terms.is_empty() {
+                return Ok(None);
+            }
+            if terms.len() <= MAX_PREFILTER_TERMS {
+                let terms: Vec<String> = terms
+                    .iter()
+                    .map(|term| format!("{}:\"{}\"", name, term))
+                    .collect();
+                clauses.push(format!("+({})", terms.join(" ")));
+            }
+        }
+        Ok(Some(clauses.join(" ")))
+    }
+
+    /// `list TAG [FILTERS]`, the values come from the aggregations when the filters can be
+    /// expressed as facets
+    fn list(&self, arguments: &[String], out: &mut String) -> CommandResult {
+        let tag_name = argument(arguments, 0)?;
+        let tag = Tag::parse(tag_name)
+            .ok_or_else(|| Ack::new(AckCode::Arg, format!("Unknown tag type: {}", tag_name)))?;
+
+        // the old form `list album ARTIST`
+        let filters = match &arguments[1..] {
+            [artist] if tag == Tag::Album => vec![TagFilter {
+                tag: Tag::Artist,
+                value: artist.clone(),
+                exact: true,
+            }],
+            rest => parse_filters(rest, true).map_err(|e| Ack::new(AckCode::Arg, e))?,
+        };
+
+        let faceted = filters
+            .iter()
+            .all(|filter| filter.exact && filter.tag.facet(&filter.value).is_some());
+        let values: Vec<String> = match (tag, tag.field()) {
+            (Tag::Date, _) if faceted => {
+                let query = self.facet_query(&filters);
+                let results = self
+                    .search_watcher
+                    .aggregate(&*query, aggregations(vec![("years", year_histogram())]))?;
+                term_counts(&results, "years")
+                    .into_iter()
+                    .filter(|(year, count)| *count > 0 && year != "0")
+                    .map(|(year, _)| year)
+                    .collect()
+            }
+            (_, Some(field)) if faceted => self
+                .search_watcher
+                .term_counts(&*self.facet_query(&filters), field)?
+                .into_iter()
+                .map(|(value, _)| value)
+                .collect(),
+            _ => {
+                let roots = self.roots();
+                let values: BTreeSet<String> = self
+                    .filtered_tracks(&filters, &roots)?
+                    .iter()
+                    .flat_map(|track| tag_values(tag, track, &roots))
+                    .collect();
+                values.into_iter().collect()
+            }
+        };
+
+        for value in values.iter().filter(|value| !value.is_empty()) {
+            writeln!(out, "{}: {}", tag.label(), value)?;
+        }
+        Ok(())
+    }
+
+    fn facet_query(&self, filters: &[TagFilter]) -> Box<dyn Query> {
+        if filters.is_empty() {
+            return Box::new(AllQuery);
+        }
+        let field = self.search_watcher.field_schema.facets;
+        let clauses = facets(filters)
+            .iter()
+            .map(|facet| {
+                let term = Term::from_facet(field, &Facet::from(facet.as_str()));
+                let query: Box<dyn Query> =
+                    Box::new(TermQuery::new(term, IndexRecordOption::Basic));
+                (Occur::Must, query)
+            })
+            .collect();
+        Box::new(BooleanQuery::new(clauses))
+    }
+
+    /// Directories are the folders of the tracks, under one directory per library root
+    fn lsinfo(&self, uri: &str, out: &mut String) -> CommandResult {
+        let roots = self.roots();
+        let tracks = self.search_watcher.all_tracks();
+        let prefix = match uri.trim_matches('/') {
+            "" => String::new(),
+            directory => format!("{}/", directory),
+        };
+
+        let mut directories = BTreeSet::new();
+        let mut songs = vec![];
+        for track in &tracks {
+            let track_uri = track_uri(&roots, track);
+            if track_uri == uri {
+                songs.push(track);
+                continue;
+            }
+            if let Some(rest) = track_uri.strip_prefix(&prefix) {
+                match rest.split_once('/') {
+                    Some((directory, _)) => {
+                        directories.insert(format!("{}{}", prefix, directory));
+                    }
+                    None => songs.push(track),
+                }
+            }
+        }
+
+        if !prefix.is_empty() && directories.is_empty() && songs.is_empty() {
+            return Err(Ack::new(AckCode::NoExist, "No such directory"));
+        }
+        for directory in &directories {
+            writeln!(out, "directory: {}", directory)?;
+        }
+        for track in songs {
+            write_song(out, &roots, track, None)?;
+        }
+        if prefix.is_empty() {
+            let playlists = PlaylistStore::open(&self.search_watcher.data_file(PLAYLISTS_FILE))?;
+            for name in playlists.playlist_names() {
+                writeln!(out, "playlist: {}", name)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn stats(&self, out: &mut String) -> CommandResult {
+        let results = self
+            .search_watcher
+            .aggregate(&AllQuery, aggregations(vec![("duration", stats("duration"))]))?;
+        let duration = stats_result(&results, "duration");
+
+        let artists = self.search_watcher.term_counts(&AllQuery, "artist")?.len();
+        let albums = self.search_watcher.term_counts(&AllQuery, "album")?.len();
+        writeln!(out, "artists: {}", artists)?;
+        writeln!(out, "albums: {}", albums)?;
+        writeln!(
+            out,
+            "songs: {}",
+            duration.as_ref().map(|stats| stats.count).unwrap_or(0)
+        )?;
+        writeln!(out, "uptime: {}", self.started.elapsed().as_secs())?;
+        writeln!(
+            out,
+            "db_playtime: {}",
+            duration.map(|stats| stats.sum as u64).unwrap_or(0)
+        )?;
+        writeln!(out, "playtime: 0")?;
+        Ok(())
+    }
+
+    /// Without a player the queue is always empty and stopped
+    fn status(&self, out: &mut String) -> CommandResult {
+        let status = self.player.as_ref().map(|_| self.player()).transpose()?;
+        let status = status.map(|player| player.status());
+
+        writeln!(out, "volume: -1")?;
+        writeln!(out, "repeat: 0")?;
+        writeln!(out, "random: 0")?;
+        writeln!(out, "single: 0")?;
+        writeln!(out, "consume: 0")?;
+        let queue_length = status.as_ref().map(|status| status.queue.len()).unwrap_or(0);
+        writeln!(out, "playlist: {}", queue_length)?;
+        writeln!(out, "playlistlength: {}", queue_length)?;
+
+        let state = status
+            .as_ref()
+            .map(|status| status.state)
+            .unwrap_or(PlaybackState::Stopped);
+        writeln!(
+            out,
+            "state: {}",
+            match state {
+                PlaybackState::Playing => "play",
+                PlaybackState::Paused => "pause",
+                PlaybackState::Stopped => "stop",
+            }
+        )?;
+
+        if let Some(status) = status {
+            if let (Some(current), true) = (status.current, state != PlaybackState::Stopped) {
+                writeln!(out, "song: {}", current)?;
+                writeln!(out, "songid: {}", current)?;
+                writeln!(out, "elapsed: {:.3}", status.position)?;
+                if let Some(duration) = status.duration {
+                    writeln!(out, "duration: {:.3}", duration)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn current_song(&self, out: &mut String) -> CommandResult {
+        if self.player.is_none() {
+            return Ok(());
+        }
+        let status = self.player()?.status();
+        if let Some((position, track)) = status
+            .current
+            .and_then(|index| status.queue.get(index).map(|track| (index, track)))
+        {
+            write_song(out, &self.roots(), track, Some(position))?;
+        }
+        Ok(())
+    }
+
+    fn playlist_info(&self, arguments: &[String], out: &mut String) -> CommandResult {
+        if self.player.is_none() {
+            return Ok(());
+        }
+        let only = arguments.first().map(|position| parse_number(position)).transpose()?;
+        let queue = self.player()?.status().queue;
+        let roots = self.roots();
+        for (position, track) in queue.iter().enumerate() {
+            if only.map_or(true, |only| only == position) {
+                write_song(out, &roots, track, Some(position))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The track at `uri`, or every track below it when it's a directory
+    fn tracks_under(&self, uri: &str) -> Vec<Track> {
+        let roots = self.roots();
+        let directory = format!("{}/", uri.trim_matches('/'));
+        let mut tracks: Vec<Track> = self
+            .search_watcher
+            .all_tracks()
+            .into_iter()
+            .filter(|track| {
+                let track_uri = track_uri(&roots, track);
+                track_uri == uri || directory == "/" || track_uri.starts_with(&directory)
+            })
+            .collect();
+        tracks.sort_by(|a, b| track_uri(&roots, a).cmp(&track_uri(&roots, b)));
+        tracks
+    }
+
+    /// Also records the plays reported since the last command, as nobody else reads the events
+    fn player(&self) -> Result<MutexGuard<Player>, Ack> {
+        let player = self
+            .player
+            .as_ref()
+            .ok_or_else(|| Ack::new(AckCode::System, "playback is disabled"))?
+            .lock()
+            .map_err(|_| Ack::new(AckCode::System, "the player is unavailable"))?;
+        for event in player.events().try_iter() {
+            match event {
+                PlayerEvent::TrackFinished(track_id) => {
+                    self.search_watcher.record_play(&track_id)?;
+                }
+                PlayerEvent::ResumePosition { track_id, position } => {
+                    self.search_watcher.set_resume_position(&track_id, position)?;
+                }
+                _ => {}
+            }
+        }
+        Ok(player)
+    }
+
+    fn roots(&self) -> Vec<LibraryRoot> {
+        match SETTINGS.read() {
+            Ok(settings) => configured_roots(&settings, BASE_AUDIO_DIRECTORY),
+            Err(_) => configured_roots(&Default::default(), BASE_AUDIO_DIRECTORY),
+        }
+    }
+}
+
+fn argument(arguments: &[String], index: usize) -> Result<&str, Ack> {
+    arguments
+        .get(index)
+        .map(String::as_str)
+        .ok_or_else(|| Ack::new(AckCode::Arg, "Missing argument"))
+}
+
+fn parse_number(value: &str) -> Result<usize, Ack> {
+    value
+        .parse()
+        .map_err(|_| Ack::new(AckCode::Arg, format!("Integer expected: {}", value)))
+}
+
+/// `library root id/path below the root`, with `/` separators whatever the platform
+pub fn track_uri(roots: &[LibraryRoot], track: &Track) -> String {
+    let abs_path = norm(&track.abs_path);
+    match roots.iter().find(|root| root.id == track.root_id) {
+        Some(root) => match abs_path.strip_prefix(&root.path) {
+            Some(relative) => format!("{}/{}", root.id, relative.trim_start_matches('/')),
+            None => abs_path,
+        },
+        None => abs_path.trim_start_matches('/').to_string(),
+    }
+}
+
+fn write_song(
+    out: &mut String,
+    roots: &[LibraryRoot],
+    track: &Track,
+    position: Option<usize>,
+) -> std::fmt::Result {
+    writeln!(out, "file: {}", track_uri(roots, track))?;
+    if let Some(modified) = NaiveDateTime::from_timestamp_opt(track.modified_date / 1000, 0) {
+        writeln!(out, "Last-Modified: {}", modified.format("%Y-%m-%dT%H:%M:%SZ"))?;
+    }
+    writeln!(out, "Time: {}", track.duration.round() as u64)?;
+    writeln!(out, "duration: {:.3}", track.duration)?;
+    writeln!(out, "Artist: {}", track.artist)?;
+    if !track.album_artist.is_empty() {
+        writeln!(out, "AlbumArtist: {}", track.album_artist)?;
+    }
+    writeln!(out, "Title: {}", track.track)?;
+    writeln!(out, "Album: {}", track.album)?;
+    if track.track_number > 0 {
+        writeln!(out, "Track: {}", track.track_number)?;
+    }
+    if track.disc_number > 0 {
+        writeln!(out, "Disc: {}", track.disc_number)?;
+    }
+    if track.year > 0 {
+        writeln!(out, "Date: {}", track.year)?;
+    }
+    for genre in &track.genres {
+        writeln!(out, "Genre: {}", genre)?;
+    }
+    if let Some(position) = position {
+        writeln!(out, "Pos: {}", position)?;
+        writeln!(out, "Id: {}", position)?;
+    }
+    Ok(())
+}
+
+/// The facets of the exact filters, artists like "A & B" narrow the results down to the tracks
+/// of their first name (checked on the results)
+fn facets(filters: &[TagFilter]) -> Vec<String> {
+    filters
+        .iter()
+        .filter(|filter| filter.exact)
+        .filter_map(|filter| match (filter.tag, filter.tag.facet(&filter.value)) {
+            (_, Some(facet)) => Some(facet),
+            (Tag::Artist, None) => ArtistRules::current()
+                .split(&filter.value)
+                .first()
+                .map(|name| format!("/artist/{}", name)),
+            _ => None,
+        })
+        .collect()
+}
+
+fn tag_values(tag: Tag, track: &Track, roots: &[LibraryRoot]) -> Vec<String> {
+    match tag {
+        Tag::Any => {
+            let mut values = vec![
+                track.artist.clone(),
+                track.album_artist.clone(),
+                track.album.clone(),
+                track.track.clone(),
+            ];
+            values.extend(track.genres.iter().cloned());
+            values
+        }
+        Tag::File | Tag::Base => vec![track_uri(roots, track)],
+        Tag::Artist => {
+            let mut values = vec![track.artist.clone()];
+            values.extend(track.artists.iter().cloned());
+            values
+        }
+        Tag::AlbumArtist => vec![track.album_artist.clone()],
+        Tag::Album => vec![track.album.clone()],
+        Tag::Title => vec![track.track.clone()],
+        Tag::Genre => track.genres.clone(),
+        Tag::Date if track.year > 0 => vec![track.year.to_string()],
+        Tag::Date => vec![],
+    }
+}
+
+fn matches(filter: &TagFilter, track: &Track, roots: &[LibraryRoot]) -> bool {
+    let values = tag_values(filter.tag, track, roots);
+    if filter.tag == Tag::Base {
+        let directory = format!("{}/", filter.value.trim_matches('/'));
+        return values.iter().any(|uri| uri.starts_with(&directory));
+    }
+    if filter.exact {
+        values.iter().any(|value| *value == filter.value)
+    } else {
+        let needle = filter.value.to_lowercase();
+        values
+            .iter()
+            .any(|value| value.to_lowercase().contains(&needle))
+    }
+}
+
+/// `TAG VALUE [TAG VALUE...]` or a filter expression like `((artist == "A") AND (album == "B"))`
+fn parse_filters(arguments: &[String], exact: bool) -> Result<Vec<TagFilter>, String> {
+    if let [expression] = arguments {
+        if expression.starts_with('(') {
+            return parse_expression(expression);
+        }
+    }
+    if arguments.len() % 2 != 0 {
+        return Err("Incorrect number of filter arguments".to_string());
+    }
+    arguments
+        .chunks(2)
+        .map(|pair| {
+            let tag = Tag::parse(&pair[0]).ok_or_else(|| format!("Unknown filter type: {}", pair[0]))?;
+            Ok(TagFilter {
+                tag,
+                value: pair[1].clone(),
+                exact,
+            })
+        })
+        .collect()
+}
+
+/// Only the `==`, `contains` and `base` forms, joined with `AND`
+fn parse_expression(expression: &str) -> Result<Vec<TagFilter>, String> {
+    let tokens = parse_arguments(&expression.replace('(', " ( ").replace(')', " ) "))?;
+
+    let mut filters = vec![];
+    let mut index = 0;
+    while index < tokens.len() {
+        match &tokens[index..] {
+            [tag, value, ..] if tag.eq_ignore_ascii_case("base") => {
+                filters.push(TagFilter {
+                    tag: Tag::Base,
+                    value: value.clone(),
+                    exact: true,
+                });
+                index += 2;
+            }
+            [tag, operator, value, ..] if operator == "==" || operator == "contains" => {
+                let tag = Tag::parse(tag).ok_or_else(|| format!("Unknown filter type: {}", tag))?;
+                filters.push(TagFilter {
+                    tag,
+                    value: value.clone(),
+                    exact: operator == "==",
+                });
+                index += 3;
+            }
+            [token, ..] if token == "(" || token == ")" || token == "AND" => index += 1,
+            [token, ..] => return Err(format!("Unsupported filter expression at {:?}", token)),
+            [] => break,
+        }
+    }
+    Ok(filters)
+}
+
+/// Splits a command line on whitespace, double quoted arguments can contain spaces and `\"`
+fn parse_arguments(line: &str) -> Result<Vec<String>, String> {
+    let mut arguments = vec![];
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let first = match chars.next() {
+            Some(first) => first,
+            None => return Ok(arguments),
+        };
+
+        let mut argument = String::new();
+        if first == '"' || first == '\'' {
+            loop {
+                match chars.next() {
+                    Some('\\') => match chars.next() {
+                        Some(escaped) => argument.push(escaped),
+                        None => return Err("Unterminated quoted argument".to_string()),
+                    },
+                    Some(c) if c == first => break,
+                    Some(c) => argument.push(c),
+                    None => return Err("Unterminated quoted argument".to_string()),
+                }
+            }
+        } else {
+            argument.push(first);
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                argument.push(c);
+            }
+        }
+        arguments.push(argument);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::playback::{NullSink, ReplayGainMode};
+    use crate::schema::TrackJson;
+    use crate::test_support::{index_tracks, library, search_watcher, track};
+
+    /// 4 artists with 2 albums of 2 tracks each
+    const LIBRARY_SIZE: usize = 16;
+
+    /// Serves a library on a free port, for as long as the tests run
+    fn start(player: Option<Player>) -> (TempDir, SocketAddr) {
+        start_with(library(LIBRARY_SIZE), player)
+    }
+
+    fn start_with(tracks: Vec<TrackJson>, player: Option<Player>) -> (TempDir, SocketAddr) {
+        let (directory, search_watcher) = search_watcher();
+        // under the root used without `library_roots`, so the uris are `default/ARTIST/ALBUM/FILE`
+        let tracks: Vec<_> = tracks
+            .into_iter()
+            .map(|mut track| {
+                let relative = track.abs_path.trim_start_matches("/music/").to_string();
+                track.abs_path = format!("{}/{}", norm(BASE_AUDIO_DIRECTORY), relative);
+                track
+            })
+            .collect();
+        index_tracks(&search_watcher, &tracks);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let search_watcher = Arc::new(search_watcher);
+        thread::spawn(move || serve(&search_watcher, player, listener));
+        (directory, address)
+    }
+
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Client {
+        fn connect(address: SocketAddr) -> Self {
+            let writer = TcpStream::connect(address).unwrap();
+            writer.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+            let mut client = Client {
+                reader: BufReader::new(writer.try_clone().unwrap()),
+                writer,
+            };
+            assert_eq!(client.read_line(), format!("OK MPD {}", PROTOCOL_VERSION));
+            client
+        }
+
+        fn read_line(&mut self) -> String {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            line.trim_end().to_string()
+        }
+
+        /// Every line of the response, up to the `OK` or `ACK` line included
+        fn send(&mut self, lines: &[&str]) -> Vec<String> {
+            for line in lines {
+                writeln!(self.writer, "{}", line).unwrap();
+            }
+            let mut response = vec![];
+            loop {
+                let line = self.read_line();
+                let last = line == "OK" || line.starts_with("ACK ");
+                response.push(line);
+                if last {
+                    return response;
+                }
+            }
+        }
+
+        fn command(&mut self, command: &str) -> Vec<String> {
+            let response = self.send(&[command]);
+            assert_eq!(response.last().map(String::as_str), Some("OK"), "{}", command);
+            response
+        }
+
+        /// The values of `key` in the response
+        fn values(&mut self, command: &str, key: &str) -> Vec<String> {
+            let prefix = format!("{}: ", key);
+            self.command(command)
+                .iter()
+                .filter_map(|line| line.strip_prefix(&prefix).map(str::to_string))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn find_and_search() {
+        let (_directory, address) = start(None);
+        let mut client = Client::connect(address);
+
+        let files = client.values(r#"find artist "Blue River""#, "file");
+        assert_eq!(files.len(), 4);
+        assert!(files.iter().all(|file| file.starts_with("default/Blue River/")));
+        assert!(client.values("find artist blue", "file").is_empty());
+
+        // substrings, whatever the case
+        assert_eq!(client.values("search artist blue", "file").len(), 4);
+        assert_eq!(client.values("search artist blu", "file").len(), 4);
+        assert_eq!(client.values("search artist LUE RIV", "file").len(), 4);
+        assert_eq!(client.values(r#"search artist "e r""#, "file").len(), 4);
+        assert!(client.values("search artist blues", "file").is_empty());
+        assert!(client.values("search artist river blue", "file").is_empty());
+        assert_eq!(client.values("search any NIGHT", "file").len(), 8);
+        assert_eq!(
+            client.values(r#"search title "song 3""#, "Title"),
+            vec!["Song 3".to_string()]
+        );
+        // checked on the results, as substrings
+        assert_eq!(client.values("search genre az", "file").len(), 8);
+        assert_eq!(
+            client.values("search artist river album summer", "file").len(),
+            2
+        );
+
+        let expression = r#"find "((artist == \"Blue River\") AND (album == \"Night Blue River\"))""#;
+        assert_eq!(client.values(expression, "file").len(), 2);
+        assert_eq!(
+            client.send(&["find artist"]).last().unwrap(),
+            "ACK [2@0] {find} Incorrect number of filter arguments"
+        );
+    }
+
+    #[test]
+    fn list() {
+        let (_directory, address) = start(None);
+        let mut client = Client::connect(address);
+
+        let mut artists = client.values("list artist", "Artist");
+        artists.sort();
+        assert_eq!(artists, ["Blue River", "Electric Ghost", "Golden Rain", "Silver Echo"]);
+
+        let mut albums = client.values(r#"list album "Blue River""#, "Album");
+        albums.sort();
+        assert_eq!(albums, ["Night Blue River", "Summer Blue River"]);
+
+        let mut years = client.values("list date", "Date");
+        years.sort();
+        assert_eq!(years, ["1990", "1991", "1992", "1993", "1994"]);
+
+        // not faceted, from the matching tracks
+        let albums = client.values(r#"list album title "Song 1""#, "Album");
+        assert_eq!(albums, ["Night Blue River"]);
+    }
+
+    #[test]
+    fn multiple_artists() {
+        let mut tracks = library(LIBRARY_SIZE);
+        let mut duet = track(LIBRARY_SIZE, "Golden Rain & Blue River", "Duets", "Rock", 2001);
+        duet.artists = vec!["Golden Rain".to_string(), "Blue River".to_string()];
+        tracks.push(duet);
+        let (_directory, address) = start_with(tracks, None);
+        let mut client = Client::connect(address);
+
+        let files = client.values(r#"find artist "Golden Rain & Blue River""#, "file");
+        assert_eq!(files, ["default/Golden Rain & Blue River/Duets/16 Song 16.mp3"]);
+        assert!(client
+            .values(r#"find artist "Blue River & Golden Rain""#, "file")
+            .is_empty());
+        // the duet is a track of each of its artists
+        assert_eq!(client.values(r#"find artist "Blue River""#, "file").len(), 5);
+        assert_eq!(client.values(r#"find artist "Golden Rain""#, "file").len(), 5);
+
+        let albums = client.values(r#"list album "Golden Rain & Blue River""#, "Album");
+        assert_eq!(albums, ["Duets"]);
+        let mut albums = client.values(r#"list album artist "Blue River""#, "Album");
+        albums.sort();
+        assert_eq!(albums, ["Duets", "Night Blue River", "Summer Blue River"]);
+
+        let artists = client.values("list artist", "Artist");
+        assert!(artists.contains(&"Golden Rain & Blue River".to_string()));
+    }
+
+    #[test]
+    fn lsinfo() {
+        let (_directory, address) = start(None);
+        let mut client = Client::connect(address);
+
+        assert_eq!(client.values("lsinfo", "directory"), ["default"]);
+        let artists = client.values("lsinfo default", "directory");
+        assert_eq!(
+            artists,
+            [
+                "default/Blue River",
+                "default/Electric Ghost",
+                "default/Golden Rain",
+                "default/Silver Echo"
+            ]
+        );
+        let files = client.values(r#"lsinfo "default/Blue River/Night Blue River""#, "file");
+        assert_eq!(files.len(), 2);
+        assert!(client
+            .values(r#"lsinfo "default/Blue River""#, "file")
+            .is_empty());
+
+        assert_eq!(
+            client.send(&["lsinfo nowhere"]).last().unwrap(),
+            "ACK [50@0] {lsinfo} No such directory"
+        );
+    }
+
+    #[test]
+    fn stats() {
+        let (_directory, address) = start(None);
+        let mut client = Client::connect(address);
+
+        let response = client.command("stats");
+        for line in ["artists: 4", "albums: 8", "songs: 16", "db_playtime: 3000"] {
+            assert!(response.iter().any(|l| l == line), "{} in {:?}", line, response);
+        }
+    }
+
+    #[test]
+    fn playlistinfo() {
+        let player = Player::headless(NullSink::new(), ReplayGainMode::Off).unwrap();
+        let (_directory, address) = start(Some(player));
+        let mut client = Client::connect(address);
+
+        assert!(client.values("playlistinfo", "file").is_empty());
+        client.command(r#"add "default/Blue River""#);
+
+        // the queue is updated by the playback thread
+        let mut files = vec![];
+        for _ in 0..100 {
+            files = client.values("playlistinfo", "file");
+            if !files.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(files.len(), 4);
+        assert!(files.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(client.values("playlistinfo 2", "Pos"), ["2"]);
+        assert_eq!(
+            client.send(&["add nowhere"]).last().unwrap(),
+            "ACK [50@0] {add} No such song"
+        );
+    }
+
+    #[test]
+    fn without_playback() {
+        let (_directory, address) = start(None);
+        let mut client = Client::connect(address);
+
+        assert_eq!(client.command("playlistinfo"), ["OK"]);
+        assert!(client.command("status").contains(&"state: stop".to_string()));
+        assert_eq!(
+            client.send(&[r#"add "default/Blue River""#]).last().unwrap(),
+            "ACK [52@0] {add} playback is disabled"
+        );
+    }
+
+    #[test]
+    fn command_lists() {
+        let (_directory, address) = start(None);
+        let mut client = Client::connect(address);
+
+        let response = client.send(&[
+            "command_list_ok_begin",
+            "ping",
+            r#"find artist "Blue River" album "Night Blue River""#,
+            "command_list_end",
+        ]);
+        assert_eq!(response[0], "list_OK");
+        assert_eq!(response.iter().filter(|l| l.starts_with("file: ")).count(), 2);
+        assert_eq!(response[response.len() - 2..], ["list_OK", "OK"]);
+
+        // stops at the failing command, without list_OK
+        let response = client.send(&[
+            "command_list_begin",
+            "ping",
+            "nothing",
+            "ping",
+            "command_list_end",
+        ]);
+        assert_eq!(response, [r#"ACK [5@1] {nothing} unknown command "nothing""#]);
+
+        // the connection is still usable
+        assert_eq!(client.command("ping"), ["OK"]);
+    }
+}
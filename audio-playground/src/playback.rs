@@ -0,0 +1,975 @@
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::decode::{AudioFormat, TrackDecoder};
+use crate::error::{AudioError, Result};
+use crate::schema::Track;
+
+/// `Previous` restarts the current track when it has played for longer than this
+const RESTART_THRESHOLD_SECS: f64 = 3.0;
+/// Audio buffered ahead of the output device, also the latency of pause/seek/next
+const OUTPUT_BUFFER_SECS: f64 = 0.25;
+
+/// Where decoded audio goes: the sound card, or nowhere for headless runs
+pub trait AudioSink {
+    /// Interleaved samples in `format`, may block until the device has room for them
+    fn write(&mut self, format: AudioFormat, samples: &[f32]) -> Result<()>;
+
+    fn pause(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Drops what's buffered but not played yet, after a seek or a skip
+    fn flush(&mut self) {}
+}
+
+/// Discards the audio as fast as it's decoded, counting the frames. Clones share the count.
+#[derive(Clone, Default, Debug)]
+pub struct NullSink {
+    frames: Arc<AtomicU64>,
+}
+
+impl NullSink {
+    pub fn new() -> Self {
+        NullSink::default()
+    }
+
+    pub fn frames_written(&self) -> u64 {
+        self.frames.load(Ordering::Relaxed)
+    }
+}
+
+impl AudioSink for NullSink {
+    fn write(&mut self, format: AudioFormat, samples: &[f32]) -> Result<()> {
+        let frames = samples.len() / format.channels.max(1);
+        self.frames.fetch_add(frames as u64, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+struct SharedBuffer {
+    samples: Mutex<VecDeque<f32>>,
+    /// Signaled whenever the device took samples out
+    room: Condvar,
+    capacity: usize,
+}
+
+/// The default output device, through cpal. The device keeps its own format, tracks are
+/// converted to it so consecutive tracks never need to reopen the stream (gapless).
+pub struct CpalSink {
+    stream: cpal::Stream,
+    buffer: Arc<SharedBuffer>,
+    output: AudioFormat,
+    converter: FormatConverter,
+}
+
+impl CpalSink {
+    pub fn open() -> Result<Self> {
+        let playback_error = |e: &dyn std::fmt::Display| AudioError::Playback(e.to_string());
+
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or_else(|| AudioError::Playback("no output device".to_string()))?;
+        let supported = device
+            .default_output_config()
+            .map_err(|e| playback_error(&e))?;
+        let sample_format = supported.sample_format();
+        let config: cpal::StreamConfig = supported.into();
+        let output = AudioFormat {
+            sample_rate: config.sample_rate.0,
+            channels: config.channels as usize,
+        };
+
+        let capacity =
+            (output.sample_rate as f64 * OUTPUT_BUFFER_SECS) as usize * output.channels.max(1);
+        let buffer = Arc::new(SharedBuffer {
+            samples: Mutex::new(VecDeque::with_capacity(capacity)),
+            room: Condvar::new(),
+            capacity,
+        });
+
+        let stream = match sample_format {
+            cpal::SampleFormat::F32 => build_stream::<f32>(&device, &config, buffer.clone()),
+            cpal::SampleFormat::I16 => build_stream::<i16>(&device, &config, buffer.clone()),
+            cpal::SampleFormat::U16 => build_stream::<u16>(&device, &config, buffer.clone()),
+        }
+        .map_err(|e| playback_error(&e))?;
+        stream.play().map_err(|e| playback_error(&e))?;
+
+        Ok(CpalSink {
+            stream,
+            buffer,
+            output,
+            converter: FormatConverter::new(output),
+        })
+    }
+}
+
+fn build_stream<T: cpal::Sample>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    buffer: Arc<SharedBuffer>,
+) -> std::result::Result<cpal::Stream, cpal::BuildStreamError> {
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            let mut samples = match buffer.samples.lock() {
+                Ok(samples) => samples,
+                Err(_) => return,
+            };
+            for out in data.iter_mut() {
+                // silence when decoding can't keep up
+                *out = T::from(&samples.pop_front().unwrap_or(0.0));
+            }
+            buffer.room.notify_all();
+        },
+        |e| error!("Audio output error: {}", e),
+    )
+}
+
+impl AudioSink for CpalSink {
+    fn write(&mut self, format: AudioFormat, samples: &[f32]) -> Result<()> {
+        let converted = self.converter.convert(format, samples);
+
+        let mut offset = 0;
+        while offset < converted.len() {
+            let mut buffered = self.buffer.samples.lock()?;
+            while buffered.len() >= self.buffer.capacity {
+                buffered = self.buffer.room.wait(buffered)?;
+            }
+            let room = self.buffer.capacity - buffered.len();
+            let end = (offset + room).min(converted.len());
+            buffered.extend(&converted[offset..end]);
+            offset = end;
+        }
+        Ok(())
+    }
+
+    fn pause(&mut self) -> Result<()> {
+        self.stream
+            .pause()
+            .map_err(|e| AudioError::Playback(e.to_string()))
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        self.stream
+            .play()
+            .map_err(|e| AudioError::Playback(e.to_string()))
+    }
+
+    fn flush(&mut self) {
+        if let Ok(mut buffered) = self.buffer.samples.lock() {
+            buffered.clear();
+        }
+        self.converter = FormatConverter::new(self.output);
+    }
+}
+
+/// Maps channels and (linearly) resamples to the output format, keeping its state between
+/// calls so packet and track boundaries don't click
+struct FormatConverter {
+    output: AudioFormat,
+    /// Input frames already mapped to the output channels, not resampled yet
+    pending: Vec<f32>,
+    /// Position of the next output frame in `pending`, in input frames
+    position: f64,
+}
+
+impl FormatConverter {
+    fn new(output: AudioFormat) -> Self {
+        FormatConverter {
+            output,
+            pending: vec![],
+            position: 0.0,
+        }
+    }
+
+    fn convert(&mut self, input: AudioFormat, samples: &[f32]) -> Vec<f32> {
+        let (in_channels, out_channels) = (input.channels.max(1), self.output.channels.max(1));
+        for frame in samples.chunks(in_channels) {
+            for channel in 0..out_channels {
+                let value = if in_channels == 1 {
+                    frame[0]
+                } else if out_channels == 1 {
+                    frame.iter().sum::<f32>() / frame.len() as f32
+                } else {
+                    frame[channel.min(frame.len() - 1)]
+                };
+                self.pending.push(value);
+            }
+        }
+
+        if input.sample_rate == self.output.sample_rate || input.sample_rate == 0 {
+            return std::mem::take(&mut self.pending);
+        }
+
+        let step = input.sample_rate as f64 / self.output.sample_rate as f64;
+        let frames = self.pending.len() / out_channels;
+        let mut output = Vec::with_capacity((frames as f64 / step) as usize * out_channels + 1);
+        while self.position + 1.0 < frames as f64 {
+            let index = self.position as usize;
+            let fraction = (self.position - index as f64) as f32;
+            for channel in 0..out_channels {
+                let a = self.pending[index * out_channels + channel];
+                let b = self.pending[(index + 1) * out_channels + channel];
+                output.push(a + (b - a) * fraction);
+            }
+            self.position += step;
+        }
+
+        // keep the frame the next output frame is interpolated from
+        let consumed = (self.position as usize).min(frames);
+        self.pending.drain(..consumed * out_channels);
+        self.position -= consumed as f64;
+        output
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum PlaybackState {
+    Stopped,
+    Playing,
+    Paused,
+}
+
+/// Which ReplayGain value (see `loudness.rs`) is applied, tracks that weren't analyzed play as is
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ReplayGainMode {
+    Off,
+    Track,
+    Album,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct PlayerStatus {
+    pub state: PlaybackState,
+    pub queue: Vec<Track>,
+    /// Index in `queue` of the track playing (or paused, or last played when stopped)
+    pub current: Option<usize>,
+    /// Seconds into the current track, as decoded (the device is up to `OUTPUT_BUFFER_SECS` behind)
+    pub position: f64,
+    pub duration: Option<f64>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PlayerEvent {
+    TrackStarted(String),
+    /// Played to the end, e.g. to record the play
+    TrackFinished(String),
+    TrackFailed { track_id: String, message: String },
+    QueueFinished,
+    /// A long-form track (see `ContentKind`) was paused, stopped or skipped at `position` seconds
+    ResumePosition { track_id: String, position: f64 },
+}
+
+enum PlayerCommand {
+    Play,
+    Pause,
+    TogglePause,
+    Stop,
+    Next,
+    Previous,
+    Seek(f64),
+    Jump(usize),
+    SetQueue(Vec<Track>, usize),
+    Enqueue(Vec<Track>),
+    Quit,
+}
+
+/// Handle of the playback thread, every method returns right away. Dropping it stops playback.
+pub struct Player {
+    commands: Sender<PlayerCommand>,
+    events: Receiver<PlayerEvent>,
+    status: Arc<Mutex<PlayerStatus>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Player {
+    /// `make_sink` runs on the playback thread, as output streams usually can't move between threads
+    pub fn start(
+        make_sink: impl FnOnce() -> Result<Box<dyn AudioSink>> + Send + 'static,
+        replaygain: ReplayGainMode,
+    ) -> Result<Self> {
+        let (command_sender, command_receiver) = channel();
+        let (event_sender, event_receiver) = channel();
+        let (ready_sender, ready_receiver) = sync_channel(1);
+        let status = Arc::new(Mutex::new(PlayerStatus {
+            state: PlaybackState::Stopped,
+            queue: vec![],
+            current: None,
+            position: 0.0,
+            duration: None,
+        }));
+
+        let thread_status = status.clone();
+        let thread = thread::Builder::new()
+            .name("playback".to_string())
+            .spawn(move || {
+                let sink = match make_sink() {
+                    Ok(sink) => {
+                        let _ = ready_sender.send(Ok(()));
+                        sink
+                    }
+                    Err(e) => {
+                        let _ = ready_sender.send(Err(e));
+                        return;
+                    }
+                };
+                Engine::new(sink, command_receiver, event_sender, thread_status, replaygain).run();
+            })?;
+
+        ready_receiver
+            .recv()
+            .map_err(|_| AudioError::Playback("the playback thread stopped".to_string()))??;
+
+        Ok(Player {
+            commands: command_sender,
+            events: event_receiver,
+            status,
+            thread: Some(thread),
+        })
+    }
+
+    /// Plays through the default output device
+    pub fn with_default_output(replaygain: ReplayGainMode) -> Result<Self> {
+        Player::start(
+            || Ok(Box::new(CpalSink::open()?) as Box<dyn AudioSink>),
+            replaygain,
+        )
+    }
+
+    /// Decodes everything without any output, as fast as possible
+    pub fn headless(sink: NullSink, replaygain: ReplayGainMode) -> Result<Self> {
+        Player::start(move || Ok(Box::new(sink) as Box<dyn AudioSink>), replaygain)
+    }
+
+    /// Replaces the queue and plays it from `start`
+    pub fn set_queue(&self, tracks: Vec<Track>, start: usize) {
+        self.send(PlayerCommand::SetQueue(tracks, start));
+    }
+
+    /// Adds to the end of the queue without interrupting playback
+    pub fn enqueue(&self, tracks: Vec<Track>) {
+        self.send(PlayerCommand::Enqueue(tracks));
+    }
+
+    pub fn play(&self) {
+        self.send(PlayerCommand::Play);
+    }
+
+    pub fn pause(&self) {
+        self.send(PlayerCommand::Pause);
+    }
+
+    pub fn toggle_pause(&self) {
+        self.send(PlayerCommand::TogglePause);
+    }
+
+    pub fn stop(&self) {
+        self.send(PlayerCommand::Stop);
+    }
+
+    pub fn next(&self) {
+        self.send(PlayerCommand::Next);
+    }
+
+    /// The previous track, or the start of this one after `RESTART_THRESHOLD_SECS`
+    pub fn previous(&self) {
+        self.send(PlayerCommand::Previous);
+    }
+
+    pub fn seek(&self, seconds: f64) {
+        self.send(PlayerCommand::Seek(seconds));
+    }
+
+    /// Plays the track at `index` in the queue
+    pub fn jump(&self, index: usize) {
+        self.send(PlayerCommand::Jump(index));
+    }
+
+    pub fn status(&self) -> PlayerStatus {
+        match self.status.lock() {
+            Ok(status) => status.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    pub fn events(&self) -> &Receiver<PlayerEvent> {
+        &self.events
+    }
+
+    /// Stops the playback thread and returns the events it sent until then, e.g. the
+    /// `ResumePosition` of the track that was playing
+    pub fn shutdown(mut self) -> Vec<PlayerEvent> {
+        let _ = self.commands.send(PlayerCommand::Quit);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        self.events.try_iter().collect()
+    }
+
+    fn send(&self, command: PlayerCommand) {
+        if self.commands.send(command).is_err() {
+            warn!("The playback thread is gone, command ignored");
+        }
+    }
+}
+
+impl Drop for Player {
+    fn drop(&mut self) {
+        let _ = self.commands.send(PlayerCommand::Quit);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Runs on the playback thread: decodes the current track into the sink between commands
+struct Engine {
+    sink: Box<dyn AudioSink>,
+    commands: Receiver<PlayerCommand>,
+    events: Sender<PlayerEvent>,
+    status: Arc<Mutex<PlayerStatus>>,
+    replaygain: ReplayGainMode,
+    state: PlaybackState,
+    queue: Vec<Track>,
+    /// The queue is only copied to the status when it changed
+    queue_changed: bool,
+    current: Option<usize>,
+    decoder: Option<TrackDecoder>,
+    /// Linear gain of the current track
+    gain: f32,
+    frames_played: u64,
+    sample_rate: u32,
+    chunk: Vec<f32>,
+}
+
+impl Engine {
+    fn new(
+        sink: Box<dyn AudioSink>,
+        commands: Receiver<PlayerCommand>,
+        events: Sender<PlayerEvent>,
+        status: Arc<Mutex<PlayerStatus>>,
+        replaygain: ReplayGainMode,
+    ) -> Self {
+        Engine {
+            sink,
+            commands,
+            events,
+            status,
+            replaygain,
+            state: PlaybackState::Stopped,
+            queue: vec![],
+            queue_changed: false,
+            current: None,
+            decoder: None,
+            gain: 1.0,
+            frames_played: 0,
+            sample_rate: 0,
+            chunk: vec![],
+        }
+    }
+
+    fn run(mut self) {
+        loop {
+            // only block on the commands when there's nothing to decode
+            let command = if self.state == PlaybackState::Playing {
+                match self.commands.try_recv() {
+                    Ok(command) => Some(command),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            } else {
+                match self.commands.recv() {
+                    Ok(command) => Some(command),
+                    Err(_) => return,
+                }
+            };
+
+            match command {
+                Some(PlayerCommand::Quit) => {
+                    self.leave_current();
+                    return;
+                }
+                Some(command) => self.handle(command),
+                None => self.play_chunk(),
+            }
+            self.publish();
+        }
+    }
+
+    fn handle(&mut self, command: PlayerCommand) {
+        match command {
+            PlayerCommand::Play => match (self.state, self.current) {
+                (PlaybackState::Paused, _) => self.resume(),
+                (PlaybackState::Stopped, Some(index)) => self.start(index),
+                (PlaybackState::Stopped, None) => self.start(0),
+                (PlaybackState::Playing, _) => {}
+            },
+            PlayerCommand::Pause => self.pause(),
+            PlayerCommand::TogglePause => match self.state {
+                PlaybackState::Playing => self.pause(),
+                _ => self.handle(PlayerCommand::Play),
+            },
+            PlayerCommand::Stop => self.stop(),
+            PlayerCommand::Next => {
+                let next = self.current.map(|index| index + 1).unwrap_or(0);
+                self.sink.flush();
+                if next < self.queue.len() {
+                    self.start(next);
+                } else {
+                    self.stop();
+                }
+            }
+            PlayerCommand::Previous => {
+                let position = self.position();
+                match self.current {
+                    Some(_) if position > RESTART_THRESHOLD_SECS => {
+                        self.handle(PlayerCommand::Seek(0.0))
+                    }
+                    Some(index) => {
+                        self.sink.flush();
+                        self.start(index.saturating_sub(1));
+                    }
+                    None => {}
+                }
+            }
+            PlayerCommand::Seek(seconds) => {
+                if let Some(decoder) = self.decoder.as_mut() {
+                    match decoder.seek(seconds) {
+                        Ok(()) => {
+                            self.sink.flush();
+                            self.frames_played = (seconds.max(0.0) * self.sample_rate as f64) as u64;
+                        }
+                        Err(e) => warn!("Seek failed: {}", e),
+                    }
+                }
+            }
+            PlayerCommand::Jump(index) => {
+                if index < self.queue.len() {
+                    self.sink.flush();
+                    self.start(index);
+                }
+            }
+            PlayerCommand::SetQueue(tracks, start) => {
+                self.sink.flush();
+                self.leave_current();
+                self.decoder = None;
+                self.queue = tracks;
+                self.queue_changed = true;
+                self.current = None;
+                if start < self.queue.len() {
+                    self.start(start);
+                } else {
+                    self.state = PlaybackState::Stopped;
+                }
+            }
+            PlayerCommand::Enqueue(tracks) => {
+                self.queue.extend(tracks);
+                self.queue_changed = true;
+            }
+            PlayerCommand::Quit => {}
+        }
+    }
+
+    /// Opens the track at `index`, skipping the ones that can't be decoded. Long-form tracks
+    /// continue from their resume position.
+    fn start(&mut self, index: usize) {
+        self.leave_current();
+        let mut index = index;
+        while let Some(track) = self.queue.get(index) {
+            match TrackDecoder::open_range(Path::new(&track.abs_path), track.start, track.end) {
+                Ok(mut decoder) => {
+                    self.sample_rate = decoder.format().sample_rate;
+                    self.frames_played = 0;
+                    if track.content_kind.is_long_form() && track.resume_position > 0.0 {
+                        match decoder.seek(track.resume_position) {
+                            Ok(()) => {
+                                self.frames_played =
+                                    (track.resume_position * self.sample_rate as f64) as u64
+                            }
+                            Err(e) => warn!("Can't resume {}: {}", track.abs_path, e),
+                        }
+                    }
+                    self.decoder = Some(decoder);
+                    self.current = Some(index);
+                    self.gain = self.gain_for(track);
+                    if self.state == PlaybackState::Paused {
+                        if let Err(e) = self.sink.resume() {
+                            error!("{}", e);
+                        }
+                    }
+                    self.state = PlaybackState::Playing;
+                    self.emit(PlayerEvent::TrackStarted(track.id.clone()));
+                    return;
+                }
+                Err(e) => {
+                    self.emit(PlayerEvent::TrackFailed {
+                        track_id: track.id.clone(),
+                        message: e.to_string(),
+                    });
+                    index += 1;
+                }
+            }
+        }
+
+        self.decoder = None;
+        self.state = PlaybackState::Stopped;
+        self.emit(PlayerEvent::QueueFinished);
+    }
+
+    fn play_chunk(&mut self) {
+        let decoder = match self.decoder.as_mut() {
+            Some(decoder) => decoder,
+            None => {
+                self.state = PlaybackState::Stopped;
+                return;
+            }
+        };
+
+        match decoder.next_samples() {
+            Ok(Some((format, samples))) => {
+                let gain = self.gain;
+                self.chunk.clear();
+                self.chunk
+                    .extend(samples.iter().map(|sample| (sample * gain).clamp(-1.0, 1.0)));
+                self.sample_rate = format.sample_rate;
+                self.frames_played += (samples.len() / format.channels.max(1)) as u64;
+
+                if let Err(e) = self.sink.write(format, &self.chunk) {
+                    error!("{}", e);
+                    self.stop();
+                }
+            }
+            Ok(None) => {
+                if let Some(index) = self.current {
+                    // played to the end, the next time starts from the beginning
+                    self.queue[index].resume_position = 0.0;
+                    self.queue_changed = true;
+                    self.emit(PlayerEvent::TrackFinished(self.queue[index].id.clone()));
+                }
+                self.decoder = None;
+                // no flush: the next track goes right after what's still buffered (gapless)
+                let next = self.current.map(|index| index + 1).unwrap_or(0);
+                self.start(next);
+            }
+            Err(e) => {
+                if let Some(track) = self.current_track() {
+                    self.emit(PlayerEvent::TrackFailed {
+                        track_id: track.id.clone(),
+                        message: e.to_string(),
+                    });
+                }
+                self.decoder = None;
+                let next = self.current.map(|index| index + 1).unwrap_or(0);
+                self.start(next);
+            }
+        }
+    }
+
+    fn pause(&mut self) {
+        if self.state == PlaybackState::Playing {
+            if let Err(e) = self.sink.pause() {
+                error!("{}", e);
+            }
+            self.state = PlaybackState::Paused;
+            self.leave_current();
+        }
+    }
+
+    fn resume(&mut self) {
+        if let Err(e) = self.sink.resume() {
+            error!("{}", e);
+        }
+        self.state = PlaybackState::Playing;
+    }
+
+    fn stop(&mut self) {
+        if self.state == PlaybackState::Paused {
+            let _ = self.sink.resume();
+        }
+        self.sink.flush();
+        self.leave_current();
+        self.decoder = None;
+        self.frames_played = 0;
+        self.state = PlaybackState::Stopped;
+    }
+
+    /// ReplayGain as a linear factor, lowered when the peak would clip
+    fn gain_for(&self, track: &Track) -> f32 {
+        let prefer_album = match self.replaygain {
+            ReplayGainMode::Off => return 1.0,
+            ReplayGainMode::Track => false,
+            ReplayGainMode::Album => true,
+        };
+        let gain_db = match track.replaygain(prefer_album) {
+            Some(gain_db) => gain_db,
+            None => return 1.0,
+        };
+
+        let gain = 10_f64.powf(gain_db / 20.0);
+        let peak = if prefer_album {
+            track.album_peak.or(track.track_peak)
+        } else {
+            track.track_peak
+        };
+        match peak {
+            Some(peak) if peak > 0.0 => gain.min(1.0 / peak) as f32,
+            _ => gain as f32,
+        }
+    }
+
+    /// Reports where a long-form track was left, while it's still open. The queue keeps the
+    /// position too, so playing it again in this session resumes there.
+    fn leave_current(&mut self) {
+        let index = match (self.current, self.decoder.as_ref()) {
+            (Some(index), Some(_)) => index,
+            _ => return,
+        };
+        let position = self.position();
+        if let Some(track) = self.queue.get_mut(index) {
+            if track.content_kind.is_long_form() && position > 0.0 {
+                track.resume_position = position;
+                self.queue_changed = true;
+                let track_id = track.id.clone();
+                self.emit(PlayerEvent::ResumePosition { track_id, position });
+            }
+        }
+    }
+
+    fn current_track(&self) -> Option<&Track> {
+        self.current.and_then(|index| self.queue.get(index))
+    }
+
+    fn position(&self) -> f64 {
+        if self.sample_rate == 0 {
+            0.0
+        } else {
+            self.frames_played as f64 / self.sample_rate as f64
+        }
+    }
+
+    fn emit(&self, event: PlayerEvent) {
+        // nobody listening is fine
+        let _ = self.events.send(event);
+    }
+
+    fn publish(&mut self) {
+        let duration = self
+            .decoder
+            .as_ref()
+            .and_then(TrackDecoder::duration)
+            .or_else(|| self.current_track().map(|track| track.duration));
+        if let Ok(mut status) = self.status.lock() {
+            status.state = self.state;
+            status.current = self.current;
+            status.position = self.position();
+            status.duration = duration;
+            // don't clone the queue for every chunk
+            if self.queue_changed {
+                status.queue = self.queue.clone();
+                self.queue_changed = false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::content_kind::ContentKind;
+    use crate::test_support::write_wav;
+
+    const SAMPLE_RATE: u32 = 8000;
+    /// Longer than `RESTART_THRESHOLD_SECS`
+    const LONG_FRAMES: u32 = SAMPLE_RATE * 4;
+    const SHORT_FRAMES: u32 = SAMPLE_RATE;
+
+    /// A 4s stereo track and a 1s mono one, so the transition changes format
+    fn fixtures() -> (TempDir, Vec<Track>) {
+        let directory = TempDir::new().unwrap();
+        let track = |name: &str, channels: u16, frames: u32| {
+            let path = directory.path().join(format!("{}.wav", name));
+            write_wav(&path, SAMPLE_RATE, channels, frames);
+            Track {
+                id: name.to_string(),
+                abs_path: path.to_string_lossy().to_string(),
+                duration: frames as f64 / SAMPLE_RATE as f64,
+                exists: true,
+                ..Default::default()
+            }
+        };
+        let tracks = vec![track("long", 2, LONG_FRAMES), track("short", 1, SHORT_FRAMES)];
+        (directory, tracks)
+    }
+
+    fn engine() -> (Engine, NullSink, Receiver<PlayerEvent>) {
+        let sink = NullSink::new();
+        // the commands are sent to `handle` directly
+        let (_, commands) = channel();
+        let (events, event_receiver) = channel();
+        let status = Arc::new(Mutex::new(PlayerStatus {
+            state: PlaybackState::Stopped,
+            queue: vec![],
+            current: None,
+            position: 0.0,
+            duration: None,
+        }));
+        let engine = Engine::new(
+            Box::new(sink.clone()),
+            commands,
+            events,
+            status,
+            ReplayGainMode::Off,
+        );
+        (engine, sink, event_receiver)
+    }
+
+    /// Decodes like `Engine::run` does while nothing is sent, until playback stops
+    fn play_to_end(engine: &mut Engine) {
+        for _ in 0..100_000 {
+            if engine.state != PlaybackState::Playing {
+                return;
+            }
+            engine.play_chunk();
+        }
+        panic!("playback didn't stop");
+    }
+
+    /// Decodes until at least `seconds` of the current track were played
+    fn play_until(engine: &mut Engine, seconds: f64) {
+        while engine.position() < seconds {
+            assert_eq!(engine.state, PlaybackState::Playing);
+            engine.play_chunk();
+        }
+    }
+
+    #[test]
+    fn queue_advances_gaplessly() {
+        let (_directory, tracks) = fixtures();
+        let (mut engine, sink, events) = engine();
+
+        engine.handle(PlayerCommand::SetQueue(tracks, 0));
+        play_to_end(&mut engine);
+
+        assert_eq!(sink.frames_written(), (LONG_FRAMES + SHORT_FRAMES) as u64);
+        assert_eq!(engine.state, PlaybackState::Stopped);
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![
+                PlayerEvent::TrackStarted("long".to_string()),
+                PlayerEvent::TrackFinished("long".to_string()),
+                PlayerEvent::TrackStarted("short".to_string()),
+                PlayerEvent::TrackFinished("short".to_string()),
+                PlayerEvent::QueueFinished,
+            ]
+        );
+    }
+
+    #[test]
+    fn next_and_previous() {
+        let (_directory, tracks) = fixtures();
+        let (mut engine, _sink, events) = engine();
+
+        engine.handle(PlayerCommand::SetQueue(tracks, 0));
+        play_until(&mut engine, 0.5);
+        engine.handle(PlayerCommand::Next);
+        assert_eq!(engine.current, Some(1));
+        assert_eq!(engine.position(), 0.0);
+
+        // early in a track, previous goes to the track before
+        engine.handle(PlayerCommand::Previous);
+        assert_eq!(engine.current, Some(0));
+
+        // later, it restarts the track
+        play_until(&mut engine, RESTART_THRESHOLD_SECS + 0.1);
+        engine.handle(PlayerCommand::Previous);
+        assert_eq!(engine.current, Some(0));
+        assert_eq!(engine.position(), 0.0);
+
+        // next on the last track stops
+        engine.handle(PlayerCommand::Jump(1));
+        engine.handle(PlayerCommand::Next);
+        assert_eq!(engine.state, PlaybackState::Stopped);
+
+        // skipped tracks weren't played to the end
+        assert!(!events
+            .try_iter()
+            .any(|event| matches!(event, PlayerEvent::TrackFinished(_))));
+    }
+
+    #[test]
+    fn seek_skips_frames() {
+        let (_directory, tracks) = fixtures();
+        let (mut engine, sink, _events) = engine();
+
+        engine.handle(PlayerCommand::SetQueue(tracks[..1].to_vec(), 0));
+        engine.handle(PlayerCommand::Seek(1.5));
+        assert_eq!(engine.position(), 1.5);
+        play_to_end(&mut engine);
+
+        let skipped = (1.5 * SAMPLE_RATE as f64) as u64;
+        assert_eq!(sink.frames_written(), LONG_FRAMES as u64 - skipped);
+    }
+
+    #[test]
+    fn pause_keeps_the_position() {
+        let (_directory, mut tracks) = fixtures();
+        let (mut engine, sink, events) = engine();
+        tracks[0].content_kind = ContentKind::Audiobook;
+
+        engine.handle(PlayerCommand::SetQueue(tracks, 0));
+        play_until(&mut engine, 1.0);
+        let position = engine.position();
+        engine.handle(PlayerCommand::TogglePause);
+        assert_eq!(engine.state, PlaybackState::Paused);
+        // long-form tracks report where they were left
+        assert!(events.try_iter().any(|event| event
+            == PlayerEvent::ResumePosition {
+                track_id: "long".to_string(),
+                position,
+            }));
+
+        engine.handle(PlayerCommand::TogglePause);
+        assert_eq!(engine.state, PlaybackState::Playing);
+        assert_eq!(engine.position(), position);
+        play_to_end(&mut engine);
+        assert_eq!(sink.frames_written(), (LONG_FRAMES + SHORT_FRAMES) as u64);
+    }
+
+    #[test]
+    fn headless_player_decodes_the_queue() {
+        let (_directory, tracks) = fixtures();
+        let sink = NullSink::new();
+        let player = Player::headless(sink.clone(), ReplayGainMode::Off).unwrap();
+
+        player.set_queue(tracks, 0);
+        loop {
+            match player.events().recv_timeout(Duration::from_secs(10)) {
+                Ok(PlayerEvent::QueueFinished) => break,
+                Ok(PlayerEvent::TrackFailed { message, .. }) => panic!("{}", message),
+                Ok(_) => {}
+                Err(e) => panic!("no end of the queue: {}", e),
+            }
+        }
+
+        assert_eq!(sink.frames_written(), (LONG_FRAMES + SHORT_FRAMES) as u64);
+        assert_eq!(player.status().queue.len(), 2);
+    }
+}
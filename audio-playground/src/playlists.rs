@@ -0,0 +1,260 @@
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use log::info;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use quick_xml::escape::escape;
+use serde::{Deserialize, Serialize};
+use tantivy::collector::DocSetCollector;
+use tantivy::query::TermQuery;
+use tantivy::schema::{IndexRecordOption, Term};
+
+use crate::error::{AudioError, Result};
+use crate::schema::{DocumentSearchRequest, SearchWatcher, Track};
+use crate::utils::{file_ext, norm, write_atomic};
+
+pub const PLAYLISTS_FILE: &str = "./data/playlists.json";
+
+/// Characters that can't be used as is in the path of a `file://` URL
+const PATH_URL_ENCODE: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// A fixed list of tracks, e.g. imported from another player
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct Playlist {
+    pub name: String,
+    pub track_ids: Vec<String>,
+}
+
+/// A search that is run again every time it's exported, so new tracks show up in it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SavedQuery {
+    pub name: String,
+    pub request: DocumentSearchRequest,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default)]
+struct PlaylistData {
+    playlists: Vec<Playlist>,
+    saved_queries: Vec<SavedQuery>,
+}
+
+pub struct PlaylistStore {
+    path: PathBuf,
+    data: RwLock<PlaylistData>,
+}
+
+impl PlaylistStore {
+    pub fn open(playlists_file_path: &str) -> Result<Self> {
+        let path = PathBuf::from(playlists_file_path);
+        let data = if path.exists() {
+            serde_json::from_str(&read_to_string(&path)?)?
+        } else {
+            PlaylistData::default()
+        };
+
+        Ok(PlaylistStore {
+            path,
+            data: RwLock::new(data),
+        })
+    }
+
+    pub fn playlist(&self, name: &str) -> Option<Playlist> {
+        let data = self.data.read().ok()?;
+        data.playlists.iter().find(|p| p.name == name).cloned()
+    }
+
+    pub fn playlist_names(&self) -> Vec<String> {
+        match self.data.read() {
+            Ok(data) => data.playlists.iter().map(|p| p.name.clone()).collect(),
+            Err(_) => vec![],
+        }
+    }
+
+    pub fn saved_query(&self, name: &str) -> Option<SavedQuery> {
+        let data = self.data.read().ok()?;
+        data.saved_queries.iter().find(|q| q.name == name).cloned()
+    }
+
+    /// Replaces the playlist with the same name
+    pub fn save_playlist(&self, playlist: Playlist) -> Result<()> {
+        let mut data = self.data.write()?;
+        data.playlists.retain(|p| p.name != playlist.name);
+        data.playlists.push(playlist);
+        self.save(&data)
+    }
+
+    /// Replaces the saved query with the same name
+    pub fn save_query(&self, saved_query: SavedQuery) -> Result<()> {
+        let mut data = self.data.write()?;
+        data.saved_queries.retain(|q| q.name != saved_query.name);
+        data.saved_queries.push(saved_query);
+        self.save(&data)
+    }
+
+    fn save(&self, data: &PlaylistData) -> Result<()> {
+        write_atomic(&self.path, &serde_json::to_string_pretty(data)?)?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlaylistFormat {
+    M3u8,
+    Xspf,
+}
+
+impl PlaylistFormat {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        match file_ext(&path.to_string_lossy()).to_lowercase().as_str() {
+            "m3u" | "m3u8" => Ok(PlaylistFormat::M3u8),
+            "xspf" => Ok(PlaylistFormat::Xspf),
+            ext => Err(AudioError::Config(format!(
+                "unsupported playlist format {:?}, use .m3u8 or .xspf",
+                ext
+            ))),
+        }
+    }
+}
+
+pub fn write_playlist(tracks: &[Track], path: &Path) -> Result<()> {
+    let contents = match PlaylistFormat::from_path(path)? {
+        PlaylistFormat::M3u8 => to_m3u8(tracks),
+        PlaylistFormat::Xspf => to_xspf(tracks),
+    };
+    write_atomic(path, &contents)?;
+    info!("Exported {} track(s) to {:?}", tracks.len(), path);
+    Ok(())
+}
+
+pub fn to_m3u8(tracks: &[Track]) -> String {
+    let mut m3u8 = String::from("#EXTM3U\n");
+    for track in tracks {
+        m3u8.push_str(&format!(
+            "#EXTINF:{},{} - {}\n{}\n",
+            track.duration.round() as i64,
+            track.artist,
+            track.title(),
+            track.abs_path
+        ));
+    }
+    m3u8
+}
+
+pub fn to_xspf(tracks: &[Track]) -> String {
+    let mut xspf = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n  <trackList>\n",
+    );
+    for track in tracks {
+        xspf.push_str("    <track>\n");
+        xspf.push_str(&format!(
+            "      <location>{}</location>\n",
+            escape(&file_url(&track.abs_path))
+        ));
+        xspf.push_str(&format!("      <title>{}</title>\n", escape(track.title())));
+        xspf.push_str(&format!("      <creator>{}</creator>\n", escape(&track.artist)));
+        xspf.push_str(&format!("      <album>{}</album>\n", escape(&track.album)));
+        if track.duration > 0.0 {
+            // XSPF durations are in milliseconds
+            xspf.push_str(&format!(
+                "      <duration>{}</duration>\n",
+                (track.duration * 1000.0).round() as u64
+            ));
+        }
+        xspf.push_str("    </track>\n");
+    }
+    xspf.push_str("  </trackList>\n</playlist>\n");
+    xspf
+}
+
+/// `C:/Music/a b.mp3` -> `file:///C:/Music/a%20b.mp3`
+pub fn file_url(abs_path: &str) -> String {
+    let path = norm(abs_path);
+    let encoded = utf8_percent_encode(&path, PATH_URL_ENCODE).to_string();
+    if path.starts_with('/') {
+        format!("file://{}", encoded)
+    } else {
+        format!("file:///{}", encoded)
+    }
+}
+
+impl SearchWatcher {
+    /// Every result of `request` (all pages), in the order of the search
+    pub fn all_results(&self, request: &DocumentSearchRequest) -> Result<Vec<Track>> {
+        let mut request = request.clone();
+        request.page_number = 0;
+        request.search_after = None;
+
+        let mut tracks = vec![];
+        loop {
+            let response = self.do_search(&request, false)?;
+            tracks.extend(response.results.into_iter().map(|result| result.track));
+            match response.cursor {
+                // the cursor keeps later pages as cheap as the first one
+                Some(cursor) if response.next_page => request.search_after = Some(cursor),
+                _ => break,
+            }
+        }
+        Ok(tracks)
+    }
+
+    pub fn export_search(&self, request: &DocumentSearchRequest, path: &Path) -> Result<()> {
+        write_playlist(&self.all_results(request)?, path)
+    }
+
+    pub fn export_saved_query(&self, name: &str, path: &Path) -> Result<()> {
+        write_playlist(&self.saved_query_results(name)?, path)
+    }
+
+    pub fn saved_query_results(&self, name: &str) -> Result<Vec<Track>> {
+        let store = PlaylistStore::open(&self.data_file(PLAYLISTS_FILE))?;
+        let saved_query = store
+            .saved_query(name)
+            .ok_or_else(|| AudioError::Config(format!("no saved query named {:?}", name)))?;
+        self.all_results(&saved_query.request)
+    }
+
+    /// Tracks that are no longer indexed are left out
+    pub fn export_playlist(&self, name: &str, path: &Path) -> Result<()> {
+        write_playlist(&self.playlist_tracks(name)?, path)
+    }
+
+    /// The indexed tracks of the saved playlist `name`, in the order of the playlist
+    pub fn playlist_tracks(&self, name: &str) -> Result<Vec<Track>> {
+        let store = PlaylistStore::open(&self.data_file(PLAYLISTS_FILE))?;
+        let playlist = store
+            .playlist(name)
+            .ok_or_else(|| AudioError::Config(format!("no playlist named {:?}", name)))?;
+        self.tracks_by_ids(&playlist.track_ids)
+    }
+
+    /// In the order of `track_ids`, ids that aren't indexed are left out
+    pub fn tracks_by_ids(&self, track_ids: &[String]) -> Result<Vec<Track>> {
+        let searcher = self.reader.searcher();
+        let mut tracks = Vec::with_capacity(track_ids.len());
+        for track_id in track_ids {
+            let query = TermQuery::new(
+                Term::from_field_text(self.field_schema.id, track_id),
+                IndexRecordOption::Basic,
+            );
+            if let Some(doc_address) = searcher.search(&query, &DocSetCollector)?.into_iter().next()
+            {
+                let doc = searcher.doc(doc_address)?;
+                tracks.push(Track::with_document(&self.field_schema, doc));
+            }
+        }
+        Ok(tracks)
+    }
+}
@@ -1,67 +1,116 @@
-use log::{error, info, trace, warn};
-use std::fs::Metadata;
-use std::io;
+use log::{error, trace, warn};
 use std::path::Path;
 
 use audiotags::{AudioTag, Tag};
 use id3;
 use mpeg_audio_header::{Header, ParseMode};
 
+use crate::chapters::read_chapters;
+use crate::content_kind::ContentRules;
+use crate::cue::{cue_sheet_for, virtual_tracks};
+use crate::decode::TrackDecoder;
+use crate::error::{AudioError, Result};
+use crate::lyrics::read_lyrics;
 use crate::schema::TrackJson;
 use crate::utils::{file_ext, norm};
 
 pub fn get_duration_for_path(path_string: &String) -> Option<f64> {
     // Duration is usually not stored in ID3 tags, so lets calculate it from the audio file itself
     let path = Path::new(&path_string);
-    let ext = file_ext(&path_string);
-
-    // `wav` files don't seem to play nice here, so just ignore them for now
-    if ext != "wav" {
-        let header = Header::read_from_path(&path, ParseMode::PreferVbrHeaders);
-        if header.is_err() {
-            error!("Error fetching duration for {:?}", &path);
-        } else {
-            return Some(header.unwrap().total_duration.as_secs_f64());
+
+    // mp3 files have no length in a container, the VBR headers are quicker than decoding
+    if file_ext(&path_string).eq_ignore_ascii_case("mp3") {
+        match Header::read_from_path(&path, ParseMode::PreferVbrHeaders) {
+            Ok(header) => return Some(header.total_duration.as_secs_f64()),
+            Err(e) => warn!("Error fetching duration for {:?}: {:?}", &path, e),
         }
-    } else {
-        warn!("Skipping wav file duration fetching");
     }
 
-    return None;
+    // m4a, wav, flac... have it in their headers, which the decoder reads
+    match TrackDecoder::open(path) {
+        Ok(decoder) => decoder.duration(),
+        Err(e) => {
+            error!("Error fetching duration for {:?}: {}", &path, e);
+            None
+        }
+    }
 }
 
-pub fn get_track_from_path(path_string: &String) -> Option<TrackJson> {
+pub fn get_track_from_path(path_string: &String) -> Result<TrackJson> {
     let path: &Path = Path::new(&path_string);
     return get_track_from_path_instance(&path_string, &path);
 }
 
-pub fn get_track_from_path_instance(path_string: &String, path: &Path) -> Option<TrackJson> {
-    let metadata_option: io::Result<Metadata> = path.metadata();
-    if metadata_option.is_err() {
-        error!("Error parsing tag {:?}", &path_string);
-        return None;
-    }
-    let metadata = metadata_option.unwrap();
+pub fn get_track_from_path_instance(path_string: &String, path: &Path) -> Result<TrackJson> {
+    let metadata = path.metadata()?;
     let ext: &str = file_ext(&path_string);
+    trace!("Reading tags for {:?}", &path_string);
 
     // audiotags does not support wav files, so we must handle them directly with the ID3 package
-    if ext == "wav" {
-        let tag: Result<id3::Tag, id3::Error> = id3::Tag::read_from_wav_path(&path_string);
-        if tag.is_err() {
-            error!("Error parsing tag {:?}", &path_string);
-            return None;
-        }
-        return Some(TrackJson::new_wav(
-            norm(&path_string),
-            metadata,
-            tag.unwrap(),
-        ));
+    let mut track = if ext == "wav" {
+        let tag: id3::Tag = id3::Tag::read_from_wav_path(&path_string)
+            .map_err(|e| AudioError::tag(path_string, e))?;
+        TrackJson::new_wav(norm(&path_string), metadata, tag)
     } else {
-        let tag: Result<Box<dyn AudioTag>, _> = Tag::new().read_from_path(&path_string);
-        if tag.is_err() {
-            error!("Error parsing tag {:?}", &path_string);
-            return None;
+        let tag: Box<dyn AudioTag> = Tag::new()
+            .read_from_path(&path_string)
+            .map_err(|e| AudioError::tag(path_string, e))?;
+        TrackJson::new(norm(&path_string), metadata, tag)
+    };
+
+    // stored in the JSON cache too, so rebuilding the index from it doesn't read every file again
+    track.duration = get_duration_for_path(path_string).unwrap_or(0.0);
+
+    let lyrics = read_lyrics(path);
+    track.lyrics = lyrics.text;
+    track.synced_lyrics = lyrics.synced;
+    Ok(track)
+}
+
+/// The tracks of the file: one per `TRACK` when a CUE sheet splits it, otherwise just the file.
+/// Each one is classified (see `ContentRules`), long-form files get their chapters.
+pub fn get_tracks_from_path(path_string: &String) -> Result<Vec<TrackJson>> {
+    let path: &Path = Path::new(&path_string);
+    let track = get_track_from_path(path_string)?;
+
+    let mut tracks = match cue_sheet_for(path) {
+        Some(sheet) => {
+            trace!("Splitting {:?} with its CUE sheet", &path_string);
+            virtual_tracks(&track, &sheet)
         }
-        return Some(TrackJson::new(norm(&path_string), metadata, tag.unwrap()));
+        None => vec![track],
+    };
+
+    let content_rules = ContentRules::current();
+    for track in &mut tracks {
+        let content_kind = content_rules.classify(track);
+        track.content_kind = Some(content_kind);
+        // chapter times are relative to the whole file
+        if content_kind.is_long_form() && !track.is_virtual() {
+            track.chapters = read_chapters(path);
+        }
+    }
+    Ok(tracks)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::test_support::write_wav;
+
+    #[test]
+    fn duration_from_the_decoder() {
+        let directory = TempDir::new().unwrap();
+        let path = directory.path().join("song.wav");
+        write_wav(&path, 8000, 2, 12000);
+        let path_string = path.to_string_lossy().to_string();
+        assert_eq!(get_duration_for_path(&path_string), Some(1.5));
+
+        // not audio at all
+        let text_path = directory.path().join("notes.mp3");
+        std::fs::write(&text_path, "not an mp3").unwrap();
+        assert_eq!(get_duration_for_path(&text_path.to_string_lossy().to_string()), None);
     }
 }
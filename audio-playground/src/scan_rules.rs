@@ -0,0 +1,215 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use jwalk::{DirEntry, WalkDir};
+use log::{trace, warn};
+
+use crate::library_roots::LibraryRoot;
+use crate::settings::Setting;
+use crate::utils::{file_ext, norm, ALLOWED_FILE_TYPES};
+
+/// Decides which files under a library root get indexed.
+///
+/// Include/exclude patterns use gitignore syntax, relative to `root`
+/// (e.g. `*.wav`, `/Podcasts/`, `**/Demos/**`, `!keep-me.mp3`).
+#[derive(Debug, Clone)]
+pub struct ScanRules {
+    pub root: PathBuf,
+    include: Gitignore,
+    exclude: Gitignore,
+    has_include: bool,
+    file_types: Vec<String>,
+    pub min_file_size: u64,
+    pub follow_symlinks: bool,
+}
+
+impl ScanRules {
+    pub fn new(
+        root: &str,
+        include: &[String],
+        exclude: &[String],
+        file_types: &[String],
+        min_file_size: u64,
+        follow_symlinks: bool,
+    ) -> Result<Self, ignore::Error> {
+        let root = PathBuf::from(norm(root));
+
+        let file_types = if file_types.is_empty() {
+            ALLOWED_FILE_TYPES.iter().map(|t| t.to_string()).collect()
+        } else {
+            file_types.iter().map(|t| t.to_lowercase()).collect()
+        };
+
+        Ok(ScanRules {
+            include: build_matcher(&root, include)?,
+            exclude: build_matcher(&root, exclude)?,
+            has_include: !include.is_empty(),
+            root,
+            file_types,
+            min_file_size,
+            follow_symlinks,
+        })
+    }
+
+    pub fn from_setting(setting: &Setting, root: &LibraryRoot) -> Result<Self, ignore::Error> {
+        let file_types = setting
+            .root_file_types
+            .get(&root.id)
+            .or_else(|| setting.root_file_types.get(&root.path))
+            .cloned()
+            .unwrap_or_default();
+
+        ScanRules::new(
+            &root.path,
+            &setting.include_index_path,
+            &setting.exclude_index_path,
+            &file_types,
+            setting.min_file_size,
+            setting.follow_symlinks,
+        )
+    }
+
+    /// Excluded directories are pruned entirely, so their children are never read
+    pub fn is_allowed_dir(&self, path: &Path) -> bool {
+        !self.exclude.matched(path, true).is_ignore()
+    }
+
+    pub fn is_allowed_file(&self, path: &Path, size: u64) -> bool {
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        let ext = file_ext(name).to_lowercase();
+        if !self.file_types.contains(&ext) {
+            return false;
+        }
+
+        if size < self.min_file_size {
+            trace!("Skipping {:?}, smaller than {} bytes", path, self.min_file_size);
+            return false;
+        }
+
+        if self.is_match(&self.exclude, path) {
+            return false;
+        }
+
+        !self.has_include || self.is_match(&self.include, path)
+    }
+
+    fn is_match(&self, matcher: &Gitignore, path: &Path) -> bool {
+        // `matched_path_or_any_parents` panics on paths outside of the root
+        if path.starts_with(&self.root) {
+            matcher.matched_path_or_any_parents(path, false).is_ignore()
+        } else {
+            matcher.matched(path, false).is_ignore()
+        }
+    }
+
+    /// Drops excluded entries from a jwalk `process_read_dir` batch
+    pub fn retain_entries(&self, children: &mut Vec<jwalk::Result<DirEntry<((), ())>>>) {
+        children.retain(|dir_entry_result| match dir_entry_result {
+            Ok(dir_entry) => {
+                let path = dir_entry.path();
+                if dir_entry.file_type().is_dir() {
+                    self.is_allowed_dir(&path)
+                } else {
+                    let size = dir_entry.metadata().map(|m| m.len()).unwrap_or(0);
+                    self.is_allowed_file(&path, size)
+                }
+            }
+            Err(_) => true,
+        });
+    }
+}
+
+pub fn walk_dir(rules: Arc<ScanRules>) -> WalkDir {
+    WalkDir::new(&rules.root)
+        .follow_links(rules.follow_symlinks)
+        .process_read_dir(move |_depth, _path, _read_dir_state, children| {
+            rules.retain_entries(children);
+        })
+}
+
+fn build_matcher(root: &Path, patterns: &[String]) -> Result<Gitignore, ignore::Error> {
+    let mut builder = GitignoreBuilder::new(root);
+    for pattern in patterns {
+        if let Err(e) = builder.add_line(None, pattern) {
+            warn!("Invalid scan pattern {:?}: {}", pattern, e);
+            return Err(e);
+        }
+    }
+    builder.build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOT: &str = "/music";
+
+    fn rules(include: &[&str], exclude: &[&str]) -> ScanRules {
+        let strings = |patterns: &[&str]| patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        ScanRules::new(ROOT, &strings(include), &strings(exclude), &[], 0, false).unwrap()
+    }
+
+    fn allowed(rules: &ScanRules, path: &str) -> bool {
+        rules.is_allowed_file(Path::new(path), 1_000)
+    }
+
+    #[test]
+    fn only_audio_files_by_default() {
+        let rules = rules(&[], &[]);
+        assert!(allowed(&rules, "/music/Artist/Album/01 Song.mp3"));
+        assert!(allowed(&rules, "/music/Artist/Album/01 Song.FLAC"));
+        assert!(!allowed(&rules, "/music/Artist/Album/cover.jpg"));
+        assert!(!allowed(&rules, "/music/Artist/Album/notes.txt"));
+    }
+
+    #[test]
+    fn include_limits_to_matching_paths() {
+        let rules = rules(&["/Rock/"], &[]);
+        assert!(allowed(&rules, "/music/Rock/Band/01 Song.mp3"));
+        assert!(!allowed(&rules, "/music/Jazz/Band/01 Song.mp3"));
+        // include doesn't prune directories, their files are checked one by one
+        assert!(rules.is_allowed_dir(Path::new("/music/Jazz")));
+    }
+
+    #[test]
+    fn exclude_skips_files_and_prunes_directories() {
+        let rules = rules(&[], &["**/Demos/**", "/Podcasts/", "*.wav"]);
+        assert!(!allowed(&rules, "/music/Band/Demos/take 1.mp3"));
+        assert!(!allowed(&rules, "/music/Podcasts/Show/episode.mp3"));
+        assert!(!allowed(&rules, "/music/Band/Album/01 Song.wav"));
+        assert!(allowed(&rules, "/music/Band/Album/01 Song.mp3"));
+
+        assert!(!rules.is_allowed_dir(Path::new("/music/Podcasts")));
+        assert!(rules.is_allowed_dir(Path::new("/music/Band")));
+    }
+
+    #[test]
+    fn negated_exclude_keeps_files() {
+        let rules = rules(&[], &["*.wav", "!keep-me.wav"]);
+        assert!(!allowed(&rules, "/music/Band/Album/01 Song.wav"));
+        assert!(allowed(&rules, "/music/Band/Album/keep-me.wav"));
+    }
+
+    #[test]
+    fn file_types_per_root() {
+        let mut setting = Setting::default();
+        setting
+            .root_file_types
+            .insert("usb".to_string(), vec!["FLAC".to_string()]);
+
+        let usb = ScanRules::from_setting(&setting, &LibraryRoot::new("usb", "/media/usb")).unwrap();
+        assert!(usb.is_allowed_file(Path::new("/media/usb/Album/01 Song.flac"), 1_000));
+        assert!(!usb.is_allowed_file(Path::new("/media/usb/Album/01 Song.mp3"), 1_000));
+
+        let other = ScanRules::from_setting(&setting, &LibraryRoot::new("home", ROOT)).unwrap();
+        assert!(allowed(&other, "/music/Album/01 Song.mp3"));
+    }
+
+    #[test]
+    fn min_file_size() {
+        let rules = ScanRules::new(ROOT, &[], &[], &[], 10_000, false).unwrap();
+        assert!(!rules.is_allowed_file(Path::new("/music/Album/short.mp3"), 9_999));
+        assert!(rules.is_allowed_file(Path::new("/music/Album/long.mp3"), 10_000));
+    }
+}
@@ -1,95 +1,235 @@
-use std::fs;
+use std::collections::HashMap;
 use std::fs::read_to_string;
 use std::fs::Metadata;
 #[cfg(unix)]
 use std::os::unix::fs::MetadataExt;
 #[cfg(windows)]
 use std::os::windows::fs::MetadataExt;
-use std::path::Path;
-use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
-
-use crate::reader::{get_duration_for_path, get_track_from_path};
-use crate::search_query::{convert_bm25_order, convert_int_order, create_query, do_search};
-use crate::utils::{
-    self, adapt_text, file_ext, genre_string_to_vec, get_order_field, is_valid_facet, norm,
-    ALLOWED_FILE_TYPES,
-};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::albums::{album_id, AlbumStore, ALBUMS_FILE};
+use crate::artists::ArtistRules;
+use crate::audio_features::{AudioFeatureStore, AudioFeatures, AUDIO_FEATURES_FILE};
+use crate::books::{BookStore, BOOKS_FILE};
+use crate::chapters::Chapter;
+use crate::content_kind::{ContentKind, ContentRules};
+use crate::error::{AudioError, Result};
+use crate::fingerprint::{FingerprintStore, FINGERPRINTS_FILE};
+use crate::gc::DEFAULT_GC_BATCH_SIZE;
+use crate::indexing::{housekeeping, open_existing, write_version, OpenedIndex};
+use crate::lyrics::LyricLine;
+use crate::loudness::{Loudness, LoudnessStore, LOUDNESS_FILE};
+use crate::metrics::{FailureReason, IndexMetrics};
+use crate::library_roots::{configured_roots, root_for_path, LibraryRoot, DEFAULT_ROOT_ID};
+use crate::reader::{get_duration_for_path, get_tracks_from_path};
+use crate::scan_rules::ScanRules;
+use crate::search_after::{Cursor, Hit};
+use crate::search_query::do_search;
+use crate::settings::SETTINGS;
+use crate::user_data::{UserData, UserDataStore, USER_DATA_FILE};
+use crate::utils::{self, genre_string_to_vec, norm};
 use audiotags::AudioTag;
 use id3::TagLike;
 use jwalk::DirEntry;
 use jwalk::WalkDir;
+use log::{debug, error, info, trace, warn};
 use serde::{Deserialize, Serialize};
 use slug::slugify;
 use tantivy::aggregation::agg_result::BucketEntry;
 use tantivy::collector::Count;
-use tantivy::collector::FacetCollector;
-use tantivy::collector::MultiCollector;
-use tantivy::collector::TopDocs;
-use tantivy::query::AllQuery;
+use tantivy::collector::DocSetCollector;
+use tantivy::query::BooleanQuery;
+use tantivy::query::Occur;
 use tantivy::query::Query;
-use tantivy::query::QueryParser;
+use tantivy::query::TermQuery;
 use tantivy::time::PrimitiveDateTime;
 use tantivy::{
     collector::FacetCounts,
     schema::{
-        Cardinality, Facet, FacetOptions, Field, IndexRecordOption, NumericOptions, Schema,
-        TextFieldIndexing, TextOptions, Value, FAST, STORED, STRING,
+        Cardinality, Facet, FacetOptions, Field, FieldValue, IndexRecordOption, NumericOptions,
+        Schema, Term, TextFieldIndexing, TextOptions, Value, FAST, STORED, STRING, TEXT,
     },
-    DocAddress, Document, Index, IndexReader, IndexWriter, TantivyError,
+    DocAddress, Document, Index, IndexReader, IndexWriter,
 };
 
 pub struct SearchWatcher {
     pub field_schema: FieldSchema,
     pub index: Index,
     pub reader: IndexReader,
-    pub writer: Arc<Mutex<IndexWriter>>,
+    /// `None` for read-only handles, see `open_read_only`
+    writer: Option<Mutex<IndexWriter>>,
+    pub user_data: UserDataStore,
+    pub loudness: LoudnessStore,
+    pub audio_features: AudioFeatureStore,
+    pub albums: AlbumStore,
+    pub books: BookStore,
+    pub fingerprints: FingerprintStore,
+    /// Where the stores above (and the playlists) are kept, see `data_file`
+    data_directory: PathBuf,
 }
 
 const JSON_DATA_FILE: &str = "./data/audio.json";
+/// The directory of the `*_FILE` paths of the stores
+pub const DATA_DIRECTORY: &str = "./data";
+
+/// Indexing threads and memory budget (shared by the threads) of the writer
+const WRITER_THREADS: usize = 2;
+const WRITER_HEAP_BYTES: usize = 140_000_000;
 
-const BASE_AUDIO_DIRECTORY: &str =
+/// Only used when no `library_roots` are configured
+pub const BASE_AUDIO_DIRECTORY: &str =
     "C:\\Users\\lukes\\Github\\rust-adventures\\audio-playground\\audio";
 
 impl SearchWatcher {
-    pub fn new(index_cache_directory: &str) -> Self {
+    /// Read-write handle: creates (or rebuilds) the index when needed and holds its only writer
+    pub fn new(index_cache_directory: &str) -> Result<Self> {
         let field_schema = FieldSchema::new();
 
         let index_path: &Path = Path::new(index_cache_directory);
-        let index;
-        if index_path.exists() {
-            index = Index::open_in_dir(&index_path).ok().unwrap();
-        } else {
-            fs::create_dir(index_path).ok();
-            index = Index::create_in_dir(&index_path, field_schema.schema.clone())
-                .ok()
-                .unwrap();
+        let OpenedIndex {
+            index,
+            needs_rebuild,
+        } = housekeeping(index_path, &field_schema)?;
+
+        let writer = index.writer_with_num_threads(WRITER_THREADS, WRITER_HEAP_BYTES)?;
+        let search_watcher = SearchWatcher::with_index(
+            field_schema,
+            index,
+            Some(writer),
+            Path::new(DATA_DIRECTORY),
+        )?;
+
+        if needs_rebuild {
+            search_watcher.rebuild(JSON_DATA_FILE)?;
+            write_version(index_path)?;
         }
 
-        let writer = Arc::new(Mutex::new(
-            index.writer_with_num_threads(2, 140_000_000).unwrap(),
-        ));
+        Ok(search_watcher)
+    }
 
+    /// Read-write handle whose stores are kept in `data_directory` instead of `DATA_DIRECTORY`,
+    /// e.g. for benchmarks and tests. A new (or outdated) index is left empty: nothing is read
+    /// from the JSON cache or the library roots.
+    pub fn with_data_directory(index_cache_directory: &str, data_directory: &str) -> Result<Self> {
+        let field_schema = FieldSchema::new();
+        let index_path: &Path = Path::new(index_cache_directory);
+        let OpenedIndex { index, .. } = housekeeping(index_path, &field_schema)?;
+        write_version(index_path)?;
+
+        let writer = index.writer_with_num_threads(WRITER_THREADS, WRITER_HEAP_BYTES)?;
+        SearchWatcher::with_index(field_schema, index, Some(writer), Path::new(data_directory))
+    }
+
+    /// Searches without a writer (or its memory), also while another process indexes: its
+    /// commits show up on their own. Fails when the index is missing or needs a rebuild,
+    /// anything that writes to the index fails with a `Config` error.
+    pub fn open_read_only(index_cache_directory: &str) -> Result<Self> {
+        SearchWatcher::open_read_only_with_data_directory(index_cache_directory, DATA_DIRECTORY)
+    }
+
+    /// `open_read_only` with the stores of `data_directory`, see `with_data_directory`
+    pub fn open_read_only_with_data_directory(
+        index_cache_directory: &str,
+        data_directory: &str,
+    ) -> Result<Self> {
+        let field_schema = FieldSchema::new();
+        let index = open_existing(Path::new(index_cache_directory), &field_schema)?;
+        SearchWatcher::with_index(field_schema, index, None, Path::new(data_directory))
+    }
+
+    fn with_index(
+        field_schema: FieldSchema,
+        index: Index,
+        writer: Option<IndexWriter>,
+        data_directory: &Path,
+    ) -> Result<Self> {
         let reader = index
             .reader_builder()
             .reload_policy(tantivy::ReloadPolicy::OnCommit)
-            .try_into()
-            .unwrap();
+            .try_into()?;
+        let data_file = |default_path: &str| data_file(data_directory, default_path);
 
-        SearchWatcher {
+        Ok(SearchWatcher {
             field_schema,
             index,
             reader,
-            writer,
+            writer: writer.map(Mutex::new),
+            user_data: UserDataStore::open(&data_file(USER_DATA_FILE))?,
+            loudness: LoudnessStore::open(&data_file(LOUDNESS_FILE))?,
+            audio_features: AudioFeatureStore::open(&data_file(AUDIO_FEATURES_FILE))?,
+            albums: AlbumStore::open(&data_file(ALBUMS_FILE))?,
+            books: BookStore::open(&data_file(BOOKS_FILE))?,
+            fingerprints: FingerprintStore::open(&data_file(FINGERPRINTS_FILE))?,
+            data_directory: data_directory.to_path_buf(),
+        })
+    }
+
+    /// Where the file of `default_path` (one of the `*_FILE` constants) is kept for this handle
+    pub fn data_file(&self, default_path: &str) -> String {
+        data_file(&self.data_directory, default_path)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.writer.is_none()
+    }
+
+    pub fn writer(&self) -> Result<MutexGuard<'_, IndexWriter>> {
+        match &self.writer {
+            Some(writer) => Ok(writer.lock()?),
+            None => Err(AudioError::Config(
+                "the index was opened read-only".to_string(),
+            )),
+        }
+    }
+    /// Fills a freshly (re)created index from the JSON cache, or rescans the library roots
+    /// when there is no usable cache
+    fn rebuild(&self, json_file_path: &str) -> Result<()> {
+        let cached = match read_tracks_json(json_file_path) {
+            Ok(data) => Some(data),
+            Err(e) => {
+                warn!("Ignoring unreadable JSON cache {}: {}", json_file_path, e);
+                None
+            }
+        };
+
+        match cached {
+            Some(data) => {
+                info!("Rebuilding index from {} ({} items)", json_file_path, data.len());
+                let roots = configured_roots(&*SETTINGS.read()?, BASE_AUDIO_DIRECTORY);
+                let mut writer = self.writer()?;
+                for mut item in data.into_iter() {
+                    // caches written before library roots existed don't have a root id
+                    if item.root_id.is_empty() {
+                        item.root_id = root_for_path(&roots, &item.abs_path)
+                            .map(|root| root.id.clone())
+                            .unwrap_or_else(|| DEFAULT_ROOT_ID.to_string());
+                    }
+                    writer.add_document(self.document_for(&item, TrackStatus::Online))?;
+                }
+                writer.commit()?;
+                drop(writer);
+                self.reader.reload()?;
+                self.rebuild_albums()?;
+                self.rebuild_books()?;
+            }
+            None => {
+                info!("Rebuilding index from a rescan of the library roots");
+                // also rebuilds the albums and books
+                self.index_since_last_opened()?;
+            }
         }
+
+        Ok(())
     }
-    pub fn search(&self, request: DocumentSearchRequest) -> tantivy::Result<()> {
-        let faced_only_flag = true;
-        let response: DocumentSearchResponse = self.do_search(&request, faced_only_flag);
+    pub fn search(&self, request: DocumentSearchRequest) -> Result<()> {
+        let response: DocumentSearchResponse = self.do_search(&request, false)?;
 
-        println!("Total {} items", response.total);
+        info!("Total {} items", response.total);
         for item in response.results {
-            println!("🎵 {} - ({})", item.track.name, item.track.id);
+            info!("🎵 {} - ({})", item.track.name, item.track.id);
         }
         // let response_json = serde_json::to_string(&response)?;
 
@@ -98,292 +238,421 @@ impl SearchWatcher {
     pub fn do_search(
         &self,
         request: &DocumentSearchRequest,
-        facet_only_flag: bool,
-    ) -> DocumentSearchResponse {
-        let query_parser = {
-            let query_parser = QueryParser::for_index(
-                &self.index,
-                vec![
-                    self.field_schema.title,
-                    self.field_schema.artist,
-                    self.field_schema.album,
-                    self.field_schema.track,
-                ],
-            );
-            query_parser
-        };
-        let text = adapt_text(&query_parser, &request.text);
+        facet_only: bool,
+    ) -> Result<DocumentSearchResponse> {
+        do_search(
+            &self.index,
+            &self.reader,
+            &self.field_schema,
+            request,
+            facet_only,
+        )
+    }
 
-        let query = if !request.text.is_empty() {
-            create_query(&query_parser, request, &self.field_schema, &text)
-        } else {
-            Box::new(AllQuery) as Box<dyn Query>
-        };
+    pub fn is_existing_by_path(&self, track_path: &str) -> Result<bool> {
+        let searcher = self.reader.searcher();
 
-        // Offset to search from
-        let results = request.result_per_page as usize;
-
-        let offset = results * request.page_number as usize;
-
-        let extra_result = results + 1;
-        let order_field = get_order_field(&self.field_schema, &request.order);
-        let facets = request
-            .faceted
-            .as_ref()
-            .map(|v| {
-                v.tags
-                    .iter()
-                    .filter(|s| is_valid_facet(*s))
-                    .cloned()
-                    .collect()
-            })
-            .unwrap_or_default();
+        let path_term = Term::from_field_text(self.field_schema.abs_path, track_path);
+        let query = TermQuery::new(path_term, IndexRecordOption::Basic);
+        let count = searcher.search(&query, &Count)?;
 
-        let mut facet_collector = FacetCollector::for_field(self.field_schema.facets);
-        for facet in &facets {
-            match Facet::from_text(facet) {
-                Ok(facet) => facet_collector.add_facet(facet),
-                Err(_) => println!("Invalid facet: {}", facet),
-            }
-        }
+        Ok(count > 0)
+    }
 
+    pub fn is_existing_by_id(&self, track_id: &str) -> Result<bool> {
         let searcher = self.reader.searcher();
 
-        // TODO: use request.filters to filter by year range and date ranges
-
-        match order_field {
-            _ if !facet_only_flag => {
-                // Just a facet search
-                let facets_count = searcher.search(&query, &facet_collector).unwrap();
-                convert_bm25_order(
-                    self.field_schema.clone(),
-                    SearchResponse {
-                        facets,
-                        query: &text,
-                        top_docs: vec![],
-                        facets_count,
-                        order_by: request.order.clone(),
-                        page_number: request.page_number,
-                        results_per_page: results as i32,
-                    },
-                    &searcher,
-                )
-            }
-            Some(order_field) => {
-                let mut multicollector = MultiCollector::new();
-                let facet_handler = multicollector.add_collector(facet_collector);
-                let count_handler = multicollector.add_collector(Count);
-
-                let topdocs_collector = TopDocs::with_limit(extra_result)
-                    .and_offset(offset)
-                    .order_by_u64_field(order_field);
-                let topdocs_handler = multicollector.add_collector(topdocs_collector);
-                let mut multi_fruit = searcher.search(&query, &multicollector).unwrap();
-                let facets_count = facet_handler.extract(&mut multi_fruit);
-                let top_docs = topdocs_handler.extract(&mut multi_fruit);
-
-                let count = count_handler.extract(&mut multi_fruit);
-
-                convert_int_order(
-                    self.field_schema.clone(),
-                    SearchResponse {
-                        facets_count,
-                        facets,
-                        top_docs,
-                        query: &text,
-                        order_by: request.order.clone(),
-                        page_number: request.page_number,
-                        results_per_page: results as i32,
-                    },
-                    &searcher,
-                )
-            }
-            None => {
-                let mut multicollector = MultiCollector::new();
-                let facet_handler = multicollector.add_collector(facet_collector);
-                let topdocs_collector = TopDocs::with_limit(extra_result).and_offset(offset);
-                let topdocs_handler = multicollector.add_collector(topdocs_collector);
-                let mut multi_fruit = searcher.search(&query, &multicollector).unwrap();
-                let facets_count = facet_handler.extract(&mut multi_fruit);
-                let top_docs = topdocs_handler.extract(&mut multi_fruit);
-
-                convert_bm25_order(
-                    self.field_schema.clone(),
-                    SearchResponse {
-                        facets_count,
-                        facets,
-                        top_docs,
-                        query: &text,
-                        order_by: request.order.clone(),
-                        page_number: request.page_number,
-                        results_per_page: results as i32,
-                    },
-                    &searcher,
-                )
-            }
-        }
+        let id_term = Term::from_field_text(self.field_schema.id, track_id);
+        let query = TermQuery::new(id_term, IndexRecordOption::Basic);
+        let count = searcher.search(&query, &Count)?;
+
+        Ok(count > 0)
     }
 
-    pub fn is_existing_by_path(&self, track_path: &str) -> bool {
-        let query_parser = {
-            let query_parser =
-                QueryParser::for_index(&self.index, vec![self.field_schema.abs_path]);
-            query_parser
+    /// Adds `item` unless it's already indexed, `false` when it was skipped
+    pub fn add(&self, item: &TrackJson) -> Result<bool> {
+        // quick duplicate check, the tracks of a CUE sheet share their path
+        let existing = if item.is_virtual() {
+            self.is_existing_by_id(&item.id)?
+        } else {
+            self.is_existing_by_path(&item.abs_path)?
         };
+        if existing {
+            return Ok(false);
+        }
 
-        let searcher = self.reader.searcher();
-
-        let path_query = format!("\"{}\"", &track_path);
-        let query = query_parser.parse_query(path_query.as_str()).unwrap();
-        let count = searcher.search(&query, &Count).unwrap();
+        let document = self.document_for(item, TrackStatus::Online);
+        self.writer()?.add_document(document)?;
+        Ok(true)
+    }
 
-        count > 0
+    /// The document for `item`, including what we keep about it outside of the index
+    pub fn document_for(&self, item: &TrackJson, status: TrackStatus) -> Document {
+        self.document_with_user_data(item, status, &self.user_data.get(&item.id))
     }
 
-    pub fn add(&self, item: &TrackJson) {
-        // quick duplicate check
-        if self.is_existing_by_path(&item.abs_path) {
-            return;
+    /// `document_for` with user data that isn't in the store (yet), e.g. from a snapshot
+    pub fn document_with_user_data(
+        &self,
+        item: &TrackJson,
+        status: TrackStatus,
+        user_data: &UserData,
+    ) -> Document {
+        let mut document = self.field_schema.to_document(item, status, user_data);
+        if let Some(loudness) = self.loudness.get(&item.id) {
+            self.field_schema.add_loudness(&mut document, &loudness);
+        }
+        if let Some(audio_features) = self.audio_features.get(&item.id) {
+            self.field_schema
+                .add_audio_features(&mut document, &audio_features);
         }
+        document
+    }
 
-        let mut document = Document::default();
-        document.add_text(self.field_schema.id, &item.id);
-        document.add_text(self.field_schema.abs_path, &item.abs_path);
-        document.add_text(self.field_schema.title, &item.name);
-        document.add_text(self.field_schema.track, &item.track);
-        document.add_text(self.field_schema.album, &item.album);
-        document.add_text(self.field_schema.artist, &item.artist);
-        document.add_text(self.field_schema.genre, &item.genre);
-        document.add_u64(self.field_schema.year, item.year as u64);
-        document.add_i64(self.field_schema.size, item.size);
+    pub fn record_play(&self, track_id: &str) -> Result<UserData> {
+        let user_data = self.user_data.record_play(track_id)?;
+        self.apply_user_data(track_id, &user_data)?;
+        Ok(user_data)
+    }
 
-        let date_time_value: tantivy::DateTime =
-            tantivy::DateTime::from_unix_timestamp(item.created_date / 1000);
-        document.add_date(self.field_schema.created_date, date_time_value);
+    pub fn set_rating(&self, track_id: &str, rating: u8) -> Result<UserData> {
+        let user_data = self.user_data.set_rating(track_id, rating)?;
+        self.apply_user_data(track_id, &user_data)?;
+        Ok(user_data)
+    }
 
-        let date_time_modified_value: tantivy::DateTime =
-            tantivy::DateTime::from_unix_timestamp(item.modified_date / 1000);
-        document.add_date(self.field_schema.modified_date, date_time_modified_value);
+    pub fn set_favourite(&self, track_id: &str, favourite: bool) -> Result<UserData> {
+        let user_data = self.user_data.set_favourite(track_id, favourite)?;
+        self.apply_user_data(track_id, &user_data)?;
+        Ok(user_data)
+    }
 
-        let date_time_indexed_value: tantivy::DateTime =
-            tantivy::DateTime::from_unix_timestamp(item.indexed_date / 1000);
-        document.add_date(self.field_schema.indexed_date, date_time_indexed_value);
+    /// Where playback of a long-form track stopped, see `PlayerEvent::ResumePosition`
+    pub fn set_resume_position(&self, track_id: &str, position: f64) -> Result<UserData> {
+        let user_data = self.user_data.set_resume_position(track_id, position)?;
+        self.apply_user_data(track_id, &user_data)?;
+        Ok(user_data)
+    }
 
-        let facet_album_string = format!("/album/{}", &item.album);
-        document.add_facet(self.field_schema.facets, Facet::from(&facet_album_string));
+    /// Copies the stored user data onto the indexed document so it can be sorted/filtered on
+    pub fn apply_user_data(&self, track_id: &str, user_data: &UserData) -> Result<()> {
+        self.apply_user_data_many(&[(track_id.to_string(), user_data.clone())])
+    }
 
-        let facet_artist_string = format!("/artist/{}", &item.artist);
-        document.add_facet(self.field_schema.facets, Facet::from(&facet_artist_string));
+    pub fn apply_user_data_many(&self, updates: &[(String, UserData)]) -> Result<()> {
+        let by_id: HashMap<&str, &UserData> = updates
+            .iter()
+            .map(|(track_id, user_data)| (track_id.as_str(), user_data))
+            .collect();
+        let track_ids: Vec<&str> = by_id.keys().copied().collect();
+
+        self.rewrite_documents(
+            &track_ids,
+            &[
+                self.field_schema.play_count,
+                self.field_schema.last_played,
+                self.field_schema.rating,
+                self.field_schema.favourite,
+                self.field_schema.resume_position,
+            ],
+            |track_id, document| {
+                if let Some(user_data) = by_id.get(track_id) {
+                    self.field_schema.add_user_data(document, user_data);
+                }
+            },
+        )
+    }
 
-        let facet_year_string = format!("/year/{}", &item.year);
-        document.add_facet(self.field_schema.facets, Facet::from(&facet_year_string));
+    /// Re-adds the documents of `track_ids` with the values of `fields` replaced by what `add`
+    /// sets, in a single commit. Used for data that doesn't come from the audio files.
+    pub fn rewrite_documents(
+        &self,
+        track_ids: &[&str],
+        fields: &[Field],
+        add: impl Fn(&str, &mut Document),
+    ) -> Result<()> {
+        if track_ids.is_empty() {
+            return Ok(());
+        }
 
-        for genre in &item.genres {
-            let facet_string = format!("/genre/{}", &genre);
-            document.add_facet(self.field_schema.facets, Facet::from(&facet_string));
+        let searcher = self.reader.searcher();
+        let mut writer = self.writer()?;
+        for track_id in track_ids {
+            let id_query = TermQuery::new(
+                Term::from_field_text(self.field_schema.id, track_id),
+                IndexRecordOption::Basic,
+            );
+            let doc_addresses = searcher.search(&id_query, &DocSetCollector)?;
+
+            // delete first, documents added after the delete in the same commit are kept
+            writer.delete_term(Term::from_field_text(self.field_schema.id, track_id));
+            for doc_address in doc_addresses {
+                let doc = searcher.doc(doc_address)?;
+                let document = self
+                    .field_schema
+                    .replace_fields(doc, fields, |document| add(track_id, document));
+                writer.add_document(document)?;
+            }
+        }
+        writer.commit()?;
+        self.reader.reload()?;
+        Ok(())
+    }
+
+    /// Every indexed track, for passes that need the whole library (albums, fingerprints)
+    pub fn all_tracks(&self) -> Vec<Track> {
+        let searcher = self.reader.searcher();
+        let mut tracks = vec![];
+        for (segment_ord, segment_reader) in searcher.segment_readers().iter().enumerate() {
+            for doc_id in segment_reader.doc_ids_alive() {
+                match searcher.doc(DocAddress::new(segment_ord as u32, doc_id)) {
+                    Ok(doc) => tracks.push(Track::with_document(&self.field_schema, doc)),
+                    Err(e) => error!("Error retrieving document from index: {}", e),
+                }
+            }
         }
+        tracks
+    }
+
+    /// Re-adds every document of `root` with the given status, the files themselves are untouched
+    pub fn set_root_status(&self, root: &LibraryRoot, status: TrackStatus) -> Result<usize> {
+        let searcher = self.reader.searcher();
 
-        if let Some(d) = get_duration_for_path(&item.abs_path) {
-            document.add_f64(self.field_schema.duration, d);
+        let root_query = TermQuery::new(
+            Term::from_field_text(self.field_schema.root_id, &root.id),
+            IndexRecordOption::Basic,
+        );
+        let status_query = TermQuery::new(
+            Term::from_field_u64(self.field_schema.status, status as u64),
+            IndexRecordOption::Basic,
+        );
+        let query = BooleanQuery::new(vec![
+            (Occur::Must, Box::new(root_query) as Box<dyn Query>),
+            (Occur::MustNot, Box::new(status_query)),
+        ]);
+
+        let doc_addresses = searcher.search(&query, &DocSetCollector)?;
+        if doc_addresses.is_empty() {
+            return Ok(0);
         }
 
-        self.writer.lock().unwrap().add_document(document).unwrap();
+        let mut writer = self.writer()?;
+        for doc_address in &doc_addresses {
+            match searcher.doc(*doc_address) {
+                Ok(doc) => {
+                    let id = doc
+                        .get_first(self.field_schema.id)
+                        .and_then(Value::as_text)
+                        .unwrap_or("")
+                        .to_string();
+
+                    let updated = self.field_schema.replace_fields(
+                        doc,
+                        &[self.field_schema.status],
+                        |document| document.add_u64(self.field_schema.status, status as u64),
+                    );
+
+                    writer.delete_term(Term::from_field_text(self.field_schema.id, &id));
+                    writer.add_document(updated)?;
+                }
+                Err(e) => error!("Error retrieving document from index: {}", e),
+            }
+        }
+        writer.commit()?;
+
+        info!(
+            "Marked {} track(s) in root {} as {:?}",
+            doc_addresses.len(),
+            root.id,
+            status
+        );
+        Ok(doc_addresses.len())
     }
-    pub fn initial_index_from_json(&self, json_file_path: &str) {
-        // Read JSON from file
-        let json_file_path_as_path = Path::new(json_file_path);
-        let json_file_str = read_to_string(json_file_path_as_path).expect("file not found");
-        let data: Vec<TrackJson> = serde_json::from_str(&json_file_str).unwrap();
 
-        println!("Indexing {} items", data.len());
+    pub fn initial_index_from_json(&self, json_file_path: &str) -> Result<()> {
+        let data: Vec<TrackJson> = read_tracks_json(json_file_path)?;
+
+        info!("Indexing {} items", data.len());
         for item in data.iter() {
-            self.add(item);
+            self.add(item)?;
+        }
+        info!("Total {} items indexed", data.len());
+
+        self.writer()?.commit()?;
+        Ok(())
+    }
+    /// Scans the online library roots for new files, the returned counters are also logged and,
+    /// with the `index_metrics_file` setting, written as JSON
+    pub fn index_since_last_opened(&self) -> Result<IndexMetrics> {
+        let mut metrics = IndexMetrics::start();
+        let roots = configured_roots(&*SETTINGS.read()?, BASE_AUDIO_DIRECTORY);
+
+        for root in roots {
+            if root.is_online() {
+                metrics.time("status", || self.set_root_status(&root, TrackStatus::Online))?;
+                self.index_root_since_last_opened(&root, &mut metrics)?;
+            } else {
+                warn!(
+                    "Library root {} ({}) is missing, marking its tracks offline",
+                    root.id, root.path
+                );
+                self.set_root_status(&root, TrackStatus::Offline)?;
+            }
         }
-        println!("Total {} items indexed", data.len());
 
-        self.writer.lock().unwrap().commit().unwrap();
+        let (gc_after_index, gc_batch_size, metrics_file) = {
+            let settings = SETTINGS.read()?;
+            (
+                settings.gc_after_index,
+                settings.gc_batch_size,
+                settings.index_metrics_file.clone(),
+            )
+        };
+        if gc_after_index {
+            let batch_size = if gc_batch_size > 0 {
+                gc_batch_size
+            } else {
+                DEFAULT_GC_BATCH_SIZE
+            };
+            metrics.time("gc", || self.gc(batch_size))?;
+        }
+
+        metrics.time("reload", || self.reader.reload())?;
+        metrics.time("albums", || self.rebuild_albums())?;
+        metrics.time("books", || self.rebuild_books())?;
+
+        metrics.finish();
+        if !metrics_file.is_empty() {
+            if let Err(e) = metrics.write(Path::new(&metrics_file)) {
+                warn!("Error writing index metrics to {}: {}", metrics_file, e);
+            }
+        }
+        Ok(metrics)
     }
-    pub fn index_since_last_opened(&self) {
-        let path = &norm(BASE_AUDIO_DIRECTORY).to_string();
-        let start = SystemTime::now();
+    fn index_root_since_last_opened(
+        &self,
+        root: &LibraryRoot,
+        metrics: &mut IndexMetrics,
+    ) -> Result<()> {
+        let scan_start = Instant::now();
 
         // TODO: pull from locally stored config (LAST_UPDATED)
         let last_opened: u128 = 1665410457180;
-        let now: u128 = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_millis();
-
-        println!("compare now {} to last opened {} ", &now, &last_opened);
-
-        let mut paths: Vec<String> = vec![];
-        let mut cnt = 0;
-
-        let mut generic = WalkDir::new(&path);
-        generic = generic.process_read_dir(move |_depth, _path, _read_dir_state, children| {
-            children.iter_mut().for_each(|dir_entry_result| {
-                if let Ok(dir_entry) = dir_entry_result {
-                    let modified = dir_entry
-                        .metadata()
-                        .unwrap()
-                        .modified()
-                        .unwrap()
-                        .duration_since(SystemTime::UNIX_EPOCH)
-                        .unwrap()
-                        .as_millis();
-
-                    // check if this file should be indexed (or at least checked) given the last indexed date
-                    if last_opened < modified {
-                        println!("✅ new file - please index");
-                        norm(dir_entry.path().to_str().unwrap_or(""));
-                    } else {
-                        println!("⏭️ should have already indexed this file");
+        let now: u128 = millis_since_epoch(SystemTime::now()) as u128;
+
+        debug!(
+            "Scanning root {} ({}), now {} last opened {}",
+            root.id, root.path, now, last_opened
+        );
+
+        let rules = ScanRules::from_setting(&*SETTINGS.read()?, root)?;
+
+        // jwalk reads the directories on its own threads
+        let excluded = Arc::new(AtomicU64::new(0));
+        let excluded_by_rules = excluded.clone();
+        let generic = WalkDir::new(&rules.root)
+            .follow_links(rules.follow_symlinks)
+            .process_read_dir(move |_depth, _path, _read_dir_state, children| {
+                let before = children.len();
+                rules.retain_entries(children);
+                excluded_by_rules.fetch_add((before - children.len()) as u64, Ordering::Relaxed);
+                children.iter_mut().for_each(|dir_entry_result| {
+                    if let Ok(dir_entry) = dir_entry_result {
+                        let modified = dir_entry
+                            .metadata()
+                            .ok()
+                            .and_then(|metadata| metadata.modified().ok())
+                            .map(|modified| millis_since_epoch(modified) as u128)
+                            .unwrap_or(u128::MAX);
+
+                        // check if this file should be indexed (or at least checked) given the last indexed date
+                        if last_opened < modified {
+                            trace!("Modified since last opened: {:?}", dir_entry.path());
+                        } else {
+                            trace!("Unchanged since last opened: {:?}", dir_entry.path());
+                        }
                     }
-                }
+                });
             });
-        });
-        println!("paths {:?} ", &paths);
 
         for entry in generic {
-            cnt += 1;
-            if entry.is_err() {
-                continue;
-            }
-
-            let en: DirEntry<((), ())> = entry.unwrap();
+            let en: DirEntry<((), ())> = match entry {
+                Ok(en) => en,
+                Err(e) => {
+                    warn!("Error reading directory entry: {}", e);
+                    metrics.failed(FailureReason::Walk);
+                    continue;
+                }
+            };
             let buf = en.path();
             let file_type = en.file_type();
             let is_dir = file_type.is_dir();
 
-            let path_string = buf.to_str().unwrap().to_string();
-            let name = en.file_name().to_str().unwrap();
-            let ext = file_ext(name);
-
-            if !is_dir & ALLOWED_FILE_TYPES.contains(&ext) {
-                if let Some(track) = get_track_from_path(&path_string) {
-                    self.add(&track)
+            let path_string = norm(&buf.to_string_lossy());
+
+            // scan rules have already dropped excluded and unsupported files
+            if !is_dir {
+                metrics.files_seen += 1;
+                // a single unreadable file shouldn't stop the rest of the library from indexing
+                match get_tracks_from_path(&path_string) {
+                    Ok(tracks) => {
+                        for mut track in tracks {
+                            track.root_id = root.id.clone();
+                            // the analysis would describe the whole file, not the CUE track
+                            if !track.is_virtual() {
+                                self.update_audio_features(&track);
+                                self.update_fingerprint(&track);
+                            }
+                            match self.add(&track) {
+                                Ok(true) => metrics.indexed += 1,
+                                Ok(false) => metrics.skipped += 1,
+                                Err(e) => {
+                                    error!("Error indexing {}: {}", path_string, e);
+                                    metrics.failed(FailureReason::Index);
+                                }
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        error!("Error reading {}: {}", path_string, e);
+                        metrics.failed(FailureReason::from(&e));
+                    }
                 }
-                // else {
-                //     tracks_failed.push(path_string)
-                // }
             }
         }
+        metrics.excluded += excluded.load(Ordering::Relaxed);
+        metrics.add_phase("scan", scan_start.elapsed());
 
-        // println!("Failed to index {} tracks", &tracks_failed.len());
-
-        let end = SystemTime::now();
-        println!(
-            "cost {}ms, total {} files",
-            end.duration_since(start).unwrap().as_millis(),
-            cnt
-        );
-
-        self.writer.lock().unwrap().commit().unwrap();
+        metrics.time("commit", || -> Result<()> {
+            self.writer()?.commit()?;
+            self.audio_features.save()?;
+            self.fingerprints.save()?;
+            Ok(())
+        })?;
 
         // TODO: on success, set the locally stored config for LAST_UPDATED
+        Ok(())
     }
 }
 
+pub fn read_tracks_json(json_file_path: &str) -> Result<Vec<TrackJson>> {
+    let json_file_str = read_to_string(Path::new(json_file_path))?;
+    Ok(serde_json::from_str(&json_file_str)?)
+}
+
+/// File dates before 1970 (or clocks going backwards) are treated as 0 rather than panicking
+pub fn millis_since_epoch(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+/// `default_path` (in `DATA_DIRECTORY`) moved to `data_directory`
+fn data_file(data_directory: &Path, default_path: &str) -> String {
+    let file_name = Path::new(default_path).file_name().unwrap_or_default();
+    data_directory.join(file_name).to_string_lossy().to_string()
+}
+
 #[derive(Debug, Clone)]
 pub struct FieldSchema {
     pub schema: Schema,
@@ -391,18 +660,50 @@ pub struct FieldSchema {
     pub id: Field,
     pub title: Field,
     pub abs_path: Field,
+    pub root_id: Field,
     pub size: Field,
     pub created_date: Field,
     pub modified_date: Field,
     pub indexed_date: Field,
     pub status: Field,
+    pub play_count: Field,
+    pub last_played: Field,
+    pub rating: Field,
+    pub favourite: Field,
+    pub track_loudness: Field,
+    pub track_peak: Field,
+    pub album_loudness: Field,
+    pub album_peak: Field,
+    pub tempo: Field,
+    pub spectral_centroid: Field,
+    pub chroma: Field,
+    pub lyrics: Field,
+    pub title_text: Field,
+    pub artist_text: Field,
+    pub album_text: Field,
+    pub track_text: Field,
+    pub synced_lyrics: Field,
     pub facets: Field,
     pub track: Field,
     pub artist: Field,
+    /// Every artist of the track (see `artists.rs`), `artist` is the tag as displayed
+    pub artists: Field,
     pub album: Field,
+    pub album_artist: Field,
+    pub album_id: Field,
+    pub track_number: Field,
+    pub disc_number: Field,
     pub year: Field,
     pub genre: Field,
     pub duration: Field,
+    /// Offsets in seconds of a track of a CUE sheet, only set on those
+    pub start: Field,
+    pub end: Field,
+    /// `ContentKind` as a number
+    pub content_kind: Field,
+    /// JSON of the `Chapter`s, only set on long-form tracks that have some
+    pub chapters: Field,
+    pub resume_position: Field,
 }
 
 impl FieldSchema {
@@ -426,54 +727,303 @@ impl FieldSchema {
             .set_indexed()
             .set_fast(Cardinality::SingleValue);
 
-        let id = sb.add_bytes_field("id", STORED | FAST);
+        // everything is stored so documents can be rebuilt without the audio file (e.g. offline roots)
+        let id = sb.add_text_field("id", STRING | STORED);
         let abs_path = sb.add_text_field("abs_path", STRING | STORED);
+        let root_id = sb.add_text_field("root_id", STRING | STORED | FAST);
         let size = sb.add_i64_field("size", num_options.clone());
-        let title = sb.add_text_field("title", STRING | FAST);
-        let track = sb.add_text_field("track", STRING | FAST);
-        let artist = sb.add_text_field("artist", STRING | FAST);
-        let album = sb.add_text_field("album", STRING | FAST);
+        let title = sb.add_text_field("title", STRING | STORED | FAST);
+        let track = sb.add_text_field("track", STRING | STORED | FAST);
+        let artist = sb.add_text_field("artist", STRING | STORED | FAST);
+        let artists = sb.add_text_field("artists", STRING | STORED);
+        let album = sb.add_text_field("album", STRING | STORED | FAST);
         let duration = sb.add_f64_field("duration", num_options.clone());
-        let year = sb.add_u64_field(
-            "year",
-            NumericOptions::default().set_fast(Cardinality::SingleValue),
-        );
-
-        let genre = sb.add_text_field("genre", STRING | FAST);
+        // CUE sheets (see `cue.rs`), several tracks of one file
+        let start = sb.add_f64_field("start", STORED);
+        let end = sb.add_f64_field("end", STORED);
+        // Podcasts and audiobooks (see `content_kind.rs` and `chapters.rs`)
+        let content_kind = sb.add_u64_field("content_kind", num_options.clone());
+        let chapters = sb.add_text_field("chapters", STORED);
+
+        // Albums (see `albums.rs`), `album_id` groups the tracks of one album
+        let album_artist = sb.add_text_field("album_artist", STRING | STORED | FAST);
+        let album_id = sb.add_text_field("album_id", STRING | STORED);
+        let track_number = sb.add_u64_field("track_number", num_options.clone());
+        let disc_number = sb.add_u64_field("disc_number", num_options.clone());
+        // indexed for the era range of `similar`
+        let year = sb.add_u64_field("year", num_options.clone());
+
+        let genre = sb.add_text_field("genre", STRING | STORED | FAST);
 
         // Dates
         let created_date = sb.add_date_field("created_date", date_options.clone());
         let modified_date = sb.add_date_field("modified_date", date_options.clone());
-        let indexed_date = sb.add_date_field("indexed_date", date_options);
-
-        // Status
-        let status = sb.add_u64_field("status", num_options);
-
-        // Facets (artist, album, year and genre)
+        let indexed_date = sb.add_date_field("indexed_date", date_options.clone());
+
+        // Status (see `TrackStatus`)
+        let status = sb.add_u64_field("status", num_options.clone());
+
+        // User data (see `UserDataStore`), copied onto the documents for sorting/filtering
+        let play_count = sb.add_u64_field("play_count", num_options.clone());
+        let last_played = sb.add_date_field("last_played", date_options.clone());
+        let rating = sb.add_u64_field("rating", num_options.clone());
+        let favourite = sb.add_u64_field("favourite", num_options.clone());
+        let resume_position = sb.add_f64_field("resume_position", num_options.clone());
+
+        // EBU R128 analysis (see `loudness.rs`), only set once a track has been analyzed
+        let track_loudness = sb.add_f64_field("track_loudness", num_options.clone());
+        let track_peak = sb.add_f64_field("track_peak", num_options.clone());
+        let album_loudness = sb.add_f64_field("album_loudness", num_options.clone());
+        let album_peak = sb.add_f64_field("album_peak", num_options.clone());
+
+        // Audio features (see `audio_features.rs`), computed during the scan pass
+        let tempo = sb.add_f64_field("tempo", num_options.clone());
+        let spectral_centroid = sb.add_f64_field("spectral_centroid", num_options);
+        let chroma = sb.add_f64_field("chroma", STORED);
+
+        // Analyzed (tokenized) copies of the raw fields above, searched by default and
+        // used for highlighting. Stored like the rest, or rewritten documents (user data,
+        // loudness, root status) would drop them and stop matching text searches.
+        let title_text = sb.add_text_field("title_text", TEXT | STORED);
+        let artist_text = sb.add_text_field("artist_text", TEXT | STORED);
+        let album_text = sb.add_text_field("album_text", TEXT | STORED);
+        let track_text = sb.add_text_field("track_text", TEXT | STORED);
+
+        // Lyrics are analyzed too, so single words/lines can be found
+        let lyrics = sb.add_text_field("lyrics", TEXT | STORED);
+        // JSON of the `LyricLine`s, only needed to find the timestamp of a matched line
+        let synced_lyrics = sb.add_text_field("synced_lyrics", STORED);
+
+        // Facets (artist, album, year and genre), one artist facet per name in `artists`
         let facets = sb.add_facet_field("facets", FacetOptions::default().set_stored());
 
         let schema = sb.build();
 
-        println!("FieldSchema::new end");
+        debug!("FieldSchema::new end");
 
         FieldSchema {
             schema,
             id,
             abs_path,
+            root_id,
             size,
             title,
             created_date,
             modified_date,
             indexed_date,
             status,
+            play_count,
+            last_played,
+            rating,
+            favourite,
+            track_loudness,
+            track_peak,
+            album_loudness,
+            album_peak,
+            tempo,
+            spectral_centroid,
+            chroma,
+            lyrics,
+            synced_lyrics,
+            title_text,
+            artist_text,
+            album_text,
+            track_text,
             facets,
             track,
             artist,
+            artists,
             album,
+            album_artist,
+            album_id,
+            track_number,
+            disc_number,
             year,
             genre,
             duration,
+            start,
+            end,
+            content_kind,
+            chapters,
+            resume_position,
+        }
+    }
+}
+
+impl FieldSchema {
+    pub fn to_document(
+        &self,
+        item: &TrackJson,
+        status: TrackStatus,
+        user_data: &UserData,
+    ) -> Document {
+        let mut document = Document::default();
+        document.add_text(self.id, &item.id);
+        document.add_text(self.abs_path, &item.abs_path);
+        document.add_text(self.root_id, &item.root_id);
+        document.add_text(self.title, &item.name);
+        document.add_text(self.title_text, &item.name);
+        self.add_tags(&mut document, item);
+        document.add_i64(self.size, item.size);
+        document.add_u64(self.status, status as u64);
+        self.add_user_data(&mut document, user_data);
+
+        let date_time_value: tantivy::DateTime =
+            tantivy::DateTime::from_unix_timestamp(item.created_date / 1000);
+        document.add_date(self.created_date, date_time_value);
+
+        let date_time_modified_value: tantivy::DateTime =
+            tantivy::DateTime::from_unix_timestamp(item.modified_date / 1000);
+        document.add_date(self.modified_date, date_time_modified_value);
+
+        let date_time_indexed_value: tantivy::DateTime =
+            tantivy::DateTime::from_unix_timestamp(item.indexed_date / 1000);
+        document.add_date(self.indexed_date, date_time_indexed_value);
+
+        if !item.lyrics.is_empty() {
+            document.add_text(self.lyrics, &item.lyrics);
+        }
+        if !item.synced_lyrics.is_empty() {
+            if let Ok(json) = serde_json::to_string(&item.synced_lyrics) {
+                document.add_text(self.synced_lyrics, &json);
+            }
+        }
+
+        if !item.chapters.is_empty() {
+            if let Ok(json) = serde_json::to_string(&item.chapters) {
+                document.add_text(self.chapters, &json);
+            }
+        }
+
+        if item.is_virtual() {
+            document.add_f64(self.start, item.start);
+            document.add_f64(self.end, item.end);
         }
+
+        // the JSON cache doesn't always have a duration, so fall back to reading the file
+        if item.duration > 0.0 {
+            document.add_f64(self.duration, item.duration);
+        } else if let Some(d) = get_duration_for_path(&item.abs_path) {
+            document.add_f64(self.duration, d);
+        }
+
+        document
+    }
+
+    /// The fields that come from the tags of the file, see `tag_fields`
+    pub fn add_tags(&self, document: &mut Document, item: &TrackJson) {
+        document.add_text(self.track, &item.track);
+        document.add_text(self.album, &item.album);
+        document.add_text(self.artist, &item.artist);
+        document.add_text(self.album_artist, item.album_artist_or_artist());
+        document.add_text(
+            self.album_id,
+            &album_id(item.album_artist_or_artist(), &item.album, item.year),
+        );
+        document.add_u64(self.track_number, item.track_number);
+        document.add_u64(self.disc_number, item.disc_number);
+        document.add_text(self.track_text, &item.track);
+        document.add_text(self.album_text, &item.album);
+        document.add_text(self.artist_text, &item.artist);
+        document.add_u64(self.year, item.year as u64);
+
+        let facet_album_string = format!("/album/{}", &item.album);
+        document.add_facet(self.facets, Facet::from(&facet_album_string));
+
+        for artist in item.artist_names() {
+            document.add_text(self.artists, &artist);
+            // featured artists only appear in the title, make them searchable as artists too
+            if !item.artist.contains(&artist) {
+                document.add_text(self.artist_text, &artist);
+            }
+            let facet_artist_string = format!("/artist/{}", &artist);
+            document.add_facet(self.facets, Facet::from(&facet_artist_string));
+        }
+
+        let facet_year_string = format!("/year/{}", &item.year);
+        document.add_facet(self.facets, Facet::from(&facet_year_string));
+
+        for genre in &item.genres {
+            document.add_text(self.genre, &genre);
+            let facet_string = format!("/genre/{}", &genre);
+            document.add_facet(self.facets, Facet::from(&facet_string));
+        }
+
+        // the kind mostly follows the genre, so it changes with the tags
+        let content_kind = item.content_kind();
+        document.add_u64(self.content_kind, content_kind as u64);
+        let facet_kind_string = format!("/kind/{}", content_kind.name());
+        document.add_facet(self.facets, Facet::from(&facet_kind_string));
+    }
+
+    /// Everything `add_tags` sets, for `replace_fields` when the tags change
+    pub fn tag_fields(&self) -> Vec<Field> {
+        vec![
+            self.track,
+            self.album,
+            self.artist,
+            self.artists,
+            self.album_artist,
+            self.album_id,
+            self.track_number,
+            self.disc_number,
+            self.track_text,
+            self.album_text,
+            self.artist_text,
+            self.year,
+            self.genre,
+            self.content_kind,
+            self.facets,
+        ]
+    }
+
+    pub fn add_user_data(&self, document: &mut Document, user_data: &UserData) {
+        document.add_u64(self.play_count, user_data.play_count);
+        document.add_date(
+            self.last_played,
+            tantivy::DateTime::from_unix_timestamp(user_data.last_played / 1000),
+        );
+        document.add_u64(self.rating, user_data.rating as u64);
+        document.add_u64(self.favourite, user_data.favourite as u64);
+        document.add_f64(self.resume_position, user_data.resume_position);
+    }
+
+    pub fn add_loudness(&self, document: &mut Document, loudness: &Loudness) {
+        document.add_f64(self.track_loudness, loudness.track_loudness);
+        document.add_f64(self.track_peak, loudness.track_peak);
+        if let Some(album_loudness) = loudness.album_loudness {
+            document.add_f64(self.album_loudness, album_loudness);
+        }
+        if let Some(album_peak) = loudness.album_peak {
+            document.add_f64(self.album_peak, album_peak);
+        }
+    }
+
+    pub fn add_audio_features(&self, document: &mut Document, audio_features: &AudioFeatures) {
+        document.add_f64(self.tempo, audio_features.tempo);
+        document.add_f64(self.spectral_centroid, audio_features.spectral_centroid);
+        for value in &audio_features.chroma {
+            document.add_f64(self.chroma, *value);
+        }
+    }
+
+    /// Copy of `doc` without the values of `fields`, which `add` can then set again.
+    /// tantivy can't update documents in place, so the result replaces the original.
+    pub fn replace_fields(
+        &self,
+        doc: Document,
+        fields: &[Field],
+        add: impl FnOnce(&mut Document),
+    ) -> Document {
+        let mut document: Document = doc
+            .field_values()
+            .iter()
+            .filter(|field_value| !fields.contains(&field_value.field()))
+            .cloned()
+            .collect::<Vec<FieldValue>>()
+            .into();
+        add(&mut document);
+        document
     }
 }
 
@@ -487,94 +1037,161 @@ impl Default for FieldSchema {
 pub struct Track {
     pub id: String,
     pub abs_path: String,
+    pub root_id: String,
     pub created_date: i64,
     pub modified_date: i64,
     pub indexed_date: i64,
     pub size: i64,
     pub album: String,
     pub artist: String,
+    /// `artist` split into single artists, plus the ones featured in the title
+    pub artists: Vec<String>,
+    /// Falls back to `artist` when the album artist isn't tagged
+    pub album_artist: String,
+    pub album_id: String,
+    pub track_number: u64,
+    pub disc_number: u64,
     pub genres: Vec<String>,
     pub name: String,
     pub track: String,
     pub year: u64,
     pub duration: f64,
+    /// Seconds into `abs_path` where a track of a CUE sheet starts and ends, `end` is 0 up to
+    /// the end of the file (and both are 0 for whole files)
+    pub start: f64,
+    pub end: f64,
+    pub content_kind: ContentKind,
+    pub chapters: Vec<Chapter>,
+    /// The library root of this track is currently unavailable
+    pub offline: bool,
     pub exists: bool,
+    pub play_count: u64,
+    pub last_played: i64,
+    pub rating: u8,
+    pub favourite: bool,
+    /// Seconds into a long-form track where playback stopped, 0 when it was finished
+    pub resume_position: f64,
+    /// Integrated loudness in LUFS, `None` until analyzed
+    pub track_loudness: Option<f64>,
+    pub track_peak: Option<f64>,
+    pub album_loudness: Option<f64>,
+    pub album_peak: Option<f64>,
+    /// Beats per minute, `None` until the audio features are computed
+    pub tempo: Option<f64>,
+    pub spectral_centroid: Option<f64>,
 }
 
 impl Track {
-    pub fn with_document(field_schema: &FieldSchema, doc: Document) -> Self {
-        let abs_path = doc
-            .get_first(field_schema.abs_path)
-            .and_then(Value::as_text)
-            .unwrap_or("")
-            .to_string();
-
-        let duration = get_duration_for_path(&abs_path).unwrap_or(0.0);
-
-        let track_json_option = get_track_from_path(&abs_path);
-
-        if track_json_option.is_none() {
-            return Track {
-                id: "".to_string(),
-                abs_path: "".to_string(),
-                size: 0,
-                created_date: 0,
-                modified_date: 0,
-                indexed_date: 0,
-                album: "".to_string(),
-                artist: "".to_string(),
-                name: "".to_string(),
-                track: "".to_string(),
-                year: 0,
-                genres: vec![],
-                duration: 0.0,
-                exists: false,
-            };
-        }
-        let track_json = track_json_option.unwrap();
-
-        let id = doc
-            .get_first(field_schema.id)
-            .and_then(Value::as_text)
-            .unwrap_or("")
-            .to_string();
+    /// See `TrackJson::is_virtual`
+    pub fn is_virtual(&self) -> bool {
+        self.start > 0.0 || self.end > 0.0
+    }
 
-        let genres = track_json.genres;
-        let size = track_json.size;
-        let album = track_json.album;
-        let artist = track_json.artist;
-        let name = track_json.name;
-        let track = track_json.track;
-        let year = track_json.year;
+    /// The title tag, the file name when the file has none
+    pub fn title(&self) -> &str {
+        if self.track.is_empty() || self.track == "untitled" {
+            &self.name
+        } else {
+            &self.track
+        }
+    }
 
-        let created_date = track_json.created_date;
-        let modified_date = track_json.modified_date;
+    pub fn with_document(field_schema: &FieldSchema, doc: Document) -> Self {
+        let text = |field: Field| {
+            doc.get_first(field)
+                .and_then(Value::as_text)
+                .unwrap_or("")
+                .to_string()
+        };
 
         let now_date_time: tantivy::DateTime =
             tantivy::DateTime::from_primitive(PrimitiveDateTime::MIN);
+        let date = |field: Field| {
+            doc.get_first(field)
+                .and_then(Value::as_date)
+                .unwrap_or(now_date_time)
+                .into_unix_timestamp()
+                * 1000
+        };
 
-        // Dates
-        let indexed_date: i64 = doc
-            .get_first(field_schema.indexed_date)
-            .and_then(Value::as_date)
-            .unwrap_or(now_date_time)
-            .into_unix_timestamp();
+        let abs_path = text(field_schema.abs_path);
+
+        let genres = doc
+            .get_all(field_schema.genre)
+            .filter_map(Value::as_text)
+            .map(|genre| genre.to_string())
+            .collect();
+
+        let artists = doc
+            .get_all(field_schema.artists)
+            .filter_map(Value::as_text)
+            .map(|artist| artist.to_string())
+            .collect();
+
+        let offline = doc
+            .get_first(field_schema.status)
+            .and_then(Value::as_u64)
+            .unwrap_or(TrackStatus::Online as u64)
+            == TrackStatus::Offline as u64;
+
+        // offline tracks keep their stored values, their files just can't be played
+        let exists = !offline && Path::new(&abs_path).exists();
+
+        let chapters = doc
+            .get_first(field_schema.chapters)
+            .and_then(Value::as_text)
+            .and_then(|json| serde_json::from_str(json).ok())
+            .unwrap_or_default();
+
+        let number = |field: Field| doc.get_first(field).and_then(Value::as_u64).unwrap_or(0);
+        let float = |field: Field| doc.get_first(field).and_then(Value::as_f64);
 
         Track {
-            id,
-            abs_path,
-            size,
-            created_date,
-            modified_date,
-            indexed_date,
-            album,
-            artist,
-            name,
-            track,
-            year,
+            id: text(field_schema.id),
+            root_id: text(field_schema.root_id),
+            size: doc
+                .get_first(field_schema.size)
+                .and_then(Value::as_i64)
+                .unwrap_or(0),
+            created_date: date(field_schema.created_date),
+            modified_date: date(field_schema.modified_date),
+            indexed_date: date(field_schema.indexed_date),
+            album: text(field_schema.album),
+            artist: text(field_schema.artist),
+            artists,
+            album_artist: text(field_schema.album_artist),
+            album_id: text(field_schema.album_id),
+            track_number: number(field_schema.track_number),
+            disc_number: number(field_schema.disc_number),
+            name: text(field_schema.title),
+            track: text(field_schema.track),
+            year: doc
+                .get_first(field_schema.year)
+                .and_then(Value::as_u64)
+                .unwrap_or(0),
             genres,
-            duration,
-            exists: true,
+            duration: doc
+                .get_first(field_schema.duration)
+                .and_then(Value::as_f64)
+                .unwrap_or(0.0),
+            start: float(field_schema.start).unwrap_or(0.0),
+            end: float(field_schema.end).unwrap_or(0.0),
+            content_kind: ContentKind::from_u64(number(field_schema.content_kind)),
+            chapters,
+            abs_path,
+            offline,
+            exists,
+            play_count: number(field_schema.play_count),
+            last_played: date(field_schema.last_played),
+            rating: number(field_schema.rating) as u8,
+            favourite: number(field_schema.favourite) == 1,
+            resume_position: float(field_schema.resume_position).unwrap_or(0.0),
+            track_loudness: float(field_schema.track_loudness),
+            track_peak: float(field_schema.track_peak),
+            album_loudness: float(field_schema.album_loudness),
+            album_peak: float(field_schema.album_peak),
+            tempo: float(field_schema.tempo),
+            spectral_centroid: float(field_schema.spectral_centroid),
         }
     }
 }
@@ -590,24 +1207,11 @@ impl TrackJson {
 
         let name = utils::path2name(path.clone());
 
-        let created_date = meta
-            .created()
-            .unwrap_or(SystemTime::now())
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as i64;
-
-        let modified_date = meta
-            .modified()
-            .unwrap_or(SystemTime::now())
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as i64;
-
-        let indexed_date = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as i64;
+        let created_date = millis_since_epoch(meta.created().unwrap_or(SystemTime::now()));
+
+        let modified_date = millis_since_epoch(meta.modified().unwrap_or(SystemTime::now()));
+
+        let indexed_date = millis_since_epoch(SystemTime::now());
 
         // create a unique id to check for existing index items
         let id = slugify(format!("{}-{}", &created_date, name.clone()));
@@ -615,6 +1219,9 @@ impl TrackJson {
         let track = tag.title().unwrap_or("untitled").to_string();
         let artist = tag.artist().unwrap_or("untitled").to_string();
         let album = tag.album().unwrap_or("untitled").to_string();
+        let album_artist = tag.album_artist().unwrap_or("").to_string();
+        let track_number = tag.track().unwrap_or(0) as u64;
+        let disc_number = tag.disc().unwrap_or(0) as u64;
         let genre = tag.genre().unwrap_or("").to_string();
         let year: u64 = tag.year().unwrap_or(0) as u64;
 
@@ -625,21 +1232,34 @@ impl TrackJson {
         // Genre
         let genres = genre_string_to_vec(&genre);
 
+        let artists = ArtistRules::current().track_artists(&artist, &track);
+
         TrackJson {
             id,
             abs_path,
+            root_id: DEFAULT_ROOT_ID.to_string(),
             created_date,
             modified_date,
             indexed_date,
             size,
             album,
             artist,
+            artists,
+            album_artist,
+            track_number,
+            disc_number,
             genre,
             genres,
             name,
             track,
             duration,
             year,
+            lyrics: String::new(),
+            synced_lyrics: vec![],
+            start: 0.0,
+            end: 0.0,
+            content_kind: None,
+            chapters: vec![],
         }
     }
     pub fn new(path: String, meta: Metadata, tag: Box<dyn AudioTag>) -> Self {
@@ -652,24 +1272,11 @@ impl TrackJson {
 
         let name = utils::path2name(path.clone());
 
-        let created_date = meta
-            .created()
-            .unwrap_or(SystemTime::now())
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as i64;
-
-        let modified_date = meta
-            .modified()
-            .unwrap_or(SystemTime::now())
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as i64;
-
-        let indexed_date = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as i64;
+        let created_date = millis_since_epoch(meta.created().unwrap_or(SystemTime::now()));
+
+        let modified_date = millis_since_epoch(meta.modified().unwrap_or(SystemTime::now()));
+
+        let indexed_date = millis_since_epoch(SystemTime::now());
 
         // create a unique id to check for existing index items
         let id = slugify(format!("{}-{}", &created_date, name.clone()));
@@ -677,6 +1284,9 @@ impl TrackJson {
         let track = tag.title().unwrap_or("untitled").to_string();
         let artist = tag.artist().unwrap_or("untitled").to_string();
         let album = tag.album_title().unwrap_or("untitled").to_string();
+        let album_artist = tag.album_artist().unwrap_or("").to_string();
+        let track_number = tag.track_number().unwrap_or(0) as u64;
+        let disc_number = tag.disc_number().unwrap_or(0) as u64;
         let genre = tag.genre().unwrap_or("").to_string();
         let year: u64 = tag.year().unwrap_or(0) as u64;
 
@@ -687,21 +1297,34 @@ impl TrackJson {
         // Genre
         let genres = genre_string_to_vec(&genre);
 
+        let artists = ArtistRules::current().track_artists(&artist, &track);
+
         TrackJson {
             id,
             abs_path,
+            root_id: DEFAULT_ROOT_ID.to_string(),
             created_date,
             modified_date,
             indexed_date,
             size,
             album,
             artist,
+            artists,
+            album_artist,
+            track_number,
+            disc_number,
             genre,
             genres,
             name,
             track,
             duration,
             year,
+            lyrics: String::new(),
+            synced_lyrics: vec![],
+            start: 0.0,
+            end: 0.0,
+            content_kind: None,
+            chapters: vec![],
         }
     }
 }
@@ -710,46 +1333,118 @@ impl TrackJson {
 pub struct TrackJson {
     pub id: String,
     pub abs_path: String,
+    #[serde(default)]
+    pub root_id: String,
     pub created_date: i64,
     pub modified_date: i64,
     pub indexed_date: i64,
     pub size: i64,
     pub album: String,
     pub artist: String,
+    /// Empty in caches written before multi-value artists, see `artist_names`
+    #[serde(default)]
+    pub artists: Vec<String>,
     pub genre: String,
     pub genres: Vec<String>,
     pub name: String,
     pub track: String,
     pub duration: f64,
     pub year: u64,
+    #[serde(default)]
+    pub album_artist: String,
+    #[serde(default)]
+    pub track_number: u64,
+    #[serde(default)]
+    pub disc_number: u64,
+    /// Plain text of the synced or unsynced lyrics
+    #[serde(default)]
+    pub lyrics: String,
+    #[serde(default)]
+    pub synced_lyrics: Vec<LyricLine>,
+    /// See `Track.start`
+    #[serde(default)]
+    pub start: f64,
+    #[serde(default)]
+    pub end: f64,
+    /// `None` in caches written before content kinds, see `content_kind()`
+    #[serde(default)]
+    pub content_kind: Option<ContentKind>,
+    #[serde(default)]
+    pub chapters: Vec<Chapter>,
+}
+
+impl TrackJson {
+    /// `artists`, or `artist` and the title split with the current `ArtistRules` when the
+    /// track was read before they existed
+    pub fn artist_names(&self) -> Vec<String> {
+        if self.artists.is_empty() {
+            ArtistRules::current().track_artists(&self.artist, &self.track)
+        } else {
+            self.artists.clone()
+        }
+    }
+
+    /// `content_kind`, or the kind the current `ContentRules` give the track when it was read
+    /// before they existed
+    pub fn content_kind(&self) -> ContentKind {
+        self.content_kind
+            .unwrap_or_else(|| ContentRules::current().classify(self))
+    }
+
+    /// A track of a CUE sheet, a part of `abs_path`
+    pub fn is_virtual(&self) -> bool {
+        self.start > 0.0 || self.end > 0.0
+    }
+
+    pub fn album_artist_or_artist(&self) -> &str {
+        if self.album_artist.is_empty() {
+            &self.artist
+        } else {
+            &self.album_artist
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrackStatus {
+    Online = 0,
+    Offline = 1,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum OrderType {
     Desc = 0,
     Asc = 1,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
 pub struct Filters {
     pub year_start: Option<i32>,
     pub year_end: Option<i32>,
     pub created_date_start: Option<i32>,
     pub created_date_end: Option<i32>,
+    /// Only search tracks from these library roots (empty means all roots)
+    pub root_ids: Vec<String>,
+    pub min_rating: Option<u8>,
+    pub min_play_count: Option<u64>,
+    pub favourites_only: bool,
+    /// e.g. `/genre/Rock`, values of the same facet (genre, year...) are OR'ed, different facets AND'ed
+    pub facets: Vec<String>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Faceted {
     pub tags: Vec<String>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct OrderBy {
     pub field: String,
     pub order_type: OrderType,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct DocumentSearchRequest {
     pub text: String,
     pub fields: Vec<String>,
@@ -759,6 +1454,12 @@ pub struct DocumentSearchRequest {
     pub page_number: i32,
     pub result_per_page: i32,
     pub reload: bool,
+    /// Return how every result was scored (slow, for debugging relevance)
+    #[serde(default)]
+    pub explain: bool,
+    /// `DocumentSearchResponse.cursor` of the previous page, replaces `page_number`
+    #[serde(default)]
+    pub search_after: Option<Cursor>,
 }
 
 #[derive(Serialize, Debug, Clone, PartialEq)]
@@ -779,10 +1480,25 @@ pub struct ResultScore {
     pub booster: f32,
 }
 
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct FieldHighlight {
+    /// `title`, `artist`, `album` or `track`
+    pub field: String,
+    pub fragment: String,
+    /// `fragment` with the matched words in `<b>` tags
+    pub html: String,
+}
+
 #[derive(Serialize, Clone, Debug, PartialEq)]
 pub struct DocumentResult {
     pub score: Option<ResultScore>,
     pub track: Track,
+    /// One entry per field the search text matched
+    pub highlights: Vec<FieldHighlight>,
+    /// tantivy's scoring breakdown as JSON, only with `DocumentSearchRequest.explain`
+    pub explanation: Option<String>,
+    #[serde(skip)]
+    pub doc_address: DocAddress,
 }
 
 #[derive(Serialize, Clone, Debug, PartialEq)]
@@ -796,13 +1512,20 @@ pub struct DocumentSearchResponse {
     /// Is there a next page
     pub next_page: bool,
     pub bm25: bool,
+    /// Pass as `search_after` to get the next page, set when `next_page` is
+    pub cursor: Option<Cursor>,
 }
 
-pub struct SearchResponse<'a, S> {
+pub struct SearchResponse<'a> {
     pub query: &'a str,
     pub facets_count: FacetCounts,
     pub facets: Vec<String>,
-    pub top_docs: Vec<(S, DocAddress)>,
+    /// Only the hits of the requested page
+    pub hits: Vec<Hit>,
+    /// Number of docs matching the query
+    pub total: usize,
+    pub next_page: bool,
+    pub bm25: bool,
     pub order_by: Option<OrderBy>,
     pub page_number: i32,
     pub results_per_page: i32,
@@ -0,0 +1,189 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use serde::{Deserialize, Serialize};
+use tantivy::collector::{Collector, SegmentCollector};
+use tantivy::fastfield::{f64_to_u64, DynamicFastFieldReader, FastFieldReader};
+use tantivy::schema::Field;
+use tantivy::{DocAddress, DocId, Score, SegmentOrdinal, SegmentReader};
+
+/// Where the previous page ended, pass it back in `DocumentSearchRequest.search_after` for the
+/// next one. Deep pages don't need the `offset` docs before them to be collected again.
+/// The doc address breaks ties, so a cursor is only valid until the next commit/merge.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Cursor {
+    /// Sort value of the last result (the score or the fast field value as `u64`)
+    pub value: u64,
+    pub segment_ord: u32,
+    pub doc_id: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortBy {
+    /// Best BM25 score first
+    Score,
+    /// Any numeric/date fast field, read with `u64_lenient`
+    Field { field: Field, descending: bool },
+}
+
+impl SortBy {
+    /// Higher ranks come first, whatever the direction
+    fn rank(&self, value: u64) -> u64 {
+        match self {
+            SortBy::Field {
+                descending: false, ..
+            } => !value,
+            _ => value,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Hit {
+    pub value: u64,
+    /// Only meaningful with `SortBy::Score`
+    pub score: Score,
+    pub doc_address: DocAddress,
+}
+
+impl Hit {
+    pub fn cursor(&self) -> Cursor {
+        Cursor {
+            value: self.value,
+            segment_ord: self.doc_address.segment_ord,
+            doc_id: self.doc_address.doc_id,
+        }
+    }
+}
+
+/// rank, then the lowest doc address first, then what's needed to rebuild the `Hit`
+type HitKey = (u64, Reverse<DocAddress>, u32, u64);
+
+fn to_hit(key: HitKey) -> Hit {
+    let (_, Reverse(doc_address), score_bits, value) = key;
+    Hit {
+        value,
+        score: Score::from_bits(score_bits),
+        doc_address,
+    }
+}
+
+/// Top `limit` docs for `sort_by` that come after `after` (all docs without a cursor),
+/// ties are broken by doc address so every doc shows up exactly once across pages
+pub struct SearchAfterCollector {
+    sort_by: SortBy,
+    limit: usize,
+    after: Option<Cursor>,
+}
+
+impl SearchAfterCollector {
+    pub fn new(sort_by: SortBy, limit: usize, after: Option<Cursor>) -> Self {
+        SearchAfterCollector {
+            sort_by,
+            limit,
+            after,
+        }
+    }
+}
+
+impl Collector for SearchAfterCollector {
+    type Fruit = Vec<Hit>;
+    type Child = SearchAfterSegmentCollector;
+
+    fn for_segment(
+        &self,
+        segment_local_id: SegmentOrdinal,
+        segment: &SegmentReader,
+    ) -> tantivy::Result<Self::Child> {
+        let fast_field = match self.sort_by {
+            SortBy::Score => None,
+            SortBy::Field { field, .. } => Some(segment.fast_fields().u64_lenient(field)?),
+        };
+
+        Ok(SearchAfterSegmentCollector {
+            sort_by: self.sort_by,
+            limit: self.limit,
+            after: self.after.map(|cursor| {
+                (
+                    self.sort_by.rank(cursor.value),
+                    DocAddress::new(cursor.segment_ord, cursor.doc_id),
+                )
+            }),
+            segment_ord: segment_local_id,
+            fast_field,
+            // not `with_capacity(limit)`, the limit can be far more than the matching docs
+            heap: BinaryHeap::new(),
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.sort_by == SortBy::Score
+    }
+
+    fn merge_fruits(&self, segment_fruits: Vec<Vec<Hit>>) -> tantivy::Result<Vec<Hit>> {
+        let mut keys: Vec<HitKey> = segment_fruits
+            .into_iter()
+            .flatten()
+            .map(|hit| {
+                (
+                    self.sort_by.rank(hit.value),
+                    Reverse(hit.doc_address),
+                    hit.score.to_bits(),
+                    hit.value,
+                )
+            })
+            .collect();
+        keys.sort_unstable_by(|a, b| b.cmp(a));
+        keys.truncate(self.limit);
+        Ok(keys.into_iter().map(to_hit).collect())
+    }
+}
+
+pub struct SearchAfterSegmentCollector {
+    sort_by: SortBy,
+    limit: usize,
+    /// Rank and address of the cursor
+    after: Option<(u64, DocAddress)>,
+    segment_ord: SegmentOrdinal,
+    fast_field: Option<DynamicFastFieldReader<u64>>,
+    /// Min-heap, the worst of the kept hits is on top
+    heap: BinaryHeap<Reverse<HitKey>>,
+}
+
+impl SegmentCollector for SearchAfterSegmentCollector {
+    type Fruit = Vec<Hit>;
+
+    fn collect(&mut self, doc: DocId, score: Score) {
+        let value = match &self.fast_field {
+            Some(fast_field) => fast_field.get(doc),
+            None => f64_to_u64(score as f64),
+        };
+        let rank = self.sort_by.rank(value);
+        let doc_address = DocAddress::new(self.segment_ord, doc);
+
+        if let Some((after_rank, after_address)) = self.after {
+            let on_previous_page =
+                rank > after_rank || (rank == after_rank && doc_address <= after_address);
+            if on_previous_page {
+                return;
+            }
+        }
+
+        let key = (rank, Reverse(doc_address), score.to_bits(), value);
+        if self.heap.len() < self.limit {
+            self.heap.push(Reverse(key));
+        } else if let Some(Reverse(worst)) = self.heap.peek() {
+            if key > *worst {
+                self.heap.pop();
+                self.heap.push(Reverse(key));
+            }
+        }
+    }
+
+    fn harvest(self) -> Vec<Hit> {
+        self.heap
+            .into_iter()
+            .map(|Reverse(key)| to_hit(key))
+            .collect()
+    }
+}
@@ -1,10 +1,12 @@
 use log::{error, info, trace, warn};
 
-use tantivy::collector::{Count, FacetCollector, MultiCollector, TopDocs};
+use tantivy::collector::{Count, FacetCollector, MultiCollector};
 use tantivy::schema::{Facet, IndexRecordOption, Term};
-use tantivy::{query::*, Document, Index, IndexReader, Searcher};
+use tantivy::{query::*, DocAddress, Document, Index, IndexReader, Searcher, SnippetGenerator};
 
-use crate::schema::{DocumentResult, OrderBy, ResultScore, Track, TrackJson};
+use crate::error::Result;
+use crate::schema::{DocumentResult, FieldHighlight, OrderType, ResultScore, Track};
+use crate::search_after::{Hit, SearchAfterCollector, SortBy};
 use crate::utils::{adapt_text, create_facets, get_order_field, is_valid_facet};
 
 use super::schema::{DocumentSearchRequest, DocumentSearchResponse, FieldSchema, SearchResponse};
@@ -14,12 +16,12 @@ pub fn create_query(
     search: &DocumentSearchRequest,
     field_schema: &FieldSchema,
     text: &str,
-) -> Box<dyn Query> {
+) -> Result<Box<dyn Query>> {
     let mut queries: Vec<(Occur, Box<dyn Query>)> = vec![];
     let main_q = if text.is_empty() {
         Box::new(AllQuery)
     } else {
-        parser.parse_query(text).unwrap()
+        parser.parse_query(text)?
     };
 
     queries.push((Occur::Must, main_q));
@@ -44,6 +46,61 @@ pub fn create_query(
         queries.push((Occur::Must, created_date_range_query));
     }
 
+    // By Library Root
+    if !search.filters.root_ids.is_empty() {
+        let root_queries: Vec<(Occur, Box<dyn Query>)> = search
+            .filters
+            .root_ids
+            .iter()
+            .map(|root_id| {
+                let root_term = Term::from_field_text(field_schema.root_id, root_id);
+                let root_query: Box<dyn Query> =
+                    Box::new(TermQuery::new(root_term, IndexRecordOption::Basic));
+                (Occur::Should, root_query)
+            })
+            .collect();
+        queries.push((Occur::Must, Box::new(BooleanQuery::new(root_queries))));
+    }
+
+    // By User Data
+    if let Some(min_rating) = search.filters.min_rating {
+        let rating_query = Box::new(RangeQuery::new_u64(
+            field_schema.rating,
+            (min_rating as u64)..u64::MAX,
+        ));
+        queries.push((Occur::Must, rating_query));
+    }
+
+    if let Some(min_play_count) = search.filters.min_play_count {
+        let play_count_query = Box::new(RangeQuery::new_u64(
+            field_schema.play_count,
+            min_play_count..u64::MAX,
+        ));
+        queries.push((Occur::Must, play_count_query));
+    }
+
+    if search.filters.favourites_only {
+        let favourite_term = Term::from_field_u64(field_schema.favourite, 1);
+        let favourite_query = Box::new(TermQuery::new(favourite_term, IndexRecordOption::Basic));
+        queries.push((Occur::Must, favourite_query));
+    }
+
+    // By Facet (selected in a facet pane)
+    let mut facet_filters: Vec<(&str, Vec<(Occur, Box<dyn Query>)>)> = vec![];
+    for value in search.filters.facets.iter().filter(|value| is_valid_facet(value)) {
+        let dimension = value.trim_start_matches('/').split('/').next().unwrap_or("");
+        let facet_term = Term::from_facet(field_schema.facets, &Facet::from(value.as_str()));
+        let facet_query: Box<dyn Query> =
+            Box::new(TermQuery::new(facet_term, IndexRecordOption::Basic));
+        match facet_filters.iter_mut().find(|(d, _)| *d == dimension) {
+            Some((_, dimension_queries)) => dimension_queries.push((Occur::Should, facet_query)),
+            None => facet_filters.push((dimension, vec![(Occur::Should, facet_query)])),
+        }
+    }
+    for (_, dimension_queries) in facet_filters {
+        queries.push((Occur::Must, Box::new(BooleanQuery::new(dimension_queries))));
+    }
+
     // Fields
     // search.fields.iter().for_each(|value| {
     //     let facet_key: String = format!("/{}", value);
@@ -65,13 +122,14 @@ pub fn create_query(
             queries.push((Occur::Should, Box::new(facet_term_query)));
         });
 
-    Box::new(BooleanQuery::new(queries))
+    Ok(Box::new(BooleanQuery::new(queries)))
 }
 
 fn handle_document_with_score(
     field_schema: &FieldSchema,
     doc: Document,
     score: Option<ResultScore>,
+    doc_address: DocAddress,
 ) -> DocumentResult {
     let track = Track::with_document(field_schema, doc);
 
@@ -80,88 +138,101 @@ fn handle_document_with_score(
     let doc_response = DocumentResult {
         score: score,
         track: track,
+        highlights: vec![],
+        explanation: None,
+        doc_address,
     };
     doc_response
 }
 
-pub fn convert_int_order(
-    field_schema: FieldSchema,
-    response: SearchResponse<u64>,
+/// Highlights the words of the query in every analyzed field that matched,
+/// and adds the scoring breakdown when `request.explain` is set
+fn annotate_results(
     searcher: &Searcher,
-) -> DocumentSearchResponse {
-    info!("convert_int_order query at {}:{}", line!(), file!());
-    let mut total = response.top_docs.len();
-    info!("\nfound {} total", &total);
-
-    let next_page: bool;
-    if total > response.results_per_page as usize {
-        next_page = true;
-        total = response.results_per_page as usize;
-    } else {
-        next_page = false;
+    query: &dyn Query,
+    field_schema: &FieldSchema,
+    request: &DocumentSearchRequest,
+    response: &mut DocumentSearchResponse,
+) -> Result<()> {
+    // analyzed field -> the stored (raw) field it is a copy of
+    let highlighted_fields = [
+        ("title", field_schema.title_text),
+        ("artist", field_schema.artist_text),
+        ("album", field_schema.album_text),
+        ("track", field_schema.track_text),
+    ];
+
+    let mut snippet_generators = Vec::with_capacity(highlighted_fields.len());
+    if !request.text.is_empty() {
+        for (name, field) in highlighted_fields {
+            snippet_generators.push((name, SnippetGenerator::create(searcher, query, field)?));
+        }
     }
-    let mut results = Vec::with_capacity(total);
 
-    info!("convert_int_order query at {}:{}", line!(), file!());
-    for (id, (_, doc_address)) in response.top_docs.into_iter().enumerate() {
-        match searcher.doc(doc_address) {
-            Ok(doc) => {
-                info!("convert_int_order OK query at {}:{}", line!(), file!());
-                let result = handle_document_with_score(
-                    &field_schema,
-                    doc,
-                    Some(ResultScore {
-                        bm25: 0.0,
-                        booster: id as f32,
-                    }),
-                );
-                results.push(result);
+    for result in response.results.iter_mut() {
+        for (name, snippet_generator) in &snippet_generators {
+            let text = match *name {
+                "title" => &result.track.name,
+                "artist" => &result.track.artist,
+                "album" => &result.track.album,
+                _ => &result.track.track,
+            };
+            let snippet = snippet_generator.snippet(text);
+            if !snippet.highlighted().is_empty() {
+                result.highlights.push(FieldHighlight {
+                    field: name.to_string(),
+                    fragment: snippet.fragment().to_string(),
+                    html: snippet.to_html(),
+                });
             }
-            Err(e) => error!("Error retrieving document from index: {}", e),
         }
-    }
 
-    let facets = create_facets(response.facets, response.facets_count);
-    info!("Document query at {}:{}", line!(), file!());
-    DocumentSearchResponse {
-        total: total as i32,
-        results,
-        facets,
-        page_number: response.page_number,
-        result_per_page: response.results_per_page,
-        query: response.query.to_string(),
-        next_page,
-        bm25: false,
+        if request.explain {
+            match query.explain(searcher, result.doc_address) {
+                Ok(explanation) => result.explanation = Some(explanation.to_pretty_json()),
+                // documents matched by the collectors always have an explanation
+                Err(e) => warn!("Error explaining {:?}: {}", result.doc_address, e),
+            }
+        }
     }
+
+    Ok(())
 }
 
-pub fn convert_bm25_order(
-    field_schema: FieldSchema,
-    response: SearchResponse<f32>,
+/// Same conversion for every search mode, `total` is the number of matching docs (not only
+/// the ones on this page)
+pub fn convert_hits(
+    field_schema: &FieldSchema,
+    response: SearchResponse,
     searcher: &Searcher,
 ) -> DocumentSearchResponse {
-    let mut total = response.top_docs.len();
-
-    let next_page: bool;
-    if total > response.results_per_page as usize {
-        next_page = true;
-        total = response.results_per_page as usize;
+    info!(
+        "convert_hits {} hit(s) of {} at {}:{}",
+        response.hits.len(),
+        response.total,
+        line!(),
+        file!()
+    );
+
+    // the next page continues after the last hit of this one
+    let cursor = if response.next_page {
+        response.hits.last().map(Hit::cursor)
     } else {
-        next_page = false;
-    }
-    let mut results = Vec::with_capacity(total);
-    info!("convert_bm25_order at {}:{}", line!(), file!());
+        None
+    };
 
-    for (id, (score, doc_address)) in response.top_docs.into_iter().take(total).enumerate() {
-        match searcher.doc(doc_address) {
+    let mut results = Vec::with_capacity(response.hits.len());
+    for (id, hit) in response.hits.into_iter().enumerate() {
+        match searcher.doc(hit.doc_address) {
             Ok(doc) => {
                 results.push(handle_document_with_score(
-                    &field_schema,
+                    field_schema,
                     doc,
                     Some(ResultScore {
-                        bm25: score,
+                        bm25: if response.bm25 { hit.score } else { 0.0 },
                         booster: id as f32,
                     }),
+                    hit.doc_address,
                 ));
             }
             Err(e) => error!("Error retrieving document from index: {}", e),
@@ -169,59 +240,64 @@ pub fn convert_bm25_order(
     }
 
     let facets = create_facets(response.facets, response.facets_count);
-    info!("Document query at {}:{}", line!(), file!());
     DocumentSearchResponse {
-        total: total as i32,
+        total: response.total as i32,
         results,
         facets,
         page_number: response.page_number,
         result_per_page: response.results_per_page,
         query: response.query.to_string(),
-        next_page,
-        bm25: true,
+        next_page: response.next_page,
+        bm25: response.bm25,
+        cursor,
     }
 }
 
 pub fn do_search(
-    index: Index,
-    reader: IndexReader,
-    field_schema: FieldSchema,
+    index: &Index,
+    reader: &IndexReader,
+    field_schema: &FieldSchema,
     request: &DocumentSearchRequest,
-    facet_only_flag: bool,
-) -> DocumentSearchResponse {
+    facet_only: bool,
+) -> Result<DocumentSearchResponse> {
     let query_parser = {
         let query_parser = QueryParser::for_index(
-            &index,
+            index,
             vec![
-                field_schema.title,
-                field_schema.artist,
-                field_schema.album,
-                field_schema.track,
+                field_schema.title_text,
+                field_schema.artist_text,
+                field_schema.album_text,
+                field_schema.track_text,
             ],
         );
         // query_parser.set_conjunction_by_default();
         query_parser
     };
-    println!("\nrequest.text {:?} ", &request.text);
+    trace!("request.text {:?} ", &request.text);
     let text = adapt_text(&query_parser, &request.text);
 
-    println!("text {:?} ", &text);
+    // filters apply to empty searches too, `create_query` falls back to `AllQuery`
+    let query = create_query(&query_parser, request, field_schema, &text)?;
 
-    let query = if !request.text.is_empty() {
-        create_query(&query_parser, request, &field_schema, &text)
-    } else {
-        Box::new(AllQuery) as Box<dyn Query>
+    let page_size = request.result_per_page.max(0) as usize;
+    // with a cursor the page starts right after it, `page_number` is ignored
+    let offset = match request.search_after {
+        Some(_) => 0,
+        None => page_size.saturating_mul(request.page_number.max(0) as usize),
+    };
+    trace!("result_per_page {}, offset {}", page_size, offset);
+
+    let sort_by = match get_order_field(field_schema, &request.order) {
+        Some(field) => SortBy::Field {
+            field,
+            descending: request
+                .order
+                .as_ref()
+                .map(|order| order.order_type == OrderType::Desc)
+                .unwrap_or(true),
+        },
+        None => SortBy::Score,
     };
-
-    // Offset to search from
-    let results = request.result_per_page as usize;
-    println!("\nresult_per_page {} ", results);
-
-    let offset = results * request.page_number as usize;
-    println!("offset {} ", offset);
-
-    let extra_result = results + 1;
-    let order_field = get_order_field(&field_schema, &request.order);
     let facets = request
         .faceted
         .as_ref()
@@ -234,90 +310,285 @@ pub fn do_search(
         })
         .unwrap_or_default();
 
-    println!("\nfacets {:?} ", facets);
-    println!("field_schema.facets {:?} ", field_schema.facets);
-
     let mut facet_collector = FacetCollector::for_field(field_schema.facets);
     for facet in &facets {
         match Facet::from_text(facet) {
             Ok(facet) => facet_collector.add_facet(facet),
-            Err(_) => println!("Invalid facet: {}", facet),
+            Err(_) => warn!("Invalid facet: {}", facet),
         }
     }
 
     let searcher = reader.searcher();
 
-    // TODO: use request.filters to filter by year range and date ranges
-
-    match order_field {
-        _ if !facet_only_flag => {
-            // Just a facet search
-            let facets_count = searcher.search(&query, &facet_collector).unwrap();
-            convert_bm25_order(
-                field_schema,
-                SearchResponse {
-                    facets,
-                    query: &text,
-                    top_docs: vec![],
-                    facets_count,
-                    order_by: request.order.clone(),
-                    page_number: request.page_number,
-                    results_per_page: results as i32,
-                },
-                &searcher,
-            )
+    // Facets and the total count are collected in every mode, hits unless it's facets only
+    let mut multicollector = MultiCollector::new();
+    let facet_handler = multicollector.add_collector(facet_collector);
+    let count_handler = multicollector.add_collector(Count);
+    let hits_handler = if facet_only {
+        None
+    } else {
+        // one extra hit tells if there is a next page
+        let hits_collector = SearchAfterCollector::new(
+            sort_by,
+            offset.saturating_add(page_size).saturating_add(1),
+            request.search_after,
+        );
+        Some(multicollector.add_collector(hits_collector))
+    };
+
+    let mut multi_fruit = searcher.search(&query, &multicollector)?;
+    let facets_count = facet_handler.extract(&mut multi_fruit);
+    let total = count_handler.extract(&mut multi_fruit);
+    let mut hits: Vec<Hit> = hits_handler
+        .map(|hits_handler| hits_handler.extract(&mut multi_fruit))
+        .unwrap_or_default()
+        .into_iter()
+        .skip(offset)
+        .collect();
+
+    let next_page = hits.len() > page_size;
+    hits.truncate(page_size);
+
+    let response = convert_hits(
+        field_schema,
+        SearchResponse {
+            facets,
+            query: &text,
+            hits,
+            facets_count,
+            total,
+            next_page,
+            bm25: !facet_only && sort_by == SortBy::Score,
+            order_by: request.order.clone(),
+            page_number: request.page_number,
+            results_per_page: page_size as i32,
+        },
+        &searcher,
+    );
+
+    let mut response = response;
+    annotate_results(&searcher, &*query, field_schema, request, &mut response)?;
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::loudness::Loudness;
+    use crate::schema::{Faceted, Filters, OrderBy, OrderType, SearchWatcher};
+    use crate::test_support::{index_tracks, library, search_watcher};
+
+    use super::*;
+
+    const LIBRARY_SIZE: usize = 60;
+    const PAGE_SIZE: i32 = 7;
+
+    /// Three commits, so pages and cursors span several segments
+    fn indexed_library() -> (tempfile::TempDir, SearchWatcher) {
+        let (directory, search_watcher) = search_watcher();
+        let tracks = library(LIBRARY_SIZE);
+        for chunk in tracks.chunks(LIBRARY_SIZE / 3) {
+            index_tracks(&search_watcher, chunk);
+        }
+        assert_eq!(search_watcher.reader.searcher().segment_readers().len(), 3);
+        (directory, search_watcher)
+    }
+
+    fn request(text: &str) -> DocumentSearchRequest {
+        DocumentSearchRequest {
+            text: text.to_string(),
+            fields: vec![],
+            filters: Filters::default(),
+            order: None,
+            faceted: None,
+            page_number: 0,
+            result_per_page: PAGE_SIZE,
+            reload: false,
+            explain: false,
+            search_after: None,
+        }
+    }
+
+    /// Every page of `request` through `search_after`, checking `total`, `next_page` and
+    /// `cursor` on the way
+    fn pages_after(
+        search_watcher: &SearchWatcher,
+        mut request: DocumentSearchRequest,
+    ) -> Vec<Vec<Track>> {
+        let mut pages = vec![];
+        let mut total = None;
+        loop {
+            let response = search_watcher.do_search(&request, false).unwrap();
+            // the total counts every match, not only the ones after the cursor
+            assert_eq!(*total.get_or_insert(response.total), response.total);
+            assert_eq!(response.next_page, response.cursor.is_some());
+            pages.push(response.results.into_iter().map(|result| result.track).collect());
+            if !response.next_page {
+                return pages;
+            }
+            request.search_after = response.cursor;
         }
-        Some(order_field) => {
-            let mut multicollector = MultiCollector::new();
-            let facet_handler = multicollector.add_collector(facet_collector);
-            let count_handler = multicollector.add_collector(Count);
-
-            let topdocs_collector = TopDocs::with_limit(extra_result)
-                .and_offset(offset)
-                .order_by_u64_field(order_field);
-            let topdocs_handler = multicollector.add_collector(topdocs_collector);
-            let mut multi_fruit = searcher.search(&query, &multicollector).unwrap();
-            let facets_count = facet_handler.extract(&mut multi_fruit);
-            let top_docs = topdocs_handler.extract(&mut multi_fruit);
-
-            let count = count_handler.extract(&mut multi_fruit);
-
-            convert_int_order(
-                field_schema,
-                SearchResponse {
-                    facets_count,
-                    facets,
-                    top_docs,
-                    query: &text,
-                    order_by: request.order.clone(),
-                    page_number: request.page_number,
-                    results_per_page: results as i32,
-                },
-                &searcher,
-            )
+    }
+
+    fn ids(pages: &[Vec<Track>]) -> Vec<String> {
+        pages.iter().flatten().map(|track| track.id.clone()).collect()
+    }
+
+    fn assert_each_once(ids: &[String], expected: usize) {
+        let unique: HashSet<&String> = ids.iter().collect();
+        assert_eq!(unique.len(), ids.len(), "a document showed up twice: {:?}", ids);
+        assert_eq!(ids.len(), expected);
+    }
+
+    #[test]
+    fn bm25_pages() {
+        let (_directory, search_watcher) = indexed_library();
+        // "Electric Ghost" is every 4th track
+        let matching = LIBRARY_SIZE / 4;
+
+        let first = search_watcher.do_search(&request("electric"), false).unwrap();
+        assert!(first.bm25);
+        assert_eq!(first.total, matching as i32);
+        assert_eq!(first.results.len(), PAGE_SIZE as usize);
+        assert!(first.next_page);
+        assert!(first.cursor.is_some());
+        for pair in first.results.windows(2) {
+            let score = |result: &DocumentResult| result.score.as_ref().unwrap().bm25;
+            assert!(score(&pair[0]) >= score(&pair[1]));
         }
-        None => {
-            let mut multicollector = MultiCollector::new();
-            let facet_handler = multicollector.add_collector(facet_collector);
-            let topdocs_collector = TopDocs::with_limit(extra_result).and_offset(offset);
-            let topdocs_handler = multicollector.add_collector(topdocs_collector);
-            let mut multi_fruit = searcher.search(&query, &multicollector).unwrap();
-            let facets_count = facet_handler.extract(&mut multi_fruit);
-            let top_docs = topdocs_handler.extract(&mut multi_fruit);
-
-            convert_bm25_order(
-                field_schema,
-                SearchResponse {
-                    facets_count,
-                    facets,
-                    top_docs,
-                    query: &text,
-                    order_by: request.order.clone(),
-                    page_number: request.page_number,
-                    results_per_page: results as i32,
-                },
-                &searcher,
-            )
+
+        let mut last = request("electric");
+        last.page_number = (matching as i32 - 1) / PAGE_SIZE;
+        let last = search_watcher.do_search(&last, false).unwrap();
+        assert_eq!(last.total, matching as i32);
+        assert_eq!(last.results.len(), matching % PAGE_SIZE as usize);
+        assert!(!last.next_page);
+        assert!(last.cursor.is_none());
+
+        let pages = pages_after(&search_watcher, request("electric"));
+        let ids = ids(&pages);
+        assert_each_once(&ids, matching);
+        assert!(pages
+            .iter()
+            .flatten()
+            .all(|track| track.artist == "Electric Ghost"));
+    }
+
+    #[test]
+    fn field_ordered_pages() {
+        let (_directory, search_watcher) = indexed_library();
+        let mut by_year = request("");
+        by_year.order = Some(OrderBy {
+            field: "year".to_string(),
+            order_type: OrderType::Desc,
+        });
+
+        let first = search_watcher.do_search(&by_year, false).unwrap();
+        assert!(!first.bm25);
+        assert_eq!(first.total, LIBRARY_SIZE as i32);
+        assert!(first.next_page);
+
+        let pages = pages_after(&search_watcher, by_year.clone());
+        assert_each_once(&ids(&pages), LIBRARY_SIZE);
+        let years: Vec<u64> = pages.iter().flatten().map(|track| track.year).collect();
+        assert!(years.windows(2).all(|pair| pair[0] >= pair[1]), "{:?}", years);
+
+        // page numbers give the same pages as the cursors
+        for (page_number, page) in pages.iter().enumerate() {
+            let mut numbered = by_year.clone();
+            numbered.page_number = page_number as i32;
+            let response = search_watcher.do_search(&numbered, false).unwrap();
+            let numbered_ids: Vec<String> =
+                response.results.into_iter().map(|result| result.track.id).collect();
+            assert_eq!(numbered_ids, ids(&[page.clone()]));
+        }
+
+        let mut ascending = by_year;
+        ascending.order = Some(OrderBy {
+            field: "year".to_string(),
+            order_type: OrderType::Asc,
+        });
+        let pages = pages_after(&search_watcher, ascending);
+        assert_each_once(&ids(&pages), LIBRARY_SIZE);
+        let years: Vec<u64> = pages.iter().flatten().map(|track| track.year).collect();
+        assert!(years.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", years);
+    }
+
+    #[test]
+    fn facet_only() {
+        let (_directory, search_watcher) = indexed_library();
+        let mut request = request("");
+        request.faceted = Some(Faceted {
+            tags: vec!["/genre".to_string()],
+        });
+
+        let response = search_watcher.do_search(&request, true).unwrap();
+        assert!(response.results.is_empty());
+        assert_eq!(response.total, LIBRARY_SIZE as i32);
+        assert!(!response.next_page);
+        assert!(response.cursor.is_none());
+        assert!(!response.bm25);
+
+        let genres = &response.facets["/genre"].facet_results;
+        let count = |tag: &str| {
+            genres
+                .iter()
+                .find(|result| result.tag == tag)
+                .map(|result| result.total)
+        };
+        assert_eq!(count("/genre/Rock"), Some(LIBRARY_SIZE as i32 / 2));
+        assert_eq!(count("/genre/Jazz"), Some(LIBRARY_SIZE as i32 / 2));
+    }
+
+    #[test]
+    fn facet_filter_pages() {
+        let (_directory, search_watcher) = indexed_library();
+        let mut request = request("");
+        request.filters.facets = vec!["/genre/Jazz".to_string()];
+
+        let pages = pages_after(&search_watcher, request);
+        assert_each_once(&ids(&pages), LIBRARY_SIZE / 2);
+        assert!(pages.iter().flatten().all(|track| track.genres == ["Jazz"]));
+    }
+
+    #[test]
+    fn rewritten_documents_still_match() {
+        let (_directory, search_watcher) = search_watcher();
+        index_tracks(&search_watcher, &library(8));
+        let matches = |text: &str| -> Vec<String> {
+            let response = search_watcher.do_search(&request(text), false).unwrap();
+            response.results.into_iter().map(|result| result.track.id).collect()
+        };
+        // "Song 1" by "Blue River" on "Night Blue River", file "01 Song 1.mp3"
+        let searches = ["blue", "night", "\"song 1\"", "01"];
+        for text in searches {
+            assert!(matches(text).contains(&"track-1".to_string()), "{}", text);
+        }
+
+        search_watcher.record_play("track-1").unwrap();
+        for text in searches {
+            assert!(matches(text).contains(&"track-1".to_string()), "{} after a play", text);
+        }
+
+        let loudness = Loudness {
+            track_loudness: -9.0,
+            track_peak: 0.9,
+            ..Default::default()
+        };
+        search_watcher
+            .apply_loudness_many(&[("track-1".to_string(), loudness)])
+            .unwrap();
+        for text in searches {
+            assert!(matches(text).contains(&"track-1".to_string()), "{} after loudness", text);
         }
+        let track = search_watcher
+            .all_tracks()
+            .into_iter()
+            .find(|track| track.id == "track-1")
+            .unwrap();
+        assert_eq!(track.play_count, 1);
+        assert_eq!(track.track_loudness, Some(-9.0));
     }
 }
@@ -1,11 +1,87 @@
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::path::Path;
+use std::sync::RwLock;
+
+use lazy_static::lazy_static;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{AudioError, Result};
+use crate::library_roots::LibraryRoot;
+
+pub const SETTINGS_FILE: &str = "./data/settings.json";
+
 lazy_static! {
-    pub static ref SETTINGS: RwLock<Setting> = RwLock::new(Setting::default());
+    pub static ref SETTINGS: RwLock<Setting> = RwLock::new(Setting::load(SETTINGS_FILE));
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Setting {
     theme: u8,
     lang: String,
-    exclude_index_path: Vec<String>,
+    /// folders/drives indexed together, each track stores the id of its root
+    pub library_roots: Vec<LibraryRoot>,
+    /// gitignore-style globs for paths that should never be indexed
+    pub exclude_index_path: Vec<String>,
+    /// gitignore-style globs for paths that should be indexed (empty means everything)
+    pub include_index_path: Vec<String>,
     ext: HashMap<String, String>,
+    /// allowed file extensions keyed by library root, falls back to `ALLOWED_FILE_TYPES`
+    pub root_file_types: HashMap<String, Vec<String>>,
+    /// files smaller than this (in bytes) are skipped
+    pub min_file_size: u64,
+    pub follow_symlinks: bool,
+    /// run `SearchWatcher::gc` after every incremental index
+    pub gc_after_index: bool,
+    /// number of indexed paths checked per gc batch, defaults to `DEFAULT_GC_BATCH_SIZE`
+    pub gc_batch_size: usize,
+    /// JSON file the `IndexMetrics` of every index run are written to, empty writes nothing
+    pub index_metrics_file: String,
+    /// separators of multi-artist tags (`A & B`, `A; B`), defaults to `DEFAULT_ARTIST_SEPARATORS`
+    pub artist_separators: Vec<String>,
+    /// artists whose name contains a separator, e.g. `Simon & Garfunkel`
+    pub artist_split_exceptions: Vec<String>,
+    /// genres of audiobooks and podcasts, default to `DEFAULT_AUDIOBOOK_GENRES`/`DEFAULT_PODCAST_GENRES`
+    pub audiobook_genres: Vec<String>,
+    pub podcast_genres: Vec<String>,
+    /// folder names whose files are audiobooks/podcasts, default to `DEFAULT_AUDIOBOOK_FOLDERS`
+    /// and `DEFAULT_PODCAST_FOLDERS`
+    pub audiobook_folders: Vec<String>,
+    pub podcast_folders: Vec<String>,
+    /// seconds from which other tracks count as long-form, defaults to `DEFAULT_LONG_FORM_SECS`
+    pub long_form_min_duration: u64,
+    /// write REPLAYGAIN_* tags to the audio files after `SearchWatcher::analyze_loudness`
+    pub write_replaygain_tags: bool,
+    /// used by the export encoder presets, defaults to `ffmpeg` on the `PATH`
+    pub ffmpeg_path: String,
+    /// program and arguments used to play a track from the terminal UI, `{path}` is replaced by
+    /// the path of the track. Empty uses the default application of the system.
+    pub player_command: Vec<String>,
+    /// user name and password of the Subsonic API, nobody can log in when it's empty
+    pub subsonic_users: HashMap<String, String>,
+}
+
+impl Setting {
+    /// Never fails, an invalid or missing settings file falls back to the defaults
+    pub fn load(settings_file_path: &str) -> Self {
+        Setting::try_load(settings_file_path).unwrap_or_else(|e| {
+            error!("{}, using default settings", e);
+            Setting::default()
+        })
+    }
+
+    pub fn try_load(settings_file_path: &str) -> Result<Self> {
+        let settings_path = Path::new(settings_file_path);
+        if !settings_path.exists() {
+            warn!("No settings file at {:?}, using defaults", settings_path);
+            return Ok(Setting::default());
+        }
+
+        let settings_str = read_to_string(settings_path)?;
+        serde_json::from_str(&settings_str).map_err(|e| {
+            AudioError::Config(format!("invalid settings {:?}: {}", settings_path, e))
+        })
+    }
 }
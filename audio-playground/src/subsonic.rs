@@ -0,0 +1,1111 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::net::TcpListener;
+use std::path::Path;
+use std::thread;
+
+use chrono::NaiveDateTime;
+use log::{info, warn};
+use percent_encoding::percent_decode_str;
+use quick_xml::escape::escape;
+use serde_json::{json, Map, Value};
+use slug::slugify;
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+
+use crate::albums::{find_cover, AlbumSummary};
+use crate::decode::TrackDecoder;
+use crate::error::{AudioError, Result};
+use crate::schema::{DocumentSearchRequest, Filters, SearchWatcher, Track};
+use crate::settings::SETTINGS;
+use crate::utils::file_ext;
+
+pub const DEFAULT_SUBSONIC_ADDRESS: &str = "127.0.0.1:4040";
+
+/// Subsonic API version implemented, clients check it before using newer endpoints
+const API_VERSION: &str = "1.16.1";
+
+/// Requests handled at the same time, a stream keeps its worker busy until it's sent
+const WORKERS: usize = 4;
+
+/// Left out of the artist index letters, as Subsonic does
+const IGNORED_ARTICLES: [&str; 7] = ["The", "El", "La", "Los", "Las", "Le", "Les"];
+
+/// Larger `*Count` parameters of `search3` are capped, as Subsonic does
+const MAX_SEARCH_COUNT: usize = 500;
+
+/// `error` codes of the API, returned with an HTTP 200 like every other response
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ErrorCode {
+    Generic = 0,
+    MissingParameter = 10,
+    WrongCredentials = 40,
+    NotFound = 70,
+}
+
+#[derive(Debug)]
+struct ApiError {
+    code: ErrorCode,
+    message: String,
+}
+
+impl ApiError {
+    fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ApiError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn not_found(what: &str, id: &str) -> Self {
+        ApiError::new(ErrorCode::NotFound, format!("{} not found: {}", what, id))
+    }
+}
+
+type ApiResult<T> = std::result::Result<T, ApiError>;
+
+impl From<AudioError> for ApiError {
+    fn from(e: AudioError) -> Self {
+        ApiError::new(ErrorCode::Generic, e.to_string())
+    }
+}
+
+/// Query string (and form body) parameters, a parameter can be repeated (e.g. `id` of `scrobble`)
+struct Params(Vec<(String, String)>);
+
+impl Params {
+    fn parse(encoded: &str) -> Self {
+        let decode = |value: &str| {
+            percent_decode_str(&value.replace('+', " "))
+                .decode_utf8_lossy()
+                .to_string()
+        };
+        Params(
+            encoded
+                .split('&')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| match pair.split_once('=') {
+                    Some((name, value)) => (decode(name), decode(value)),
+                    None => (decode(pair), String::new()),
+                })
+                .collect(),
+        )
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn all(&self, name: &str) -> Vec<&str> {
+        self.0
+            .iter()
+            .filter(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    fn required(&self, name: &str) -> ApiResult<&str> {
+        self.get(name).ok_or_else(|| {
+            ApiError::new(
+                ErrorCode::MissingParameter,
+                format!("Required parameter is missing: {}", name),
+            )
+        })
+    }
+
+    fn number(&self, name: &str, default: usize) -> usize {
+        self.get(name)
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(default)
+    }
+}
+
+/// Serves the index to Subsonic clients until the listener fails, users come from the
+/// `subsonic_users` setting
+pub fn serve(search_watcher: &SearchWatcher, listener: TcpListener) -> Result<()> {
+    let address = listener.local_addr()?;
+    let server = Server::from_listener(listener, None)
+        .map_err(|e| AudioError::Io(io::Error::new(io::ErrorKind::Other, e.to_string())))?;
+    info!("Subsonic API listening on {}", address);
+
+    thread::scope(|scope| {
+        for _ in 0..WORKERS {
+            scope.spawn(|| loop {
+                let request = match server.recv() {
+                    Ok(request) => request,
+                    Err(e) => {
+                        warn!("Subsonic server stopped: {}", e);
+                        return;
+                    }
+                };
+                if let Err(e) = handle_request(search_watcher, request) {
+                    warn!("Subsonic response failed: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// The API answers in XML unless `f=json`
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Xml,
+    Json,
+}
+
+fn handle_request(search_watcher: &SearchWatcher, mut request: Request) -> io::Result<()> {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let endpoint = path
+        .rsplit('/')
+        .next()
+        .unwrap_or("")
+        .trim_end_matches(".view")
+        .to_string();
+
+    let mut params = Params::parse(query);
+    // clients can also post their parameters as a form (OpenSubsonic `formPost`)
+    if *request.method() == Method::Post {
+        let mut body = String::new();
+        request.as_reader().read_to_string(&mut body)?;
+        params.0.extend(Params::parse(&body).0);
+    }
+    let format = match params.get("f") {
+        Some("json") => Format::Json,
+        _ => Format::Xml,
+    };
+
+    if let Err(e) = authenticate(&params) {
+        return request.respond(api_response(format, Err(e)));
+    }
+
+    match endpoint.as_str() {
+        "stream" | "download" => match stream_track(search_watcher, &params) {
+            Ok(track) if track.is_virtual() => match WavRange::open(&track) {
+                Ok(wav) => respond_wav(request, wav),
+                Err(e) => request.respond(api_response(format, Err(e.into()))),
+            },
+            Ok(track) => respond_file(request, &track.abs_path),
+            Err(e) => request.respond(api_response(format, Err(e))),
+        },
+        "getCoverArt" => match cover_art(search_watcher, &params) {
+            Ok((data, mime_type)) => request.respond(with_headers(
+                Response::from_data(data),
+                &[("Content-Type", mime_type)],
+            )),
+            Err(e) => request.respond(api_response(format, Err(e))),
+        },
+        _ => {
+            let payload = api_call(search_watcher, &endpoint, &params);
+            request.respond(api_response(format, payload))
+        }
+    }
+}
+
+/// Endpoints answering with a `subsonic-response`
+fn api_call(
+    search_watcher: &SearchWatcher,
+    endpoint: &str,
+    params: &Params,
+) -> ApiResult<Value> {
+    match endpoint {
+        "ping" => Ok(json!({})),
+        "getLicense" => Ok(json!({ "license": { "valid": true } })),
+        "getMusicFolders" => Ok(json!({
+            "musicFolders": { "musicFolder": [{ "id": 1, "name": "Music" }] }
+        })),
+        "getArtists" => Ok(json!({ "artists": artist_index(&search_watcher.list_albums()) })),
+        "getArtist" => {
+            let id = params.required("id")?;
+            let albums: Vec<AlbumSummary> = search_watcher
+                .list_albums()
+                .into_iter()
+                .filter(|album| artist_id(&album.album_artist) == id)
+                .collect();
+            let name = albums
+                .first()
+                .map(|album| album.album_artist.clone())
+                .ok_or_else(|| ApiError::not_found("Artist", id))?;
+
+            let mut artist = artist_json(&name, albums.len());
+            artist["album"] = albums.iter().map(album_json).collect();
+            Ok(json!({ "artist": artist }))
+        }
+        "getAlbum" => {
+            let id = params.required("id")?;
+            let album = search_watcher
+                .album(id)
+                .ok_or_else(|| ApiError::not_found("Album", id))?;
+            let track_ids: Vec<String> = album.tracks.iter().map(|track| track.id.clone()).collect();
+            let tracks = search_watcher.tracks_by_ids(&track_ids)?;
+
+            let mut album_value = album_json(&AlbumSummary::from(&album));
+            album_value["genre"] = json!(album.genres.first().cloned().unwrap_or_default());
+            album_value["created"] = json!(iso_date(album.created_date));
+            album_value["song"] = tracks.iter().map(song_json).collect();
+            Ok(json!({ "album": album_value }))
+        }
+        "search3" => search3(search_watcher, params),
+        "scrobble" => {
+            // `submission=false` is "now playing", only finished plays are recorded
+            if params.get("submission") != Some("false") {
+                for id in params.all("id") {
+                    search_watcher.record_play(id)?;
+                }
+            }
+            Ok(json!({}))
+        }
+        _ => Err(ApiError::new(
+            ErrorCode::NotFound,
+            format!("Unknown endpoint: {}", endpoint),
+        )),
+    }
+}
+
+/// `u` plus either `t` = md5(password + `s`) or the password itself in `p` (`enc:` for hex)
+fn authenticate(params: &Params) -> ApiResult<()> {
+    let user = params.required("u")?;
+    let wrong_credentials =
+        || ApiError::new(ErrorCode::WrongCredentials, "Wrong username or password");
+
+    let password = SETTINGS
+        .read()
+        .map_err(|e| ApiError::new(ErrorCode::Generic, e.to_string()))?
+        .subsonic_users
+        .get(user)
+        .cloned()
+        .ok_or_else(wrong_credentials)?;
+
+    let valid = match (params.get("t"), params.get("s"), params.get("p")) {
+        (Some(token), Some(salt), _) => {
+            let expected = format!("{:x}", md5::compute(format!("{}{}", password, salt)));
+            expected.eq_ignore_ascii_case(token)
+        }
+        (_, _, Some(given)) => match given.strip_prefix("enc:") {
+            Some(hex) => decode_hex(hex).as_deref() == Some(password.as_bytes()),
+            None => given == password,
+        },
+        _ => {
+            return Err(ApiError::new(
+                ErrorCode::MissingParameter,
+                "Required parameter is missing: t or p",
+            ))
+        }
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(wrong_credentials())
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn search3(
+    search_watcher: &SearchWatcher,
+    params: &Params,
+) -> ApiResult<Value> {
+    // clients sync the whole library with an empty (or `""`) query
+    let query = params.get("query").unwrap_or("").trim_matches('"').to_string();
+    let needle = query.to_lowercase();
+    let page = |name: &str| {
+        (
+            params.number(&format!("{}Offset", name), 0),
+            params
+                .number(&format!("{}Count", name), 20)
+                .min(MAX_SEARCH_COUNT),
+        )
+    };
+
+    let albums = search_watcher.list_albums();
+    let mut artists: BTreeMap<String, usize> = BTreeMap::new();
+    for album in &albums {
+        if album.album_artist.to_lowercase().contains(&needle) {
+            *artists.entry(album.album_artist.clone()).or_insert(0) += 1;
+        }
+    }
+    let (artist_offset, artist_count) = page("artist");
+    let artist_values: Vec<Value> = artists
+        .iter()
+        .skip(artist_offset)
+        .take(artist_count)
+        .map(|(name, album_count)| artist_json(name, *album_count))
+        .collect();
+
+    let (album_offset, album_count) = page("album");
+    let album_values: Vec<Value> = albums
+        .iter()
+        .filter(|album| {
+            album.title.to_lowercase().contains(&needle)
+                || album.album_artist.to_lowercase().contains(&needle)
+        })
+        .skip(album_offset)
+        .take(album_count)
+        .map(album_json)
+        .collect();
+
+    // an empty text matches everything, `create_query` falls back to `AllQuery`
+    let (song_offset, song_count) = page("song");
+    let response = search_watcher.do_search(
+        &DocumentSearchRequest {
+            text: query,
+            fields: vec![],
+            filters: Filters::default(),
+            order: None,
+            faceted: None,
+            page_number: 0,
+            result_per_page: song_offset
+                .saturating_add(song_count)
+                .min(i32::MAX as usize) as i32,
+            reload: false,
+            explain: false,
+            search_after: None,
+        },
+        false,
+    )?;
+    let song_values: Vec<Value> = response
+        .results
+        .iter()
+        .skip(song_offset)
+        .map(|result| song_json(&result.track))
+        .collect();
+
+    Ok(json!({
+        "searchResult3": {
+            "artist": artist_values,
+            "album": album_values,
+            "song": song_values,
+        }
+    }))
+}
+
+/// Artists are the album artists of `albums.rs`, grouped by their first letter
+fn artist_index(albums: &[AlbumSummary]) -> Value {
+    let mut album_counts: BTreeMap<String, usize> = BTreeMap::new();
+    for album in albums {
+        *album_counts.entry(album.album_artist.clone()).or_insert(0) += 1;
+    }
+
+    let mut index: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+    for (name, album_count) in &album_counts {
+        let letter = index_letter(name);
+        index
+            .entry(letter)
+            .or_default()
+            .push(artist_json(name, *album_count));
+    }
+
+    json!({
+        "ignoredArticles": IGNORED_ARTICLES.join(" "),
+        "index": index
+            .into_iter()
+            .map(|(name, artists)| json!({ "name": name, "artist": artists }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+fn index_letter(artist: &str) -> String {
+    let without_article = IGNORED_ARTICLES
+        .iter()
+        .find_map(|article| artist.strip_prefix(&format!("{} ", article)))
+        .unwrap_or(artist);
+    match without_article.chars().next() {
+        Some(c) if c.is_alphabetic() => c.to_uppercase().to_string(),
+        _ => "#".to_string(),
+    }
+}
+
+fn artist_id(name: &str) -> String {
+    format!("ar-{}", slugify(name))
+}
+
+fn artist_json(name: &str, album_count: usize) -> Value {
+    json!({
+        "id": artist_id(name),
+        "name": name,
+        "albumCount": album_count,
+    })
+}
+
+fn album_json(album: &AlbumSummary) -> Value {
+    let mut value = json!({
+        "id": album.id,
+        "name": album.title,
+        "artist": album.album_artist,
+        "artistId": artist_id(&album.album_artist),
+        "songCount": album.track_count,
+        "duration": album.total_duration.round() as u64,
+    });
+    if album.year > 0 {
+        value["year"] = json!(album.year);
+    }
+    if album.cover.is_some() {
+        value["coverArt"] = json!(album.id);
+    }
+    value
+}
+
+fn song_json(track: &Track) -> Value {
+    let suffix = file_ext(&track.abs_path).to_lowercase();
+    let mut value = json!({
+        "id": track.id,
+        "parent": track.album_id,
+        "isDir": false,
+        "title": track.track,
+        "album": track.album,
+        "artist": track.artist,
+        "albumId": track.album_id,
+        "artistId": artist_id(&track.album_artist),
+        "coverArt": track.album_id,
+        "size": track.size,
+        "contentType": content_type(&suffix),
+        "suffix": suffix,
+        "duration": track.duration.round() as u64,
+        "path": track.abs_path,
+        "type": "music",
+        "playCount": track.play_count,
+        "created": iso_date(track.created_date),
+    });
+    if track.track_number > 0 {
+        value["track"] = json!(track.track_number);
+    }
+    if track.disc_number > 0 {
+        value["discNumber"] = json!(track.disc_number);
+    }
+    if track.year > 0 {
+        value["year"] = json!(track.year);
+    }
+    if let Some(genre) = track.genres.first() {
+        value["genre"] = json!(genre);
+    }
+    if track.rating > 0 {
+        value["userRating"] = json!(track.rating);
+    }
+    if track.is_virtual() {
+        // see `WavRange`
+        value["transcodedSuffix"] = json!("wav");
+        value["transcodedContentType"] = json!(content_type("wav"));
+    }
+    value
+}
+
+fn content_type(suffix: &str) -> &'static str {
+    match suffix {
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "m4a" | "mp4" | "aac" => "audio/mp4",
+        "wav" => "audio/wav",
+        "ogg" | "opus" => "audio/ogg",
+        _ => "application/octet-stream",
+    }
+}
+
+fn iso_date(millis: i64) -> String {
+    NaiveDateTime::from_timestamp_opt(millis / 1000, 0)
+        .map(|date| date.format("%Y-%m-%dT%H:%M:%S.000Z").to_string())
+        .unwrap_or_default()
+}
+
+fn stream_track(
+    search_watcher: &SearchWatcher,
+    params: &Params,
+) -> ApiResult<Track> {
+    let id = params.required("id")?;
+    let track = search_watcher
+        .tracks_by_ids(&[id.to_string()])?
+        .pop()
+        .ok_or_else(|| ApiError::not_found("Song", id))?;
+    if !track.exists {
+        return Err(ApiError::not_found("File", &track.abs_path));
+    }
+    Ok(track)
+}
+
+/// `id` is an album id (the `coverArt` of albums and songs) or a track id
+fn cover_art(
+    search_watcher: &SearchWatcher,
+    params: &Params,
+) -> ApiResult<(Vec<u8>, String)> {
+    let id = params.required("id")?;
+    let cover = match search_watcher.album(id) {
+        Some(album) => album.cover,
+        None => search_watcher
+            .tracks_by_ids(&[id.to_string()])?
+            .pop()
+            .and_then(|track| find_cover(Path::new(&track.abs_path))),
+    };
+    let cover = cover.ok_or_else(|| ApiError::not_found("Cover art", id))?;
+    Ok(cover.read()?)
+}
+
+/// The whole file, or the part asked for with `Range: bytes=START-[END]` so clients can seek
+fn respond_file(request: Request, path: &str) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    let total = file.metadata()?.len();
+    let suffix = file_ext(path).to_lowercase();
+    let mut headers = vec![
+        ("Content-Type", content_type(&suffix).to_string()),
+        ("Accept-Ranges", "bytes".to_string()),
+    ];
+
+    let range = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Range"))
+        .and_then(|header| parse_range(header.value.as_str(), total));
+
+    match range {
+        Some((start, end)) => {
+            file.seek(SeekFrom::Start(start))?;
+            let length = end - start + 1;
+            headers.push(("Content-Range", format!("bytes {}-{}/{}", start, end, total)));
+            let response = Response::new(
+                StatusCode(206),
+                vec![],
+                file.take(length),
+                Some(length as usize),
+                None,
+            );
+            request.respond(with_headers(response, &headers))
+        }
+        None => request.respond(with_headers(Response::from_file(file), &headers)),
+    }
+}
+
+/// The whole WAV, ranges aren't supported as it's decoded while it's sent
+fn respond_wav(request: Request, wav: WavRange) -> io::Result<()> {
+    let length = wav.len();
+    let response = Response::new(StatusCode(200), vec![], wav, Some(length), None);
+    request.respond(with_headers(
+        response,
+        &[("Content-Type", content_type("wav").to_string())],
+    ))
+}
+
+/// A track of a CUE sheet as 16 bit WAV, decoded from its part of the file (which holds the
+/// whole album) while it's read. Exactly the frames announced in the header are sent, cut or
+/// padded with silence, so the length is known up front.
+struct WavRange {
+    decoder: TrackDecoder,
+    channels: usize,
+    /// Frames still to send after `buffer`
+    remaining: u64,
+    buffer: Vec<u8>,
+    offset: usize,
+    length: usize,
+}
+
+impl WavRange {
+    fn open(track: &Track) -> Result<Self> {
+        let decoder =
+            TrackDecoder::open_range(Path::new(&track.abs_path), track.start, track.end)?;
+        let format = decoder.format();
+        let duration = decoder
+            .duration()
+            .filter(|_| format.sample_rate > 0 && format.channels > 0)
+            .ok_or_else(|| AudioError::decode(&track.abs_path, "unknown length or format"))?;
+
+        let frames = (duration * format.sample_rate as f64).round() as u64;
+        let block_align = format.channels as u32 * 2;
+        let data_size = frames as u32 * block_align;
+        let mut header = Vec::with_capacity(44);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&(36 + data_size).to_le_bytes());
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&(format.channels as u16).to_le_bytes());
+        header.extend_from_slice(&format.sample_rate.to_le_bytes());
+        header.extend_from_slice(&(format.sample_rate * block_align).to_le_bytes());
+        header.extend_from_slice(&(block_align as u16).to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&data_size.to_le_bytes());
+
+        Ok(WavRange {
+            decoder,
+            channels: format.channels,
+            remaining: frames,
+            length: header.len() + data_size as usize,
+            buffer: header,
+            offset: 0,
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.length
+    }
+
+    /// Refills `buffer` with the next packet, or silence after the end of the decoded samples
+    fn fill(&mut self) -> io::Result<()> {
+        self.buffer.clear();
+        self.offset = 0;
+        let samples = self
+            .decoder
+            .next_samples()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        match samples {
+            Some((_, samples)) => {
+                let frames = ((samples.len() / self.channels) as u64).min(self.remaining);
+                for sample in &samples[..frames as usize * self.channels] {
+                    let sample = (sample * 32768.0).round().clamp(-32768.0, 32767.0) as i16;
+                    self.buffer.extend_from_slice(&sample.to_le_bytes());
+                }
+                self.remaining -= frames;
+            }
+            None => {
+                let frames = self.remaining.min(4096);
+                self.buffer.resize(frames as usize * self.channels * 2, 0);
+                self.remaining -= frames;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for WavRange {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.offset == self.buffer.len() {
+            if self.remaining == 0 {
+                return Ok(0);
+            }
+            self.fill()?;
+        }
+        let count = out.len().min(self.buffer.len() - self.offset);
+        out[..count].copy_from_slice(&self.buffer[self.offset..self.offset + count]);
+        self.offset += count;
+        Ok(count)
+    }
+}
+
+/// Inclusive byte range, `None` when it's missing or can't be served
+fn parse_range(value: &str, total: u64) -> Option<(u64, u64)> {
+    let (start, end) = value.strip_prefix("bytes=")?.split_once('-')?;
+    let start: u64 = start.trim().parse().ok()?;
+    let end: u64 = match end.trim() {
+        "" => total.checked_sub(1)?,
+        end => end.parse::<u64>().ok()?.min(total.checked_sub(1)?),
+    };
+    (start <= end).then(|| (start, end))
+}
+
+fn with_headers<R: Read>(mut response: Response<R>, headers: &[(&str, String)]) -> Response<R> {
+    for (name, value) in headers {
+        match Header::from_bytes(name.as_bytes(), value.as_bytes()) {
+            Ok(header) => response.add_header(header),
+            Err(_) => warn!("Invalid header {}: {}", name, value),
+        }
+    }
+    response
+}
+
+fn api_response(
+    format: Format,
+    payload: ApiResult<Value>,
+) -> Response<io::Cursor<Vec<u8>>> {
+    let mut body = Map::new();
+    match payload {
+        Ok(Value::Object(fields)) => {
+            body.insert("status".to_string(), json!("ok"));
+            body.extend(fields);
+        }
+        Ok(_) => {
+            body.insert("status".to_string(), json!("ok"));
+        }
+        Err(e) => {
+            body.insert("status".to_string(), json!("failed"));
+            body.insert(
+                "error".to_string(),
+                json!({ "code": e.code as u32, "message": e.message }),
+            );
+        }
+    }
+    body.insert("version".to_string(), json!(API_VERSION));
+    body.insert("type".to_string(), json!("audio-playground"));
+    body.insert("openSubsonic".to_string(), json!(true));
+
+    match format {
+        Format::Json => {
+            let json = json!({ "subsonic-response": Value::Object(body) }).to_string();
+            with_headers(
+                Response::from_data(json.into_bytes()),
+                &[("Content-Type", "application/json".to_string())],
+            )
+        }
+        Format::Xml => {
+            body.insert("xmlns".to_string(), json!("http://subsonic.org/restapi"));
+            let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>");
+            write_xml(&mut xml, "subsonic-response", &Value::Object(body));
+            with_headers(
+                Response::from_data(xml.into_bytes()),
+                &[("Content-Type", "text/xml; charset=utf-8".to_string())],
+            )
+        }
+    }
+}
+
+/// The XML form of the JSON responses: scalars become attributes, objects child elements
+/// and arrays repeated elements
+fn write_xml(out: &mut String, name: &str, value: &Value) {
+    let fields = match value {
+        Value::Object(fields) => fields,
+        scalar => {
+            out.push_str(&format!("<{0}>{1}</{0}>", name, escape(&scalar_text(scalar))));
+            return;
+        }
+    };
+
+    out.push('<');
+    out.push_str(name);
+    for (key, value) in fields {
+        if !value.is_object() && !value.is_array() {
+            out.push_str(&format!(" {}=\"{}\"", key, escape(&scalar_text(value))));
+        }
+    }
+
+    let children: Vec<(&String, &Value)> = fields
+        .iter()
+        .filter(|(_, value)| value.is_object() || value.is_array())
+        .collect();
+    if children.is_empty() {
+        out.push_str("/>");
+        return;
+    }
+    out.push('>');
+    for (key, value) in children {
+        match value {
+            Value::Array(items) => items.iter().for_each(|item| write_xml(out, key, item)),
+            child => write_xml(out, key, child),
+        }
+    }
+    out.push_str(&format!("</{}>", name));
+}
+
+fn scalar_text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::Arc;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::albums::album_id;
+    use crate::test_support::{index_tracks, search_watcher, track, write_wav};
+
+    const USER: &str = "alice";
+    const PASSWORD: &str = "sesame";
+    /// 44 bytes of header and 2000 of samples
+    const WAV_FRAMES: u32 = 1000;
+
+    /// Serves 3 album artists (one with an article) with an album each, the first track is
+    /// a real WAV file
+    fn start() -> (TempDir, Arc<SearchWatcher>, SocketAddr) {
+        SETTINGS
+            .write()
+            .unwrap()
+            .subsonic_users
+            .insert(USER.to_string(), PASSWORD.to_string());
+
+        let (directory, search_watcher) = search_watcher();
+        let wav_path = directory.path().join("01 Song 0.wav");
+        write_wav(&wav_path, 8000, 1, WAV_FRAMES);
+        let mut tracks = vec![
+            track(0, "Blue River", "Night Drive", "Rock", 2001),
+            track(1, "Blue River", "Night Drive", "Rock", 2001),
+            track(2, "Golden Rain", "Morning", "Jazz", 2005),
+            track(3, "The Silver Echo", "Echoes", "Jazz", 2010),
+        ];
+        tracks[0].abs_path = wav_path.to_string_lossy().to_string();
+        index_tracks(&search_watcher, &tracks);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let search_watcher = Arc::new(search_watcher);
+        let server = search_watcher.clone();
+        thread::spawn(move || serve(&server, listener));
+        (directory, search_watcher, address)
+    }
+
+    struct HttpResponse {
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    }
+
+    impl HttpResponse {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        }
+    }
+
+    fn get(address: SocketAddr, url: &str, headers: &[&str]) -> HttpResponse {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n", url).unwrap();
+        for header in headers {
+            write!(stream, "{}\r\n", header).unwrap();
+        }
+        write!(stream, "\r\n").unwrap();
+
+        let mut response = vec![];
+        stream.read_to_end(&mut response).unwrap();
+        let split = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .expect("headers");
+        let head = String::from_utf8_lossy(&response[..split]).to_string();
+        let mut lines = head.lines();
+        let status = lines
+            .next()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|status| status.parse().ok())
+            .expect("status line");
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+        HttpResponse {
+            status,
+            headers,
+            body: response[split + 4..].to_vec(),
+        }
+    }
+
+    /// The `subsonic-response` of `endpoint`, `credentials` are the `u` and `t`/`s`/`p` parameters
+    fn call_as(address: SocketAddr, endpoint: &str, credentials: &str, params: &str) -> Value {
+        let url = format!("/rest/{}.view?f=json&{}&{}", endpoint, credentials, params);
+        let response = get(address, &url, &[]);
+        assert_eq!(response.status, 200);
+        let mut body: Value = serde_json::from_slice(&response.body).unwrap();
+        body["subsonic-response"].take()
+    }
+
+    fn call(address: SocketAddr, endpoint: &str, params: &str) -> Value {
+        let response = call_as(address, endpoint, &format!("u={}&p={}", USER, PASSWORD), params);
+        assert_eq!(response["status"], "ok", "{}", response);
+        response
+    }
+
+    fn error_code(response: &Value) -> u64 {
+        assert_eq!(response["status"], "failed");
+        response["error"]["code"].as_u64().unwrap()
+    }
+
+    fn hex(text: &str) -> String {
+        text.bytes().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn authentication() {
+        let (_directory, _search_watcher, address) = start();
+        let token = |password: &str, salt: &str| {
+            format!("u={}&t={:x}&s={}", USER, md5::compute(format!("{}{}", password, salt)), salt)
+        };
+        let status = |credentials: &str| call_as(address, "ping", credentials, "");
+
+        assert_eq!(status(&token(PASSWORD, "c19b2d"))["status"], "ok");
+        assert_eq!(error_code(&status(&token("wrong", "c19b2d"))), 40);
+        // the token of another salt
+        let other_salt = token(PASSWORD, "c19b2d").replace("s=c19b2d", "s=000000");
+        assert_eq!(error_code(&status(&other_salt)), 40);
+
+        let encoded = |password: &str| format!("u={}&p=enc:{}", USER, hex(password));
+        assert_eq!(status(&encoded(PASSWORD))["status"], "ok");
+        assert_eq!(error_code(&status(&encoded("wrong"))), 40);
+        assert_eq!(error_code(&status(&format!("u={}&p=enc:zz", USER))), 40);
+
+        assert_eq!(status(&format!("u={}&p={}", USER, PASSWORD))["status"], "ok");
+        assert_eq!(error_code(&status(&format!("u=mallory&p={}", PASSWORD))), 40);
+        assert_eq!(error_code(&status(&format!("u={}", USER))), 10);
+        assert_eq!(error_code(&status("")), 10);
+
+        // XML unless asked for JSON
+        let url = format!("/rest/ping.view?u={}&p={}", USER, PASSWORD);
+        let xml = String::from_utf8(get(address, &url, &[]).body).unwrap();
+        assert!(xml.contains("<subsonic-response "), "{}", xml);
+        assert!(xml.contains(" status=\"ok\""), "{}", xml);
+    }
+
+    #[test]
+    fn artists_and_albums() {
+        let (_directory, _search_watcher, address) = start();
+
+        let artists = call(address, "getArtists", "")["artists"].take();
+        let index: Vec<(&str, Vec<&str>)> = artists["index"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|letter| {
+                let names = letter["artist"].as_array().unwrap();
+                let names = names.iter().map(|a| a["name"].as_str().unwrap()).collect();
+                (letter["name"].as_str().unwrap(), names)
+            })
+            .collect();
+        assert_eq!(
+            index,
+            vec![
+                ("B", vec!["Blue River"]),
+                ("G", vec!["Golden Rain"]),
+                ("S", vec!["The Silver Echo"]),
+            ]
+        );
+
+        let id = album_id("Blue River", "Night Drive", 2001);
+        let album = call(address, "getAlbum", &format!("id={}", id))["album"].take();
+        assert_eq!(album["name"], "Night Drive");
+        assert_eq!(album["artistId"], "ar-blue-river");
+        assert_eq!(album["year"], 2001);
+        let songs: Vec<&str> = album["song"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|song| song["id"].as_str().unwrap())
+            .collect();
+        assert_eq!(songs, ["track-0", "track-1"]);
+
+        let missing = call_as(address, "getAlbum", &format!("u={}&p={}", USER, PASSWORD), "id=x");
+        assert_eq!(error_code(&missing), 70);
+        let without_id = call_as(address, "getAlbum", &format!("u={}&p={}", USER, PASSWORD), "");
+        assert_eq!(error_code(&without_id), 10);
+    }
+
+    #[test]
+    fn search3() {
+        let (_directory, _search_watcher, address) = start();
+        let names = |result: &Value, kind: &str, key: &str| -> Vec<String> {
+            result["searchResult3"][kind]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|value| value[key].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        let result = call(address, "search3", "query=river");
+        assert_eq!(names(&result, "artist", "name"), ["Blue River"]);
+        assert_eq!(names(&result, "album", "name"), ["Night Drive"]);
+        let mut songs = names(&result, "song", "id");
+        songs.sort();
+        assert_eq!(songs, ["track-0", "track-1"]);
+
+        // an empty query syncs everything, a page at a time
+        let everything = call(address, "search3", "query=%22%22&songCount=3");
+        assert_eq!(names(&everything, "artist", "name").len(), 3);
+        assert_eq!(names(&everything, "album", "name").len(), 3);
+        assert_eq!(names(&everything, "song", "id").len(), 3);
+        let rest = call(address, "search3", "query=&songCount=3&songOffset=3&albumOffset=3");
+        assert_eq!(names(&rest, "song", "id").len(), 1);
+        assert!(names(&rest, "album", "name").is_empty());
+
+        // counts are capped, offsets can't overflow
+        let huge = call(address, "search3", "query=&songCount=18446744073709551615");
+        assert_eq!(names(&huge, "song", "id").len(), 4);
+        let past = call(
+            address,
+            "search3",
+            "query=&songCount=100&songOffset=18446744073709551615",
+        );
+        assert!(names(&past, "song", "id").is_empty());
+    }
+
+    #[test]
+    fn stream_ranges() {
+        let (directory, _search_watcher, address) = start();
+        let file = fs::read(directory.path().join("01 Song 0.wav")).unwrap();
+        let url = format!("/rest/stream.view?u={}&p={}&id=track-0", USER, PASSWORD);
+
+        let whole = get(address, &url, &[]);
+        assert_eq!(whole.status, 200);
+        assert_eq!(whole.header("Content-Type"), Some("audio/wav"));
+        assert_eq!(whole.header("Accept-Ranges"), Some("bytes"));
+        assert_eq!(whole.body, file);
+
+        let part = get(address, &url, &["Range: bytes=100-199"]);
+        assert_eq!(part.status, 206);
+        let total = 44 + 2 * WAV_FRAMES;
+        assert_eq!(
+            part.header("Content-Range"),
+            Some(format!("bytes 100-199/{}", total).as_str())
+        );
+        assert_eq!(part.body, &file[100..200]);
+
+        let end = get(address, &url, &["Range: bytes=2000-"]);
+        assert_eq!(end.status, 206);
+        assert_eq!(end.body, &file[2000..]);
+
+        let url = format!("/rest/stream.view?u={}&p={}&id=nothing&f=json", USER, PASSWORD);
+        let missing: Value = serde_json::from_slice(&get(address, &url, &[]).body).unwrap();
+        assert_eq!(error_code(&missing["subsonic-response"]), 70);
+    }
+
+    #[test]
+    fn stream_cue_track() {
+        let (directory, search_watcher, address) = start();
+        // 2s of which the track is the second quarter to the third
+        let path = directory.path().join("Live.wav");
+        write_wav(&path, 8000, 2, 16000);
+        let mut cue_track = track(4, "Blue River", "Live", "Rock", 2001);
+        cue_track.abs_path = path.to_string_lossy().to_string();
+        cue_track.start = 0.5;
+        cue_track.end = 1.5;
+        cue_track.duration = 1.0;
+        index_tracks(&search_watcher, &[cue_track]);
+
+        let url = format!("/rest/stream.view?u={}&p={}&id=track-4", USER, PASSWORD);
+        // ranges can't be served, the whole track is sent
+        let response = get(address, &url, &["Range: bytes=100-199"]);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Content-Type"), Some("audio/wav"));
+        assert_eq!(response.body.len(), 44 + 8000 * 2 * 2);
+        assert_eq!(&response.body[..4], b"RIFF");
+        let samples: Vec<i16> = response.body[44..]
+            .chunks(2)
+            .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]))
+            .collect();
+        for (index, pair) in samples.chunks(2).enumerate() {
+            let expected = ((4000 + index) % 1000) as i16;
+            assert_eq!(pair, [expected, expected], "frame {}", index);
+        }
+
+        let song = call(address, "search3", "query=live")["searchResult3"]["song"][0].take();
+        assert_eq!(song["id"], "track-4");
+        assert_eq!(song["transcodedSuffix"], "wav");
+    }
+
+    #[test]
+    fn scrobble() {
+        let (_directory, search_watcher, address) = start();
+
+        call(address, "scrobble", "id=track-1&id=track-2");
+        call(address, "scrobble", "id=track-3&submission=false");
+
+        assert_eq!(search_watcher.user_data.get("track-1").play_count, 1);
+        assert_eq!(search_watcher.user_data.get("track-2").play_count, 1);
+        assert_eq!(search_watcher.user_data.get("track-3").play_count, 0);
+        assert_eq!(search_watcher.user_data.get("track-0").play_count, 0);
+    }
+}
@@ -0,0 +1,712 @@
+use std::collections::BTreeSet;
+use std::io;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::execute;
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use tui::backend::{Backend, CrosstermBackend};
+use tui::layout::{Constraint, Direction, Layout, Rect};
+use tui::style::{Color, Modifier, Style};
+use tui::text::{Span, Spans};
+use tui::widgets::{
+    Block, Borders, Cell, Clear, List, ListItem, ListState, Paragraph, Row, Table, TableState,
+    Wrap,
+};
+use tui::{Frame, Terminal};
+
+use crate::error::{AudioError, Result};
+use crate::schema::{
+    DocumentSearchRequest, Faceted, FacetResult, Filters, OrderBy, OrderType, SearchWatcher, Track,
+};
+use crate::settings::SETTINGS;
+
+const RESULTS_PER_PAGE: i32 = 100;
+
+struct Column {
+    title: &'static str,
+    width: Constraint,
+    /// Sorted by the index when set (see `get_order_field`), otherwise only the page is sorted
+    order_field: Option<&'static str>,
+}
+
+const COLUMNS: [Column; 7] = [
+    Column {
+        title: "Title",
+        width: Constraint::Percentage(30),
+        order_field: None,
+    },
+    Column {
+        title: "Artist",
+        width: Constraint::Percentage(22),
+        order_field: None,
+    },
+    Column {
+        title: "Album",
+        width: Constraint::Percentage(24),
+        order_field: None,
+    },
+    Column {
+        title: "Year",
+        width: Constraint::Length(6),
+        order_field: Some("year"),
+    },
+    Column {
+        title: "Time",
+        width: Constraint::Length(7),
+        order_field: Some("duration"),
+    },
+    Column {
+        title: "Rating",
+        width: Constraint::Length(7),
+        order_field: Some("rating"),
+    },
+    Column {
+        title: "Plays",
+        width: Constraint::Length(6),
+        order_field: Some("play_count"),
+    },
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Focus {
+    Search,
+    Facet(usize),
+    Results,
+}
+
+struct FacetPane {
+    title: &'static str,
+    /// e.g. `/genre`
+    tag: &'static str,
+    entries: Vec<FacetResult>,
+    state: ListState,
+    selected: BTreeSet<String>,
+}
+
+impl FacetPane {
+    fn new(title: &'static str, tag: &'static str) -> Self {
+        FacetPane {
+            title,
+            tag,
+            entries: vec![],
+            state: ListState::default(),
+            selected: BTreeSet::new(),
+        }
+    }
+}
+
+struct App<'a> {
+    search_watcher: &'a SearchWatcher,
+    text: String,
+    focus: Focus,
+    facet_panes: Vec<FacetPane>,
+    results: Vec<Track>,
+    total: i32,
+    page_number: i32,
+    next_page: bool,
+    table_state: TableState,
+    /// Index in `COLUMNS`, `None` is by relevance
+    sort_column: Option<usize>,
+    descending: bool,
+    show_detail: bool,
+    status: String,
+    /// Started by `play_external`, reaped once they exit
+    players: Vec<Child>,
+}
+
+/// Full screen browser over `SearchWatcher::do_search`, returns when the user quits
+pub fn run(search_watcher: &SearchWatcher) -> Result<()> {
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+
+    let mut app = App::new(search_watcher);
+    app.search();
+    let result = app.event_loop(&mut terminal);
+
+    // restore the terminal even when the loop failed
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+    result
+}
+
+impl<'a> App<'a> {
+    fn new(search_watcher: &'a SearchWatcher) -> Self {
+        App {
+            search_watcher,
+            text: String::new(),
+            focus: Focus::Search,
+            facet_panes: vec![
+                FacetPane::new("Genre", "/genre"),
+                FacetPane::new("Year", "/year"),
+                FacetPane::new("Artist", "/artist"),
+                FacetPane::new("Album", "/album"),
+            ],
+            results: vec![],
+            total: 0,
+            page_number: 0,
+            next_page: false,
+            table_state: TableState::default(),
+            sort_column: None,
+            descending: true,
+            show_detail: false,
+            status: String::new(),
+            players: vec![],
+        }
+    }
+
+    fn event_loop<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> Result<()> {
+        loop {
+            self.reap_players();
+            terminal.draw(|frame| self.draw(frame))?;
+
+            if !event::poll(Duration::from_millis(250))? {
+                continue;
+            }
+            if let Event::Key(key) = event::read()? {
+                // Windows also reports key releases
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                if !self.handle_key(key) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Waits for the players that exited, so they don't linger as zombie processes
+    fn reap_players(&mut self) {
+        self.players
+            .retain_mut(|player| matches!(player.try_wait(), Ok(None)));
+    }
+
+    fn request(&self) -> DocumentSearchRequest {
+        let order = self
+            .sort_column
+            .and_then(|column| COLUMNS[column].order_field)
+            .map(|field| OrderBy {
+                field: field.to_string(),
+                order_type: if self.descending {
+                    OrderType::Desc
+                } else {
+                    OrderType::Asc
+                },
+            });
+
+        DocumentSearchRequest {
+            text: self.text.clone(),
+            fields: vec![],
+            filters: Filters {
+                facets: self
+                    .facet_panes
+                    .iter()
+                    .flat_map(|pane| pane.selected.iter().cloned())
+                    .collect(),
+                ..Default::default()
+            },
+            order,
+            faceted: Some(Faceted {
+                tags: self
+                    .facet_panes
+                    .iter()
+                    .map(|pane| pane.tag.to_string())
+                    .collect(),
+            }),
+            page_number: self.page_number,
+            result_per_page: RESULTS_PER_PAGE,
+            reload: false,
+            explain: false,
+            search_after: None,
+        }
+    }
+
+    /// Runs the search again, called on every change of the text, facets, sort or page
+    fn search(&mut self) {
+        let response = match self.search_watcher.do_search(&self.request(), false) {
+            Ok(response) => response,
+            Err(e) => {
+                self.status = format!("Search failed: {}", e);
+                return;
+            }
+        };
+
+        self.total = response.total;
+        self.next_page = response.next_page;
+        self.results = response
+            .results
+            .into_iter()
+            .map(|result| result.track)
+            .collect();
+        self.sort_page();
+
+        for pane in self.facet_panes.iter_mut() {
+            pane.entries = response
+                .facets
+                .get(pane.tag)
+                .map(|facet_results| facet_results.facet_results.clone())
+                .unwrap_or_default();
+            // selected values stay visible even when the other filters leave no tracks for them
+            for selected in &pane.selected {
+                if !pane.entries.iter().any(|entry| &entry.tag == selected) {
+                    pane.entries.push(FacetResult {
+                        tag: selected.clone(),
+                        total: 0,
+                    });
+                }
+            }
+            let selected = pane.state.selected().unwrap_or(0);
+            pane.state.select(if pane.entries.is_empty() {
+                None
+            } else {
+                Some(selected.min(pane.entries.len() - 1))
+            });
+        }
+
+        self.table_state
+            .select(if self.results.is_empty() { None } else { Some(0) });
+        self.status.clear();
+    }
+
+    /// Text columns can't be sorted by the index, they sort the current page
+    fn sort_page(&mut self) {
+        let column = match self.sort_column {
+            Some(column) if COLUMNS[column].order_field.is_none() => column,
+            _ => return,
+        };
+        self.results.sort_by(|a, b| {
+            let (a, b) = (cell_text(a, column).to_lowercase(), cell_text(b, column).to_lowercase());
+            if self.descending {
+                b.cmp(&a)
+            } else {
+                a.cmp(&b)
+            }
+        });
+    }
+
+    fn selected_track(&self) -> Option<&Track> {
+        self.table_state
+            .selected()
+            .and_then(|selected| self.results.get(selected))
+    }
+
+    /// `false` to quit
+    fn handle_key(&mut self, key: KeyEvent) -> bool {
+        if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
+            return false;
+        }
+
+        if self.show_detail {
+            if matches!(key.code, KeyCode::Esc | KeyCode::Enter | KeyCode::Char('q')) {
+                self.show_detail = false;
+            }
+            return true;
+        }
+
+        match key.code {
+            KeyCode::Tab => {
+                self.focus = match self.focus {
+                    Focus::Search => Focus::Facet(0),
+                    Focus::Facet(i) if i + 1 < self.facet_panes.len() => Focus::Facet(i + 1),
+                    Focus::Facet(_) => Focus::Results,
+                    Focus::Results => Focus::Search,
+                };
+                return true;
+            }
+            KeyCode::BackTab => {
+                self.focus = match self.focus {
+                    Focus::Search => Focus::Results,
+                    Focus::Facet(0) => Focus::Search,
+                    Focus::Facet(i) => Focus::Facet(i - 1),
+                    Focus::Results => Focus::Facet(self.facet_panes.len() - 1),
+                };
+                return true;
+            }
+            KeyCode::Esc => return false,
+            _ => {}
+        }
+
+        match self.focus {
+            Focus::Search => match key.code {
+                KeyCode::Char(c) => {
+                    self.text.push(c);
+                    self.page_number = 0;
+                    self.search();
+                }
+                KeyCode::Backspace => {
+                    self.text.pop();
+                    self.page_number = 0;
+                    self.search();
+                }
+                KeyCode::Enter | KeyCode::Down => self.focus = Focus::Results,
+                _ => {}
+            },
+            Focus::Facet(i) => {
+                let pane = &mut self.facet_panes[i];
+                match key.code {
+                    KeyCode::Up => move_selection(&mut pane.state, pane.entries.len(), -1),
+                    KeyCode::Down => move_selection(&mut pane.state, pane.entries.len(), 1),
+                    KeyCode::Enter | KeyCode::Char(' ') => {
+                        if let Some(entry) = pane.state.selected().and_then(|s| pane.entries.get(s)) {
+                            let tag = entry.tag.clone();
+                            if !pane.selected.remove(&tag) {
+                                pane.selected.insert(tag);
+                            }
+                            self.page_number = 0;
+                            self.search();
+                        }
+                    }
+                    KeyCode::Char('c') => {
+                        pane.selected.clear();
+                        self.page_number = 0;
+                        self.search();
+                    }
+                    KeyCode::Char('q') => return false,
+                    _ => {}
+                }
+            }
+            Focus::Results => match key.code {
+                KeyCode::Up => move_table_selection(&mut self.table_state, self.results.len(), -1),
+                KeyCode::Down => move_table_selection(&mut self.table_state, self.results.len(), 1),
+                KeyCode::PageDown | KeyCode::Right if self.next_page => {
+                    self.page_number += 1;
+                    self.search();
+                }
+                KeyCode::PageUp | KeyCode::Left if self.page_number > 0 => {
+                    self.page_number -= 1;
+                    self.search();
+                }
+                KeyCode::Enter => self.show_detail = self.selected_track().is_some(),
+                KeyCode::Char('s') => {
+                    // relevance, then every column in turn
+                    self.sort_column = match self.sort_column {
+                        None => Some(0),
+                        Some(column) if column + 1 < COLUMNS.len() => Some(column + 1),
+                        Some(_) => None,
+                    };
+                    self.page_number = 0;
+                    self.search();
+                }
+                KeyCode::Char('r') => {
+                    self.descending = !self.descending;
+                    self.page_number = 0;
+                    self.search();
+                }
+                KeyCode::Char('p') => self.play_selected(),
+                KeyCode::Char('/') => self.focus = Focus::Search,
+                KeyCode::Char('q') => return false,
+                _ => {}
+            },
+        }
+        true
+    }
+
+    fn play_selected(&mut self) {
+        let track = match self.selected_track() {
+            Some(track) => track.clone(),
+            None => return,
+        };
+        if !track.exists {
+            self.status = format!("{} is missing or offline", track.abs_path);
+            return;
+        }
+
+        match play_external(&track.abs_path) {
+            Ok(player) => {
+                self.players.push(player);
+                self.status = format!("Playing {} - {}", track.artist, track.title());
+                if let Err(e) = self.search_watcher.record_play(&track.id) {
+                    self.status = format!("Playing, but the play wasn't recorded: {}", e);
+                }
+            }
+            Err(e) => self.status = format!("Can't play {}: {}", track.abs_path, e),
+        }
+    }
+
+    fn draw<B: Backend>(&mut self, frame: &mut Frame<B>) {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Length(3),
+                Constraint::Min(5),
+                Constraint::Length(1),
+            ])
+            .split(frame.size());
+        let columns = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Length(30), Constraint::Min(40)])
+            .split(rows[1]);
+
+        self.draw_search(frame, rows[0]);
+        self.draw_facets(frame, columns[0]);
+        self.draw_results(frame, columns[1]);
+
+        let help = if self.status.is_empty() {
+            "Tab: next pane  Enter: select/detail  s: sort  r: reverse  p: play  \u{2190}/\u{2192}: page  Esc: quit"
+                .to_string()
+        } else {
+            self.status.clone()
+        };
+        frame.render_widget(
+            Paragraph::new(help).style(Style::default().fg(Color::DarkGray)),
+            rows[2],
+        );
+
+        if self.show_detail {
+            if let Some(track) = self.selected_track() {
+                draw_detail(frame, track);
+            }
+        }
+    }
+
+    fn draw_search<B: Backend>(&self, frame: &mut Frame<B>, area: Rect) {
+        let title = format!(" Search ({} tracks) ", self.total);
+        let search = Paragraph::new(self.text.as_str())
+            .block(Block::default().borders(Borders::ALL).title(title))
+            .style(focus_style(self.focus == Focus::Search));
+        frame.render_widget(search, area);
+        if self.focus == Focus::Search {
+            frame.set_cursor(area.x + 1 + self.text.chars().count() as u16, area.y + 1);
+        }
+    }
+
+    fn draw_facets<B: Backend>(&mut self, frame: &mut Frame<B>, area: Rect) {
+        let constraints: Vec<Constraint> = self
+            .facet_panes
+            .iter()
+            .map(|_| Constraint::Ratio(1, self.facet_panes.len() as u32))
+            .collect();
+        let areas = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(constraints)
+            .split(area);
+
+        for (i, pane) in self.facet_panes.iter_mut().enumerate() {
+            let prefix = format!("{}/", pane.tag);
+            let items: Vec<ListItem> = pane
+                .entries
+                .iter()
+                .map(|entry| {
+                    let mark = if pane.selected.contains(&entry.tag) {
+                        "[x]"
+                    } else {
+                        "[ ]"
+                    };
+                    let label = entry.tag.strip_prefix(&prefix).unwrap_or(&entry.tag);
+                    ListItem::new(format!("{} {} ({})", mark, label, entry.total))
+                })
+                .collect();
+            let list = List::new(items)
+                .block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .title(format!(" {} ", pane.title))
+                        .border_style(focus_style(self.focus == Focus::Facet(i))),
+                )
+                .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+            frame.render_stateful_widget(list, areas[i], &mut pane.state);
+        }
+    }
+
+    fn draw_results<B: Backend>(&mut self, frame: &mut Frame<B>, area: Rect) {
+        let header_cells = COLUMNS.iter().enumerate().map(|(i, column)| {
+            let arrow = match (self.sort_column == Some(i), self.descending) {
+                (true, true) => " \u{25bc}",
+                (true, false) => " \u{25b2}",
+                _ => "",
+            };
+            Cell::from(format!("{}{}", column.title, arrow))
+        });
+        let header = Row::new(header_cells).style(Style::default().add_modifier(Modifier::BOLD));
+
+        let rows = self.results.iter().map(|track| {
+            let style = if track.exists {
+                Style::default()
+            } else {
+                Style::default().fg(Color::DarkGray)
+            };
+            Row::new((0..COLUMNS.len()).map(|column| Cell::from(cell_text(track, column))))
+                .style(style)
+        });
+
+        let sorted_by = match self.sort_column {
+            Some(column) => COLUMNS[column].title,
+            None => "relevance",
+        };
+        let title = format!(
+            " Results, page {}{} (sorted by {}) ",
+            self.page_number + 1,
+            if self.next_page { "+" } else { "" },
+            sorted_by
+        );
+        let widths: Vec<Constraint> = COLUMNS.iter().map(|column| column.width).collect();
+        let table = Table::new(rows)
+            .header(header)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(title)
+                    .border_style(focus_style(self.focus == Focus::Results)),
+            )
+            .widths(&widths)
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(table, area, &mut self.table_state);
+    }
+}
+
+fn draw_detail<B: Backend>(frame: &mut Frame<B>, track: &Track) {
+    let area = centered(frame.size(), 70, 60);
+    let optional = |value: Option<f64>, unit: &str| {
+        value
+            .map(|value| format!("{:.1} {}", value, unit))
+            .unwrap_or_else(|| "-".to_string())
+    };
+    let line = |label: &str, value: String| {
+        Spans::from(vec![
+            Span::styled(
+                format!("{:<14}", label),
+                Style::default().add_modifier(Modifier::BOLD),
+            ),
+            Span::raw(value),
+        ])
+    };
+
+    let lines = vec![
+        line("Title", track.title().to_string()),
+        line("Artist", track.artist.clone()),
+        line("Album artist", track.album_artist.clone()),
+        line("Album", track.album.clone()),
+        line("Disc / track", format!("{} / {}", track.disc_number, track.track_number)),
+        line("Year", track.year.to_string()),
+        line("Genres", track.genres.join(", ")),
+        line("Duration", format_duration(track.duration)),
+        line("Size", format!("{:.1} MB", track.size as f64 / 1_000_000.0)),
+        line("Rating", "\u{2605}".repeat(track.rating as usize)),
+        line("Plays", track.play_count.to_string()),
+        line("Favourite", if track.favourite { "yes" } else { "no" }.to_string()),
+        line("Loudness", optional(track.track_loudness, "LUFS")),
+        line("Tempo", optional(track.tempo, "BPM")),
+        line("Path", track.abs_path.clone()),
+        line(
+            "Status",
+            if track.offline {
+                "offline".to_string()
+            } else if track.exists {
+                "online".to_string()
+            } else {
+                "missing".to_string()
+            },
+        ),
+    ];
+
+    let detail = Paragraph::new(lines)
+        .block(Block::default().borders(Borders::ALL).title(" Track "))
+        .wrap(Wrap { trim: true });
+    frame.render_widget(Clear, area);
+    frame.render_widget(detail, area);
+}
+
+/// Starts the `player_command` setting (or the default application) without waiting for it,
+/// the caller has to reap the returned process
+pub fn play_external(path: &str) -> Result<Child> {
+    let player_command = SETTINGS.read()?.player_command.clone();
+    let mut command = if let Some((program, args)) = player_command.split_first() {
+        let mut command = Command::new(program);
+        command.args(args.iter().map(|arg| arg.replace("{path}", path)));
+        if !args.iter().any(|arg| arg.contains("{path}")) {
+            command.arg(path);
+        }
+        command
+    } else if cfg!(windows) {
+        // not `cmd /C start`: cmd would run what follows a `&` in the path as another command
+        let mut command = Command::new("explorer");
+        command.arg(path);
+        command
+    } else if cfg!(target_os = "macos") {
+        let mut command = Command::new("open");
+        command.arg(path);
+        command
+    } else {
+        let mut command = Command::new("xdg-open");
+        command.arg(path);
+        command
+    };
+
+    // the player's output would draw over the terminal UI
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| AudioError::Config(format!("can't start the player: {}", e)))
+}
+
+fn cell_text(track: &Track, column: usize) -> String {
+    match COLUMNS[column].title {
+        "Title" => track.title().to_string(),
+        "Artist" => track.artist.clone(),
+        "Album" => track.album.clone(),
+        "Year" if track.year > 0 => track.year.to_string(),
+        "Time" => format_duration(track.duration),
+        "Rating" => "\u{2605}".repeat(track.rating as usize),
+        "Plays" => track.play_count.to_string(),
+        _ => String::new(),
+    }
+}
+
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.max(0.0).round() as u64;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+fn focus_style(focused: bool) -> Style {
+    if focused {
+        Style::default().fg(Color::Yellow)
+    } else {
+        Style::default()
+    }
+}
+
+fn move_selection(state: &mut ListState, len: usize, step: isize) {
+    if len == 0 {
+        state.select(None);
+        return;
+    }
+    let current = state.selected().unwrap_or(0) as isize;
+    state.select(Some((current + step).clamp(0, len as isize - 1) as usize));
+}
+
+fn move_table_selection(state: &mut TableState, len: usize, step: isize) {
+    if len == 0 {
+        state.select(None);
+        return;
+    }
+    let current = state.selected().unwrap_or(0) as isize;
+    state.select(Some((current + step).clamp(0, len as isize - 1) as usize));
+}
+
+/// `percent_x` by `percent_y` of `area`, in its middle
+fn centered(area: Rect, percent_x: u16, percent_y: u16) -> Rect {
+    let vertical = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Percentage((100 - percent_y) / 2),
+            Constraint::Percentage(percent_y),
+            Constraint::Percentage((100 - percent_y) / 2),
+        ])
+        .split(area);
+    Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Percentage((100 - percent_x) / 2),
+            Constraint::Percentage(percent_x),
+            Constraint::Percentage((100 - percent_x) / 2),
+        ])
+        .split(vertical[1])[1]
+}
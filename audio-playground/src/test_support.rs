@@ -0,0 +1,112 @@
+//! Fixtures shared by the unit tests: generated tracks, temporary indexes and WAV files
+
+use std::fs;
+use std::path::Path;
+
+use tempfile::TempDir;
+
+use crate::content_kind::ContentKind;
+use crate::library_roots::DEFAULT_ROOT_ID;
+use crate::schema::{SearchWatcher, TrackJson, TrackStatus};
+
+/// A track of `album` by `artist`, `index` makes the id, path and title unique
+pub fn track(index: usize, artist: &str, album: &str, genre: &str, year: u64) -> TrackJson {
+    let track = format!("Song {}", index);
+    TrackJson {
+        id: format!("track-{}", index),
+        abs_path: format!("/music/{}/{}/{:02} {}.mp3", artist, album, index, track),
+        root_id: DEFAULT_ROOT_ID.to_string(),
+        created_date: 1_600_000_000_000 + index as i64 * 60_000,
+        modified_date: 1_600_000_000_000,
+        indexed_date: 1_650_000_000_000,
+        size: 4_000_000,
+        album: album.to_string(),
+        artist: artist.to_string(),
+        artists: vec![artist.to_string()],
+        genre: genre.to_string(),
+        genres: vec![genre.to_string()],
+        name: format!("{:02} {}", index, track),
+        track,
+        duration: 180.0 + index as f64,
+        year,
+        track_number: index as u64,
+        disc_number: 1,
+        // set, so the tests don't depend on `./data/settings.json`
+        content_kind: Some(ContentKind::Music),
+        ..Default::default()
+    }
+}
+
+/// `size` tracks over 4 artists with 2 albums each, years repeat so sorting has ties
+pub fn library(size: usize) -> Vec<TrackJson> {
+    const ARTISTS: [&str; 4] = ["Electric Ghost", "Blue River", "Golden Rain", "Silver Echo"];
+    const GENRES: [&str; 2] = ["Rock", "Jazz"];
+    (0..size)
+        .map(|index| {
+            let artist = ARTISTS[index % ARTISTS.len()];
+            let album = format!("{} {}", if index % 8 < 4 { "Night" } else { "Summer" }, artist);
+            let genre = GENRES[index % GENRES.len()];
+            track(index, artist, &album, genre, 1990 + (index % 5) as u64)
+        })
+        .collect()
+}
+
+/// A read-write handle on an empty index, its stores are kept in the returned directory too
+pub fn search_watcher() -> (TempDir, SearchWatcher) {
+    let directory = TempDir::new().expect("a temporary directory");
+    let path = |name: &str| directory.path().join(name).to_string_lossy().to_string();
+    let search_watcher = SearchWatcher::with_data_directory(&path("index"), &path("data"))
+        .expect("the index can be created");
+    (directory, search_watcher)
+}
+
+/// Adds `tracks` in one commit (so one segment) and rebuilds the albums
+pub fn index_tracks(search_watcher: &SearchWatcher, tracks: &[TrackJson]) {
+    {
+        let mut writer = search_watcher.writer().expect("read-write handle");
+        for track in tracks {
+            writer
+                .add_document(search_watcher.document_for(track, TrackStatus::Online))
+                .expect("the document can be added");
+        }
+        writer.commit().expect("the index can be committed");
+    }
+    search_watcher.reader.reload().expect("the reader can reload");
+    search_watcher.rebuild_albums().expect("the albums can be rebuilt");
+}
+
+/// A 16 bit PCM WAV file of `frames` frames, every sample is the number of its frame (mod 1000)
+/// so decoded positions can be checked
+pub fn write_wav(path: &Path, sample_rate: u32, channels: u16, frames: u32) {
+    write_wav_with(path, sample_rate, channels, frames, |frame| (frame % 1000) as i16);
+}
+
+/// `write_wav` with the sample of every frame (the same on each channel) given by `sample`
+pub fn write_wav_with(
+    path: &Path,
+    sample_rate: u32,
+    channels: u16,
+    frames: u32,
+    sample: impl Fn(u32) -> i16,
+) {
+    let data_size = frames * channels as u32 * 2;
+    let mut bytes = Vec::with_capacity(44 + data_size as usize);
+    bytes.extend_from_slice(b"RIFF");
+    bytes.extend_from_slice(&(36 + data_size).to_le_bytes());
+    bytes.extend_from_slice(b"WAVEfmt ");
+    bytes.extend_from_slice(&16u32.to_le_bytes());
+    bytes.extend_from_slice(&1u16.to_le_bytes());
+    bytes.extend_from_slice(&channels.to_le_bytes());
+    bytes.extend_from_slice(&sample_rate.to_le_bytes());
+    bytes.extend_from_slice(&(sample_rate * channels as u32 * 2).to_le_bytes());
+    bytes.extend_from_slice(&(channels * 2).to_le_bytes());
+    bytes.extend_from_slice(&16u16.to_le_bytes());
+    bytes.extend_from_slice(b"data");
+    bytes.extend_from_slice(&data_size.to_le_bytes());
+    for frame in 0..frames {
+        for _ in 0..channels {
+            bytes.extend_from_slice(&sample(frame).to_le_bytes());
+        }
+    }
+    fs::write(path, bytes).expect("the WAV file can be written");
+}
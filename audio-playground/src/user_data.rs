@@ -0,0 +1,87 @@
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{AudioError, Result};
+use crate::json_store::JsonStore;
+use crate::schema::millis_since_epoch;
+
+pub const USER_DATA_FILE: &str = "./data/user_data.json";
+pub const MAX_RATING: u8 = 5;
+
+/// What we know about a track from actually listening to it. Kept outside of the tantivy
+/// index (keyed by `Track.id`) so it survives the index being rebuilt.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct UserData {
+    pub play_count: u64,
+    /// Milliseconds since the epoch, 0 when never played
+    pub last_played: i64,
+    /// 0 (unrated) to `MAX_RATING`
+    pub rating: u8,
+    pub favourite: bool,
+    /// Seconds into a long-form track (see `ContentKind`) where playback stopped, 0 when it
+    /// was never started or played to the end
+    pub resume_position: f64,
+    /// Milliseconds since the epoch when `resume_position` was saved
+    pub resumed_date: i64,
+}
+
+pub struct UserDataStore {
+    store: JsonStore<UserData>,
+}
+
+impl UserDataStore {
+    pub fn open(user_data_file_path: &str) -> Result<Self> {
+        Ok(UserDataStore {
+            store: JsonStore::open(user_data_file_path)?,
+        })
+    }
+
+    pub fn get(&self, track_id: &str) -> UserData {
+        self.store.get(track_id).unwrap_or_default()
+    }
+
+    pub fn record_play(&self, track_id: &str) -> Result<UserData> {
+        self.store.update(track_id, |user_data| {
+            user_data.play_count += 1;
+            user_data.last_played = millis_since_epoch(SystemTime::now());
+            // played to the end, the next time starts from the beginning
+            user_data.resume_position = 0.0;
+        })
+    }
+
+    pub fn set_resume_position(&self, track_id: &str, position: f64) -> Result<UserData> {
+        self.store.update(track_id, |user_data| {
+            user_data.resume_position = position.max(0.0);
+            user_data.resumed_date = millis_since_epoch(SystemTime::now());
+        })
+    }
+
+    pub fn set_rating(&self, track_id: &str, rating: u8) -> Result<UserData> {
+        if rating > MAX_RATING {
+            return Err(AudioError::Config(format!(
+                "rating must be between 0 and {}, got {}",
+                MAX_RATING, rating
+            )));
+        }
+        self.store
+            .update(track_id, |user_data| user_data.rating = rating)
+    }
+
+    pub fn set_favourite(&self, track_id: &str, favourite: bool) -> Result<UserData> {
+        self.store
+            .update(track_id, |user_data| user_data.favourite = favourite)
+    }
+
+    /// Overwrites everything stored for `track_id`, e.g. when importing from another player
+    pub fn set(&self, track_id: &str, user_data: UserData) -> Result<UserData> {
+        self.store
+            .update(track_id, |existing| *existing = user_data)
+    }
+
+    /// Like `set` for many tracks at once, the file is only written once
+    pub fn set_many(&self, updates: &[(String, UserData)]) -> Result<()> {
+        self.store.set_many(updates)
+    }
+}
@@ -1,6 +1,10 @@
 use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
 
 use crate::schema::{FacetResult, FacetResults, FieldSchema, OrderBy, TheRealBucket};
+use log::warn;
 use regex::Regex;
 use tantivy::{
     aggregation::agg_result::{AggregationResult, AggregationResults},
@@ -28,7 +32,7 @@ pub fn adapt_text(parser: &QueryParser, text: &str) -> String {
             .parse_query(text)
             .map(|_| text.to_string())
             .unwrap_or_else(|e| {
-                println!("Error during parsing query: {e}. Input query: {text}");
+                warn!("Error during parsing query: {e}. Input query: {text}");
                 format!("\"{}\"", text.replace('"', ""))
             }),
     }
@@ -38,6 +42,25 @@ pub fn norm(path: &str) -> String {
     str::replace(path, "\\", "/")
 }
 
+/// Writes to a temp file first and renames it, so a crash mid-write can't leave a truncated file
+pub fn write_atomic(path: &Path, contents: &str) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() && !parent.exists() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, contents)?;
+    if let Err(e) = fs::rename(&tmp_path, path) {
+        warn!("Error replacing {:?}: {}", path, e);
+        fs::copy(&tmp_path, path)?;
+        fs::remove_file(&tmp_path)?;
+    }
+    Ok(())
+}
+
 pub fn get_genre_regex() -> regex::Regex {
     return Regex::new(r#"[/,;]"#).unwrap();
 }
@@ -81,9 +104,18 @@ pub fn genre_string_to_vec(genre: &str) -> Vec<String> {
     genres
 }
 
+/// The name of a genre of `genre_string_to_vec`, which keeps ID3v1 genres as their index
+pub fn genre_name(genre: &str) -> &str {
+    genre
+        .parse::<usize>()
+        .ok()
+        .and_then(|index| ID3V1_GENRES.get(index).copied())
+        .unwrap_or(genre)
+}
+
 pub fn is_valid_facet(maybe_facet: &str) -> bool {
     Facet::from_text(maybe_facet)
-        .map_err(|_| println!("Invalid facet: {maybe_facet}"))
+        .map_err(|_| warn!("Invalid facet: {maybe_facet}"))
         .is_ok()
 }
 
@@ -120,8 +152,14 @@ pub fn get_order_field(
             "created_date" => Some(field_schema.created_date),
             "modified_date" => Some(field_schema.modified_date),
             "indexed_date" => Some(field_schema.indexed_date),
+            "play_count" => Some(field_schema.play_count),
+            "last_played" => Some(field_schema.last_played),
+            "rating" => Some(field_schema.rating),
+            "year" => Some(field_schema.year),
+            "duration" => Some(field_schema.duration),
+            "size" => Some(field_schema.size),
             _ => {
-                println!("Order by {} is not currently supported.", order.field);
+                warn!("Order by {} is not currently supported.", order.field);
                 None
             }
         },